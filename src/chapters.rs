@@ -0,0 +1,751 @@
+//! Chapters of audiobooks, podcasts and other long recordings, read from ID3v2
+//! `CHAP` frames, MP4 chapter tracks and Nero `chpl` boxes, Matroska
+//! `Chapters` and Vorbis comment `CHAPTERnnn` tags.
+//!
+//! symphonia doesn't expose any of these, so the file is read again here. A
+//! file whose chapters can't be read simply has none.
+
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+use symphonia::core::meta::Tag;
+
+/// Largest metadata block read into memory: an MP4 `moov` box or Matroska
+/// `Chapters` element
+const MAX_BLOCK: u64 = 64 << 20;
+
+/// A chapter of a file, with times in milliseconds from its start
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Chapter {
+    pub title: Option<String>,
+    pub start_ms: i64,
+    /// Where the next chapter starts, or the end of the file if last
+    pub end_ms: Option<i64>,
+}
+
+/// Chapters of the file at `path`, in order, falling back on chapter tags
+/// among `tags`. `duration_ms` ends the last chapter if the file doesn't.
+pub fn read(path: &Path, tags: &[Tag], duration_ms: Option<i64>) -> Vec<Chapter> {
+    let chapters = match read_file(path) {
+        Ok(chapters) if !chapters.is_empty() => chapters,
+        _ => from_tags(tags),
+    };
+    finish(chapters, duration_ms)
+}
+
+fn read_file(path: &Path) -> io::Result<Vec<Chapter>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+    match magic {
+        [b'I', b'D', b'3', ..] => id3v2(&mut file),
+        [0x1A, 0x45, 0xDF, 0xA3, ..] => matroska(&mut file),
+        [_, _, _, _, b'f', b't', b'y', b'p'] => mp4(&mut file),
+        _ => Ok(Vec::new()),
+    }
+}
+
+/// Index of the chapter playing at `position_ms`
+pub fn at(chapters: &[Chapter], position_ms: i64) -> Option<usize> {
+    chapters
+        .iter()
+        .rposition(|chapter| chapter.start_ms <= position_ms)
+}
+
+/// Sort chapters and end each where the next begins
+fn finish(mut chapters: Vec<Chapter>, duration_ms: Option<i64>) -> Vec<Chapter> {
+    chapters.sort_by_key(|chapter| chapter.start_ms);
+    chapters.dedup_by_key(|chapter| chapter.start_ms);
+    let starts: Vec<i64> = chapters.iter().map(|chapter| chapter.start_ms).collect();
+    for (i, chapter) in chapters.iter_mut().enumerate() {
+        let next = starts.get(i + 1).copied().or(duration_ms);
+        chapter.end_ms = match (chapter.end_ms, next) {
+            (Some(end), Some(next)) => Some(end.min(next)),
+            (end, next) => end.or(next),
+        }
+        .filter(|&end| end > chapter.start_ms);
+    }
+    chapters
+}
+
+/// Vorbis comment chapters: `CHAPTER001=00:01:02.500` and `CHAPTER001NAME=Title`
+fn from_tags(tags: &[Tag]) -> Vec<Chapter> {
+    let mut chapters = Vec::new();
+    for tag in tags {
+        let key = tag.key.to_ascii_uppercase();
+        let Some(number) = key.strip_prefix("CHAPTER") else {
+            continue;
+        };
+        if number.is_empty() || !number.bytes().all(|b| b.is_ascii_digit()) {
+            continue;
+        }
+        let Some(start_ms) = parse_timestamp(&tag.value.to_string()) else {
+            continue;
+        };
+        let name = format!("{}NAME", key);
+        let title = tags
+            .iter()
+            .find(|tag| tag.key.eq_ignore_ascii_case(&name))
+            .map(|tag| tag.value.to_string());
+        chapters.push(Chapter {
+            title,
+            start_ms,
+            end_ms: None,
+        });
+    }
+    chapters
+}
+
+/// `hh:mm:ss.sss` in milliseconds
+fn parse_timestamp(text: &str) -> Option<i64> {
+    let mut parts = text.trim().split(':');
+    let (hours, minutes, seconds) = (parts.next()?, parts.next()?, parts.next()?);
+    if parts.next().is_some() {
+        return None;
+    }
+    let seconds: f64 = seconds.parse().ok()?;
+    let minutes = hours.parse::<i64>().ok()? * 60 + minutes.parse::<i64>().ok()?;
+    Some(minutes * 60_000 + (seconds * 1000.0).round() as i64)
+}
+
+fn be_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn be_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+/// Read `len` bytes at `offset`, refusing blocks too large to be metadata
+fn read_at(file: &mut File, offset: u64, len: u64) -> io::Result<Vec<u8>> {
+    if len > MAX_BLOCK {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "metadata block too large",
+        ));
+    }
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Text of an ID3v2 or MP4 text sample: Latin-1, UTF-16 with or without a
+/// byte order mark, or UTF-8
+fn decode_text(encoding: u8, bytes: &[u8]) -> String {
+    let text = match encoding {
+        0 => bytes.iter().map(|&b| b as char).collect(),
+        1 | 2 => {
+            let (big_endian, bytes) = match bytes {
+                [0xFF, 0xFE, rest @ ..] => (false, rest),
+                [0xFE, 0xFF, rest @ ..] => (true, rest),
+                _ => (encoding == 2, bytes),
+            };
+            let units: Vec<u16> = bytes
+                .chunks_exact(2)
+                .map(|pair| match big_endian {
+                    true => u16::from_be_bytes([pair[0], pair[1]]),
+                    false => u16::from_le_bytes([pair[0], pair[1]]),
+                })
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(bytes).into_owned(),
+    };
+    text.trim_end_matches('\0').to_owned()
+}
+
+/// ID3v2 `CHAP` frames, titled by their `TIT2` subframe
+fn id3v2(file: &mut File) -> io::Result<Vec<Chapter>> {
+    let mut header = [0u8; 10];
+    file.read_exact(&mut header)?;
+    let version = header[3];
+    let synchsafe = |b: &[u8]| b.iter().fold(0u32, |n, &b| (n << 7) | (b & 0x7F) as u32);
+    let size = synchsafe(&header[6..10]) as u64;
+    // Whole-tag unsynchronisation would need undoing first; it is rare
+    if !(3..=4).contains(&version) || header[5] & 0x80 != 0 {
+        return Ok(Vec::new());
+    }
+    let tag = read_at(file, 10, size)?;
+    let mut start = 0;
+    if header[5] & 0x40 != 0 {
+        start = match version {
+            3 => 4 + be_u32(&tag, 0).unwrap_or(0) as usize,
+            _ => synchsafe(tag.get(0..4).unwrap_or_default()) as usize,
+        };
+    }
+    let frame_size = |bytes: &[u8]| match version {
+        3 => be_u32(bytes, 0),
+        _ => bytes.get(0..4).map(synchsafe),
+    };
+
+    let mut chapters = Vec::new();
+    for (id, body) in id3_frames(tag.get(start..).unwrap_or_default(), frame_size) {
+        if id != *b"CHAP" {
+            continue;
+        }
+        // Element id, then start and end times and byte offsets
+        let Some(nul) = body.iter().position(|&b| b == 0) else {
+            continue;
+        };
+        let (Some(start_ms), Some(end_ms)) = (be_u32(body, nul + 1), be_u32(body, nul + 5)) else {
+            continue;
+        };
+        let subframes = body.get(nul + 17..).unwrap_or_default();
+        let title = id3_frames(subframes, frame_size)
+            .find(|(id, _)| id == b"TIT2")
+            .and_then(|(_, text)| Some(decode_text(*text.first()?, &text[1..])));
+        chapters.push(Chapter {
+            title,
+            start_ms: start_ms.into(),
+            end_ms: (end_ms != u32::MAX).then_some(end_ms.into()),
+        });
+    }
+    Ok(chapters)
+}
+
+/// Frame ids and bodies of an ID3v2 tag, up to the padding
+fn id3_frames(
+    mut bytes: &[u8],
+    frame_size: impl Fn(&[u8]) -> Option<u32>,
+) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let id: [u8; 4] = bytes.get(0..4)?.try_into().ok()?;
+        if id[0] == 0 {
+            return None;
+        }
+        let size = frame_size(bytes.get(4..8)?)? as usize;
+        let body = bytes.get(10..10 + size)?;
+        bytes = &bytes[10 + size..];
+        Some((id, body))
+    })
+}
+
+/// MP4 boxes within `bytes`, by type
+fn mp4_boxes(mut bytes: &[u8]) -> impl Iterator<Item = ([u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let size = be_u32(bytes, 0)? as u64;
+        let kind: [u8; 4] = bytes.get(4..8)?.try_into().ok()?;
+        let (header, size) = match size {
+            0 => (8, bytes.len() as u64),
+            1 => (16, be_u64(bytes, 8)?),
+            size => (8, size),
+        };
+        let size = usize::try_from(size).ok().filter(|&size| size >= header)?;
+        let body = bytes.get(header..size)?;
+        bytes = &bytes[size..];
+        Some((kind, body))
+    })
+}
+
+fn mp4_child<'a>(bytes: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    mp4_boxes(bytes)
+        .find(|(k, _)| k == kind)
+        .map(|(_, body)| body)
+}
+
+/// Chapters of an MP4 file (M4A, M4B): the text track a `chap` track
+/// reference points to, as iTunes writes them, or else a Nero `chpl` box
+fn mp4(file: &mut File) -> io::Result<Vec<Chapter>> {
+    // Find `moov` among the top-level boxes without reading `mdat`
+    let length = file.metadata()?.len();
+    let mut offset = 0;
+    let moov = loop {
+        if offset + 8 > length {
+            return Ok(Vec::new());
+        }
+        let header = read_at(file, offset, 16.min(length - offset))?;
+        let (header_len, size) = match be_u32(&header, 0).unwrap_or(0) as u64 {
+            0 => (8, length - offset),
+            1 => (16, be_u64(&header, 8).unwrap_or(0)),
+            size => (8, size),
+        };
+        if size < header_len {
+            return Ok(Vec::new());
+        }
+        if &header[4..8] == b"moov" {
+            break read_at(file, offset + header_len, size - header_len)?;
+        }
+        offset += size;
+    };
+
+    let tracks: Vec<&[u8]> = mp4_boxes(&moov)
+        .filter(|(kind, _)| kind == b"trak")
+        .map(|(_, body)| body)
+        .collect();
+    let chapter_ids: Vec<u32> = tracks
+        .iter()
+        .filter_map(|trak| mp4_child(mp4_child(trak, b"tref")?, b"chap"))
+        .flat_map(|ids| ids.chunks_exact(4).map(|id| be_u32(id, 0).unwrap_or(0)))
+        .collect();
+    let chapter_track = tracks.iter().find(|trak| {
+        mp4_child(trak, b"tkhd")
+            .and_then(|tkhd| be_u32(tkhd, if tkhd.first() == Some(&1) { 20 } else { 12 }))
+            .is_some_and(|id| chapter_ids.contains(&id))
+    });
+    if let Some(chapters) = chapter_track.and_then(|trak| text_samples(file, trak)) {
+        return Ok(chapters);
+    }
+    let chpl = mp4_child(&moov, b"udta").and_then(|udta| mp4_child(udta, b"chpl"));
+    Ok(chpl.map(nero_chapters).unwrap_or_default())
+}
+
+/// Each sample of a QuickTime text track is a chapter title, lasting as long
+/// as the sample
+fn text_samples(file: &mut File, trak: &[u8]) -> Option<Vec<Chapter>> {
+    let mdia = mp4_child(trak, b"mdia")?;
+    let mdhd = mp4_child(mdia, b"mdhd")?;
+    let timescale = be_u32(mdhd, if mdhd[0] == 1 { 20 } else { 12 })?.max(1) as i64;
+    let stbl = mp4_child(mp4_child(mdia, b"minf")?, b"stbl")?;
+
+    // Sample durations, run-length encoded
+    let stts = mp4_child(stbl, b"stts")?;
+    let mut durations = Vec::new();
+    for entry in 0..be_u32(stts, 4)? as usize {
+        let (count, delta) = (be_u32(stts, 8 + entry * 8)?, be_u32(stts, 12 + entry * 8)?);
+        durations.extend(std::iter::repeat_n(
+            delta as i64,
+            count.min(1 << 16) as usize,
+        ));
+    }
+    let stsz = mp4_child(stbl, b"stsz")?;
+    let sizes: Vec<u32> = match be_u32(stsz, 4)? {
+        0 => (0..be_u32(stsz, 8)? as usize)
+            .map(|i| be_u32(stsz, 12 + i * 4))
+            .collect::<Option<_>>()?,
+        size => vec![size; durations.len()],
+    };
+    let chunks: Vec<u64> = match (mp4_child(stbl, b"stco"), mp4_child(stbl, b"co64")) {
+        (Some(stco), _) => (0..be_u32(stco, 4)? as usize)
+            .map(|i| be_u32(stco, 8 + i * 4).map(u64::from))
+            .collect::<Option<_>>()?,
+        (None, Some(co64)) => (0..be_u32(co64, 4)? as usize)
+            .map(|i| be_u64(co64, 8 + i * 8))
+            .collect::<Option<_>>()?,
+        _ => return None,
+    };
+    // Samples per chunk, for runs of chunks starting at each entry's chunk
+    let stsc = mp4_child(stbl, b"stsc")?;
+    let runs: Vec<(u32, u32)> = (0..be_u32(stsc, 4)? as usize)
+        .map(|i| Some((be_u32(stsc, 8 + i * 12)?, be_u32(stsc, 12 + i * 12)?)))
+        .collect::<Option<_>>()?;
+
+    let mut offsets = Vec::with_capacity(sizes.len());
+    for (index, &chunk) in chunks.iter().enumerate() {
+        let number = index as u32 + 1;
+        let per_chunk = runs
+            .iter()
+            .rev()
+            .find(|(first, _)| *first <= number)
+            .map_or(1, |(_, samples)| *samples);
+        let mut offset = chunk;
+        for _ in 0..per_chunk {
+            let Some(&size) = sizes.get(offsets.len()) else {
+                break;
+            };
+            offsets.push((offset, size));
+            offset += size as u64;
+        }
+    }
+
+    let mut chapters = Vec::new();
+    let mut time = 0;
+    for ((offset, size), duration) in offsets.into_iter().zip(durations) {
+        let sample = read_at(file, offset, size.into()).ok()?;
+        let len = sample
+            .get(0..2)
+            .map_or(0, |len| u16::from_be_bytes([len[0], len[1]]) as usize);
+        let text = sample.get(2..2 + len).unwrap_or_default();
+        let encoding = if text.starts_with(&[0xFE, 0xFF]) {
+            1
+        } else {
+            3
+        };
+        chapters.push(Chapter {
+            title: Some(decode_text(encoding, text)).filter(|title| !title.is_empty()),
+            start_ms: time * 1000 / timescale,
+            end_ms: Some((time + duration) * 1000 / timescale),
+        });
+        time += duration;
+    }
+    Some(chapters)
+}
+
+/// A Nero `chpl` box: start times in 100 ns units, each with a title
+fn nero_chapters(chpl: &[u8]) -> Vec<Chapter> {
+    let mut at = if chpl.first().is_some_and(|&version| version > 0) {
+        8
+    } else {
+        4
+    };
+    let count = chpl.get(at).copied().unwrap_or(0);
+    at += 1;
+    let mut chapters = Vec::new();
+    for _ in 0..count {
+        let (Some(start), Some(&len)) = (be_u64(chpl, at), chpl.get(at + 8)) else {
+            break;
+        };
+        let title = chpl.get(at + 9..at + 9 + len as usize).unwrap_or_default();
+        chapters.push(Chapter {
+            title: Some(decode_text(3, title)).filter(|title| !title.is_empty()),
+            start_ms: (start / 10_000) as i64,
+            end_ms: None,
+        });
+        at += 9 + len as usize;
+    }
+    chapters
+}
+
+const EBML_SEGMENT: u32 = 0x1853_8067;
+const EBML_SEEK_HEAD: u32 = 0x114D_9B74;
+const EBML_SEEK: u32 = 0x4DBB;
+const EBML_SEEK_ID: u32 = 0x53AB;
+const EBML_SEEK_POSITION: u32 = 0x53AC;
+const EBML_CLUSTER: u32 = 0x1F43_B675;
+const EBML_CHAPTERS: u32 = 0x1043_A770;
+const EBML_EDITION: u32 = 0x45B9;
+const EBML_EDITION_DEFAULT: u32 = 0x45DB;
+const EBML_ATOM: u32 = 0xB6;
+const EBML_TIME_START: u32 = 0x91;
+const EBML_TIME_END: u32 = 0x92;
+const EBML_HIDDEN: u32 = 0x98;
+const EBML_DISPLAY: u32 = 0x80;
+const EBML_STRING: u32 = 0x85;
+
+/// An EBML variable-length integer: its value and length. Element ids keep
+/// their length marker; sizes don't, and all ones means unknown.
+fn ebml_vint(bytes: &[u8], keep_marker: bool) -> Option<(u64, usize)> {
+    let first = *bytes.first()?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+    let mut value = match keep_marker {
+        true => first as u64,
+        false => (first as u64) & (0xFF >> len),
+    };
+    for &byte in bytes.get(1..len)? {
+        value = (value << 8) | byte as u64;
+    }
+    if !keep_marker && value == (1 << (7 * len)) - 1 {
+        value = u64::MAX;
+    }
+    Some((value, len))
+}
+
+/// An element header: id, body size (`u64::MAX` if unknown) and header length
+fn ebml_header(bytes: &[u8]) -> Option<(u32, u64, usize)> {
+    let (id, id_len) = ebml_vint(bytes, true)?;
+    let (size, size_len) = ebml_vint(bytes.get(id_len..)?, false)?;
+    Some((id as u32, size, id_len + size_len))
+}
+
+/// Child elements within `bytes`, by id
+fn ebml_children(mut bytes: &[u8]) -> impl Iterator<Item = (u32, &[u8])> {
+    std::iter::from_fn(move || {
+        let (id, size, header) = ebml_header(bytes)?;
+        let end = header.checked_add(usize::try_from(size).ok()?)?;
+        let body = bytes.get(header..end)?;
+        bytes = &bytes[end..];
+        Some((id, body))
+    })
+}
+
+fn ebml_uint(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |n, &b| (n << 8) | b as u64)
+}
+
+fn read_header(file: &mut File, offset: u64) -> io::Result<Option<(u32, u64, usize)>> {
+    let mut buf = [0u8; 12];
+    file.seek(SeekFrom::Start(offset))?;
+    let read = file.read(&mut buf)?;
+    Ok(ebml_header(&buf[..read]))
+}
+
+/// Matroska (MKA, MKV, WebM) chapters from the default edition: its
+/// top-level chapter atoms that aren't hidden
+fn matroska(file: &mut File) -> io::Result<Vec<Chapter>> {
+    let length = file.metadata()?.len();
+    // The EBML header, then the segment
+    let Some((_, size, header)) = read_header(file, 0)? else {
+        return Ok(Vec::new());
+    };
+    let segment_at = header as u64 + size;
+    let Some((EBML_SEGMENT, _, header)) = read_header(file, segment_at)? else {
+        return Ok(Vec::new());
+    };
+    let start = segment_at + header as u64;
+
+    // Walk the segment's children up to the first cluster, following the
+    // seek head if chapters come later
+    let mut offset = start;
+    let mut chapters_at = None;
+    while chapters_at.is_none() && offset < length {
+        let Some((id, size, header)) = read_header(file, offset)? else {
+            break;
+        };
+        match id {
+            EBML_CHAPTERS => chapters_at = Some(offset),
+            EBML_SEEK_HEAD => {
+                let seek_head = read_at(file, offset + header as u64, size)?;
+                chapters_at = ebml_children(&seek_head)
+                    .filter(|(id, _)| *id == EBML_SEEK)
+                    .find_map(|(_, seek)| {
+                        let mut target = None;
+                        let mut position = None;
+                        for (id, body) in ebml_children(seek) {
+                            match id {
+                                EBML_SEEK_ID => target = Some(ebml_uint(body) as u32),
+                                EBML_SEEK_POSITION => position = Some(ebml_uint(body)),
+                                _ => {}
+                            }
+                        }
+                        (target == Some(EBML_CHAPTERS)).then_some(start + position?)
+                    });
+            }
+            EBML_CLUSTER => break,
+            _ => {}
+        }
+        if size == u64::MAX {
+            break;
+        }
+        offset += header as u64 + size;
+    }
+    let Some(offset) = chapters_at else {
+        return Ok(Vec::new());
+    };
+    let Some((EBML_CHAPTERS, size, header)) = read_header(file, offset)? else {
+        return Ok(Vec::new());
+    };
+    let element = read_at(file, offset + header as u64, size)?;
+
+    let editions: Vec<&[u8]> = ebml_children(&element)
+        .filter(|(id, _)| *id == EBML_EDITION)
+        .map(|(_, body)| body)
+        .collect();
+    let edition = editions
+        .iter()
+        .find(|edition| {
+            ebml_children(edition)
+                .any(|(id, body)| id == EBML_EDITION_DEFAULT && ebml_uint(body) == 1)
+        })
+        .or(editions.first());
+    let Some(edition) = edition else {
+        return Ok(Vec::new());
+    };
+
+    let mut chapters = Vec::new();
+    for (_, atom) in ebml_children(edition).filter(|(id, _)| *id == EBML_ATOM) {
+        let mut chapter = Chapter {
+            title: None,
+            start_ms: 0,
+            end_ms: None,
+        };
+        let mut hidden = false;
+        for (id, body) in ebml_children(atom) {
+            match id {
+                // Nanoseconds
+                EBML_TIME_START => chapter.start_ms = (ebml_uint(body) / 1_000_000) as i64,
+                EBML_TIME_END => chapter.end_ms = Some((ebml_uint(body) / 1_000_000) as i64),
+                EBML_HIDDEN => hidden = ebml_uint(body) == 1,
+                EBML_DISPLAY if chapter.title.is_none() => {
+                    chapter.title = ebml_children(body)
+                        .find(|(id, _)| *id == EBML_STRING)
+                        .map(|(_, text)| decode_text(3, text));
+                }
+                _ => {}
+            }
+        }
+        if !hidden {
+            chapters.push(chapter);
+        }
+    }
+    Ok(chapters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::meta::Value;
+    use tempfile::tempdir;
+
+    fn chapter(title: &str, start_ms: i64, end_ms: Option<i64>) -> Chapter {
+        Chapter {
+            title: Some(title.into()),
+            start_ms,
+            end_ms,
+        }
+    }
+
+    fn id3_frame(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut frame = id.to_vec();
+        frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    fn ebml(id: &[u8], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        match body.len() {
+            len @ 0..0x7F => out.push(0x80 | len as u8),
+            len => out.extend_from_slice(&(0x4000 | len as u16).to_be_bytes()),
+        }
+        out.extend_from_slice(body);
+        out
+    }
+
+    #[test]
+    fn test_id3_and_tag_chapters() {
+        let dir = tempdir().unwrap();
+        let mut frames = Vec::new();
+        // Out of order, the second with a UTF-16 title
+        for (id, start, end, title) in [
+            (
+                &b"ch1"[..],
+                60_000u32,
+                90_000u32,
+                &b"\x01\xFF\xFEO\0u\0t\0"[..],
+            ),
+            (b"ch0", 0, 60_000, b"\x03Intro"),
+        ] {
+            let mut body = id.to_vec();
+            body.push(0);
+            for n in [start, end, u32::MAX, u32::MAX] {
+                body.extend_from_slice(&n.to_be_bytes());
+            }
+            body.extend_from_slice(&id3_frame(b"TIT2", title));
+            frames.extend_from_slice(&id3_frame(b"CHAP", &body));
+        }
+        frames.extend_from_slice(&[0; 16]);
+        let mut tag = b"ID3\x03\x00\x00".to_vec();
+        let size = frames.len() as u32;
+        tag.extend((0..4).rev().map(|i| ((size >> (7 * i)) & 0x7F) as u8));
+        tag.extend_from_slice(&frames);
+        let path = dir.path().join("podcast.mp3");
+        std::fs::write(&path, &tag).unwrap();
+
+        assert_eq!(
+            read(&path, &[], Some(100_000)),
+            vec![
+                chapter("Intro", 0, Some(60_000)),
+                chapter("Out", 60_000, Some(90_000))
+            ]
+        );
+
+        let tags = [
+            Tag::new(None, "CHAPTER001", Value::from("00:00:00.000")),
+            Tag::new(None, "CHAPTER001NAME", Value::from("One")),
+            Tag::new(None, "CHAPTER002", Value::from("00:01:30.250")),
+        ];
+        let chapters = read(&dir.path().join("missing.ogg"), &tags, None);
+        assert_eq!(chapters[0], chapter("One", 0, Some(90_250)));
+        assert_eq!(
+            (chapters[1].title.as_ref(), chapters[1].end_ms),
+            (None, None)
+        );
+        assert_eq!(at(&chapters, 90_249), Some(0));
+        assert_eq!(at(&chapters, 100_000), Some(1));
+    }
+
+    #[test]
+    fn test_mp4_and_matroska_chapters() {
+        let dir = tempdir().unwrap();
+        let mut chpl = vec![1, 0, 0, 0, 0, 0, 0, 0, 2];
+        for (start, title) in [(0u64, "Start"), (125_000_000, "Middle")] {
+            chpl.extend_from_slice(&start.to_be_bytes());
+            chpl.push(title.len() as u8);
+            chpl.extend_from_slice(title.as_bytes());
+        }
+        let mut mp4 = mp4_box(b"ftyp", b"M4B \0\0\0\0");
+        mp4.extend_from_slice(&mp4_box(b"mdat", &[0; 32]));
+        mp4.extend_from_slice(&mp4_box(
+            b"moov",
+            &mp4_box(b"udta", &mp4_box(b"chpl", &chpl)),
+        ));
+        let path = dir.path().join("book.m4b");
+        std::fs::write(&path, &mp4).unwrap();
+        assert_eq!(
+            read(&path, &[], Some(20_000)),
+            vec![
+                chapter("Start", 0, Some(12_500)),
+                chapter("Middle", 12_500, Some(20_000))
+            ]
+        );
+
+        // iTunes style: a text track the audio track points to, which wins
+        let ftyp = mp4_box(b"ftyp", b"M4A \0\0\0\0");
+        let mut samples = Vec::new();
+        for title in ["Prologue", "Part One"] {
+            samples.extend_from_slice(&(title.len() as u16).to_be_bytes());
+            samples.extend_from_slice(title.as_bytes());
+        }
+        let first = ftyp.len() as u32 + 8;
+        let full = |fields: &[u32]| -> Vec<u8> {
+            let mut body = vec![0; 4];
+            fields
+                .iter()
+                .for_each(|n| body.extend_from_slice(&n.to_be_bytes()));
+            body
+        };
+        let trak = |id: u32, extra: Vec<u8>| {
+            let mut body = mp4_box(b"tkhd", &full(&[0, 0, id]));
+            body.extend_from_slice(&extra);
+            mp4_box(b"trak", &body)
+        };
+        let mut stbl = mp4_box(b"stts", &full(&[1, 2, 3000]));
+        stbl.extend_from_slice(&mp4_box(b"stsz", &full(&[0, 2, 10, 10])));
+        stbl.extend_from_slice(&mp4_box(b"stsc", &full(&[1, 1, 2, 1])));
+        stbl.extend_from_slice(&mp4_box(b"stco", &full(&[1, first])));
+        let mut mdia = mp4_box(b"mdhd", &full(&[0, 0, 1000, 6000]));
+        mdia.extend_from_slice(&mp4_box(b"minf", &mp4_box(b"stbl", &stbl)));
+        let mut moov = trak(1, mp4_box(b"tref", &mp4_box(b"chap", &2u32.to_be_bytes())));
+        moov.extend_from_slice(&trak(2, mp4_box(b"mdia", &mdia)));
+        moov.extend_from_slice(&mp4_box(b"udta", &mp4_box(b"chpl", &chpl)));
+        let mut mp4 = ftyp;
+        mp4.extend_from_slice(&mp4_box(b"mdat", &samples));
+        mp4.extend_from_slice(&mp4_box(b"moov", &moov));
+        std::fs::write(&path, &mp4).unwrap();
+        assert_eq!(
+            read(&path, &[], None),
+            vec![
+                chapter("Prologue", 0, Some(3_000)),
+                chapter("Part One", 3_000, Some(6_000))
+            ]
+        );
+
+        let atom = |start_ns: u32, title: &str, hidden: u8| {
+            let mut body = ebml(&[0x91], &start_ns.to_be_bytes());
+            body.extend_from_slice(&ebml(&[0x98], &[hidden]));
+            body.extend_from_slice(&ebml(&[0x80], &ebml(&[0x85], title.as_bytes())));
+            ebml(&[0xB6], &body)
+        };
+        let mut edition = atom(0, "Opening", 0);
+        edition.extend_from_slice(&atom(3_000_000_000, "Hidden", 1));
+        edition.extend_from_slice(&atom(4_000_000_000, "Closing", 0));
+        let chapters = ebml(&[0x10, 0x43, 0xA7, 0x70], &ebml(&[0x45, 0xB9], &edition));
+        let mut segment = ebml(&[0x15, 0x49, 0xA9, 0x66], &[]);
+        segment.extend_from_slice(&chapters);
+        segment.extend_from_slice(&ebml(&[0x1F, 0x43, 0xB6, 0x75], &[0; 8]));
+        let mut mkv = ebml(&[0x1A, 0x45, 0xDF, 0xA3], &ebml(&[0x42, 0x82], b"matroska"));
+        mkv.extend_from_slice(&ebml(&[0x18, 0x53, 0x80, 0x67], &segment));
+        let path = dir.path().join("talk.mka");
+        std::fs::write(&path, &mkv).unwrap();
+        assert_eq!(
+            read(&path, &[], None),
+            vec![
+                chapter("Opening", 0, Some(4_000)),
+                chapter("Closing", 4_000, None)
+            ]
+        );
+    }
+}
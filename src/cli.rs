@@ -1,19 +1,117 @@
-use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use anyhow::Result;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::time::{Duration, Instant};
 
-use crate::player::{Player, PlayerError};
+use crate::config::{self, Config, LibraryConfig, ScrobblingConfig};
+use crate::cue;
+use crate::db::{
+    DB, DbOptions, Expr, Field, Love, Page, Playlist, PlaylistOrder, Ranked, SmartRules, SortKey,
+    StatsReport, Track, Window, civil_from_days, now,
+};
+use crate::formats;
+use crate::player::{Player, PlayerOptions};
+use crate::playlist;
+use crate::probe::{self, FileInfo};
+use crate::resume;
+use crate::scanner::{self, Progress, ScanOptions};
+use crate::scrobble::{self, LastFm, ListenBrainz, Scrobbler};
+use crate::tags;
+use crate::watcher::{self, LibraryWatcher};
 
 #[derive(Parser, Debug)]
 #[command(name = "rustyplayer", version, about = "A small Rust media player MVP")]
 pub struct Cli {
+    /// Settings file; defaults to ~/.config/rustyplayer/config.toml
+    #[arg(long, global = true, env = "RUSTYPLAYER_CONFIG")]
+    config: Option<PathBuf>,
+    #[command(flatten)]
+    database: DatabaseArgs,
     #[command(subcommand)]
     command: Commands,
 }
 
+#[derive(Args, Debug)]
+struct DatabaseArgs {
+    /// Path to the library database; defaults to
+    /// ~/.local/share/rustyplayer/library.db
+    #[arg(long, global = true, env = "RUSTYPLAYER_DB")]
+    db: Option<PathBuf>,
+    /// Milliseconds to wait for another process to release the database
+    /// [default: 5000]
+    #[arg(long, global = true, env = "RUSTYPLAYER_BUSY_TIMEOUT")]
+    busy_timeout: Option<u64>,
+}
+
+/// The library database, as chosen by flags, environment and settings
+struct Database {
+    path: PathBuf,
+    options: DbOptions,
+}
+
+impl Database {
+    fn new(args: DatabaseArgs, settings: &LibraryConfig) -> Result<Self> {
+        let path = match args.db.or_else(|| settings.db.clone()) {
+            Some(path) => path,
+            None => config::default_db_path()?,
+        };
+        let mut options = DbOptions::default();
+        if let Some(ms) = args.busy_timeout.or(settings.busy_timeout) {
+            options.busy_timeout = Duration::from_millis(ms);
+        }
+        Ok(Self { path, options })
+    }
+
+    fn open_quietly(&self) -> Result<DB> {
+        if let Some(dir) = self.path.parent()
+            && !dir.as_os_str().is_empty()
+            && !self.path.exists()
+        {
+            std::fs::create_dir_all(dir)?;
+        }
+        DB::open_with(&self.path, &self.options)
+    }
+
+    /// Open the library, warning if it could only be opened read-only
+    fn open(&self) -> Result<DB> {
+        let db = self.open_quietly()?;
+        if db.is_read_only() {
+            eprintln!(
+                "Warning: {} is not writable; opened read-only, so changes won't be saved",
+                self.path.display()
+            );
+        }
+        Ok(db)
+    }
+
+    /// Open the library for a command whose whole job is to update it
+    fn open_writable(&self) -> Result<DB> {
+        let db = self.open_quietly()?;
+        if db.is_read_only() {
+            anyhow::bail!(
+                "Can't update the library: {} is not writable",
+                self.path.display()
+            );
+        }
+        Ok(db)
+    }
+}
+
 #[derive(Subcommand, Debug)]
 enum Commands {
-    /// Play a file
-    Play { path: PathBuf },
+    /// Play a file, or queue every entry of an M3U, M3U8, PLS or XSPF playlist
+    Play {
+        path: PathBuf,
+        /// Audio stream to play from a file with several, counting from 0
+        #[arg(long)]
+        audio_track: Option<usize>,
+        /// Play the audio stream in this language, e.g. `en` or `fre`
+        #[arg(long)]
+        language: Option<String>,
+    },
     /// Pause playback
     Pause,
     /// Resume playback
@@ -22,40 +120,1407 @@ enum Commands {
     Stop,
     /// Seek to position (in seconds)
     Seek { seconds: u64 },
-    /// Scan a directory (import into library)
-    Scan { path: PathBuf },
+    /// Skip to the next chapter of an audiobook or other long file, in the
+    /// player of a running `serve`
+    #[cfg(feature = "server")]
+    NextChapter {
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Go back to the start of the chapter, or to the previous one near its start
+    #[cfg(feature = "server")]
+    PrevChapter {
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Jump to chapter N, counting from 1
+    #[cfg(feature = "server")]
+    Chapter {
+        number: usize,
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    /// Show what the decoder makes of a file: container, codec parameters, tags,
+    /// embedded pictures and any errors from decoding it to the end
+    #[command(visible_alias = "probe")]
+    Info {
+        path: PathBuf,
+        /// Print the details as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Scan a directory (import into library); Ctrl-C stops it and the next scan
+    /// resumes where it left off
+    Scan {
+        /// Defaults to the library roots in the config file
+        path: Option<PathBuf>,
+        /// Files to probe at once; defaults to the number of CPUs
+        #[arg(long, short)]
+        jobs: Option<usize>,
+        /// Read every file again, not just new and modified ones
+        #[arg(long)]
+        full: bool,
+    },
+    /// Keep the library in sync with directories as files are added, changed,
+    /// renamed or deleted
+    Watch {
+        /// Defaults to the library roots in the config file
+        roots: Vec<PathBuf>,
+    },
+    /// Search the library by title, artist, album, genre, comment or file name
+    Search {
+        query: String,
+        /// Maximum number of results
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+    },
+    /// List library tracks matching a query such as
+    /// 'artist:"Boards of Canada" year:>=1998 -genre:live played:<30d'
+    Library {
+        /// Query; bare words are full-text searches, `field:value` filters a field
+        #[arg(allow_hyphen_values = true, default_value = "")]
+        query: String,
+        /// Comma-separated sort keys; prefix with `-` to reverse, or use `random`
+        #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+        sort: Vec<SortKey>,
+        /// Group tracks under a heading per album or artist
+        #[arg(long)]
+        group: Option<Group>,
+        /// Comma-separated columns to print
+        #[arg(long, value_delimiter = ',', default_value = "id,artist,title,album")]
+        columns: Vec<Field>,
+        /// Maximum number of tracks
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Show or set the rating of a library track, e.g. `rate song.flac 4.5`
+    Rate {
+        /// Track file path or library id
+        track: String,
+        /// Stars from 0 to 5 in half steps
+        rating: Option<f64>,
+        /// Clear the rating, leaving the track unrated
+        #[arg(long, conflicts_with = "rating")]
+        clear: bool,
+        /// Mark the track as loved, banned or neutral
+        #[arg(long)]
+        loved: Option<Love>,
+        /// Also write the rating to the file's POPM and FMPS_Rating tags (MP3 and FLAC)
+        #[arg(long)]
+        write_tags: bool,
+    },
+    /// Report listening statistics: top tracks, artists, albums and genres,
+    /// listening time, streaks and when you listen
+    Stats {
+        /// Start of the period, as an age such as `30d` or a date such as `2024-01-01`
+        #[arg(long)]
+        since: Option<String>,
+        /// End of the period, in the same forms
+        #[arg(long)]
+        until: Option<String>,
+        /// Entries per list
+        #[arg(long, default_value_t = 10)]
+        limit: usize,
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Name positions in audiobooks and other long files, and jump back to them
+    Bookmark {
+        #[command(subcommand)]
+        command: BookmarkCommand,
+    },
+    /// Manage static and smart playlists
+    Playlist {
+        #[command(subcommand)]
+        command: PlaylistCommand,
+    },
+    /// Show or submit listens waiting for Last.fm and ListenBrainz
+    Scrobble {
+        #[command(subcommand)]
+        command: ScrobbleCommand,
+        #[command(flatten)]
+        services: ScrobbleArgs,
+    },
+    /// Show or change settings in the config file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Serve the HTTP/JSON API and WebSocket event feed
+    #[cfg(feature = "server")]
+    Serve {
+        /// Address to listen on [default: 127.0.0.1:8080]
+        #[arg(long)]
+        listen: Option<String>,
+        /// Bearer token clients must send
+        #[arg(long, env = "RUSTYPLAYER_API_TOKEN", hide_env_values = true)]
+        token: Option<String>,
+        /// Also serve the Subsonic API under /rest/ for this user
+        #[arg(long)]
+        subsonic_user: Option<String>,
+        /// Password for the Subsonic user
+        #[arg(long, env = "RUSTYPLAYER_SUBSONIC_PASSWORD", hide_env_values = true)]
+        subsonic_password: Option<String>,
+        /// ffmpeg binary used to transcode Subsonic streams on request
+        #[arg(long)]
+        transcoder: Option<PathBuf>,
+        /// Keep the library in sync with this directory while serving; repeatable.
+        /// Defaults to the library roots in the config file.
+        #[arg(long = "watch", value_name = "DIR")]
+        watch: Vec<PathBuf>,
+        #[command(flatten)]
+        scrobbling: ScrobbleArgs,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    /// Print a setting, such as `playback.volume`
+    Get { key: String },
+    /// Change a setting; lists are written as TOML, as in '["~/Music"]'
+    Set { key: String, value: String },
+    /// Open the config file in $VISUAL or $EDITOR
+    Edit,
+}
+
+#[derive(Subcommand, Debug)]
+enum ScrobbleCommand {
+    /// Show how many listens are waiting for each service
+    Status,
+    /// Submit waiting listens now, ignoring any backoff
+    Flush,
+}
+
+/// The running `serve` whose player the chapter commands control
+#[cfg(feature = "server")]
+#[derive(Args, Debug)]
+struct RemoteArgs {
+    /// Address the server listens on [default: server.listen, or 127.0.0.1:8080]
+    #[arg(long)]
+    server: Option<String>,
+    /// Bearer token of the server [default: server.token]
+    #[arg(long, env = "RUSTYPLAYER_API_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+}
+
+#[cfg(feature = "server")]
+impl RemoteArgs {
+    /// POST to a player endpoint of the server and read back the status
+    fn post(
+        self,
+        settings: &config::ServerConfig,
+        endpoint: &str,
+        body: serde_json::Value,
+    ) -> Result<RemoteStatus> {
+        let server = self
+            .server
+            .or_else(|| settings.listen.clone())
+            .unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+        let Some(token) = self.token.or_else(|| settings.token.clone()) else {
+            anyhow::bail!("An API token is needed: --token, RUSTYPLAYER_API_TOKEN or server.token");
+        };
+        let url = format!("http://{}/api/v1/player/{}", server, endpoint);
+        let response = ureq::post(&url)
+            .timeout(Duration::from_secs(10))
+            .set("Authorization", &format!("Bearer {}", token))
+            .set("Content-Type", "application/json")
+            .send_string(&body.to_string());
+        match response {
+            Ok(response) => Ok(serde_json::from_str(&response.into_string()?)?),
+            // The server explains what went wrong in the body
+            Err(ureq::Error::Status(status, response)) => {
+                let error = response
+                    .into_string()
+                    .ok()
+                    .and_then(|body| serde_json::from_str::<serde_json::Value>(&body).ok())
+                    .and_then(|body| body["error"].as_str().map(String::from))
+                    .unwrap_or_else(|| format!("status {}", status));
+                anyhow::bail!("The server refused: {}", error)
+            }
+            Err(e) => anyhow::bail!(
+                "Can't reach the player at {} (is `serve` running?): {}",
+                server,
+                e
+            ),
+        }
+    }
+}
+
+/// The parts of the server's player status the chapter commands print
+#[cfg(feature = "server")]
+#[derive(serde::Deserialize)]
+struct RemoteStatus {
+    chapters: Vec<serde::de::IgnoredAny>,
+    chapter: Option<RemoteChapter>,
+}
+
+#[cfg(feature = "server")]
+#[derive(serde::Deserialize)]
+struct RemoteChapter {
+    index: usize,
+    title: Option<String>,
+    position_secs: Option<u64>,
+    duration_secs: Option<u64>,
+}
+
+/// Scrobbling service accounts; a service is used when its credentials are given
+#[derive(Args, Debug)]
+struct ScrobbleArgs {
+    /// Last.fm API key
+    #[arg(long, env = "RUSTYPLAYER_LASTFM_API_KEY")]
+    lastfm_api_key: Option<String>,
+    /// Last.fm API shared secret
+    #[arg(long, env = "RUSTYPLAYER_LASTFM_API_SECRET", hide_env_values = true)]
+    lastfm_api_secret: Option<String>,
+    /// Last.fm session key authorising this application
+    #[arg(long, env = "RUSTYPLAYER_LASTFM_SESSION_KEY", hide_env_values = true)]
+    lastfm_session_key: Option<String>,
+    /// Last.fm API endpoint
+    #[arg(long, env = "RUSTYPLAYER_LASTFM_URL")]
+    lastfm_url: Option<String>,
+    /// ListenBrainz user token
+    #[arg(long, env = "RUSTYPLAYER_LISTENBRAINZ_TOKEN", hide_env_values = true)]
+    listenbrainz_token: Option<String>,
+    /// ListenBrainz API root
+    #[arg(long, env = "RUSTYPLAYER_LISTENBRAINZ_URL")]
+    listenbrainz_url: Option<String>,
+}
+
+impl ScrobbleArgs {
+    /// Combine the accounts given here with those in the config file
+    fn scrobbler(self, settings: ScrobblingConfig) -> Result<Scrobbler> {
+        let mut services: Vec<Box<dyn scrobble::Service>> = Vec::new();
+        match (
+            self.lastfm_api_key.or(settings.lastfm_api_key),
+            self.lastfm_api_secret.or(settings.lastfm_api_secret),
+            self.lastfm_session_key.or(settings.lastfm_session_key),
+        ) {
+            (Some(api_key), Some(api_secret), Some(session_key)) => {
+                services.push(Box::new(LastFm {
+                    url: self
+                        .lastfm_url
+                        .or(settings.lastfm_url)
+                        .unwrap_or_else(|| scrobble::LASTFM_URL.to_owned()),
+                    api_key,
+                    api_secret,
+                    session_key,
+                }));
+            }
+            (None, None, None) => {}
+            _ => anyhow::bail!("Last.fm needs an API key, an API secret and a session key"),
+        }
+        if let Some(token) = self.listenbrainz_token.or(settings.listenbrainz_token) {
+            services.push(Box::new(ListenBrainz {
+                url: self
+                    .listenbrainz_url
+                    .or(settings.listenbrainz_url)
+                    .unwrap_or_else(|| scrobble::LISTENBRAINZ_URL.to_owned()),
+                token,
+            }));
+        }
+        Ok(Scrobbler::new(services))
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum BookmarkCommand {
+    /// Bookmark a position in a library track
+    Add {
+        /// Track file path or library id
+        track: String,
+        name: String,
+        /// Position as seconds or [h:]mm:ss
+        #[arg(long, value_parser = parse_clock)]
+        at: Duration,
+    },
+    /// List the bookmarks of a track
+    List { track: String },
+    /// Play a track from a bookmark
+    Play { id: i64 },
+    /// Delete a bookmark
+    Delete { id: i64 },
+}
+
+#[derive(Subcommand, Debug)]
+enum PlaylistCommand {
+    /// List playlists
+    List,
+    /// Create a playlist; with --query it is a smart playlist that updates itself
+    Create {
+        name: String,
+        /// Rules in the `library` query language, e.g. 'rating:>=4 -played:<60d'
+        #[arg(long, allow_hyphen_values = true)]
+        query: Option<String>,
+        /// library, random, most-played, most-played:<age>, recently-added or recently-played
+        #[arg(long, requires = "query")]
+        order: Option<PlaylistOrder>,
+        /// Keep only this many tracks
+        #[arg(long, requires = "query")]
+        limit: Option<usize>,
+    },
+    /// Change the rules of a smart playlist
+    Edit {
+        playlist: String,
+        #[arg(long, allow_hyphen_values = true)]
+        query: Option<String>,
+        #[arg(long)]
+        order: Option<PlaylistOrder>,
+        /// Keep only this many tracks; 0 removes the limit
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Print the tracks of a playlist
+    Show { playlist: String },
+    /// Queue a playlist and start playing it
+    Play { playlist: String },
+    /// Write a playlist to a file, or to stdout
+    Export {
+        playlist: String,
+        output: Option<PathBuf>,
+        /// m3u, m3u8, pls or xspf; guessed from the output file name by default
+        #[arg(long)]
+        format: Option<playlist::Format>,
+    },
+    /// Create a static playlist from an M3U, M3U8, PLS or XSPF file
+    Import {
+        file: PathBuf,
+        /// Playlist name; defaults to the file name
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Delete a playlist
+    Delete { playlist: String },
 }
 
-pub fn run() -> Result<(), PlayerError> {
-    let cli = Cli::parse();
-    let player = Player::new()?;
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Group {
+    Album,
+    Artist,
+}
+
+pub fn run() -> Result<()> {
+    let Cli {
+        config: config_path,
+        database,
+        command,
+    } = Cli::parse();
+    let config_path = match config_path {
+        Some(path) => path,
+        None => config::default_config_path()?,
+    };
+    // Settings can be fixed even when the file no longer reads
+    let command = match command {
+        Commands::Config { command } => return run_config(&config_path, command),
+        command => command,
+    };
+    let settings = Config::load(&config_path)?;
+    formats::set_soundfont(settings.playback.soundfont.clone());
+    formats::set_dsd_rate(settings.playback.dsd_rate);
+    let database = Database::new(database, &settings.library)?;
+    let mut player_options = settings.player_options();
 
-    match cli.command {
-        Commands::Play { path } => {
-            player.play(&path)?;
+    // Only playback commands open the audio device
+    if let Commands::Play {
+        audio_track,
+        language,
+        ..
+    } = &command
+    {
+        player_options.stream.index = *audio_track;
+        player_options.stream.language = language.clone().or(player_options.stream.language);
+    }
+    match command {
+        Commands::Play { path, .. } if playlist::Format::from_path(&path).is_some() => {
+            let resolved = read_playlist_file(&database.open()?, &path)?;
+            let files: Vec<&Path> = resolved.iter().filter_map(|r| r.path.as_deref()).collect();
+            let player = Player::with_options(&player_options)?;
+            for file in &files {
+                player.enqueue(file);
+            }
+            if player.play_next()?.is_some() {
+                println!("Playing: {} ({} tracks)", path.display(), files.len());
+            }
+        }
+        Commands::Play {
+            path,
+            audio_track,
+            language,
+        } => {
+            let player = Player::with_options(&player_options)?;
+            let known = library_track(&database, &path)?;
+            // The stream picked for this file before, unless one is asked for
+            let choice = match &known {
+                Some((db, track)) if audio_track.is_none() && language.is_none() => {
+                    db.stream_choice(track.id)?
+                }
+                _ => None,
+            };
+            match &choice {
+                Some(choice) => player.play_stream(&path, choice)?,
+                None => player.play(&path)?,
+            }
             println!("Playing: {}", path.display());
+            if let Some((db, track)) = &known
+                && (audio_track.is_some() || language.is_some())
+                && let Err(e) = db.set_stream_choice(track.id, &player_options.stream)
+            {
+                eprintln!("Failed to remember the audio stream: {:#}", e);
+            }
+            if let Some((db, track)) = &known
+                && let Some(position) =
+                    resume::start_position(db, track, &settings.resume_options())?
+            {
+                player.seek_to(position)?;
+                println!("Resuming at {}", clock(position));
+            }
         }
         Commands::Pause => {
-            player.pause()?;
+            Player::with_options(&player_options)?.pause()?;
             println!("Paused playback");
         }
         Commands::Resume => {
-            player.resume()?;
+            Player::with_options(&player_options)?.resume()?;
             println!("Resumed playback");
         }
         Commands::Stop => {
-            player.stop()?;
+            Player::with_options(&player_options)?.stop()?;
             println!("Stopped playback");
         }
         Commands::Seek { seconds } => {
-            player.seek(seconds)?;
+            Player::with_options(&player_options)?.seek(seconds)?;
             println!("Seeking to {}s", seconds);
         }
-        Commands::Scan { path } => {
-            println!("Scanning directory: {}", path.display());
-            // TODO: Implement scanner
+        #[cfg(feature = "server")]
+        Commands::NextChapter { remote } => {
+            print_chapter(&remote.post(&settings.server, "chapter/next", serde_json::json!({}))?);
+        }
+        #[cfg(feature = "server")]
+        Commands::PrevChapter { remote } => {
+            print_chapter(&remote.post(&settings.server, "chapter/prev", serde_json::json!({}))?);
+        }
+        #[cfg(feature = "server")]
+        Commands::Chapter { number, remote } => {
+            let body = serde_json::json!({ "index": number.saturating_sub(1) });
+            print_chapter(&remote.post(&settings.server, "chapter", body)?);
+        }
+        Commands::Info { path, json } => {
+            let info = probe::probe(&path)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&info)?);
+            } else {
+                print_info(&info);
+            }
+        }
+        Commands::Scan { path, jobs, full } => {
+            let db = database.open_writable()?;
+            let paths = match path {
+                Some(path) => vec![path],
+                None => library_roots(&settings)?,
+            };
+            let mut options = ScanOptions {
+                full,
+                ..Default::default()
+            };
+            if let Some(jobs) = jobs {
+                options.threads = jobs;
+            }
+            let cancel = Arc::new(AtomicBool::new(false));
+            signal_hook::flag::register(signal_hook::consts::SIGINT, cancel.clone())?;
+            for path in paths {
+                println!("Scanning directory: {}", path.display());
+                let mut bar = ProgressBar::new();
+                let report = scanner::scan_with(&db, &path, &options, &cancel, |p| bar.draw(p))?;
+                bar.finish();
+                for (file, error) in &report.failed {
+                    eprintln!("Skipped {}: {}", file.display(), error);
+                }
+                if report.resumed > 0 {
+                    println!(
+                        "Resumed an interrupted scan ({} directories already done)",
+                        report.resumed
+                    );
+                }
+                println!(
+                    "Imported {} tracks ({} unchanged, {} skipped)",
+                    report.imported,
+                    report.unchanged,
+                    report.failed.len()
+                );
+                if report.cancelled {
+                    println!("Scan interrupted; run it again to continue");
+                    break;
+                }
+            }
+        }
+        Commands::Watch { mut roots } => {
+            if roots.is_empty() {
+                roots = library_roots(&settings)?;
+            }
+            let db = database.open_writable()?;
+            let watcher = LibraryWatcher::new(&roots)?;
+            for root in watcher.roots() {
+                println!("Watching {}", root.display());
+            }
+            watcher::log(&watcher.reconcile(&db)?);
+            loop {
+                if let Some(report) = watcher.poll(&db, Duration::from_secs(3600))? {
+                    watcher::log(&report);
+                }
+            }
+        }
+        Commands::Search { query, limit } => {
+            let db = database.open()?;
+            for track in db.search(&query, limit)? {
+                println!(
+                    "{}\t{} - {}\t{}",
+                    track.id,
+                    track.artist.as_deref().unwrap_or("Unknown artist"),
+                    track.title.as_deref().unwrap_or(&track.path),
+                    track.album.as_deref().unwrap_or("")
+                );
+            }
+        }
+        Commands::Library {
+            query,
+            sort,
+            group,
+            columns,
+            limit,
+        } => list_library(&database.open()?, &query, &sort, group, &columns, limit)?,
+        Commands::Rate {
+            track,
+            rating,
+            clear,
+            loved,
+            write_tags,
+        } => {
+            // `None` leaves the rating alone, `Some(None)` clears it
+            let rating = if clear { Some(None) } else { rating.map(Some) };
+            rate_track(&database.open()?, &track, rating, loved, write_tags)?
+        }
+        Commands::Scrobble { command, services } => run_scrobble(
+            &database.open()?,
+            command,
+            services.scrobbler(settings.scrobbling)?,
+        )?,
+        Commands::Stats {
+            since,
+            until,
+            limit,
+            json,
+        } => {
+            let db = database.open()?;
+            let utc_offset = db.utc_offset()?;
+            let bound = |text: Option<String>| -> Result<Option<i64>> {
+                text.map(|text| {
+                    Window::parse_bound(&text, utc_offset).ok_or_else(|| {
+                        anyhow::anyhow!("Expected an age such as 30d or a date, got {}", text)
+                    })
+                })
+                .transpose()
+            };
+            let window = Window {
+                since: bound(since)?,
+                until: bound(until)?,
+                utc_offset,
+            };
+            let report = db.stats(window, limit)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&report)?);
+            } else {
+                print_stats(&report);
+            }
+        }
+        Commands::Bookmark { command } => {
+            run_bookmark(&database.open()?, command, &player_options)?
+        }
+        Commands::Playlist { command } => {
+            run_playlist(&database.open()?, command, &player_options)?
+        }
+        Commands::Config { .. } => unreachable!("handled before the settings are read"),
+        #[cfg(feature = "server")]
+        Commands::Serve {
+            listen,
+            token,
+            subsonic_user,
+            subsonic_password,
+            transcoder,
+            watch,
+            scrobbling,
+        } => {
+            let resume_options = settings.resume_options();
+            let server_settings = settings.server;
+            let listen = listen
+                .or(server_settings.listen)
+                .unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+            let Some(token) = token.or(server_settings.token) else {
+                anyhow::bail!(
+                    "An API token is needed: --token, RUSTYPLAYER_API_TOKEN or server.token"
+                );
+            };
+            if token.trim().is_empty() {
+                anyhow::bail!("The API token can't be empty");
+            }
+            let db = database.open()?;
+            let player = Player::with_options(&player_options)?;
+            let mut server = crate::server::Server::bind(&listen, token, db, player)?;
+            server.set_resume_options(resume_options);
+            match (
+                subsonic_user.or(server_settings.subsonic_user),
+                subsonic_password.or(server_settings.subsonic_password),
+            ) {
+                (Some(username), Some(password)) => {
+                    server.enable_subsonic(crate::server::SubsonicConfig {
+                        username,
+                        password,
+                        transcoder: transcoder.or(server_settings.transcoder),
+                    });
+                }
+                (None, _) => {}
+                (Some(_), None) => anyhow::bail!("The Subsonic user needs a password"),
+            }
+            let watch = if watch.is_empty() {
+                settings.library.roots
+            } else {
+                watch
+            };
+            if !watch.is_empty() {
+                LibraryWatcher::new(&watch)?.spawn(database.open_writable()?);
+            }
+            let scrobbler = scrobbling.scrobbler(settings.scrobbling)?;
+            if !scrobbler.is_empty() {
+                println!("Scrobbling to {}", scrobbler.service_names().join(", "));
+                server.enable_scrobbling(scrobble::ScrobbleWorker::spawn(
+                    database.open_writable()?,
+                    scrobbler,
+                ));
+            }
+            if let Some(addr) = server.local_addr() {
+                println!("Serving API on http://{}", addr);
+            }
+            server.run()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Library roots from the config file, for commands given no directories
+fn library_roots(settings: &Config) -> Result<Vec<PathBuf>> {
+    if settings.library.roots.is_empty() {
+        anyhow::bail!(
+            "No directory given, and no library roots configured \
+             (rustyplayer config set library.roots '[\"~/Music\"]')"
+        );
+    }
+    Ok(settings.library.roots.clone())
+}
+
+fn run_config(path: &Path, command: ConfigCommand) -> Result<()> {
+    match command {
+        ConfigCommand::Get { key } => match Config::load(path)?.get(&key)? {
+            Some(toml::Value::String(text)) => println!("{}", text),
+            Some(value) => println!("{}", value),
+            None => anyhow::bail!("{} is not set", key),
+        },
+        ConfigCommand::Set { key, value } => {
+            config::set(path, &key, &value)?;
+            println!("Set {} in {}", key, path.display());
         }
+        ConfigCommand::Edit => config::edit(path)?,
+    }
+    Ok(())
+}
+
+/// The library and its track for `path`, when it has one
+fn library_track(database: &Database, path: &Path) -> Result<Option<(DB, Track)>> {
+    // Playing a file shouldn't create a library
+    if !database.path.exists() {
+        return Ok(None);
     }
+    let db = database.open_quietly()?;
+    Ok(find_track(&db, &path.to_string_lossy())
+        .ok()
+        .map(|track| (db, track)))
+}
 
+fn run_bookmark(db: &DB, command: BookmarkCommand, player: &PlayerOptions) -> Result<()> {
+    match command {
+        BookmarkCommand::Add { track, name, at } => {
+            let track = find_track(db, &track)?;
+            let id = db.add_bookmark(track.id, &name, at.as_millis() as i64)?;
+            println!("Added bookmark {} at {}", id, clock(at));
+        }
+        BookmarkCommand::List { track } => {
+            let track = find_track(db, &track)?;
+            for bookmark in db.bookmarks(track.id)? {
+                println!(
+                    "{}\t{}\t{}",
+                    bookmark.id,
+                    clock(Duration::from_millis(bookmark.position_ms as u64)),
+                    bookmark.name
+                );
+            }
+        }
+        BookmarkCommand::Play { id } => {
+            let bookmark = db
+                .bookmark(id)?
+                .ok_or_else(|| anyhow::anyhow!("No bookmark {}", id))?;
+            let track = db
+                .track(bookmark.track_id)?
+                .ok_or_else(|| anyhow::anyhow!("No track {}", bookmark.track_id))?;
+            let position = Duration::from_millis(bookmark.position_ms as u64);
+            let player = Player::with_options(player)?;
+            player.play(Path::new(&track.path))?;
+            player.seek_to(position)?;
+            println!("Playing {} from {}", bookmark.name, clock(position));
+        }
+        BookmarkCommand::Delete { id } => {
+            if !db.delete_bookmark(id)? {
+                anyhow::bail!("No bookmark {}", id);
+            }
+            println!("Deleted bookmark {}", id);
+        }
+    }
     Ok(())
 }
+
+fn run_playlist(db: &DB, command: PlaylistCommand, player: &PlayerOptions) -> Result<()> {
+    match command {
+        PlaylistCommand::List => {
+            for playlist in db.playlists(Page::all())? {
+                let kind = match &playlist.rules {
+                    Some(rules) => format!("smart: {}", rules.query),
+                    None => "static".to_owned(),
+                };
+                println!(
+                    "{}\t{}\t{} tracks\t{}",
+                    playlist.id, playlist.name, playlist.track_count, kind
+                );
+            }
+        }
+        PlaylistCommand::Create {
+            name, query: None, ..
+        } => {
+            let id = db.create_playlist(&name)?;
+            println!("Created playlist {} ({})", name, id);
+        }
+        PlaylistCommand::Create {
+            name,
+            query: Some(query),
+            order,
+            limit,
+        } => {
+            let rules = SmartRules {
+                query,
+                order: order.unwrap_or_default(),
+                limit,
+            };
+            let id = db.create_smart_playlist(&name, &rules)?;
+            println!("Created smart playlist {} ({})", name, id);
+        }
+        PlaylistCommand::Edit {
+            playlist,
+            query,
+            order,
+            limit,
+        } => {
+            let playlist = find_playlist(db, &playlist)?;
+            let Some(mut rules) = playlist.rules else {
+                anyhow::bail!("{} is not a smart playlist", playlist.name);
+            };
+            if let Some(query) = query {
+                rules.query = query;
+            }
+            if let Some(order) = order {
+                rules.order = order;
+            }
+            if let Some(limit) = limit {
+                rules.limit = (limit > 0).then_some(limit);
+            }
+            db.update_smart_playlist(playlist.id, &rules)?;
+        }
+        PlaylistCommand::Show { playlist } => {
+            let playlist = find_playlist(db, &playlist)?;
+            for track in db.playlist_tracks(playlist.id)? {
+                println!(
+                    "{}\t{} - {}",
+                    track.id,
+                    track.artist.as_deref().unwrap_or("Unknown artist"),
+                    track.title.as_deref().unwrap_or(&track.path)
+                );
+            }
+        }
+        PlaylistCommand::Play { playlist } => {
+            let playlist = find_playlist(db, &playlist)?;
+            let tracks = db.playlist_tracks(playlist.id)?;
+            let player = Player::with_options(player)?;
+            for track in &tracks {
+                player.enqueue(Path::new(&track.path));
+            }
+            if player.play_next()?.is_some() {
+                println!("Playing {} ({} tracks)", playlist.name, tracks.len());
+            } else {
+                println!("{} is empty", playlist.name);
+            }
+        }
+        PlaylistCommand::Export {
+            playlist,
+            output,
+            format,
+        } => {
+            let playlist = find_playlist(db, &playlist)?;
+            let entries: Vec<playlist::Entry> = db
+                .playlist_tracks(playlist.id)?
+                .iter()
+                .map(playlist::Entry::from)
+                .collect();
+            let format = format
+                .or_else(|| output.as_deref().and_then(playlist::Format::from_path))
+                .unwrap_or(playlist::Format::M3u8);
+            match output {
+                Some(path) => {
+                    let file = std::fs::File::create(&path)?;
+                    playlist::write(format, &entries, std::io::BufWriter::new(file))?;
+                }
+                None => playlist::write(format, &entries, std::io::stdout().lock())?,
+            }
+        }
+        PlaylistCommand::Import { file, name } => {
+            let name = match name {
+                Some(name) => name,
+                None => file
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("Can't name a playlist after {}", file.display())
+                    })?,
+            };
+            let resolved = read_playlist_file(db, &file)?;
+            let id = db.create_playlist(&name)?;
+            let mut added = 0;
+            for item in &resolved {
+                match &item.track {
+                    Some(track) => {
+                        db.add_to_playlist(id, track.id)?;
+                        added += 1;
+                    }
+                    None if item.path.is_some() => {
+                        eprintln!("Not in library: {}", item.entry.location)
+                    }
+                    None => {}
+                }
+            }
+            println!(
+                "Imported {} of {} entries into {}",
+                added,
+                resolved.len(),
+                name
+            );
+        }
+        PlaylistCommand::Delete { playlist } => {
+            let playlist = find_playlist(db, &playlist)?;
+            db.delete_playlist(playlist.id)?;
+            println!("Deleted playlist {}", playlist.name);
+        }
+    }
+    Ok(())
+}
+
+/// A one-line scan progress bar on stderr, drawn only when it is a terminal
+struct ProgressBar {
+    enabled: bool,
+    last_draw: Option<Instant>,
+}
+
+impl ProgressBar {
+    const WIDTH: usize = 30;
+
+    fn new() -> Self {
+        Self {
+            enabled: std::io::stderr().is_terminal(),
+            last_draw: None,
+        }
+    }
+
+    fn draw(&mut self, progress: &Progress) {
+        let due = self
+            .last_draw
+            .is_none_or(|at| at.elapsed() >= Duration::from_millis(100));
+        if !self.enabled || !(due || progress.done == progress.total) {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+        let filled = (Self::WIDTH * progress.done)
+            .checked_div(progress.total)
+            .unwrap_or(Self::WIDTH);
+        let eta = progress.eta().map_or("--:--".to_owned(), |eta| {
+            let secs = eta.as_secs();
+            format!("{}:{:02}", secs / 60, secs % 60)
+        });
+        eprint!(
+            "\r[{}{}] {}/{} files  {:.1} files/s  ETA {}\x1b[K",
+            "#".repeat(filled),
+            "-".repeat(Self::WIDTH - filled),
+            progress.done,
+            progress.total,
+            progress.rate(),
+            eta
+        );
+        let _ = std::io::stderr().flush();
+    }
+
+    fn finish(&self) {
+        if self.enabled && self.last_draw.is_some() {
+            eprintln!();
+        }
+    }
+}
+
+fn run_scrobble(db: &DB, command: ScrobbleCommand, scrobbler: Scrobbler) -> Result<()> {
+    if scrobbler.is_empty() {
+        anyhow::bail!("No scrobbling service configured; pass Last.fm or ListenBrainz credentials");
+    }
+    if let ScrobbleCommand::Flush = command {
+        // Asked to submit right now, so don't wait out any backoff
+        for service in scrobbler.service_names() {
+            db.retry_listens(service)?;
+        }
+        let report = scrobbler.flush(db, now())?;
+        for (service, error) in &report.errors {
+            eprintln!("{}: {}", service, error);
+        }
+        println!(
+            "Submitted {} listens ({} rejected)",
+            report.submitted, report.rejected
+        );
+    }
+    for service in scrobbler.service_names() {
+        let queue = db.listen_queue(service)?;
+        print!("{}: {} waiting", service, queue.pending);
+        if let Some(retry_at) = queue.retry_at.filter(|_| queue.pending > 0) {
+            print!(", retrying in {}s", (retry_at - now()).max(0));
+        }
+        if let Some(error) = queue.last_error.filter(|_| queue.pending > 0) {
+            print!(" (last error: {})", error);
+        }
+        println!();
+    }
+    Ok(())
+}
+
+/// Read a playlist file and match it against the library, reporting entries
+/// that match nothing on stderr
+fn read_playlist_file(db: &DB, path: &Path) -> Result<Vec<playlist::Resolved>> {
+    let entries = playlist::read(path)?;
+    let base = path
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let resolved = playlist::resolve(db, entries, &std::path::absolute(base)?)?;
+    for item in resolved.iter().filter(|r| r.path.is_none()) {
+        eprintln!("Not found: {}", item.entry.location);
+    }
+    Ok(resolved)
+}
+
+/// Look a playlist up by name, or by id if no playlist has that name
+fn find_playlist(db: &DB, name_or_id: &str) -> Result<Playlist> {
+    if let Some(playlist) = db.playlist_by_name(name_or_id)? {
+        return Ok(playlist);
+    }
+    if let Ok(id) = name_or_id.parse()
+        && let Some(playlist) = db.playlist(id)?
+    {
+        return Ok(playlist);
+    }
+    anyhow::bail!("No playlist named {}", name_or_id)
+}
+
+/// Find a library track by file path, or failing that by id
+fn find_track(db: &DB, path_or_id: &str) -> Result<Track> {
+    let path = Path::new(path_or_id);
+    if path.exists() {
+        for path in [std::path::absolute(path)?, std::fs::canonicalize(path)?] {
+            if let Some(track) = db.track_by_path(&path.to_string_lossy())? {
+                return Ok(track);
+            }
+        }
+        anyhow::bail!("{} is not in the library", path.display());
+    }
+    // A track a cue sheet cut from a file, such as `album.flac#03`
+    if cue::split_path(path).is_some()
+        && let Some(track) = db.track_by_path(&std::path::absolute(path)?.to_string_lossy())?
+    {
+        return Ok(track);
+    }
+    if let Ok(id) = path_or_id.parse()
+        && let Some(track) = db.track(id)?
+    {
+        return Ok(track);
+    }
+    anyhow::bail!("No track with path or id {}", path_or_id)
+}
+
+fn rate_track(
+    db: &DB,
+    path_or_id: &str,
+    rating: Option<Option<f64>>,
+    loved: Option<Love>,
+    write_tags: bool,
+) -> Result<()> {
+    let track = find_track(db, path_or_id)?;
+    if let Some(rating) = rating {
+        db.set_rating(track.id, rating)?;
+        // One file holds every track of a cue sheet, so it has no rating of its own
+        if write_tags && track.start_ms.is_some() {
+            eprintln!(
+                "Not writing the rating of {}: it shares a file with other tracks",
+                track.path
+            );
+        } else if write_tags {
+            tags::write_rating(Path::new(&track.path), rating)?;
+        }
+    }
+    if let Some(loved) = loved {
+        db.set_loved(track.id, loved)?;
+    }
+    let rating = db.get_rating(track.id)?;
+    let loved = loved.unwrap_or(track.loved);
+    println!(
+        "{}\t{}\t{}\t{}",
+        track.id,
+        track.title.as_deref().unwrap_or(&track.path),
+        rating.map_or("unrated".to_owned(), |r| format!("{} stars", r)),
+        loved
+    );
+    Ok(())
+}
+
+/// `YYYY-MM-DD` of a unix time in the library's time zone
+fn local_date(secs: i64, utc_offset: i64) -> String {
+    let (year, month, day) = civil_from_days((secs + utc_offset).div_euclid(86_400));
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+fn hours(seconds: i64) -> String {
+    format!("{}h{:02}m", seconds / 3600, seconds % 3600 / 60)
+}
+
+fn print_info(info: &FileInfo) {
+    println!("File: {}", info.path);
+    match &info.format {
+        Some(format) => println!("Format: {} ({})", format.short, format.long),
+        None => println!("Format: unknown"),
+    }
+    let field = |name: &str, value: Option<String>| {
+        if let Some(value) = value {
+            println!("  {:<24}{}", format!("{}:", name), value);
+        }
+    };
+    for track in &info.tracks {
+        println!(
+            "\nTrack {}: {} ({})",
+            track.id, track.codec.short, track.codec.long
+        );
+        field("Language", track.language.clone());
+        field(
+            "Sample rate",
+            track.sample_rate.map(|rate| format!("{} Hz", rate)),
+        );
+        field("Sample format", track.sample_format.clone());
+        field(
+            "Bits per sample",
+            track.bits_per_sample.map(|bits| bits.to_string()),
+        );
+        field(
+            "Bits per coded sample",
+            track.bits_per_coded_sample.map(|bits| bits.to_string()),
+        );
+        field(
+            "Channels",
+            track
+                .channels
+                .map(|count| format!("{} ({})", count, track.channel_map.as_deref().unwrap_or(""))),
+        );
+        field("Channel layout", track.channel_layout.clone());
+        field("Frames", track.frames.map(|frames| frames.to_string()));
+        field("Start", Some(track.start_ts.to_string()));
+        field("Time base", track.time_base.clone());
+        field(
+            "Duration",
+            track
+                .duration_seconds
+                .map(|seconds| format!("{:.3}s", seconds)),
+        );
+        field(
+            "Encoder delay",
+            track.delay.map(|frames| format!("{} frames", frames)),
+        );
+        field(
+            "Encoder padding",
+            track.padding.map(|frames| format!("{} frames", frames)),
+        );
+        field(
+            "Max frames per packet",
+            track.max_frames_per_packet.map(|frames| frames.to_string()),
+        );
+    }
+    for revision in &info.metadata {
+        println!("\nMetadata ({})", revision.source);
+        for tag in &revision.tags {
+            match &tag.standard_key {
+                Some(key) => println!("  {} [{}]: {}", tag.key, key, tag.value.escape_debug()),
+                None => println!("  {}: {}", tag.key, tag.value.escape_debug()),
+            }
+        }
+        for visual in &revision.visuals {
+            let size = match (visual.width, visual.height) {
+                (Some(width), Some(height)) => format!(", {}x{}", width, height),
+                _ => String::new(),
+            };
+            println!(
+                "  Picture: {}, {}{}, {} bytes",
+                visual.usage.as_deref().unwrap_or("unspecified"),
+                visual.media_type,
+                size,
+                visual.bytes
+            );
+        }
+        for ident in &revision.vendor_data {
+            println!("  Vendor data: {}", ident);
+        }
+    }
+    println!("\nTrial decode");
+    for report in &info.decode {
+        match &report.unsupported {
+            Some(error) => println!("  Track {}: not decodable: {}", report.track_id, error),
+            None => println!(
+                "  Track {}: {} packets, {} frames, {} errors",
+                report.track_id, report.packets, report.frames, report.error_count
+            ),
+        }
+        for error in &report.errors {
+            println!("    {}", error);
+        }
+        if report.error_count > report.errors.len() {
+            println!(
+                "    ... and {} more",
+                report.error_count - report.errors.len()
+            );
+        }
+    }
+    if let Some(error) = &info.demux_error {
+        println!("  Stopped early: {}", error);
+    }
+}
+
+fn print_stats(report: &StatsReport) {
+    let offset = report.window.utc_offset;
+    let totals = &report.totals;
+    println!(
+        "{} plays of {} tracks, {} listened",
+        totals.plays,
+        totals.tracks,
+        hours(totals.seconds)
+    );
+    if let (Some(first), Some(last)) = (totals.first_play, totals.last_play) {
+        println!(
+            "From {} to {}",
+            local_date(first, offset),
+            local_date(last, offset)
+        );
+    }
+    let streaks = &report.streaks;
+    print!(
+        "Streaks: {} days now, {} days longest",
+        streaks.current, streaks.longest
+    );
+    match streaks.longest_start {
+        Some(start) => println!(" (from {})", local_date(start, offset)),
+        None => println!(),
+    }
+
+    println!("\nTop tracks");
+    for top in &report.top_tracks {
+        println!(
+            "{:>6}  {} - {}",
+            top.plays,
+            top.track.artist.as_deref().unwrap_or("Unknown artist"),
+            top.track.title.as_deref().unwrap_or(&top.track.path)
+        );
+    }
+    let ranked = |heading: &str, list: &[Ranked]| {
+        println!("\n{}", heading);
+        for entry in list {
+            match &entry.artist {
+                Some(artist) => println!("{:>6}  {} - {}", entry.plays, artist, entry.name),
+                None => println!("{:>6}  {}", entry.plays, entry.name),
+            }
+        }
+    };
+    ranked("Top artists", &report.top_artists);
+    ranked("Top albums", &report.top_albums);
+    ranked("Top genres", &report.top_genres);
+
+    println!("\nBy hour");
+    for (hour, plays) in report.by_hour.iter().enumerate() {
+        println!("{:>6}  {:02}:00", plays, hour);
+    }
+    println!("\nBy weekday");
+    let weekdays = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+    for (day, plays) in weekdays.iter().zip(report.by_weekday) {
+        println!("{:>6}  {}", plays, day);
+    }
+
+    println!("\nFirst listens");
+    for first in &report.first_listens {
+        println!("{}  {}", local_date(first.played_at, offset), first.artist);
+    }
+    println!("\nForgotten favourites");
+    for track in &report.forgotten_favourites {
+        println!(
+            "{:>6}  {} - {}",
+            track.play_count,
+            track.artist.as_deref().unwrap_or("Unknown artist"),
+            track.title.as_deref().unwrap_or(&track.path)
+        );
+    }
+}
+
+fn list_library(
+    db: &DB,
+    query: &str,
+    sort: &[SortKey],
+    group: Option<Group>,
+    columns: &[Field],
+    limit: Option<usize>,
+) -> Result<()> {
+    let expr = Expr::parse(query)?;
+
+    // Group headings need the tracks of each group to be adjacent
+    let mut order = match group {
+        Some(Group::Album) => vec![SortKey::Asc(Field::Artist), SortKey::Asc(Field::Album)],
+        Some(Group::Artist) => vec![SortKey::Asc(Field::Artist)],
+        None => Vec::new(),
+    };
+    order.extend_from_slice(sort);
+    let page = Page {
+        limit: limit.unwrap_or(Page::all().limit),
+        offset: 0,
+    };
+
+    let utc_offset = db.utc_offset()?;
+    let mut heading = None;
+    for track in db.query_tracks(&expr, &order, page)? {
+        let indent = if let Some(group) = group {
+            let artist = track.artist.as_deref().unwrap_or("Unknown artist");
+            let key = match group {
+                Group::Album => format!(
+                    "{} - {}",
+                    artist,
+                    track.album.as_deref().unwrap_or("Unknown album")
+                ),
+                Group::Artist => artist.to_owned(),
+            };
+            if heading.as_ref() != Some(&key) {
+                println!("{}", key);
+                heading = Some(key);
+            }
+            "    "
+        } else {
+            ""
+        };
+        let cells: Vec<String> = columns
+            .iter()
+            .map(|c| format_field(&track, *c, utc_offset))
+            .collect();
+        println!("{}{}", indent, cells.join("\t"));
+    }
+    Ok(())
+}
+
+/// Read a position given as seconds, `mm:ss` or `h:mm:ss`
+fn parse_clock(text: &str) -> Result<Duration, String> {
+    let parts: Vec<&str> = text.split(':').collect();
+    if parts.len() > 3 {
+        return Err(format!("expected seconds or [h:]mm:ss, got {}", text));
+    }
+    parts
+        .iter()
+        .try_fold(0, |secs: u64, part| {
+            part.parse::<u64>().map(|n| secs * 60 + n)
+        })
+        .map(Duration::from_secs)
+        .map_err(|_| format!("expected seconds or [h:]mm:ss, got {}", text))
+}
+
+/// `m:ss`, or `h:mm:ss` from an hour up
+fn clock(time: Duration) -> String {
+    let secs = time.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        hours => format!("{}:{:02}:{:02}", hours, secs / 60 % 60, secs % 60),
+    }
+}
+
+/// The chapter playing and how far into it playback is
+#[cfg(feature = "server")]
+fn print_chapter(status: &RemoteStatus) {
+    let Some(chapter) = &status.chapter else {
+        println!("No chapter playing");
+        return;
+    };
+    let title = chapter.title.as_deref().unwrap_or("");
+    let progress = match (chapter.position_secs, chapter.duration_secs) {
+        (Some(into), Some(length)) => format!(
+            "{} / {}",
+            clock(Duration::from_secs(into)),
+            clock(Duration::from_secs(length))
+        ),
+        (Some(into), None) => clock(Duration::from_secs(into)),
+        (None, _) => String::new(),
+    };
+    println!(
+        "Chapter {}/{}\t{}\t{}",
+        chapter.index + 1,
+        status.chapters.len(),
+        title,
+        progress
+    );
+}
+
+fn format_field(track: &Track, field: Field, utc_offset: i64) -> String {
+    let date = |secs: Option<i64>| secs.map(|s| local_date(s, utc_offset)).unwrap_or_default();
+    let flag = |set: bool| if set { "yes" } else { "" }.to_owned();
+    let text = |value: &Option<String>| value.clone().unwrap_or_default();
+    match field {
+        Field::Id => track.id.to_string(),
+        Field::Path => track.path.clone(),
+        Field::Title => text(&track.title),
+        Field::Artist => text(&track.artist),
+        Field::Album => text(&track.album),
+        Field::AlbumArtist => text(&track.album_artist),
+        Field::Genre => text(&track.genre),
+        Field::Comment => text(&track.comment),
+        Field::Composer => text(&track.composer),
+        Field::Year => track.year.map(|y| y.to_string()).unwrap_or_default(),
+        Field::Track => track
+            .track_number
+            .map(|n| n.to_string())
+            .unwrap_or_default(),
+        Field::Disc => track.disc_number.map(|n| n.to_string()).unwrap_or_default(),
+        Field::Rating => track.rating.map(|r| r.to_string()).unwrap_or_default(),
+        Field::Plays => track.play_count.to_string(),
+        Field::Duration => track
+            .duration_seconds
+            .map(|s| format!("{}:{:02}", s / 60, s % 60))
+            .unwrap_or_default(),
+        Field::Added => date(track.added_at),
+        Field::Played => date(track.last_played),
+        Field::Starred => flag(track.starred_at.is_some()),
+        Field::Loved => flag(track.loved == Love::Loved),
+        Field::Banned => flag(track.loved == Love::Banned),
+    }
+}
@@ -0,0 +1,137 @@
+//! The decoders the player, scanner and `info` use: everything symphonia
+//! was built with, plus Opus through libopus with the `opus` feature.
+
+use std::sync::OnceLock;
+use symphonia::core::codecs::CodecRegistry;
+
+#[cfg(test)]
+pub(crate) mod fixtures;
+#[cfg(feature = "opus")]
+mod opus;
+
+/// The registry to make decoders from, in place of
+/// `symphonia::default::get_codecs`
+pub fn get() -> &'static CodecRegistry {
+    static CODECS: OnceLock<CodecRegistry> = OnceLock::new();
+    CODECS.get_or_init(|| {
+        let mut registry = CodecRegistry::new();
+        symphonia::default::register_enabled_codecs(&mut registry);
+        #[cfg(feature = "opus")]
+        registry.register_all::<opus::OpusDecoder>();
+        registry
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures;
+    use crate::probe::{self, FileInfo};
+    use crate::scanner;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    /// Write `bytes` out as `name`, check the scanner takes it, and decode
+    /// it to nothing with `info`
+    fn decode(dir: &Path, name: &str, bytes: Vec<u8>) -> FileInfo {
+        let path = dir.join(name);
+        std::fs::write(&path, bytes).unwrap();
+        assert!(scanner::is_audio_file(&path), "{}", name);
+        assert_eq!(scanner::read_tracks(&path).unwrap().len(), 1, "{}", name);
+        probe::probe(&path).unwrap()
+    }
+
+    /// Container and codec short names, frames decoded and decode errors
+    fn summary(info: &FileInfo) -> (String, String, u64, usize) {
+        let report = &info.decode[0];
+        assert_eq!(report.unsupported, None, "{}", info.path);
+        assert_eq!(info.demux_error, None, "{}", info.path);
+        (
+            info.format.as_ref().unwrap().short.clone(),
+            info.tracks[0].codec.short.clone(),
+            report.frames,
+            report.error_count,
+        )
+    }
+
+    #[test]
+    fn test_decode_corpus() {
+        let dir = tempdir().unwrap();
+        let cases = [
+            ("tone.m4a", fixtures::alac_m4a(), "isomp4", "alac", 8192),
+            ("tone.caf", fixtures::alac_caf(), "caf", "alac", 8192),
+            ("hush.aac", fixtures::aac_adts(), "aac", "aac", 8192),
+            ("hush.m4a", fixtures::aac_m4a(), "isomp4", "aac", 8192),
+            ("ramp.flac", fixtures::flac_chapters(), "flac", "flac", 8000),
+            ("hush.ogg", fixtures::vorbis_ogg(), "ogg", "vorbis", 1024),
+            (
+                "hush.webm",
+                fixtures::vorbis_webm(),
+                "matroska",
+                "vorbis",
+                1024,
+            ),
+        ];
+        for (name, bytes, format, codec, frames) in cases {
+            let info = decode(dir.path(), name, bytes);
+            assert_eq!(
+                summary(&info),
+                (format.into(), codec.into(), frames, 0),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_streams() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("ramp.mka");
+        std::fs::write(&path, fixtures::flac_streams_mka()).unwrap();
+        let info = probe::probe(&path).unwrap();
+        let codecs: Vec<_> = info
+            .tracks
+            .iter()
+            .map(|track| track.codec.short.as_str())
+            .collect();
+        assert_eq!(codecs, ["flac", "flac"]);
+        for report in &info.decode {
+            assert_eq!(report.unsupported, None);
+            assert_eq!(
+                (report.frames, report.error_count),
+                (8000, 0),
+                "{:?}",
+                report.errors
+            );
+        }
+    }
+
+    #[test]
+    fn test_decode_opus() {
+        let dir = tempdir().unwrap();
+        for (name, bytes, format) in [
+            ("hush.opus", fixtures::opus_ogg(), "ogg"),
+            ("hush.mka", fixtures::opus_mka(), "matroska"),
+        ] {
+            let info = decode(dir.path(), name, bytes);
+            assert_eq!(info.tracks[0].sample_rate, Some(48_000));
+            #[cfg(feature = "opus")]
+            assert_eq!(
+                summary(&info),
+                (
+                    format.into(),
+                    "opus".into(),
+                    50 * 960 - fixtures::OPUS_PRE_SKIP as u64,
+                    0
+                ),
+                "{}",
+                name
+            );
+            // Found and named, but left undecoded
+            #[cfg(not(feature = "opus"))]
+            {
+                assert_eq!(info.format.unwrap().short, format);
+                assert!(info.decode[0].unsupported.is_some(), "{}", name);
+            }
+        }
+    }
+}
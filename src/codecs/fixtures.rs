@@ -0,0 +1,685 @@
+//! Tiny files in each codec and container the player supports, built here
+//! rather than checked in. The audio is the least each codec allows: ALAC
+//! and FLAC frames stored verbatim, and AAC, Vorbis and Opus packets of
+//! silence.
+
+/// Packs bits into bytes, first bit in the high bit of each byte as AAC
+/// and ALAC read them, or in the low bit as Vorbis does
+struct Bits {
+    bytes: Vec<u8>,
+    len: usize,
+    low_first: bool,
+}
+
+impl Bits {
+    fn new(low_first: bool) -> Self {
+        Self {
+            bytes: Vec::new(),
+            len: 0,
+            low_first,
+        }
+    }
+
+    /// Append fields of `(value, width)`, each most significant bit first
+    /// unless the stream is low bit first
+    fn put(&mut self, fields: &[(u64, u32)]) -> &mut Self {
+        for &(value, count) in fields {
+            self.put_field(value, count);
+        }
+        self
+    }
+
+    fn put_field(&mut self, value: u64, count: u32) {
+        for i in 0..count {
+            let bit = match self.low_first {
+                true => value >> i & 1,
+                false => value >> (count - 1 - i) & 1,
+            };
+            if self.len.is_multiple_of(8) {
+                self.bytes.push(0);
+            }
+            let shift = match self.low_first {
+                true => self.len % 8,
+                false => 7 - self.len % 8,
+            };
+            *self.bytes.last_mut().unwrap() |= (bit as u8) << shift;
+            self.len += 1;
+        }
+    }
+
+    fn bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+}
+
+// ALAC
+
+const ALAC_RATE: u32 = 8000;
+const ALAC_FRAME: u32 = 4096;
+
+/// `ALACSpecificConfig` for 16-bit mono
+fn alac_cookie() -> Vec<u8> {
+    let mut cookie = ALAC_FRAME.to_be_bytes().to_vec();
+    // Version, bit depth, then the Rice coding defaults
+    cookie.extend_from_slice(&[0, 16, 40, 10, 14, 1]);
+    cookie.extend_from_slice(&255u16.to_be_bytes());
+    cookie.extend_from_slice(&(ALAC_FRAME * 2 + 8).to_be_bytes());
+    cookie.extend_from_slice(&0u32.to_be_bytes());
+    cookie.extend_from_slice(&ALAC_RATE.to_be_bytes());
+    cookie
+}
+
+/// Two frames of a 440 Hz tone, stored uncompressed as ALAC's escape
+/// frames allow
+fn alac_packets() -> Vec<Vec<u8>> {
+    (0..2)
+        .map(|frame| {
+            let mut bits = Bits::new(false);
+            // Mono element, instance 0, unused header, no frame size, no
+            // shifted bits, escaped
+            bits.put(&[(0, 3), (0, 4), (0, 12), (0, 1), (0, 2), (1, 1)]);
+            for i in 0..ALAC_FRAME {
+                let n = (frame * ALAC_FRAME + i) as f64;
+                let sample = (n * 440.0 / ALAC_RATE as f64 * std::f64::consts::TAU).sin() * 8000.0;
+                bits.put(&[(sample as i16 as u16 as u64, 16)]);
+            }
+            // End element
+            bits.put(&[(7, 3)]).bytes()
+        })
+        .collect()
+}
+
+/// The tone in an M4A
+pub(crate) fn alac_m4a() -> Vec<u8> {
+    let entry = sample_entry(b"alac", 1, ALAC_RATE, &full_atom(b"alac", &alac_cookie()));
+    mp4(&entry, ALAC_RATE, ALAC_FRAME, &alac_packets())
+}
+
+/// The tone in a Core Audio file
+pub(crate) fn alac_caf() -> Vec<u8> {
+    let packets = alac_packets();
+    let chunk = |kind: &[u8; 4], body: &[u8]| {
+        let mut out = kind.to_vec();
+        out.extend_from_slice(&(body.len() as i64).to_be_bytes());
+        out.extend_from_slice(body);
+        out
+    };
+    let mut desc = (ALAC_RATE as f64).to_be_bytes().to_vec();
+    desc.extend_from_slice(b"alac");
+    // Format flags, bytes per packet (varying), frames per packet, channels
+    // and bits per channel (none, being compressed)
+    for field in [0, 0, ALAC_FRAME, 1, 0] {
+        desc.extend_from_slice(&field.to_be_bytes());
+    }
+    let mut table = (packets.len() as i64).to_be_bytes().to_vec();
+    table.extend_from_slice(&(packets.len() as i64 * ALAC_FRAME as i64).to_be_bytes());
+    table.extend_from_slice(&[0; 8]);
+    for packet in &packets {
+        // Sizes as big-endian 7-bit groups, all but the last flagged
+        let size = packet.len();
+        table.extend_from_slice(&[0x80 | (size >> 7) as u8, (size & 0x7f) as u8]);
+    }
+    // No edits
+    let mut data = vec![0; 4];
+    packets
+        .iter()
+        .for_each(|packet| data.extend_from_slice(packet));
+
+    let mut caf = b"caff".to_vec();
+    caf.extend_from_slice(&[0, 1, 0, 0]);
+    caf.extend_from_slice(&chunk(b"desc", &desc));
+    caf.extend_from_slice(&chunk(b"kuki", &alac_cookie()));
+    caf.extend_from_slice(&chunk(b"pakt", &table));
+    caf.extend_from_slice(&chunk(b"data", &data));
+    caf
+}
+
+// AAC
+
+const AAC_RATE: u32 = 48_000;
+const AAC_FRAME: u32 = 1024;
+
+/// A silent AAC-LC mono frame: a channel element with no scale factor
+/// bands, and so no spectral data
+fn aac_packet() -> Vec<u8> {
+    let mut bits = Bits::new(false);
+    // Single channel element 0, global gain
+    bits.put(&[(0, 3), (0, 4), (100, 8)]);
+    // Long window, no bands, no prediction
+    bits.put(&[(0, 1), (0, 2), (0, 1), (0, 6), (0, 1)]);
+    // No pulse, TNS or gain control data, then the end element
+    bits.put(&[(0, 1), (0, 1), (0, 1), (7, 3)]);
+    bits.bytes()
+}
+
+/// `AudioSpecificConfig`: AAC-LC, 48 kHz, mono
+fn aac_config() -> Vec<u8> {
+    Bits::new(false)
+        .put(&[(2, 5), (3, 4), (1, 4), (0, 3)])
+        .bytes()
+}
+
+/// Eight silent frames with ADTS headers
+pub(crate) fn aac_adts() -> Vec<u8> {
+    let packet = aac_packet();
+    let frame_len = 7 + packet.len() as u64;
+    let mut header = Bits::new(false);
+    // Sync, MPEG-4, layer 0, no CRC
+    header.put(&[(0xfff, 12), (0, 1), (0, 2), (1, 1)]);
+    // AAC-LC, 48 kHz, private bit, mono, original, home
+    header.put(&[(1, 2), (3, 4), (0, 1), (1, 3), (0, 1), (0, 1)]);
+    // Copyright bits, frame length, buffer fullness (variable), one block
+    header.put(&[(0, 2), (frame_len, 13), (0x7ff, 11), (0, 2)]);
+    (0..8)
+        .flat_map(|_| [header.bytes(), packet.clone()].concat())
+        .collect()
+}
+
+/// Eight silent frames in an M4A
+pub(crate) fn aac_m4a() -> Vec<u8> {
+    let descriptor = |tag: u8, body: &[u8]| [&[tag, body.len() as u8][..], body].concat();
+    let config = [
+        // MPEG-4 audio, audio stream, buffer size, bit rates
+        &[0x40, 0x15][..],
+        &[0; 3],
+        &[0; 8],
+        &descriptor(0x05, &aac_config()),
+    ]
+    .concat();
+    let es = [
+        // Stream id, no flags
+        &[0, 1, 0][..],
+        &descriptor(0x04, &config),
+        // The predefined MP4 sync layer
+        &descriptor(0x06, &[2]),
+    ]
+    .concat();
+    let esds = full_atom(b"esds", &descriptor(0x03, &es));
+    let entry = sample_entry(b"mp4a", 1, AAC_RATE, &esds);
+    mp4(&entry, AAC_RATE, AAC_FRAME, &vec![aac_packet(); 8])
+}
+
+// MP4
+
+fn atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut out = (body.len() as u32 + 8).to_be_bytes().to_vec();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(body);
+    out
+}
+
+/// An atom with version 0 and no flags
+fn full_atom(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    atom(kind, &[&[0; 4][..], body].concat())
+}
+
+fn words(fields: &[u32]) -> Vec<u8> {
+    fields
+        .iter()
+        .flat_map(|field| field.to_be_bytes())
+        .collect()
+}
+
+/// A version 0 audio sample entry of 16-bit samples
+fn sample_entry(kind: &[u8; 4], channels: u16, rate: u32, codec: &[u8]) -> Vec<u8> {
+    let mut body = vec![0; 6];
+    // Data reference, version, revision and vendor
+    body.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    body.extend_from_slice(&channels.to_be_bytes());
+    body.extend_from_slice(&16u16.to_be_bytes());
+    body.extend_from_slice(&[0; 4]);
+    // 16.16 fixed point
+    body.extend_from_slice(&(rate << 16).to_be_bytes());
+    body.extend_from_slice(codec);
+    atom(kind, &body)
+}
+
+/// An M4A of one audio track, its packets in one chunk ahead of the index
+fn mp4(entry: &[u8], rate: u32, frames_per_packet: u32, packets: &[Vec<u8>]) -> Vec<u8> {
+    let count = packets.len() as u32;
+    let duration = count * frames_per_packet;
+    let ftyp = atom(b"ftyp", b"M4A \0\0\0\0M4A mp42isom");
+    let mdat = atom(b"mdat", &packets.concat());
+
+    let mut stsd = words(&[1]);
+    stsd.extend_from_slice(entry);
+    let mut stsz = words(&[0, count]);
+    packets
+        .iter()
+        .for_each(|packet| stsz.extend_from_slice(&(packet.len() as u32).to_be_bytes()));
+    let stbl = [
+        full_atom(b"stsd", &stsd),
+        full_atom(b"stts", &words(&[1, count, frames_per_packet])),
+        full_atom(b"stsc", &words(&[1, 1, count, 1])),
+        full_atom(b"stsz", &stsz),
+        full_atom(b"stco", &words(&[1, ftyp.len() as u32 + 8])),
+    ]
+    .concat();
+    let url = atom(b"url ", &[0, 0, 0, 1]);
+    let minf = [
+        full_atom(b"smhd", &[0; 4]),
+        atom(
+            b"dinf",
+            &full_atom(b"dref", &[&words(&[1])[..], &url].concat()),
+        ),
+        atom(b"stbl", &stbl),
+    ]
+    .concat();
+    // Handler, then an empty name
+    let hdlr = [&[0; 4][..], b"soun", &[0; 13]].concat();
+    // Language `und`
+    let mdhd = [&words(&[0, 0, rate, duration])[..], &[0x55, 0xc4, 0, 0]].concat();
+    let mdia = [
+        full_atom(b"mdhd", &mdhd),
+        full_atom(b"hdlr", &hdlr),
+        atom(b"minf", &minf),
+    ]
+    .concat();
+    let matrix = words(&[0x10000, 0, 0, 0, 0x10000, 0, 0, 0, 0x4000_0000]);
+    let tkhd = [
+        &words(&[0, 0, 1, 0, duration, 0, 0])[..],
+        &[0, 0, 0, 0, 1, 0, 0, 0],
+        &matrix,
+        &[0; 8],
+    ]
+    .concat();
+    let mvhd = [
+        &words(&[0, 0, rate, duration, 0x10000])[..],
+        &[1, 0],
+        &[0; 10],
+        &matrix,
+        &[0; 24],
+        &words(&[2]),
+    ]
+    .concat();
+    let trak = [full_atom(b"tkhd", &tkhd), atom(b"mdia", &mdia)].concat();
+    let moov = [full_atom(b"mvhd", &mvhd), atom(b"trak", &trak)].concat();
+    [ftyp, mdat, atom(b"moov", &moov)].concat()
+}
+
+// Vorbis
+
+const VORBIS_RATE: u32 = 44_100;
+
+/// Identification header: mono, blocks of 256 and 2048 samples
+fn vorbis_ident() -> Vec<u8> {
+    let mut header = b"\x01vorbis".to_vec();
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.push(1);
+    header.extend_from_slice(&VORBIS_RATE.to_le_bytes());
+    header.extend_from_slice(&[0; 12]);
+    header.extend_from_slice(&[0xb8, 1]);
+    header
+}
+
+fn vorbis_comment() -> Vec<u8> {
+    let vendor = b"rustyplayer";
+    let mut header = b"\x03vorbis".to_vec();
+    header.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    header.extend_from_slice(vendor);
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.push(1);
+    header
+}
+
+/// The smallest setup Vorbis allows: one codebook, and one each of floor,
+/// residue, mapping and mode, the mode using short blocks only
+fn vorbis_setup() -> Vec<u8> {
+    let mut bits = Bits::new(true);
+    // One codebook of one dimension, two entries of one bit, no lookup
+    bits.put(&[(0, 8), (0x564342, 24), (1, 16), (2, 24)]);
+    bits.put(&[(0, 1), (0, 1), (0, 5), (0, 5), (0, 4)]);
+    // One time domain transform placeholder
+    bits.put(&[(0, 6), (0, 16)]);
+    // One type 1 floor without partitions: multiplier, range bits
+    bits.put(&[(0, 6), (1, 16), (0, 5), (0, 2), (4, 4)]);
+    // One type 0 residue covering nothing, with one unused class
+    bits.put(&[(0, 6), (0, 16), (0, 24), (0, 24), (0, 24)]);
+    bits.put(&[(0, 6), (0, 8), (0, 3), (0, 1)]);
+    // One mapping: one submap, no coupling, floor 0, residue 0
+    bits.put(&[(0, 6), (0, 16), (0, 1), (0, 1), (0, 2)]);
+    bits.put(&[(0, 8), (0, 8), (0, 8)]);
+    // One short block mode, then the framing bit
+    bits.put(&[(0, 6), (0, 1), (0, 16), (0, 16), (0, 8), (1, 1)]);
+    [&b"\x05vorbis"[..], &bits.bytes()].concat()
+}
+
+/// Audio packets whose one channel has an unused floor, and so is silent:
+/// a 0 bit for audio, then a 0 bit for the floor. Each short block after the
+/// first adds 128 samples.
+fn vorbis_packets() -> Vec<Vec<u8>> {
+    vec![vec![0]; 9]
+}
+
+/// 1024 silent samples in Ogg
+pub(crate) fn vorbis_ogg() -> Vec<u8> {
+    let mut ogg = Ogg::default();
+    ogg.page(&[vorbis_ident()], 0);
+    ogg.page(&[vorbis_comment(), vorbis_setup()], 0);
+    ogg.last_page(&vorbis_packets(), 1024);
+    ogg.bytes
+}
+
+/// The same in WebM
+pub(crate) fn vorbis_webm() -> Vec<u8> {
+    // Xiph lacing: the number of headers less one, then all sizes but the
+    // last as runs of 255
+    let headers = [vorbis_ident(), vorbis_comment(), vorbis_setup()];
+    let mut private = vec![2];
+    for header in &headers[..2] {
+        private.extend(std::iter::repeat_n(255, header.len() / 255));
+        private.push((header.len() % 255) as u8);
+    }
+    headers
+        .iter()
+        .for_each(|header| private.extend_from_slice(header));
+    matroska(
+        b"webm",
+        b"A_VORBIS",
+        &private,
+        VORBIS_RATE,
+        &vorbis_packets(),
+    )
+}
+
+// Opus
+
+/// Frames a decoder drops from the start of the Opus streams, as many as
+/// libopus's encoder asks for
+pub(crate) const OPUS_PRE_SKIP: u16 = 312;
+
+/// Identification header: mono, `OPUS_PRE_SKIP`, no gain, family 0
+fn opus_head() -> Vec<u8> {
+    let mut head = b"OpusHead\x01\x01".to_vec();
+    head.extend_from_slice(&OPUS_PRE_SKIP.to_le_bytes());
+    head.extend_from_slice(&48_000u32.to_le_bytes());
+    head.extend_from_slice(&[0, 0, 0]);
+    head
+}
+
+/// A second of 20 ms packets with only a table of contents byte, each an
+/// empty frame which libopus fills with silence
+fn opus_packets() -> Vec<Vec<u8>> {
+    vec![vec![0x08]; 50]
+}
+
+pub(crate) fn opus_ogg() -> Vec<u8> {
+    let mut tags = b"OpusTags".to_vec();
+    tags.extend_from_slice(&11u32.to_le_bytes());
+    tags.extend_from_slice(b"rustyplayer");
+    tags.extend_from_slice(&0u32.to_le_bytes());
+    let mut ogg = Ogg::default();
+    ogg.page(&[opus_head()], 0);
+    ogg.page(&[tags], 0);
+    ogg.last_page(&opus_packets(), 48_000);
+    ogg.bytes
+}
+
+pub(crate) fn opus_mka() -> Vec<u8> {
+    matroska(
+        b"matroska",
+        b"A_OPUS",
+        &opus_head(),
+        48_000,
+        &opus_packets(),
+    )
+}
+
+// FLAC
+
+const FLAC_RATE: u32 = 8000;
+const FLAC_FRAME: u32 = 160;
+
+/// STREAMINFO for 16-bit mono of `frames` frames
+fn flac_stream_info(frames: u64) -> Vec<u8> {
+    let mut info = Bits::new(false);
+    // Block sizes, frame sizes (unknown), rate, channels, bits per sample
+    info.put(&[
+        (FLAC_FRAME as u64, 16),
+        (FLAC_FRAME as u64, 16),
+        (0, 24),
+        (0, 24),
+    ]);
+    info.put(&[(FLAC_RATE as u64, 20), (0, 3), (15, 5), (frames, 36)]);
+    [info.bytes(), vec![0; 16]].concat()
+}
+
+/// A metadata block, `last` marking the one before the frames
+fn flac_block(kind: u8, last: bool, body: &[u8]) -> Vec<u8> {
+    let header = (last as u32) << 31 | (kind as u32) << 24 | body.len() as u32;
+    [&header.to_be_bytes()[..], body].concat()
+}
+
+/// Matroska codec private data: the stream marker and STREAMINFO
+fn flac_header(frames: u64) -> Vec<u8> {
+    [
+        &b"fLaC"[..],
+        &flac_block(0, true, &flac_stream_info(frames)),
+    ]
+    .concat()
+}
+
+/// A second of 16-bit mono whose samples count its frames up from `first`,
+/// in frames with verbatim subframes
+fn flac_ramp(first: u16) -> Vec<Vec<u8>> {
+    (0..FLAC_RATE / FLAC_FRAME)
+        .map(|frame| {
+            let mut header = Bits::new(false);
+            // Sync, fixed block size, block size in 8 bits at the end, rate
+            // from STREAMINFO, mono, 16 bits, frame number, block size
+            header.put(&[(0x3ffe, 14), (0, 1), (0, 1), (6, 4), (0, 4)]);
+            header.put(&[(0, 4), (4, 3), (0, 1), (frame as u64, 8)]);
+            header.put(&[(FLAC_FRAME as u64 - 1, 8)]);
+            let mut out = header.bytes();
+            out.push(crc(&out, 8, 0x07) as u8);
+            // Verbatim subframe, no wasted bits
+            out.push(0x02);
+            for i in 0..FLAC_FRAME {
+                let sample = first + (frame * FLAC_FRAME + i) as u16;
+                out.extend_from_slice(&sample.to_be_bytes());
+            }
+            let footer = crc(&out, 16, 0x8005) as u16;
+            out.extend_from_slice(&footer.to_be_bytes());
+            out
+        })
+        .collect()
+}
+
+/// The ramp counting from 0 in a FLAC file, with Vorbis comment chapters
+/// starting at 0, 0.25 and 0.5 seconds
+pub(crate) fn flac_chapters() -> Vec<u8> {
+    let mut comment = 11u32.to_le_bytes().to_vec();
+    comment.extend_from_slice(b"rustyplayer");
+    let fields = [
+        "CHAPTER001=00:00:00.000",
+        "CHAPTER002=00:00:00.250",
+        "CHAPTER003=00:00:00.500",
+    ];
+    comment.extend_from_slice(&(fields.len() as u32).to_le_bytes());
+    for field in fields {
+        comment.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        comment.extend_from_slice(field.as_bytes());
+    }
+    [
+        b"fLaC".to_vec(),
+        flac_block(0, false, &flac_stream_info(FLAC_RATE as u64)),
+        flac_block(4, true, &comment),
+        flac_ramp(0).concat(),
+    ]
+    .concat()
+}
+
+/// A CRC of `width` bits, not reflected and starting from zero, as FLAC
+/// frames use
+fn crc(data: &[u8], width: u32, polynomial: u32) -> u32 {
+    let top = 1 << (width - 1);
+    let mask = (1u64 << width) as u32 - 1;
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u32) << (width - 8), |crc, _| {
+            match crc & top {
+                0 => crc << 1 & mask,
+                _ => (crc << 1 ^ polynomial) & mask,
+            }
+        })
+    })
+}
+
+/// A Matroska file with two FLAC streams of the ramp, the first counting
+/// from 0 and the second from 16000, so that a sample tells which stream
+/// it came from and where
+pub(crate) fn flac_streams_mka() -> Vec<u8> {
+    let header = flac_header(FLAC_RATE as u64);
+    let (first, second) = (flac_ramp(0), flac_ramp(16_000));
+    matroska_tracks(
+        b"matroska",
+        &[
+            (b"A_FLAC", &header, FLAC_RATE, &first),
+            (b"A_FLAC", &header, FLAC_RATE, &second),
+        ],
+    )
+}
+
+// Ogg
+
+/// An Ogg stream of one logical bitstream, written page by page
+#[derive(Default)]
+struct Ogg {
+    bytes: Vec<u8>,
+    sequence: u32,
+}
+
+impl Ogg {
+    fn page(&mut self, packets: &[Vec<u8>], granule: u64) {
+        let flags = match self.sequence {
+            0 => 0x02,
+            _ => 0,
+        };
+        self.write(packets, granule, flags);
+    }
+
+    fn last_page(&mut self, packets: &[Vec<u8>], granule: u64) {
+        self.write(packets, granule, 0x04);
+    }
+
+    fn write(&mut self, packets: &[Vec<u8>], granule: u64, flags: u8) {
+        let mut lacing = Vec::new();
+        for packet in packets {
+            lacing.extend(std::iter::repeat_n(255, packet.len() / 255));
+            lacing.push((packet.len() % 255) as u8);
+        }
+        let mut page = b"OggS\0".to_vec();
+        page.push(flags);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&1u32.to_le_bytes());
+        page.extend_from_slice(&self.sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        packets
+            .iter()
+            .for_each(|packet| page.extend_from_slice(packet));
+        let crc = ogg_crc(&page);
+        page[22..26].copy_from_slice(&crc.to_le_bytes());
+        self.bytes.extend_from_slice(&page);
+        self.sequence += 1;
+    }
+}
+
+/// CRC-32 as Ogg pages use it: polynomial 0x04c11db7, not reflected
+fn ogg_crc(data: &[u8]) -> u32 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ (byte as u32) << 24, |crc, _| {
+            match crc & 0x8000_0000 {
+                0 => crc << 1,
+                _ => crc << 1 ^ 0x04c1_1db7,
+            }
+        })
+    })
+}
+
+// Matroska
+
+fn ebml(id: &[u8], body: &[u8]) -> Vec<u8> {
+    // Sizes as eight-byte variable length integers
+    let mut out = id.to_vec();
+    out.push(0x01);
+    out.extend_from_slice(&body.len().to_be_bytes()[1..]);
+    out.extend_from_slice(body);
+    out
+}
+
+/// A Matroska audio track: codec ID, codec private data, sample rate and
+/// packets
+type MatroskaTrack<'a> = (&'a [u8], &'a [u8], u32, &'a [Vec<u8>]);
+
+/// A Matroska file of one audio track
+fn matroska(
+    doc_type: &[u8],
+    codec: &[u8],
+    private: &[u8],
+    rate: u32,
+    packets: &[Vec<u8>],
+) -> Vec<u8> {
+    matroska_tracks(doc_type, &[(codec, private, rate, packets)])
+}
+
+/// A Matroska file of audio tracks with every packet in one cluster, each
+/// track's 20 ms apart, and a cue point at the cluster so that readers can
+/// seek back
+fn matroska_tracks(doc_type: &[u8], tracks: &[MatroskaTrack]) -> Vec<u8> {
+    let header = ebml(&[0x1a, 0x45, 0xdf, 0xa3], &ebml(&[0x42, 0x82], doc_type));
+    let info = ebml(
+        &[0x15, 0x49, 0xa9, 0x66],
+        &ebml(&[0x2a, 0xd7, 0xb1], &[0x0f, 0x42, 0x40]),
+    );
+    let mut entries = Vec::new();
+    for (i, &(codec, private, rate, _)) in tracks.iter().enumerate() {
+        let number = i as u8 + 1;
+        let audio = [
+            ebml(&[0xb5], &(rate as f64).to_be_bytes()),
+            ebml(&[0x9f], &[1]),
+        ]
+        .concat();
+        let entry = [
+            // Number, UID, audio
+            ebml(&[0xd7], &[number]),
+            ebml(&[0x73, 0xc5], &[number]),
+            ebml(&[0x83], &[2]),
+            ebml(&[0x86], codec),
+            ebml(&[0x63, 0xa2], private),
+            ebml(&[0xe1], &audio),
+        ]
+        .concat();
+        entries.extend_from_slice(&ebml(&[0xae], &entry));
+    }
+    let tracks_element = ebml(&[0x16, 0x54, 0xae, 0x6b], &entries);
+    let mut cluster = ebml(&[0xe7], &[0]);
+    let longest = tracks.iter().map(|track| track.3.len()).max().unwrap_or(0);
+    for i in 0..longest {
+        for (number, &(.., packets)) in tracks.iter().enumerate() {
+            let Some(packet) = packets.get(i) else {
+                continue;
+            };
+            // Track number, time in milliseconds from the cluster's, keyframe
+            let mut block = vec![0x81 + number as u8];
+            block.extend_from_slice(&(i as i16 * 20).to_be_bytes());
+            block.push(0x80);
+            block.extend_from_slice(packet);
+            cluster.extend_from_slice(&ebml(&[0xa3], &block));
+        }
+    }
+    let cluster = ebml(&[0x1f, 0x43, 0xb6, 0x75], &cluster);
+    // A cue point at time 0 for track 1, at the cluster's position in the
+    // segment. Sizes being fixed, the position doesn't change the cues' own.
+    let cues = |position: u64| {
+        let positions = [ebml(&[0xf7], &[1]), ebml(&[0xf1], &position.to_be_bytes())].concat();
+        let point = [ebml(&[0xb3], &[0]), ebml(&[0xb7], &positions)].concat();
+        ebml(&[0x1c, 0x53, 0xbb, 0x6b], &ebml(&[0xbb], &point))
+    };
+    let cues = cues((info.len() + tracks_element.len() + cues(0).len()) as u64);
+    let segment = ebml(
+        &[0x18, 0x53, 0x80, 0x67],
+        &[info, tracks_element, cues, cluster].concat(),
+    );
+    [header, segment].concat()
+}
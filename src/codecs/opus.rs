@@ -0,0 +1,164 @@
+//! Opus decoding with libopus, for Ogg Opus, and Opus in Matroska and WebM.
+//! Mono and stereo only: surround Opus needs libopus's multistream decoder.
+
+use std::sync::Mutex;
+
+use audiopus::coder::{Decoder as Libopus, GenericCtl};
+use audiopus::packet::Packet as OpusPacket;
+use audiopus::{MutSignals, SampleRate};
+use symphonia::core::audio::{
+    AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec,
+};
+use symphonia::core::codecs::{
+    CODEC_TYPE_OPUS, CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult,
+};
+use symphonia::core::errors::{Result, decode_error, unsupported_error};
+use symphonia::core::formats::Packet;
+use symphonia::core::support_codec;
+use symphonia::core::units::TimeBase;
+
+/// Containers time Opus at 48 kHz, whatever the rate of the original
+const SAMPLE_RATE: u32 = 48_000;
+
+/// The longest packet Opus allows is 120 ms
+const MAX_FRAMES: usize = 5760;
+
+pub struct OpusDecoder {
+    params: CodecParameters,
+    /// libopus keeps state between packets, and isn't `Sync`
+    decoder: Mutex<Libopus>,
+    channels: usize,
+    /// Frames at the start of the stream that only prime the decoder
+    pre_skip: u64,
+    /// libopus's interleaved output, before it is split into `buf`
+    pcm: Vec<f32>,
+    buf: AudioBuffer<f32>,
+}
+
+/// What the `OpusHead` identification header says about the stream
+struct Head {
+    channels: usize,
+    /// Frames to drop from the start of the decoded stream
+    pre_skip: u16,
+    /// Channel mapping family
+    mapping: u8,
+    /// Output gain in Q7.8 dB
+    gain: i16,
+}
+
+impl Head {
+    fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 19 || !data.starts_with(b"OpusHead") {
+            return None;
+        }
+        Some(Self {
+            channels: data[9].into(),
+            pre_skip: u16::from_le_bytes([data[10], data[11]]),
+            gain: i16::from_le_bytes([data[16], data[17]]),
+            mapping: data[18],
+        })
+    }
+}
+
+impl Decoder for OpusDecoder {
+    fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> Result<Self> {
+        let head = params.extra_data.as_deref().and_then(Head::parse);
+        let channels = match &head {
+            Some(head) => head.channels,
+            None => params.channels.map_or(0, |channels| channels.count()),
+        };
+        // Families 0 and 1 code up to two channels as one plain Opus stream
+        let single_stream = head.as_ref().is_none_or(|head| head.mapping <= 1);
+        let (layout, positions) = match channels {
+            1 if single_stream => (audiopus::Channels::Mono, Channels::FRONT_LEFT),
+            2 if single_stream => (
+                audiopus::Channels::Stereo,
+                Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+            ),
+            _ => return unsupported_error("opus: only mono and stereo streams are supported"),
+        };
+        let decoder = Libopus::new(SampleRate::Hz48000, layout)
+            .or_else(|_| unsupported_error("opus: libopus could not make a decoder"))?;
+        let pre_skip = head.as_ref().map_or(0, |head| head.pre_skip.into());
+        if let Some(head) = head.filter(|head| head.gain != 0) {
+            decoder
+                .set_gain(head.gain.into())
+                .or_else(|_| decode_error("opus: invalid output gain"))?;
+        }
+
+        Ok(Self {
+            params: params.clone(),
+            decoder: Mutex::new(decoder),
+            channels,
+            pre_skip,
+            pcm: vec![0.0; MAX_FRAMES * channels],
+            buf: AudioBuffer::new(MAX_FRAMES as u64, SignalSpec::new(SAMPLE_RATE, positions)),
+        })
+    }
+
+    fn supported_codecs() -> &'static [CodecDescriptor] {
+        &[support_codec!(CODEC_TYPE_OPUS, "opus", "Opus")]
+    }
+
+    fn reset(&mut self) {
+        // Keeps the output gain
+        let _ = self.decoder.get_mut().unwrap().reset_state();
+    }
+
+    fn codec_params(&self) -> &CodecParameters {
+        &self.params
+    }
+
+    fn decode(&mut self, packet: &Packet) -> Result<AudioBufferRef<'_>> {
+        self.buf.clear();
+        // An empty packet is a lost one, which libopus conceals
+        let input = match packet.buf() {
+            [] => None,
+            data => Some(
+                OpusPacket::try_from(data).or_else(|_| decode_error("opus: packet too large"))?,
+            ),
+        };
+        let output = MutSignals::try_from(&mut self.pcm[..])
+            .or_else(|_| decode_error("opus: output buffer too large"))?;
+        let frames = self
+            .decoder
+            .get_mut()
+            .unwrap()
+            .decode_float(input, output, false)
+            .or_else(|_| decode_error("opus: invalid packet"))?;
+
+        // Packets are timed from the first frame decoded, pre-skip included
+        let start = match self.params.time_base {
+            Some(time_base) => frames_at(time_base, packet.ts()),
+            None => packet.ts(),
+        };
+        let skip = self.pre_skip.saturating_sub(start).min(frames as u64) as usize;
+
+        self.buf.render_reserved(Some(frames - skip));
+        for channel in 0..self.channels {
+            let samples = self
+                .pcm
+                .iter()
+                .skip(skip * self.channels + channel)
+                .step_by(self.channels);
+            for (out, sample) in self.buf.chan_mut(channel).iter_mut().zip(samples) {
+                *out = *sample;
+            }
+        }
+        Ok(self.buf.as_audio_buffer_ref())
+    }
+
+    fn finalize(&mut self) -> FinalizeResult {
+        FinalizeResult::default()
+    }
+
+    fn last_decoded(&self) -> AudioBufferRef<'_> {
+        self.buf.as_audio_buffer_ref()
+    }
+}
+
+/// A timestamp in frames at 48 kHz
+fn frames_at(time_base: TimeBase, ts: u64) -> u64 {
+    let time = time_base.calc_time(ts);
+    time.seconds * SAMPLE_RATE as u64 + (time.frac * SAMPLE_RATE as f64).round() as u64
+}
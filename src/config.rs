@@ -0,0 +1,396 @@
+//! Settings file: a TOML file in the XDG config directory
+//! (`~/.config/rustyplayer/config.toml`) holding the library location and
+//! roots, playback, scrobbling and server settings.
+//!
+//! Everything is optional. Command-line flags and `RUSTYPLAYER_*` environment
+//! variables override the file, which overrides built-in defaults.
+
+use anyhow::{Context, Result, bail};
+use directories::{BaseDirs, ProjectDirs};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::formats::dsd::OUTPUT_RATES;
+use crate::player::{PlayerOptions, ReplayGain};
+use crate::resume::ResumeOptions;
+use crate::streams::StreamChoice;
+
+/// Written by `config set` and `config edit` when there is no file yet
+const TEMPLATE: &str = r#"# rustyplayer settings. Command-line flags and RUSTYPLAYER_* environment
+# variables override anything set here.
+
+[library]
+# Library database; defaults to ~/.local/share/rustyplayer/library.db
+# db = "~/Music/library.db"
+# Directories scan, watch and serve use when none are given
+# roots = ["~/Music"]
+# Milliseconds to wait for another process to release the database
+# busy_timeout = 5000
+
+[playback]
+# Starting volume, 0.0 to 1.0
+# volume = 1.0
+# Output device name; the system default if unset
+# device = "default"
+# ReplayGain tags to apply: "off", "track" or "album"
+# replaygain = "off"
+# Files at least this many seconds long remember where they were stopped
+# resume_min_length = 600
+# Seconds to go back when resuming one
+# resume_rewind = 5
+# Audio stream to play from videos and other files with several, by language
+# audio_language = "en"
+# SoundFont to play MIDI files with; a system General MIDI one if unset
+# soundfont = "/usr/share/sounds/sf2/FluidR3_GM.sf2"
+# Rate DSD (.dsf, .dff) is converted to PCM at, in Hz; the nearest the file allows
+# dsd_rate = 88200
+
+[scrobbling]
+# lastfm_api_key = ""
+# lastfm_api_secret = ""
+# lastfm_session_key = ""
+# lastfm_url = "https://ws.audioscrobbler.com/2.0/"
+# listenbrainz_token = ""
+# listenbrainz_url = "https://api.listenbrainz.org"
+
+[server]
+# Address the API listens on
+# listen = "127.0.0.1:8080"
+# token = ""
+# subsonic_user = ""
+# subsonic_password = ""
+# transcoder = "/usr/bin/ffmpeg"
+"#;
+
+/// Settings `config get` and `config set` know about
+pub const KEYS: &[&str] = &[
+    "library.db",
+    "library.roots",
+    "library.busy_timeout",
+    "playback.volume",
+    "playback.device",
+    "playback.replaygain",
+    "playback.resume_min_length",
+    "playback.resume_rewind",
+    "playback.audio_language",
+    "playback.soundfont",
+    "playback.dsd_rate",
+    "scrobbling.lastfm_api_key",
+    "scrobbling.lastfm_api_secret",
+    "scrobbling.lastfm_session_key",
+    "scrobbling.lastfm_url",
+    "scrobbling.listenbrainz_token",
+    "scrobbling.listenbrainz_url",
+    "server.listen",
+    "server.token",
+    "server.subsonic_user",
+    "server.subsonic_password",
+    "server.transcoder",
+];
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub library: LibraryConfig,
+    pub playback: PlaybackConfig,
+    pub scrobbling: ScrobblingConfig,
+    pub server: ServerConfig,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibraryConfig {
+    pub db: Option<PathBuf>,
+    pub roots: Vec<PathBuf>,
+    /// Milliseconds
+    pub busy_timeout: Option<u64>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlaybackConfig {
+    pub volume: Option<f64>,
+    pub device: Option<String>,
+    pub replaygain: ReplayGain,
+    /// Seconds
+    pub resume_min_length: Option<u64>,
+    /// Seconds
+    pub resume_rewind: Option<u64>,
+    pub audio_language: Option<String>,
+    pub soundfont: Option<PathBuf>,
+    /// Hz
+    pub dsd_rate: Option<u32>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ScrobblingConfig {
+    pub lastfm_api_key: Option<String>,
+    pub lastfm_api_secret: Option<String>,
+    pub lastfm_session_key: Option<String>,
+    pub lastfm_url: Option<String>,
+    pub listenbrainz_token: Option<String>,
+    pub listenbrainz_url: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Option<String>,
+    pub token: Option<String>,
+    pub subsonic_user: Option<String>,
+    pub subsonic_password: Option<String>,
+    pub transcoder: Option<PathBuf>,
+}
+
+fn project_dirs() -> Result<ProjectDirs> {
+    ProjectDirs::from("", "", "rustyplayer").context("Can't find the home directory")
+}
+
+/// `~/.config/rustyplayer/config.toml`, or the platform's equivalent
+pub fn default_config_path() -> Result<PathBuf> {
+    Ok(project_dirs()?.config_dir().join("config.toml"))
+}
+
+/// `~/.local/share/rustyplayer/library.db`, or the platform's equivalent
+pub fn default_db_path() -> Result<PathBuf> {
+    Ok(project_dirs()?.data_dir().join("library.db"))
+}
+
+/// Expand a leading `~` to the home directory
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), BaseDirs::new()) {
+        (Ok(rest), Some(dirs)) => dirs.home_dir().join(rest),
+        _ => path.to_owned(),
+    }
+}
+
+impl Config {
+    /// Read the settings at `path`. A missing file is the same as an empty
+    /// one, so nothing has to be set up before first use.
+    pub fn load(path: &Path) -> Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).with_context(|| format!("in {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e).with_context(|| format!("reading {}", path.display())),
+        }
+    }
+
+    fn parse(text: &str) -> Result<Self> {
+        let mut config: Config = toml::from_str(text)?;
+        if let Some(volume) = config.playback.volume
+            && !(0.0..=1.0).contains(&volume)
+        {
+            bail!(
+                "playback.volume must be between 0.0 and 1.0, got {}",
+                volume
+            );
+        }
+        if let Some(rate) = config.playback.dsd_rate
+            && !OUTPUT_RATES.contains(&rate)
+        {
+            bail!(
+                "playback.dsd_rate must be one of {}, got {}",
+                OUTPUT_RATES
+                    .iter()
+                    .map(|rate| rate.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+                rate
+            );
+        }
+        let library = &mut config.library;
+        library.db = library.db.as_deref().map(expand_home);
+        library.roots = library.roots.iter().map(|root| expand_home(root)).collect();
+        config.playback.soundfont = config.playback.soundfont.as_deref().map(expand_home);
+        config.server.transcoder = config.server.transcoder.as_deref().map(expand_home);
+        Ok(config)
+    }
+
+    pub fn player_options(&self) -> PlayerOptions {
+        PlayerOptions {
+            device: self.playback.device.clone(),
+            volume: self.playback.volume.unwrap_or(1.0) as f32,
+            replaygain: self.playback.replaygain,
+            stream: StreamChoice {
+                index: None,
+                language: self.playback.audio_language.clone(),
+            },
+        }
+    }
+
+    pub fn resume_options(&self) -> ResumeOptions {
+        let defaults = ResumeOptions::default();
+        ResumeOptions {
+            min_length: self
+                .playback
+                .resume_min_length
+                .map_or(defaults.min_length, Duration::from_secs),
+            rewind: self
+                .playback
+                .resume_rewind
+                .map_or(defaults.rewind, Duration::from_secs),
+        }
+    }
+
+    /// The value of a setting as TOML, or `None` if it isn't set
+    pub fn get(&self, key: &str) -> Result<Option<toml::Value>> {
+        let (section, name) = split_key(key)?;
+        let value = toml::Value::try_from(self)?;
+        Ok(value
+            .get(section)
+            .and_then(|section| section.get(name))
+            .filter(|value| !value.as_array().is_some_and(|array| array.is_empty()))
+            .cloned())
+    }
+}
+
+fn split_key(key: &str) -> Result<(&str, &str)> {
+    match key.split_once('.') {
+        Some(parts) if KEYS.contains(&key) => Ok(parts),
+        _ => bail!(
+            "Unknown setting {:?}; settings are {}",
+            key,
+            KEYS.join(", ")
+        ),
+    }
+}
+
+/// Change one setting in the file at `path`, keeping its comments and layout.
+/// `value` is read as TOML (`0.5`, `true`, `["~/Music"]`), falling back to a
+/// plain string.
+pub fn set(path: &Path, key: &str, value: &str) -> Result<()> {
+    let (section, name) = split_key(key)?;
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => TEMPLATE.to_owned(),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", path.display())),
+    };
+    let mut document: toml_edit::DocumentMut = text
+        .parse()
+        .with_context(|| format!("in {}", path.display()))?;
+
+    let string = toml_edit::Value::from(value);
+    let mut candidates = vec![string];
+    if let Ok(parsed) = value.parse::<toml_edit::Value>()
+        && !parsed.is_str()
+    {
+        candidates.insert(0, parsed);
+    }
+    let mut error = None;
+    for candidate in candidates {
+        document[section][name] = toml_edit::value(candidate);
+        match Config::parse(&document.to_string()) {
+            Ok(_) => return write(path, &document.to_string()),
+            Err(e) => error = Some(e),
+        }
+    }
+    Err(error.unwrap().context(format!("Invalid value for {}", key)))
+}
+
+/// Open the file at `path` in `$VISUAL` or `$EDITOR`, creating it from a
+/// commented template first, then check that it still reads
+pub fn edit(path: &Path) -> Result<()> {
+    if !path.exists() {
+        write(path, TEMPLATE)?;
+    }
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned());
+    // The editor may come with arguments, as in `code --wait`
+    let mut words = editor.split_whitespace();
+    let program = words.next().context("$EDITOR is empty")?;
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .with_context(|| format!("running {}", editor))?;
+    if !status.success() {
+        bail!("{} exited with {}", editor, status);
+    }
+    Config::load(path).map(|_| ())
+}
+
+/// Write the settings file, readable only by its owner since it may hold
+/// passwords and API secrets
+fn write(path: &Path, text: &str) -> Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("writing {}", path.display()))?;
+    file.write_all(text.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_template_covers_every_key() {
+        let uncommented: String = TEMPLATE
+            .lines()
+            .map(|line| match line.strip_prefix("# ") {
+                Some(setting) if setting.contains(" = ") => setting,
+                _ => line,
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        let config = Config::parse(&uncommented).unwrap();
+        for key in KEYS {
+            assert!(
+                config.get(key).unwrap().is_some(),
+                "{} is not in the template",
+                key
+            );
+        }
+        assert_eq!(Config::parse(TEMPLATE).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_set_and_get() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("rustyplayer").join("config.toml");
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+
+        set(&path, "playback.volume", "0.5").unwrap();
+        set(&path, "playback.replaygain", "album").unwrap();
+        set(&path, "library.roots", r#"["/music", "/mnt/nas"]"#).unwrap();
+        // Looks like a number, but tokens are strings
+        set(&path, "server.token", "1234").unwrap();
+        set(&path, "server.listen", "0.0.0.0:4533").unwrap();
+
+        let config = Config::load(&path).unwrap();
+        assert_eq!(config.playback.volume, Some(0.5));
+        assert_eq!(config.playback.replaygain, ReplayGain::Album);
+        assert_eq!(
+            config.library.roots,
+            [Path::new("/music"), Path::new("/mnt/nas")]
+        );
+        assert_eq!(config.get("server.token").unwrap(), Some("1234".into()));
+        assert_eq!(config.get("library.db").unwrap(), None);
+        // Comments from the template are kept
+        assert!(
+            fs::read_to_string(&path)
+                .unwrap()
+                .contains("# Starting volume")
+        );
+
+        assert!(set(&path, "playback.volume", "2").is_err());
+        assert!(set(&path, "playback.replaygain", "loud").is_err());
+        assert!(set(&path, "playback.dsd_rate", "50000").is_err());
+        assert!(set(&path, "playback.colour", "red").is_err());
+        assert!(config.get("nope").is_err());
+        assert_eq!(Config::load(&path).unwrap(), config);
+    }
+}
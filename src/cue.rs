@@ -0,0 +1,529 @@
+//! Cue sheets: albums ripped to one audio file and split into tracks by a
+//! `.cue` file beside it, a `CUESHEET` tag or a FLAC cuesheet block.
+//!
+//! Each cue track becomes a library track of its own. Its path is the audio
+//! file's path with `#` and the track number appended, as in
+//! `/music/Album.flac#03`, and the player reads the sheet again to find where
+//! it starts and ends.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use symphonia::core::formats::Cue;
+use symphonia::core::meta::Tag;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CueError {
+    #[error("Invalid cue sheet, line {line}: {message}")]
+    Invalid { line: usize, message: String },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// CD sectors per second, the unit of cue sheet times
+const SECTORS_PER_SECOND: u64 = 75;
+
+/// `CueTrack::start` until an `INDEX` line sets it
+const NO_INDEX: u64 = u64::MAX;
+
+#[derive(Clone, Copy)]
+enum Scope {
+    Sheet,
+    Track,
+    /// A data track, whose lines are ignored
+    Skipped,
+}
+
+/// A parsed cue sheet
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueSheet {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    /// From `REM GENRE`
+    pub genre: Option<String>,
+    /// From `REM DATE`
+    pub date: Option<String>,
+    pub files: Vec<CueFile>,
+}
+
+/// An audio file named by a cue sheet and the tracks in it
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueFile {
+    pub name: String,
+    pub tracks: Vec<CueTrack>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CueTrack {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub isrc: Option<String>,
+    /// `INDEX 01` (or `INDEX 00` if that is all there is), in 1/75 s sectors
+    pub start: u64,
+}
+
+impl CueFile {
+    /// Whether this entry is for `file`. Names are compared without the
+    /// extension too, since sheets often still name the `.wav` that was later
+    /// compressed.
+    fn names(&self, file: &Path) -> bool {
+        // Sheets written on Windows use backslashes
+        let name = Path::new(self.name.rsplit(['/', '\\']).next().unwrap_or(""));
+        let same = |a: Option<&std::ffi::OsStr>, b: Option<&std::ffi::OsStr>| {
+            a.zip(b).is_some_and(|(a, b)| {
+                a.to_string_lossy()
+                    .eq_ignore_ascii_case(&b.to_string_lossy())
+            })
+        };
+        same(name.file_name(), file.file_name()) || same(name.file_stem(), file.file_stem())
+    }
+}
+
+/// One track's span of an audio file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Segment {
+    pub number: u32,
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub songwriter: Option<String>,
+    pub isrc: Option<String>,
+    /// First frame, counted in samples per channel from the start of the file
+    pub start: u64,
+    /// Frame after the last; `None` runs to the end of the file
+    pub end: Option<u64>,
+}
+
+/// The tracks of an audio file split by a cue sheet, with the album details
+/// the sheet gives
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Split {
+    pub title: Option<String>,
+    pub performer: Option<String>,
+    pub genre: Option<String>,
+    pub date: Option<String>,
+    pub segments: Vec<Segment>,
+}
+
+/// Split `command "quoted words" more` into words
+fn words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut rest = line.trim();
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let (word, after) = quoted.split_once('"').unwrap_or((quoted, ""));
+            words.push(word.to_owned());
+            rest = after.trim_start();
+        } else {
+            let (word, after) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+            words.push(word.to_owned());
+            rest = after.trim_start();
+        }
+    }
+    words
+}
+
+/// `mm:ss:ff` in sectors, up to what fits in 32 bits (over 600 days) so that
+/// turning it into frames can't overflow
+fn parse_time(time: &str) -> Option<u64> {
+    let mut parts = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (minutes, seconds, sectors) = (parts.next()??, parts.next()??, parts.next()??);
+    if parts.next().is_some() || seconds >= 60 || sectors >= SECTORS_PER_SECOND {
+        return None;
+    }
+    let total = minutes
+        .checked_mul(60)?
+        .checked_add(seconds)?
+        .checked_mul(SECTORS_PER_SECOND)?
+        .checked_add(sectors)?;
+    (total <= u64::from(u32::MAX)).then_some(total)
+}
+
+impl CueSheet {
+    pub fn parse(text: &str) -> Result<Self, CueError> {
+        let mut sheet = CueSheet::default();
+        // Which the lines that follow describe
+        let mut scope = Scope::Sheet;
+        for (index, line) in text.lines().enumerate() {
+            let invalid = |message: &str| CueError::Invalid {
+                line: index + 1,
+                message: message.to_owned(),
+            };
+            let words = words(line.trim_start_matches('\u{feff}'));
+            let Some(command) = words.first() else {
+                continue;
+            };
+            let command = command.to_ascii_uppercase();
+            let value = words.get(1).cloned();
+            match command.as_str() {
+                "FILE" => {
+                    let name = value.ok_or_else(|| invalid("FILE without a name"))?;
+                    sheet.files.push(CueFile {
+                        name,
+                        tracks: Vec::new(),
+                    });
+                    scope = Scope::Sheet;
+                    continue;
+                }
+                "TRACK" => {
+                    let file = sheet
+                        .files
+                        .last_mut()
+                        .ok_or_else(|| invalid("TRACK before any FILE"))?;
+                    if file
+                        .tracks
+                        .last()
+                        .is_some_and(|last| last.start == NO_INDEX)
+                    {
+                        return Err(invalid("the previous track has no INDEX"));
+                    }
+                    let number = value
+                        .and_then(|number| number.parse().ok())
+                        .ok_or_else(|| invalid("TRACK without a number"))?;
+                    // Data tracks have nothing to play
+                    if words
+                        .get(2)
+                        .is_some_and(|kind| !kind.eq_ignore_ascii_case("AUDIO"))
+                    {
+                        scope = Scope::Skipped;
+                        continue;
+                    }
+                    file.tracks.push(CueTrack {
+                        number,
+                        start: NO_INDEX,
+                        ..Default::default()
+                    });
+                    scope = Scope::Track;
+                    continue;
+                }
+                _ => {}
+            }
+            match scope {
+                Scope::Skipped => {}
+                Scope::Sheet => match command.as_str() {
+                    "TITLE" => sheet.title = value,
+                    "PERFORMER" => sheet.performer = value,
+                    "SONGWRITER" => sheet.songwriter = value,
+                    "REM" => {
+                        let text = words.get(2..).map(|rest| rest.join(" "));
+                        match value.map(|key| key.to_ascii_uppercase()).as_deref() {
+                            Some("GENRE") => sheet.genre = text,
+                            Some("DATE") => sheet.date = text,
+                            _ => {}
+                        }
+                    }
+                    _ => {}
+                },
+                Scope::Track => {
+                    let Some(track) = sheet.files.last_mut().and_then(|f| f.tracks.last_mut())
+                    else {
+                        continue;
+                    };
+                    match command.as_str() {
+                        "TITLE" => track.title = value,
+                        "PERFORMER" => track.performer = value,
+                        "SONGWRITER" => track.songwriter = value,
+                        "ISRC" => track.isrc = value,
+                        "INDEX" => {
+                            let time = words
+                                .get(2)
+                                .and_then(|time| parse_time(time))
+                                .ok_or_else(|| invalid("INDEX without a valid mm:ss:ff time"))?;
+                            // A pregap (INDEX 00) plays at the end of the track before
+                            match value.and_then(|n| n.parse::<u32>().ok()) {
+                                Some(1) => track.start = time,
+                                Some(_) if track.start == NO_INDEX => track.start = time,
+                                Some(_) => {}
+                                None => return Err(invalid("INDEX without a number")),
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+        let tracks = sheet.files.iter().flat_map(|file| &file.tracks);
+        if let Some(track) = tracks.clone().find(|track| track.start == NO_INDEX) {
+            return Err(CueError::Invalid {
+                line: text.lines().count(),
+                message: format!("track {} has no INDEX", track.number),
+            });
+        }
+        Ok(sheet)
+    }
+
+    /// Read a `.cue` file, which may be UTF-8 or, from older rippers, Latin-1
+    pub fn read(path: &Path) -> Result<Self, CueError> {
+        let bytes = fs::read(path)?;
+        let text = match String::from_utf8(bytes) {
+            Ok(text) => text,
+            Err(err) => err.into_bytes().iter().map(|&b| b as char).collect(),
+        };
+        Self::parse(&text)
+    }
+
+    /// The entry for `file`; a sheet for one file matches any file
+    fn file(&self, file: &Path) -> Option<&CueFile> {
+        self.files
+            .iter()
+            .find(|entry| entry.names(file))
+            .or(self.files.first().filter(|_| self.files.len() == 1))
+    }
+
+    /// Cut `file` into segments at the sheet's track starts
+    fn split(&self, file: &Path, sample_rate: u32) -> Option<Split> {
+        let entry = self.file(file)?;
+        let frames = |sectors: u64| sectors * u64::from(sample_rate) / SECTORS_PER_SECOND;
+        let mut segments: Vec<Segment> = entry
+            .tracks
+            .iter()
+            .map(|track| Segment {
+                number: track.number,
+                title: track.title.clone(),
+                performer: track.performer.clone(),
+                songwriter: track.songwriter.clone().or(self.songwriter.clone()),
+                isrc: track.isrc.clone(),
+                start: frames(track.start),
+                end: None,
+            })
+            .collect();
+        close_segments(&mut segments);
+        (!segments.is_empty()).then(|| Split {
+            title: self.title.clone(),
+            performer: self.performer.clone(),
+            genre: self.genre.clone(),
+            date: self.date.clone(),
+            segments,
+        })
+    }
+}
+
+/// End each segment where the next one starts
+fn close_segments(segments: &mut [Segment]) {
+    for i in 1..segments.len() {
+        segments[i - 1].end = Some(segments[i].start);
+    }
+}
+
+/// Path of track `number` of an audio file split by a cue sheet
+pub fn track_path(file: &Path, number: u32) -> String {
+    format!("{}#{:02}", file.display(), number)
+}
+
+/// The audio file and track number of a path made by `track_path`. Paths
+/// that name an existing file are never split, whatever they look like.
+pub fn split_path(path: &Path) -> Option<(PathBuf, u32)> {
+    if path.exists() {
+        return None;
+    }
+    let (file, number) = path.to_str()?.rsplit_once('#')?;
+    let number = number.parse().ok()?;
+    Path::new(file).is_file().then(|| (file.into(), number))
+}
+
+/// The audio file a library path belongs to; the path itself unless it is a
+/// cue track made by `track_path`
+pub fn file_of(path: &str) -> &str {
+    match path.rsplit_once('#') {
+        Some((file, number)) if number.len() >= 2 && number.bytes().all(|b| b.is_ascii_digit()) => {
+            file
+        }
+        _ => path,
+    }
+}
+
+/// `.cue` files in the same directory as `file`
+fn sibling_sheets(file: &Path) -> Vec<PathBuf> {
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut sheets: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| is_cue_file(path))
+        .collect();
+    sheets.sort();
+    sheets
+}
+
+/// Whether a path has the `.cue` extension
+pub fn is_cue_file(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("cue"))
+}
+
+/// Latest modification time, in unix seconds, of the cue sheets beside `file`
+pub fn sheets_modified(file: &Path) -> Option<i64> {
+    sibling_sheets(file)
+        .iter()
+        .filter_map(|sheet| fs::metadata(sheet).ok()?.modified().ok())
+        .filter_map(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|age| age.as_secs() as i64)
+        .max()
+}
+
+/// Find how `file` is split into tracks: by a `.cue` file beside it that
+/// names it, by a `CUESHEET` tag, or by a FLAC cuesheet block (`cues`), in
+/// that order. `None` if it is a single track.
+pub fn find_split(file: &Path, tags: &[Tag], cues: &[Cue], sample_rate: u32) -> Option<Split> {
+    for path in sibling_sheets(file) {
+        let Ok(sheet) = CueSheet::read(&path) else {
+            continue;
+        };
+        // A sheet beside the file may be for another image in the directory
+        if sheet.files.iter().any(|entry| entry.names(file))
+            && let Some(split) = sheet.split(file, sample_rate)
+        {
+            return Some(split);
+        }
+    }
+    let embedded = tags
+        .iter()
+        .find(|tag| tag.key.eq_ignore_ascii_case("CUESHEET"))
+        .and_then(|tag| CueSheet::parse(&tag.value.to_string()).ok())
+        .and_then(|sheet| {
+            let only = CueSheet {
+                files: sheet.files.into_iter().take(1).collect(),
+                ..sheet
+            };
+            only.split(file, sample_rate)
+        });
+    embedded.or_else(|| split_cues(cues))
+}
+
+/// Segments from a FLAC cuesheet block. It has no titles, and the lead-out
+/// (the track with no index points) only marks where the last track ends.
+fn split_cues(cues: &[Cue]) -> Option<Split> {
+    let mut segments: Vec<Segment> = cues
+        .iter()
+        .filter(|cue| !cue.points.is_empty())
+        .map(|cue| Segment {
+            number: cue.index,
+            isrc: cue
+                .tags
+                .iter()
+                .find(|tag| tag.key == "ISRC")
+                .map(|tag| tag.value.to_string())
+                .filter(|isrc| !isrc.is_empty()),
+            start: cue.start_ts + cue.points[0].start_offset_ts,
+            ..Default::default()
+        })
+        .collect();
+    if segments.len() < 2 {
+        return None;
+    }
+    close_segments(&mut segments);
+    if let (Some(last), Some(lead_out)) = (
+        segments.last_mut(),
+        cues.iter().find(|cue| cue.points.is_empty()),
+    ) {
+        last.end = Some(lead_out.start_ts);
+    }
+    Some(Split {
+        segments,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    const SHEET: &str = r#"REM GENRE Electronic
+REM DATE 2002
+PERFORMER "Boards of Canada"
+TITLE "Geogaddi"
+FILE "Geogaddi.wav" WAVE
+  TRACK 01 AUDIO
+    TITLE "Ready Lets Go"
+    INDEX 01 00:00:00
+  TRACK 02 AUDIO
+    TITLE "Music Is Math"
+    PERFORMER "BoC"
+    INDEX 00 00:59:70
+    INDEX 01 01:00:00
+  TRACK 03 AUDIO
+    TITLE "Beware the Friendly Stranger"
+    INDEX 01 06:21:37
+"#;
+
+    #[test]
+    fn test_parse_and_split() {
+        let sheet = CueSheet::parse(SHEET).unwrap();
+        assert_eq!(sheet.title.as_deref(), Some("Geogaddi"));
+        assert_eq!(sheet.genre.as_deref(), Some("Electronic"));
+        assert_eq!(sheet.date.as_deref(), Some("2002"));
+        let tracks = &sheet.files[0].tracks;
+        assert_eq!(tracks.len(), 3);
+        assert_eq!(tracks[1].performer.as_deref(), Some("BoC"));
+        // INDEX 01, not the pregap, starts the track
+        assert_eq!(tracks[1].start, 60 * 75);
+
+        // The sheet names the WAV the FLAC was made from
+        let split = sheet
+            .split(Path::new("/music/Geogaddi.flac"), 44100)
+            .unwrap();
+        let bounds: Vec<_> = split.segments.iter().map(|s| (s.start, s.end)).collect();
+        let track_3 = (6 * 60 + 21) * 44100 + 37 * 588;
+        assert_eq!(
+            bounds,
+            [
+                (0, Some(60 * 44100)),
+                (60 * 44100, Some(track_3)),
+                (track_3, None)
+            ]
+        );
+
+        assert!(CueSheet::parse("TRACK 01 AUDIO").is_err());
+        assert!(CueSheet::parse("FILE \"a.wav\" WAVE\nTRACK 01 AUDIO\nTITLE x").is_err());
+        assert!(CueSheet::parse("FILE \"a.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 1:99:00").is_err());
+        // Times too large to work with are rejected, not overflowed
+        for time in ["99999999999999999:00:00", "954437:10:46"] {
+            let sheet = format!("FILE \"a.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 {}", time);
+            assert!(matches!(
+                CueSheet::parse(&sheet),
+                Err(CueError::Invalid { line: 3, .. })
+            ));
+        }
+        let sheet = "FILE \"a.wav\" WAVE\nTRACK 01 AUDIO\nINDEX 01 954437:10:45";
+        let sheet = CueSheet::parse(sheet).unwrap();
+        assert_eq!(sheet.files[0].tracks[0].start, u64::from(u32::MAX));
+    }
+
+    #[test]
+    fn test_track_paths() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("Album #1.flac");
+        fs::write(&file, b"").unwrap();
+        let path = track_path(&file, 3);
+        assert!(path.ends_with("Album #1.flac#03"));
+        assert_eq!(split_path(Path::new(&path)), Some((file.clone(), 3)));
+        assert_eq!(file_of(&path), file.to_str().unwrap());
+        // A real file is never a cue track
+        assert_eq!(split_path(&file), None);
+        assert_eq!(file_of("/music/Take #5"), "/music/Take #5");
+
+        // Only a sheet naming the file splits it
+        fs::write(dir.path().join("Geogaddi.cue"), SHEET).unwrap();
+        assert_eq!(find_split(&file, &[], &[], 48000), None);
+        let sheet = SHEET.replace("Geogaddi.wav", "Album #1.wav");
+        fs::write(dir.path().join("Album #1.cue"), sheet).unwrap();
+        let split = find_split(&file, &[], &[], 48000).unwrap();
+        assert_eq!(
+            split.segments[2].title.as_deref(),
+            Some("Beware the Friendly Stranger")
+        );
+        assert_eq!(
+            find_split(&dir.path().join("Other.flac"), &[], &[], 48000),
+            None
+        );
+    }
+}
@@ -1,39 +1,1324 @@
-use anyhow::Result;
-use rusqlite::Connection;
+use anyhow::{Result, bail};
+use percent_encoding::{AsciiSet, CONTROLS, utf8_percent_encode};
+use rusqlite::types::{FromSql, FromSqlResult, ToSqlOutput, Value, ValueRef};
+use rusqlite::{
+    Connection, DatabaseName, ErrorCode, OpenFlags, OptionalExtension, Row, ToSql, Transaction,
+    TransactionBehavior, params,
+};
+use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::chapters::Chapter;
+
+mod bookmarks;
+mod catalog;
+mod files;
+mod query;
+mod scrobbles;
+mod search;
+mod smart;
+mod stats;
+mod streams;
+
+pub use bookmarks::Bookmark;
+pub use catalog::{Album, Artist, Credit, Role, UNKNOWN_ARTIST, VARIOUS_ARTISTS, split_credit};
+pub(crate) use query::civil_from_days;
+pub use query::{Expr, Field, Op, Operand, ParseError, SortKey};
+pub use scrobbles::{Listen, QueueStatus, QueuedListen};
+pub use smart::{PlaylistOrder, SmartRules};
+pub use stats::{
+    FORGOTTEN_AFTER, FirstListen, Ranked, StatsReport, Streaks, TopTrack, Totals, Window,
+};
+
+/// Schema migrations, applied in order and tracked through `PRAGMA user_version`.
+///
+/// Never edit an entry once it has shipped; append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE IF NOT EXISTS tracks (
+        id INTEGER PRIMARY KEY,
+        path TEXT UNIQUE NOT NULL,
+        title TEXT,
+        artist TEXT,
+        album TEXT,
+        duration_seconds INTEGER,
+        added_at INTEGER,
+        play_count INTEGER DEFAULT 0,
+        last_played INTEGER
+    );",
+    "CREATE TABLE playlists (
+        id INTEGER PRIMARY KEY,
+        name TEXT UNIQUE NOT NULL,
+        created_at INTEGER
+    );
+    CREATE TABLE playlist_entries (
+        playlist_id INTEGER NOT NULL REFERENCES playlists(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        track_id INTEGER NOT NULL REFERENCES tracks(id) ON DELETE CASCADE,
+        PRIMARY KEY (playlist_id, position)
+    );
+    CREATE INDEX idx_tracks_artist_album ON tracks(artist, album);",
+    "ALTER TABLE tracks ADD COLUMN rating REAL;
+    ALTER TABLE tracks ADD COLUMN starred_at INTEGER;
+    CREATE TABLE play_events (
+        id INTEGER PRIMARY KEY,
+        track_id INTEGER NOT NULL REFERENCES tracks(id) ON DELETE CASCADE,
+        played_at INTEGER NOT NULL
+    );
+    CREATE INDEX idx_play_events_played_at ON play_events(played_at);",
+    // Full-text index over the searchable columns, kept in sync by triggers.
+    // `filename` is the last path component of `tracks.path`.
+    "ALTER TABLE tracks ADD COLUMN album_artist TEXT;
+    ALTER TABLE tracks ADD COLUMN genre TEXT;
+    ALTER TABLE tracks ADD COLUMN comment TEXT;
+    CREATE VIRTUAL TABLE tracks_fts USING fts5(
+        title, artist, album, album_artist, genre, comment, filename,
+        tokenize = 'unicode61 remove_diacritics 2',
+        prefix = '2 3'
+    );
+    INSERT INTO tracks_fts(tracks_fts, rank) VALUES ('rank', 'bm25(10.0, 5.0, 4.0, 3.0, 1.0, 0.5, 1.0)');
+    INSERT INTO tracks_fts(rowid, title, artist, album, album_artist, genre, comment, filename)
+        SELECT id, title, artist, album, album_artist, genre, comment,
+            replace(path, rtrim(path, replace(path, '/', '')), '')
+        FROM tracks;
+    CREATE TRIGGER tracks_fts_insert AFTER INSERT ON tracks BEGIN
+        INSERT INTO tracks_fts(rowid, title, artist, album, album_artist, genre, comment, filename)
+        VALUES (new.id, new.title, new.artist, new.album, new.album_artist, new.genre, new.comment,
+            replace(new.path, rtrim(new.path, replace(new.path, '/', '')), ''));
+    END;
+    CREATE TRIGGER tracks_fts_delete AFTER DELETE ON tracks BEGIN
+        DELETE FROM tracks_fts WHERE rowid = old.id;
+    END;
+    CREATE TRIGGER tracks_fts_update
+    AFTER UPDATE OF path, title, artist, album, album_artist, genre, comment ON tracks BEGIN
+        DELETE FROM tracks_fts WHERE rowid = old.id;
+        INSERT INTO tracks_fts(rowid, title, artist, album, album_artist, genre, comment, filename)
+        VALUES (new.id, new.title, new.artist, new.album, new.album_artist, new.genre, new.comment,
+            replace(new.path, rtrim(new.path, replace(new.path, '/', '')), ''));
+    END;",
+    "ALTER TABLE tracks ADD COLUMN year INTEGER;",
+    // Smart playlists keep their rules here instead of entries
+    "ALTER TABLE playlists ADD COLUMN query TEXT;
+    ALTER TABLE playlists ADD COLUMN ordering TEXT;
+    ALTER TABLE playlists ADD COLUMN track_limit INTEGER;",
+    // Normalised artists and albums, derived from the tag columns of `tracks`.
+    // Existing rows are linked afterwards by `link_existing_tracks`.
+    "CREATE TABLE artists (
+        id INTEGER PRIMARY KEY,
+        name TEXT UNIQUE NOT NULL,
+        mbid TEXT
+    );
+    CREATE TABLE albums (
+        id INTEGER PRIMARY KEY,
+        title TEXT NOT NULL,
+        artist_id INTEGER NOT NULL REFERENCES artists(id),
+        compilation INTEGER NOT NULL DEFAULT 0,
+        year INTEGER,
+        mbid TEXT,
+        UNIQUE (title, artist_id)
+    );
+    CREATE TABLE track_artists (
+        track_id INTEGER NOT NULL REFERENCES tracks(id) ON DELETE CASCADE,
+        artist_id INTEGER NOT NULL REFERENCES artists(id),
+        role TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        PRIMARY KEY (track_id, role, position)
+    );
+    CREATE INDEX idx_track_artists_artist ON track_artists(artist_id);
+    ALTER TABLE tracks ADD COLUMN album_id INTEGER REFERENCES albums(id);
+    ALTER TABLE tracks ADD COLUMN composer TEXT;
+    ALTER TABLE tracks ADD COLUMN track_number INTEGER;
+    ALTER TABLE tracks ADD COLUMN track_total INTEGER;
+    ALTER TABLE tracks ADD COLUMN disc_number INTEGER;
+    ALTER TABLE tracks ADD COLUMN disc_total INTEGER;
+    ALTER TABLE tracks ADD COLUMN date TEXT;
+    ALTER TABLE tracks ADD COLUMN mbid TEXT;
+    CREATE INDEX idx_tracks_album_id ON tracks(album_id, disc_number, track_number);",
+    // 1 for loved, -1 for banned, NULL for neither
+    "ALTER TABLE tracks ADD COLUMN loved INTEGER;",
+    // Per-track play history, for statistics and first-listen dates
+    "CREATE INDEX idx_play_events_track ON play_events(track_id, played_at);",
+    // Listens waiting for Last.fm or ListenBrainz. They are copied rather than
+    // referenced so that removing a track doesn't lose its pending scrobbles.
+    "CREATE TABLE scrobbles (
+        id INTEGER PRIMARY KEY,
+        service TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        title TEXT NOT NULL,
+        album TEXT,
+        album_artist TEXT,
+        track_number INTEGER,
+        duration_seconds INTEGER,
+        mbid TEXT,
+        played_at INTEGER NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL DEFAULT 0,
+        last_error TEXT,
+        UNIQUE (service, played_at, artist, title)
+    );
+    CREATE INDEX idx_scrobbles_due ON scrobbles(service, next_attempt_at, played_at);",
+    // Lets rescans and the watcher skip files that haven't changed since import
+    "ALTER TABLE tracks ADD COLUMN file_mtime INTEGER;",
+    // Directories an interrupted scan already finished, so it can resume
+    "CREATE TABLE scan_progress (
+        root TEXT NOT NULL,
+        directory TEXT NOT NULL,
+        PRIMARY KEY (root, directory)
+    );",
+    // Tracks cut from a single-file album by a cue sheet: where in the file
+    // each starts and ends. A NULL end runs to the end of the file.
+    "ALTER TABLE tracks ADD COLUMN start_ms INTEGER;
+    ALTER TABLE tracks ADD COLUMN end_ms INTEGER;",
+    // Chapters of audiobooks and long recordings, in order
+    "CREATE TABLE chapters (
+        track_id INTEGER NOT NULL REFERENCES tracks(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        title TEXT,
+        start_ms INTEGER NOT NULL,
+        end_ms INTEGER,
+        PRIMARY KEY (track_id, position)
+    );",
+    // Where long files were stopped, and named positions in them
+    "CREATE TABLE resume_positions (
+        track_id INTEGER PRIMARY KEY REFERENCES tracks(id) ON DELETE CASCADE,
+        position_ms INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE TABLE bookmarks (
+        id INTEGER PRIMARY KEY,
+        track_id INTEGER NOT NULL REFERENCES tracks(id) ON DELETE CASCADE,
+        name TEXT NOT NULL,
+        position_ms INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX idx_bookmarks_track ON bookmarks(track_id);",
+    // Audio stream to play from files with several
+    "CREATE TABLE stream_choices (
+        track_id INTEGER PRIMARY KEY REFERENCES tracks(id) ON DELETE CASCADE,
+        stream_index INTEGER,
+        language TEXT
+    );",
+];
+
+/// Index into `MIGRATIONS` of the one that adds artists and albums
+const CATALOG_MIGRATION: usize = 6;
 
 pub struct DB {
     conn: Connection,
+    read_only: bool,
+}
+
+/// Characters escaped in a path given to SQLite as a URI
+const URI_PATH: &AsciiSet = &CONTROLS.add(b'%').add(b'?').add(b'#');
+
+/// How to open the library database
+#[derive(Debug, Clone)]
+pub struct DbOptions {
+    /// How long to keep retrying while another process holds a lock
+    pub busy_timeout: Duration,
+    /// Open read-only even if the file is writable
+    pub read_only: bool,
+}
+
+impl Default for DbOptions {
+    fn default() -> Self {
+        Self {
+            busy_timeout: Duration::from_secs(5),
+            read_only: false,
+        }
+    }
+}
+
+/// Identifies a track in the library; stable across rescans and renames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TrackId(pub i64);
+
+impl fmt::Display for TrackId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for TrackId {
+    type Err = std::num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(TrackId)
+    }
+}
+
+impl ToSql for TrackId {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        self.0.to_sql()
+    }
+}
+
+impl FromSql for TrackId {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        i64::column_result(value).map(TrackId)
+    }
+}
+
+/// A track row from the library
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Track {
+    pub id: TrackId,
+    pub path: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+    pub year: Option<i64>,
+    pub duration_seconds: Option<i64>,
+    pub added_at: Option<i64>,
+    pub play_count: i64,
+    pub last_played: Option<i64>,
+    /// Stars from 0 to 5 in steps of a half
+    pub rating: Option<f64>,
+    pub starred_at: Option<i64>,
+    pub loved: Love,
+    pub album_id: Option<i64>,
+    pub composer: Option<String>,
+    pub track_number: Option<i64>,
+    pub track_total: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub disc_total: Option<i64>,
+    /// Release date as tagged, e.g. `2001` or `2001-03-12`
+    pub date: Option<String>,
+    /// MusicBrainz recording id
+    pub mbid: Option<String>,
+    /// For a track cut from a longer file by a cue sheet, where it starts and
+    /// ends in the file, in milliseconds (see `crate::cue`)
+    pub start_ms: Option<i64>,
+    pub end_ms: Option<i64>,
+}
+
+/// Whether the listener loves a track, has banned it, or neither
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Love {
+    #[default]
+    Neutral,
+    Loved,
+    Banned,
+}
+
+impl Love {
+    fn to_sql(self) -> Option<i64> {
+        match self {
+            Love::Neutral => None,
+            Love::Loved => Some(1),
+            Love::Banned => Some(-1),
+        }
+    }
+
+    fn from_sql(value: Option<i64>) -> Love {
+        match value {
+            Some(v) if v > 0 => Love::Loved,
+            Some(v) if v < 0 => Love::Banned,
+            _ => Love::Neutral,
+        }
+    }
+}
+
+impl fmt::Display for Love {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Love::Neutral => "neutral",
+            Love::Loved => "loved",
+            Love::Banned => "banned",
+        })
+    }
+}
+
+impl FromStr for Love {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "neutral" | "none" => Ok(Love::Neutral),
+            "loved" | "love" => Ok(Love::Loved),
+            "banned" | "ban" => Ok(Love::Banned),
+            _ => Err(format!(
+                "unknown value `{}` (expected loved, banned or neutral)",
+                s
+            )),
+        }
+    }
+}
+
+/// Metadata for inserting or updating a track
+#[derive(Debug, Clone, Default)]
+pub struct NewTrack {
+    pub path: String,
+    pub title: Option<String>,
+    /// Artist credit as displayed, e.g. "Daft Punk feat. Romanthony"
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub genre: Option<String>,
+    pub comment: Option<String>,
+    pub year: Option<i64>,
+    pub duration_seconds: Option<i64>,
+    /// Individually tagged main artists; split from `artist` when empty
+    pub artists: Vec<String>,
+    pub featured_artists: Vec<String>,
+    pub composer: Option<String>,
+    /// Part of a various-artists compilation
+    pub compilation: bool,
+    pub track_number: Option<i64>,
+    pub track_total: Option<i64>,
+    pub disc_number: Option<i64>,
+    pub disc_total: Option<i64>,
+    pub date: Option<String>,
+    pub mbid: Option<String>,
+    /// Rating read from the file's tags, only used if the track has none yet
+    pub rating: Option<f64>,
+    pub album_mbid: Option<String>,
+    pub artist_mbid: Option<String>,
+    pub album_artist_mbid: Option<String>,
+    /// Modification time of the file when it was read, in unix seconds
+    pub file_mtime: Option<i64>,
+    /// Where a cue sheet track lies in its file, in milliseconds
+    pub start_ms: Option<i64>,
+    pub end_ms: Option<i64>,
+    /// Replaces the chapters stored for the track
+    pub chapters: Vec<Chapter>,
+}
+
+/// A stored playlist
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Playlist {
+    pub id: i64,
+    pub name: String,
+    pub created_at: Option<i64>,
+    pub track_count: usize,
+    /// Set for smart playlists, whose tracks come from these rules
+    pub rules: Option<SmartRules>,
+}
+
+/// Window into a listing: at most `limit` rows starting at `offset`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Page {
+    pub limit: usize,
+    pub offset: usize,
+}
+
+impl Page {
+    /// A page covering every row
+    pub fn all() -> Self {
+        Self {
+            limit: i64::MAX as usize,
+            offset: 0,
+        }
+    }
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Self {
+            limit: 50,
+            offset: 0,
+        }
+    }
+}
+
+/// Optional filters for track listings; `text` is a full-text query (see `DB::search`)
+#[derive(Debug, Clone, Default)]
+pub struct TrackFilter {
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub text: Option<String>,
+    pub starred: bool,
+}
+
+impl TrackFilter {
+    fn where_clause(&self) -> (String, Vec<Value>) {
+        let mut clauses = Vec::new();
+        let mut args = Vec::new();
+        if let Some(artist) = &self.artist {
+            clauses.push("artist = ?");
+            args.push(Value::Text(artist.clone()));
+        }
+        if let Some(album) = &self.album {
+            clauses.push("album = ?");
+            args.push(Value::Text(album.clone()));
+        }
+        if let Some(query) = self.text.as_deref().and_then(search::fts_query) {
+            clauses.push("id IN (SELECT rowid FROM tracks_fts WHERE tracks_fts MATCH ?)");
+            args.push(Value::Text(query));
+        }
+        if self.starred {
+            clauses.push("starred_at IS NOT NULL");
+        }
+        if clauses.is_empty() {
+            (String::new(), args)
+        } else {
+            (format!(" WHERE {}", clauses.join(" AND ")), args)
+        }
+    }
+}
+
+const TRACK_COLUMNS: &str = "id, path, title, artist, album, album_artist, genre, comment, \
+     duration_seconds, added_at, play_count, last_played, rating, starred_at, year, \
+     album_id, composer, track_number, track_total, disc_number, disc_total, date, mbid, loved, \
+     start_ms, end_ms";
+
+/// `TRACK_COLUMNS` qualified with a table alias such as `"t."`
+fn track_columns(prefix: &str) -> String {
+    TRACK_COLUMNS
+        .split(", ")
+        .map(|c| format!("{}{}", prefix, c))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn track_from_row(row: &Row) -> rusqlite::Result<Track> {
+    Ok(Track {
+        id: row.get(0)?,
+        path: row.get(1)?,
+        title: row.get(2)?,
+        artist: row.get(3)?,
+        album: row.get(4)?,
+        album_artist: row.get(5)?,
+        genre: row.get(6)?,
+        comment: row.get(7)?,
+        duration_seconds: row.get(8)?,
+        added_at: row.get(9)?,
+        play_count: row.get::<_, Option<i64>>(10)?.unwrap_or(0),
+        last_played: row.get(11)?,
+        rating: row.get(12)?,
+        starred_at: row.get(13)?,
+        year: row.get(14)?,
+        album_id: row.get(15)?,
+        composer: row.get(16)?,
+        track_number: row.get(17)?,
+        track_total: row.get(18)?,
+        disc_number: row.get(19)?,
+        disc_total: row.get(20)?,
+        date: row.get(21)?,
+        mbid: row.get(22)?,
+        loved: Love::from_sql(row.get(23)?),
+        start_ms: row.get(24)?,
+        end_ms: row.get(25)?,
+    })
+}
+
+fn playlist_from_row(row: &Row) -> rusqlite::Result<Playlist> {
+    Ok(Playlist {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        created_at: row.get(2)?,
+        track_count: row.get::<_, i64>(3)? as usize,
+        rules: None,
+    })
+}
+
+/// The body of `DB::insert_track`, for callers that batch inserts into their own
+/// transaction
+fn upsert_track(conn: &Connection, track: &NewTrack) -> Result<TrackId> {
+    let id: TrackId = conn.query_row(
+        "INSERT INTO tracks (path, title, artist, album, album_artist, genre, comment,
+            year, duration_seconds, composer, track_number, track_total, disc_number,
+            disc_total, date, mbid, rating, file_mtime, start_ms, end_ms, added_at)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17,
+            ?18, ?19, ?20, ?21)
+         ON CONFLICT(path) DO UPDATE SET
+            title = excluded.title,
+            artist = excluded.artist,
+            album = excluded.album,
+            album_artist = excluded.album_artist,
+            genre = excluded.genre,
+            comment = excluded.comment,
+            year = excluded.year,
+            duration_seconds = excluded.duration_seconds,
+            composer = excluded.composer,
+            track_number = excluded.track_number,
+            track_total = excluded.track_total,
+            disc_number = excluded.disc_number,
+            disc_total = excluded.disc_total,
+            date = excluded.date,
+            mbid = excluded.mbid,
+            rating = COALESCE(tracks.rating, excluded.rating),
+            file_mtime = excluded.file_mtime,
+            start_ms = excluded.start_ms,
+            end_ms = excluded.end_ms
+         RETURNING id",
+        params![
+            track.path,
+            track.title,
+            track.artist,
+            track.album,
+            track.album_artist,
+            track.genre,
+            track.comment,
+            track.year,
+            track.duration_seconds,
+            track.composer,
+            track.track_number,
+            track.track_total,
+            track.disc_number,
+            track.disc_total,
+            track.date,
+            track.mbid,
+            track.rating,
+            track.file_mtime,
+            track.start_ms,
+            track.end_ms,
+            now()
+        ],
+        |row| row.get(0),
+    )?;
+    catalog::link_track(conn, id, track)?;
+    conn.execute("DELETE FROM chapters WHERE track_id = ?1", [id])?;
+    for (position, chapter) in track.chapters.iter().enumerate() {
+        conn.execute(
+            "INSERT INTO chapters (track_id, position, title, start_ms, end_ms)
+             VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                id,
+                position,
+                chapter.title,
+                chapter.start_ms,
+                chapter.end_ms
+            ],
+        )?;
+    }
+    Ok(id)
+}
+
+pub(crate) fn now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
 }
 
 /// Database operations for media library
 impl DB {
-    /// Open or create the database at the given path and run minimal migrations.
+    /// Open or create the database at the given path and run pending migrations.
     pub fn open(path: &Path) -> Result<Self> {
-        let conn = Connection::open(path)?;
-        conn.execute_batch(
-            "CREATE TABLE IF NOT EXISTS tracks (
-                id INTEGER PRIMARY KEY,
-                path TEXT UNIQUE NOT NULL,
-                title TEXT,
-                artist TEXT,
-                album TEXT,
-                duration_seconds INTEGER,
-                added_at INTEGER,
-                play_count INTEGER DEFAULT 0,
-                last_played INTEGER
-            );",
-        )?;
-        Ok(Self { conn })
+        Self::open_with(path, &DbOptions::default())
+    }
+
+    /// Open the database in WAL mode, so readers don't block the writer, with
+    /// a busy timeout that retries while another process holds a lock. A file
+    /// that can't be written to is opened read-only instead; check
+    /// `is_read_only`.
+    pub fn open_with(path: &Path, options: &DbOptions) -> Result<Self> {
+        if !options.read_only {
+            match open_writable(path, options.busy_timeout) {
+                Ok(Some(conn)) => {
+                    return Ok(Self {
+                        conn,
+                        read_only: false,
+                    });
+                }
+                Ok(None) => {}
+                Err(e)
+                    if path.exists()
+                        && matches!(
+                            e.downcast_ref::<rusqlite::Error>()
+                                .and_then(rusqlite::Error::sqlite_error_code),
+                            Some(
+                                ErrorCode::ReadOnly
+                                    | ErrorCode::CannotOpen
+                                    | ErrorCode::PermissionDenied
+                            )
+                        ) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let conn = match open_read_only(path, options.busy_timeout) {
+            // Reading WAL takes a `-shm` file, which SQLite can't create here.
+            // Opening the file as immutable would skip it, but would also
+            // miss changes a writer makes, so don't.
+            Err(rusqlite::Error::SqliteFailure(e, _)) if e.code == ErrorCode::ReadOnly => {
+                bail!(
+                    "{} can't be opened read-only: it needs a writable {}-shm file or directory",
+                    path.display(),
+                    path.display()
+                );
+            }
+            result => result?,
+        };
+        let version: usize =
+            conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+        if version < MIGRATIONS.len() {
+            bail!("{} needs upgrading, but it is read-only", path.display());
+        }
+        Ok(Self {
+            conn,
+            read_only: true,
+        })
+    }
+
+    /// Whether the database was opened read-only, so changes will fail
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Begin a write transaction. It takes the write lock up front, so a
+    /// concurrent writer makes it wait out the busy timeout rather than fail
+    /// halfway through.
+    fn write_transaction(&self) -> Result<Transaction<'_>> {
+        Ok(Transaction::new_unchecked(
+            self.writer()?,
+            TransactionBehavior::Immediate,
+        )?)
+    }
+
+    /// The connection, for a write of a single statement. Refused up front
+    /// when the database is open read-only.
+    fn writer(&self) -> Result<&Connection> {
+        if self.read_only {
+            bail!("The library database is open read-only");
+        }
+        Ok(&self.conn)
     }
 
     /// Get the total number of tracks in the library
     pub fn track_count(&self) -> Result<usize> {
-        let count: i64 = self.conn.query_row(
-            "SELECT COUNT(*) FROM tracks",
-            [],
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM tracks", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Insert a track, or refresh its metadata if the path is already known,
+    /// and link it to its artists and album. Returns the track id.
+    pub fn insert_track(&self, track: &NewTrack) -> Result<TrackId> {
+        let tx = self.write_transaction()?;
+        let id = upsert_track(&tx, track)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// Delete a track along with its plays, playlist entries and credits.
+    /// Returns whether there was such a track.
+    pub fn delete_track(&self, track_id: TrackId) -> Result<bool> {
+        let tx = self.write_transaction()?;
+        let deleted = tx.execute("DELETE FROM tracks WHERE id = ?1", [track_id])?;
+        tx.commit()?;
+        if deleted > 0 {
+            self.prune_catalog()?;
+        }
+        Ok(deleted > 0)
+    }
+
+    /// Record that a track was played at `played_at` (unix seconds), bumping its
+    /// play count and last-played time.
+    pub fn record_play(&self, track_id: TrackId, played_at: i64) -> Result<()> {
+        let tx = self.write_transaction()?;
+        let updated = tx.execute(
+            "UPDATE tracks SET play_count = COALESCE(play_count, 0) + 1,
+                last_played = MAX(COALESCE(last_played, 0), ?2)
+             WHERE id = ?1",
+            params![track_id, played_at],
+        )?;
+        if updated == 0 {
+            anyhow::bail!("No track with id {}", track_id);
+        }
+        tx.execute(
+            "INSERT INTO play_events (track_id, played_at) VALUES (?1, ?2)",
+            params![track_id, played_at],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Check a rating is 0–5 in half stars
+    pub fn check_rating(rating: f64) -> Result<()> {
+        if !(0.0..=5.0).contains(&rating) {
+            anyhow::bail!("Rating must be between 0 and 5, got {}", rating);
+        }
+        if (rating * 2.0).fract() != 0.0 {
+            anyhow::bail!("Rating must be a whole or half star, got {}", rating);
+        }
+        Ok(())
+    }
+
+    /// Set or clear a track's rating (0–5, in half stars)
+    pub fn set_rating(&self, track_id: TrackId, rating: Option<f64>) -> Result<()> {
+        if let Some(r) = rating {
+            Self::check_rating(r)?;
+        }
+        self.update_track(track_id, "rating", rating)
+    }
+
+    /// A track's rating, `None` if it is unrated
+    pub fn get_rating(&self, track_id: TrackId) -> Result<Option<f64>> {
+        self.conn
+            .query_row(
+                "SELECT rating FROM tracks WHERE id = ?1",
+                [track_id],
+                |row| row.get(0),
+            )
+            .optional()?
+            .ok_or_else(|| anyhow::anyhow!("No track with id {}", track_id))
+    }
+
+    /// Mark a track as loved or banned, or neither
+    pub fn set_loved(&self, track_id: TrackId, loved: Love) -> Result<()> {
+        self.update_track(track_id, "loved", loved.to_sql())
+    }
+
+    /// Star or unstar a track
+    pub fn set_starred(&self, track_id: TrackId, starred: bool) -> Result<()> {
+        self.update_track(track_id, "starred_at", starred.then(now))
+    }
+
+    fn update_track<T: rusqlite::ToSql>(
+        &self,
+        track_id: TrackId,
+        column: &str,
+        value: T,
+    ) -> Result<()> {
+        let sql = format!("UPDATE tracks SET {} = ?2 WHERE id = ?1", column);
+        if self.writer()?.execute(&sql, params![track_id, value])? == 0 {
+            anyhow::bail!("No track with id {}", track_id);
+        }
+        Ok(())
+    }
+
+    /// Look up a single track by id
+    pub fn track(&self, id: TrackId) -> Result<Option<Track>> {
+        let sql = format!("SELECT {} FROM tracks WHERE id = ?1", TRACK_COLUMNS);
+        Ok(self.conn.query_row(&sql, [id], track_from_row).optional()?)
+    }
+
+    /// Look up a single track by its file path
+    pub fn track_by_path(&self, path: &str) -> Result<Option<Track>> {
+        let sql = format!("SELECT {} FROM tracks WHERE path = ?1", TRACK_COLUMNS);
+        Ok(self
+            .conn
+            .query_row(&sql, [path], track_from_row)
+            .optional()?)
+    }
+
+    /// Chapters of a track, in order
+    pub fn chapters(&self, track_id: TrackId) -> Result<Vec<Chapter>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT title, start_ms, end_ms FROM chapters WHERE track_id = ?1
+             ORDER BY position",
+        )?;
+        let rows = stmt.query_map([track_id], |row| {
+            Ok(Chapter {
+                title: row.get(0)?,
+                start_ms: row.get(1)?,
+                end_ms: row.get(2)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// List tracks matching `filter`, ordered by artist, album, disc, track number
+    /// and title
+    pub fn tracks(&self, filter: &TrackFilter, page: Page) -> Result<Vec<Track>> {
+        let (where_clause, mut args) = filter.where_clause();
+        let sql = format!(
+            "SELECT {} FROM tracks{} ORDER BY artist, album, disc_number, track_number, title, path
+             LIMIT ? OFFSET ?",
+            TRACK_COLUMNS, where_clause
+        );
+        args.push(Value::Integer(page.limit as i64));
+        args.push(Value::Integer(page.offset as i64));
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(args), track_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Count the tracks matching `filter`
+    pub fn count_tracks(&self, filter: &TrackFilter) -> Result<usize> {
+        let (where_clause, args) = filter.where_clause();
+        let sql = format!("SELECT COUNT(*) FROM tracks{}", where_clause);
+        let count: i64 = self
+            .conn
+            .query_row(&sql, rusqlite::params_from_iter(args), |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Create an empty playlist and return its id
+    pub fn create_playlist(&self, name: &str) -> Result<i64> {
+        Ok(self.writer()?.query_row(
+            "INSERT INTO playlists (name, created_at) VALUES (?1, ?2) RETURNING id",
+            params![name, now()],
             |row| row.get(0),
+        )?)
+    }
+
+    /// Append a track to the end of a static playlist
+    pub fn add_to_playlist(&self, playlist_id: i64, track_id: TrackId) -> Result<()> {
+        self.ensure_static(playlist_id)?;
+        self.writer()?.execute(
+            "INSERT INTO playlist_entries (playlist_id, position, track_id)
+             SELECT ?1, COALESCE(MAX(position) + 1, 0), ?2
+             FROM playlist_entries WHERE playlist_id = ?1",
+            params![playlist_id, track_id],
+        )?;
+        Ok(())
+    }
+
+    /// Delete a playlist and its entries
+    pub fn delete_playlist(&self, playlist_id: i64) -> Result<bool> {
+        Ok(self
+            .writer()?
+            .execute("DELETE FROM playlists WHERE id = ?1", [playlist_id])?
+            > 0)
+    }
+
+    fn ensure_static(&self, playlist_id: i64) -> Result<()> {
+        if self.smart_rules(playlist_id)?.is_some() {
+            anyhow::bail!(
+                "Playlist {} is a smart playlist; edit its rules instead",
+                playlist_id
+            );
+        }
+        Ok(())
+    }
+
+    /// Fill in the rules and live track count of a smart playlist
+    fn with_rules(&self, mut playlist: Playlist) -> Result<Playlist> {
+        playlist.rules = self.smart_rules(playlist.id)?;
+        if let Some(rules) = &playlist.rules {
+            playlist.track_count = self.count_smart_tracks(rules)?;
+        }
+        Ok(playlist)
+    }
+
+    /// Remove every entry from a playlist but keep the playlist itself.
+    /// Returns `false` if there is no such playlist.
+    pub fn clear_playlist(&self, playlist_id: i64) -> Result<bool> {
+        if self.playlist(playlist_id)?.is_none() {
+            return Ok(false);
+        }
+        self.ensure_static(playlist_id)?;
+        self.writer()?.execute(
+            "DELETE FROM playlist_entries WHERE playlist_id = ?1",
+            [playlist_id],
         )?;
+        Ok(true)
+    }
+
+    /// Look up a single playlist by id
+    pub fn playlist(&self, playlist_id: i64) -> Result<Option<Playlist>> {
+        self.conn
+            .query_row(
+                "SELECT p.id, p.name, p.created_at,
+                    (SELECT COUNT(*) FROM playlist_entries e WHERE e.playlist_id = p.id)
+                 FROM playlists p WHERE p.id = ?1",
+                [playlist_id],
+                playlist_from_row,
+            )
+            .optional()?
+            .map(|p| self.with_rules(p))
+            .transpose()
+    }
+
+    /// Look up a single playlist by name
+    pub fn playlist_by_name(&self, name: &str) -> Result<Option<Playlist>> {
+        let id: Option<i64> = self
+            .conn
+            .query_row("SELECT id FROM playlists WHERE name = ?1", [name], |row| {
+                row.get(0)
+            })
+            .optional()?;
+        match id {
+            Some(id) => self.playlist(id),
+            None => Ok(None),
+        }
+    }
+
+    /// List stored playlists by name
+    pub fn playlists(&self, page: Page) -> Result<Vec<Playlist>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT p.id, p.name, p.created_at, COUNT(e.track_id)
+             FROM playlists p
+             LEFT JOIN playlist_entries e ON e.playlist_id = p.id
+             GROUP BY p.id
+             ORDER BY p.name
+             LIMIT ?1 OFFSET ?2",
+        )?;
+        let rows = stmt.query_map(
+            params![page.limit as i64, page.offset as i64],
+            playlist_from_row,
+        )?;
+        rows.map(|p| self.with_rules(p?)).collect()
+    }
+
+    /// Count stored playlists
+    pub fn count_playlists(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM playlists", [], |row| row.get(0))?;
         Ok(count as usize)
     }
+
+    /// Tracks of a playlist in playlist order. Smart playlists are evaluated
+    /// against the library as it is now.
+    pub fn playlist_tracks(&self, playlist_id: i64) -> Result<Vec<Track>> {
+        if let Some(rules) = self.smart_rules(playlist_id)? {
+            return self.smart_tracks(&rules);
+        }
+        let sql = format!(
+            "SELECT {} FROM playlist_entries e JOIN tracks t ON t.id = e.track_id
+             WHERE e.playlist_id = ?1 ORDER BY e.position",
+            track_columns("t.")
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map([playlist_id], track_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+/// Open the database for writing, switch it to WAL and migrate it. Returns
+/// `None` if SQLite could only open the file read-only.
+fn open_writable(path: &Path, busy_timeout: Duration) -> Result<Option<Connection>> {
+    let mut conn = Connection::open(path)?;
+    conn.busy_timeout(busy_timeout)?;
+    if conn.is_readonly(DatabaseName::Main)? {
+        return Ok(None);
+    }
+    // In-memory and temporary databases stay in their own journal mode
+    let _: String = conn.query_row("PRAGMA journal_mode = WAL", [], |row| row.get(0))?;
+    conn.execute_batch("PRAGMA foreign_keys = ON; PRAGMA synchronous = NORMAL;")?;
+    migrate(&mut conn)?;
+    Ok(Some(conn))
+}
+
+/// Open the database read-only, checking that it can actually be read
+fn open_read_only(path: &Path, busy_timeout: Duration) -> rusqlite::Result<Connection> {
+    let uri = format!(
+        "file:{}",
+        utf8_percent_encode(&path.to_string_lossy(), URI_PATH)
+    );
+    let conn = Connection::open_with_flags(
+        uri,
+        OpenFlags::SQLITE_OPEN_READ_ONLY
+            | OpenFlags::SQLITE_OPEN_URI
+            | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    conn.busy_timeout(busy_timeout)?;
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    conn.query_row("SELECT COUNT(*) FROM sqlite_master", [], |_| Ok(()))?;
+    Ok(conn)
+}
+
+/// Bring the schema up to date, one transaction per migration.
+fn migrate(conn: &mut Connection) -> Result<()> {
+    let version: usize =
+        conn.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+    for (index, sql) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+        // Another process may have migrated while this one waited for the lock
+        let current: usize =
+            tx.query_row("PRAGMA user_version", [], |row| row.get::<_, i64>(0))? as usize;
+        if current > index {
+            continue;
+        }
+        tx.execute_batch(sql)?;
+        if index == CATALOG_MIGRATION {
+            link_existing_tracks(&tx)?;
+        }
+        tx.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Credit the tracks already in the library to their artists and album,
+/// splitting the artist text as a scan would. Only the columns the catalog
+/// migration found are read.
+fn link_existing_tracks(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("SELECT id, artist, album, album_artist, year FROM tracks")?;
+    let tracks = stmt
+        .query_map([], |row| {
+            let track = NewTrack {
+                artist: row.get(1)?,
+                album: row.get(2)?,
+                album_artist: row.get(3)?,
+                year: row.get(4)?,
+                ..Default::default()
+            };
+            Ok((row.get::<_, TrackId>(0)?, track))
+        })?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    for (id, track) in &tracks {
+        catalog::link_track(conn, *id, track)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn track(path: &str, artist: &str, album: &str, title: &str) -> NewTrack {
+        NewTrack {
+            path: path.into(),
+            title: Some(title.into()),
+            artist: Some(artist.into()),
+            album: Some(album.into()),
+            duration_seconds: Some(180),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_migrations_are_idempotent() {
+        let file = NamedTempFile::new().expect("Failed to create temp file");
+        DB::open(file.path()).expect("Failed to open db");
+        let db = DB::open(file.path()).expect("Failed to reopen db");
+        assert_eq!(db.track_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_catalog_migration_splits_credits() {
+        let file = NamedTempFile::new().expect("Failed to create temp file");
+        {
+            let mut conn = Connection::open(file.path()).unwrap();
+            let tx = conn.transaction().unwrap();
+            for sql in &MIGRATIONS[..CATALOG_MIGRATION] {
+                tx.execute_batch(sql).unwrap();
+            }
+            tx.execute(
+                "INSERT INTO tracks (path, artist, album) VALUES
+                    ('/m/1.flac', 'Daft Punk feat. Romanthony', 'Discovery'),
+                    ('/m/2.flac', 'Daft Punk', 'Discovery')",
+                [],
+            )
+            .unwrap();
+            tx.execute_batch(&format!("PRAGMA user_version = {}", CATALOG_MIGRATION))
+                .unwrap();
+            tx.commit().unwrap();
+        }
+
+        let db = DB::open(file.path()).unwrap();
+        let credits: Vec<_> = db
+            .track_credits(TrackId(1))
+            .unwrap()
+            .into_iter()
+            .map(|credit| (credit.name, credit.role))
+            .collect();
+        assert_eq!(
+            credits,
+            [
+                ("Daft Punk".to_owned(), Role::Main),
+                ("Romanthony".to_owned(), Role::Featured)
+            ]
+        );
+        // Both tracks are on the one album, by the main artist
+        let albums = db.album_list(Page::default()).unwrap();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(
+            (albums[0].artist.as_str(), albums[0].track_count),
+            ("Daft Punk", 2)
+        );
+        let artists = db.artist_list(Page::default()).unwrap();
+        assert!(artists.iter().all(|artist| !artist.name.contains("feat.")));
+    }
+
+    #[test]
+    fn test_listing_and_paging() {
+        let file = NamedTempFile::new().expect("Failed to create temp file");
+        let db = DB::open(file.path()).unwrap();
+        db.insert_track(&track("/m/a1.flac", "Autechre", "Amber", "Foil"))
+            .unwrap();
+        db.insert_track(&track("/m/a2.flac", "Autechre", "Amber", "Montreal"))
+            .unwrap();
+        db.insert_track(&track(
+            "/m/b1.flac",
+            "Boards of Canada",
+            "Geogaddi",
+            "Julie",
+        ))
+        .unwrap();
+
+        // Re-inserting the same path updates rather than duplicates
+        db.insert_track(&track("/m/a1.flac", "Autechre", "Amber", "Foil (remaster)"))
+            .unwrap();
+        assert_eq!(db.track_count().unwrap(), 3);
+
+        let filter = TrackFilter {
+            artist: Some("Autechre".into()),
+            ..Default::default()
+        };
+        assert_eq!(db.count_tracks(&filter).unwrap(), 2);
+        let page = db
+            .tracks(
+                &filter,
+                Page {
+                    limit: 1,
+                    offset: 1,
+                },
+            )
+            .unwrap();
+        assert_eq!(page.len(), 1);
+        assert_eq!(page[0].title.as_deref(), Some("Montreal"));
+
+        let text = TrackFilter {
+            text: Some("julie".into()),
+            ..Default::default()
+        };
+        assert_eq!(db.tracks(&text, Page::default()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_playlists() {
+        let file = NamedTempFile::new().expect("Failed to create temp file");
+        let db = DB::open(file.path()).unwrap();
+        let a = db
+            .insert_track(&track("/m/a.mp3", "A", "X", "One"))
+            .unwrap();
+        let b = db
+            .insert_track(&track("/m/b.mp3", "B", "Y", "Two"))
+            .unwrap();
+
+        let id = db.create_playlist("mix").unwrap();
+        db.add_to_playlist(id, b).unwrap();
+        db.add_to_playlist(id, a).unwrap();
+
+        let lists = db.playlists(Page::default()).unwrap();
+        assert_eq!(lists.len(), 1);
+        assert_eq!(lists[0].track_count, 2);
+
+        let tracks = db.playlist_tracks(id).unwrap();
+        assert_eq!(tracks.iter().map(|t| t.id).collect::<Vec<_>>(), vec![b, a]);
+
+        assert_eq!(db.playlist(id).unwrap().unwrap().name, "mix");
+        assert!(db.delete_playlist(id).unwrap());
+        assert!(db.playlist(id).unwrap().is_none());
+        assert!(db.playlist_tracks(id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_plays_ratings_and_stars() {
+        let file = NamedTempFile::new().expect("Failed to create temp file");
+        let db = DB::open(file.path()).unwrap();
+        let id = db
+            .insert_track(&track("/m/a.mp3", "A", "X", "One"))
+            .unwrap();
+
+        db.record_play(id, 1_000).unwrap();
+        db.record_play(id, 2_000).unwrap();
+        assert!(db.record_play(TrackId(id.0 + 1), 2_000).is_err());
+
+        db.set_rating(id, Some(4.5)).unwrap();
+        assert!(db.set_rating(id, Some(7.0)).is_err());
+        db.set_starred(id, true).unwrap();
+
+        let t = db.track(id).unwrap().unwrap();
+        assert_eq!(t.play_count, 2);
+        assert_eq!(t.last_played, Some(2_000));
+        assert_eq!(t.rating, Some(4.5));
+        assert!(t.starred_at.is_some());
+
+        db.set_starred(id, false).unwrap();
+        assert!(db.track(id).unwrap().unwrap().starred_at.is_none());
+    }
+
+    #[test]
+    fn test_ratings_and_love() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let id = db
+            .insert_track(&track("/m/a.mp3", "A", "X", "One"))
+            .unwrap();
+
+        assert_eq!(db.get_rating(id).unwrap(), None);
+        assert!(db.get_rating(TrackId(id.0 + 1)).is_err());
+        assert!(db.set_rating(id, Some(3.3)).is_err());
+        db.set_rating(id, Some(0.5)).unwrap();
+        assert_eq!(db.get_rating(id).unwrap(), Some(0.5));
+
+        // Ratings from tags only fill in tracks that have none
+        let tagged = |path: &str| NewTrack {
+            rating: Some(4.0),
+            ..track(path, "B", "X", "One")
+        };
+        db.insert_track(&tagged("/m/a.mp3")).unwrap();
+        assert_eq!(db.get_rating(id).unwrap(), Some(0.5));
+        let other = db.insert_track(&tagged("/m/b.mp3")).unwrap();
+        assert_eq!(db.get_rating(other).unwrap(), Some(4.0));
+
+        db.set_loved(id, Love::Loved).unwrap();
+        db.set_loved(other, Love::Banned).unwrap();
+        assert_eq!(db.track(id).unwrap().unwrap().loved, Love::Loved);
+        let ids = |query: &str| -> Vec<TrackId> {
+            db.query_tracks(&Expr::parse(query).unwrap(), &[], Page::default())
+                .unwrap()
+                .iter()
+                .map(|t| t.id)
+                .collect()
+        };
+        assert_eq!(ids("loved:yes"), vec![id]);
+        assert_eq!(ids("banned:yes"), vec![other]);
+        db.set_loved(other, Love::Neutral).unwrap();
+        assert_eq!(ids("loved:no banned:no"), vec![other]);
+        assert_eq!("ban".parse(), Ok(Love::Banned));
+    }
+
+    #[test]
+    fn test_concurrent_connections() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let mode: String = db
+            .conn
+            .query_row("PRAGMA journal_mode", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(mode, "wal");
+
+        // A second writer waits for the lock instead of failing
+        let other = DB::open(file.path()).unwrap();
+        let (locked, wait) = std::sync::mpsc::channel();
+        let writer = std::thread::spawn(move || {
+            let tx = other.write_transaction().unwrap();
+            tx.execute("INSERT INTO playlists (name) VALUES ('held')", [])
+                .unwrap();
+            locked.send(()).unwrap();
+            std::thread::sleep(Duration::from_millis(200));
+            tx.commit().unwrap();
+        });
+        wait.recv().unwrap();
+        let id = db
+            .insert_track(&track("/m/a.mp3", "A", "X", "One"))
+            .unwrap();
+        writer.join().unwrap();
+        assert_eq!(db.count_playlists().unwrap(), 1);
+
+        // Readers see the library; writes are refused up front
+        let options = DbOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let reader = DB::open_with(file.path(), &options).unwrap();
+        assert!(reader.is_read_only());
+        assert_eq!(
+            reader.track(id).unwrap().unwrap().title.as_deref(),
+            Some("One")
+        );
+        let error = reader.insert_track(&track("/m/b.mp3", "A", "X", "Two"));
+        assert!(error.unwrap_err().to_string().contains("read-only"));
+        assert!(reader.record_play(id, 1).is_err());
+        let refused = [
+            reader.set_starred(id, true).err(),
+            reader.create_playlist("Mix").err(),
+            reader.add_bookmark(id, "Here", 1_000).err(),
+            reader.save_resume_position(id, 1_000).err(),
+            reader
+                .set_stream_choice(id, &crate::streams::StreamChoice::default())
+                .err(),
+            reader.prune_catalog().err(),
+        ];
+        for error in refused {
+            assert!(error.unwrap().to_string().contains("read-only"));
+        }
+    }
 }
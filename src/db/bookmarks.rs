@@ -0,0 +1,147 @@
+//! Places in long files: where listening last stopped, so playback can pick
+//! up there, and positions saved under a name.
+
+use anyhow::Result;
+use rusqlite::{OptionalExtension, Row, params};
+use serde::Serialize;
+
+use super::{DB, TrackId, now};
+
+/// A named position in a track
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Bookmark {
+    pub id: i64,
+    pub track_id: TrackId,
+    pub name: String,
+    pub position_ms: i64,
+    /// Unix seconds
+    pub created_at: i64,
+}
+
+fn bookmark_from_row(row: &Row) -> rusqlite::Result<Bookmark> {
+    Ok(Bookmark {
+        id: row.get(0)?,
+        track_id: row.get(1)?,
+        name: row.get(2)?,
+        position_ms: row.get(3)?,
+        created_at: row.get(4)?,
+    })
+}
+
+impl DB {
+    /// Where playback of a track last stopped, in milliseconds
+    pub fn resume_position(&self, track_id: TrackId) -> Result<Option<i64>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT position_ms FROM resume_positions WHERE track_id = ?1",
+                [track_id],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+
+    pub fn save_resume_position(&self, track_id: TrackId, position_ms: i64) -> Result<()> {
+        self.writer()?.execute(
+            "INSERT INTO resume_positions (track_id, position_ms, updated_at) VALUES (?1, ?2, ?3)
+             ON CONFLICT(track_id) DO UPDATE SET
+                position_ms = excluded.position_ms,
+                updated_at = excluded.updated_at",
+            params![track_id, position_ms, now()],
+        )?;
+        Ok(())
+    }
+
+    /// Forget where a track stopped, as when it was played to the end
+    pub fn clear_resume_position(&self, track_id: TrackId) -> Result<()> {
+        self.writer()?.execute(
+            "DELETE FROM resume_positions WHERE track_id = ?1",
+            [track_id],
+        )?;
+        Ok(())
+    }
+
+    /// Bookmark a position in a track. Returns the bookmark's id.
+    pub fn add_bookmark(&self, track_id: TrackId, name: &str, position_ms: i64) -> Result<i64> {
+        Ok(self.writer()?.query_row(
+            "INSERT INTO bookmarks (track_id, name, position_ms, created_at)
+             VALUES (?1, ?2, ?3, ?4) RETURNING id",
+            params![track_id, name, position_ms, now()],
+            |row| row.get(0),
+        )?)
+    }
+
+    /// Bookmarks of a track, in the order they come in it
+    pub fn bookmarks(&self, track_id: TrackId) -> Result<Vec<Bookmark>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, track_id, name, position_ms, created_at FROM bookmarks
+             WHERE track_id = ?1 ORDER BY position_ms, id",
+        )?;
+        let rows = stmt.query_map([track_id], bookmark_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    pub fn bookmark(&self, id: i64) -> Result<Option<Bookmark>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT id, track_id, name, position_ms, created_at FROM bookmarks WHERE id = ?1",
+                [id],
+                bookmark_from_row,
+            )
+            .optional()?)
+    }
+
+    /// Returns whether there was such a bookmark
+    pub fn delete_bookmark(&self, id: i64) -> Result<bool> {
+        Ok(self
+            .writer()?
+            .execute("DELETE FROM bookmarks WHERE id = ?1", [id])?
+            > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::NewTrack;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_resume_positions_and_bookmarks() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let id = db
+            .insert_track(&NewTrack {
+                path: "/books/dune.m4b".into(),
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert_eq!(db.resume_position(id).unwrap(), None);
+        db.save_resume_position(id, 60_000).unwrap();
+        db.save_resume_position(id, 90_000).unwrap();
+        assert_eq!(db.resume_position(id).unwrap(), Some(90_000));
+        db.clear_resume_position(id).unwrap();
+        assert_eq!(db.resume_position(id).unwrap(), None);
+
+        let later = db.add_bookmark(id, "Arrakis", 3_600_000).unwrap();
+        let earlier = db.add_bookmark(id, "Prologue", 0).unwrap();
+        let names: Vec<_> = db
+            .bookmarks(id)
+            .unwrap()
+            .into_iter()
+            .map(|b| b.id)
+            .collect();
+        assert_eq!(names, vec![earlier, later]);
+        assert_eq!(db.bookmark(later).unwrap().unwrap().name, "Arrakis");
+        assert!(db.delete_bookmark(later).unwrap());
+        assert!(!db.delete_bookmark(later).unwrap());
+
+        // They go with the track
+        db.save_resume_position(id, 1_000).unwrap();
+        db.delete_track(id).unwrap();
+        assert_eq!(db.bookmark(earlier).unwrap(), None);
+        assert_eq!(db.resume_position(id).unwrap(), None);
+    }
+}
@@ -0,0 +1,520 @@
+//! Normalised artists and albums.
+//!
+//! `tracks` keeps the artist and album text as tagged, for display and search;
+//! `artists`, `albums` and `track_artists` are derived from it on every insert so
+//! that albums group by album artist and a track can credit several artists.
+
+use anyhow::Result;
+use rusqlite::{Connection, OptionalExtension, Row, params};
+use serde::Serialize;
+
+use super::query::escape_like;
+use super::{DB, NewTrack, Page, Track, TrackId, track_columns, track_from_row};
+
+/// Album artist of compilations without an album artist tag
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// Album artist of albums whose tracks have no artist at all
+pub const UNKNOWN_ARTIST: &str = "Unknown Artist";
+
+/// An artist credited on tracks or albums
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Artist {
+    pub id: i64,
+    pub name: String,
+    pub mbid: Option<String>,
+    /// Albums with this album artist
+    pub album_count: usize,
+    /// Tracks crediting this artist in any role
+    pub track_count: usize,
+}
+
+/// An album, identified by its title and album artist
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Album {
+    pub id: i64,
+    pub title: String,
+    pub artist_id: i64,
+    pub artist: String,
+    pub compilation: bool,
+    pub year: Option<i64>,
+    pub mbid: Option<String>,
+    pub track_count: usize,
+    pub duration_seconds: i64,
+}
+
+/// How an artist is credited on a track
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Main,
+    Featured,
+    Composer,
+}
+
+impl Role {
+    fn as_str(self) -> &'static str {
+        match self {
+            Role::Main => "main",
+            Role::Featured => "featured",
+            Role::Composer => "composer",
+        }
+    }
+
+    fn parse(s: &str) -> Role {
+        match s {
+            "featured" => Role::Featured,
+            "composer" => Role::Composer,
+            _ => Role::Main,
+        }
+    }
+}
+
+/// One artist credit of a track
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Credit {
+    pub artist_id: i64,
+    pub name: String,
+    pub role: Role,
+}
+
+/// Markers that start the featured part of an artist credit, lowercase
+const FEATURING: [&str; 6] = [
+    " (feat. ",
+    " (ft. ",
+    " (featuring ",
+    " feat. ",
+    " ft. ",
+    " featuring ",
+];
+
+/// Split `"A feat. B & C"` into main (`["A"]`) and featured (`["B", "C"]`) artists.
+/// The main part is not split further, so "Simon & Garfunkel" stays one artist.
+pub fn split_credit(credit: &str) -> (Vec<String>, Vec<String>) {
+    let lower = credit.to_ascii_lowercase();
+    let marker = FEATURING
+        .iter()
+        .filter_map(|m| lower.find(m).map(|at| (at, m.len())))
+        .min();
+    let (main, featured) = match marker {
+        Some((at, len)) => (&credit[..at], credit[at + len..].trim_end_matches(')')),
+        None => (credit, ""),
+    };
+    let featured = featured
+        .split([',', '&'])
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect();
+    let main = main.trim();
+    let main = if main.is_empty() {
+        Vec::new()
+    } else {
+        vec![main.to_owned()]
+    };
+    (main, featured)
+}
+
+/// Split a composer tag such as `"A / B; C"` into names
+fn split_names(text: &str) -> Vec<String> {
+    text.split(';')
+        .flat_map(|part| part.split(" / "))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn upsert_artist(conn: &Connection, name: &str, mbid: Option<&str>) -> Result<i64> {
+    Ok(conn.query_row(
+        "INSERT INTO artists (name, mbid) VALUES (?1, ?2)
+         ON CONFLICT(name) DO UPDATE SET mbid = COALESCE(excluded.mbid, artists.mbid)
+         RETURNING id",
+        params![name, mbid],
+        |row| row.get(0),
+    )?)
+}
+
+/// Rebuild the artist credits and album of a track from its tags
+pub(super) fn link_track(conn: &Connection, track_id: TrackId, track: &NewTrack) -> Result<()> {
+    let (main, featured) = if track.artists.is_empty() {
+        track
+            .artist
+            .as_deref()
+            .map(split_credit)
+            .unwrap_or_default()
+    } else {
+        (track.artists.clone(), track.featured_artists.clone())
+    };
+    let composers = track
+        .composer
+        .as_deref()
+        .map(split_names)
+        .unwrap_or_default();
+
+    conn.execute("DELETE FROM track_artists WHERE track_id = ?1", [track_id])?;
+    for (role, names) in [
+        (Role::Main, &main),
+        (Role::Featured, &featured),
+        (Role::Composer, &composers),
+    ] {
+        for (position, name) in names.iter().enumerate() {
+            // A single artist MBID can only be attributed to a lone main artist
+            let mbid = track
+                .artist_mbid
+                .as_deref()
+                .filter(|_| role == Role::Main && main.len() == 1);
+            let artist_id = upsert_artist(conn, name, mbid)?;
+            conn.execute(
+                "INSERT OR IGNORE INTO track_artists (track_id, artist_id, role, position)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![track_id, artist_id, role.as_str(), position as i64],
+            )?;
+        }
+    }
+
+    let album_id: Option<i64> = match &track.album {
+        Some(title) => {
+            let album_artist = match &track.album_artist {
+                Some(name) => name.clone(),
+                None if track.compilation => VARIOUS_ARTISTS.to_owned(),
+                None if !main.is_empty() => main.join(", "),
+                None => UNKNOWN_ARTIST.to_owned(),
+            };
+            let artist_id = upsert_artist(conn, &album_artist, track.album_artist_mbid.as_deref())?;
+            Some(conn.query_row(
+                "INSERT INTO albums (title, artist_id, compilation, year, mbid)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT(title, artist_id) DO UPDATE SET
+                    compilation = MAX(albums.compilation, excluded.compilation),
+                    year = COALESCE(excluded.year, albums.year),
+                    mbid = COALESCE(excluded.mbid, albums.mbid)
+                 RETURNING id",
+                params![
+                    title,
+                    artist_id,
+                    track.compilation,
+                    track.year,
+                    track.album_mbid
+                ],
+                |row| row.get(0),
+            )?)
+        }
+        None => None,
+    };
+    conn.execute(
+        "UPDATE tracks SET album_id = ?2 WHERE id = ?1",
+        params![track_id, album_id],
+    )?;
+    Ok(())
+}
+
+const ALBUM_QUERY: &str = "SELECT al.id, al.title, al.artist_id, ar.name, al.compilation, al.year,
+        al.mbid, COUNT(t.id), COALESCE(SUM(t.duration_seconds), 0)
+     FROM albums al
+     JOIN artists ar ON ar.id = al.artist_id
+     LEFT JOIN tracks t ON t.album_id = al.id";
+
+fn album_from_row(row: &Row) -> rusqlite::Result<Album> {
+    Ok(Album {
+        id: row.get(0)?,
+        title: row.get(1)?,
+        artist_id: row.get(2)?,
+        artist: row.get(3)?,
+        compilation: row.get(4)?,
+        year: row.get(5)?,
+        mbid: row.get(6)?,
+        track_count: row.get::<_, i64>(7)? as usize,
+        duration_seconds: row.get(8)?,
+    })
+}
+
+const ARTIST_QUERY: &str = "SELECT ar.id, ar.name, ar.mbid,
+        (SELECT COUNT(*) FROM albums al WHERE al.artist_id = ar.id),
+        (SELECT COUNT(DISTINCT ta.track_id) FROM track_artists ta WHERE ta.artist_id = ar.id)
+     FROM artists ar";
+
+fn artist_from_row(row: &Row) -> rusqlite::Result<Artist> {
+    Ok(Artist {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        mbid: row.get(2)?,
+        album_count: row.get::<_, i64>(3)? as usize,
+        track_count: row.get::<_, i64>(4)? as usize,
+    })
+}
+
+impl DB {
+    /// Look up an album by id
+    pub fn album(&self, album_id: i64) -> Result<Option<Album>> {
+        let sql = format!("{} WHERE al.id = ?1 GROUP BY al.id", ALBUM_QUERY);
+        Ok(self
+            .conn
+            .query_row(&sql, [album_id], album_from_row)
+            .optional()?)
+    }
+
+    /// List albums by album artist, year and title
+    pub fn album_list(&self, page: Page) -> Result<Vec<Album>> {
+        let sql = format!(
+            "{} GROUP BY al.id ORDER BY ar.name, al.year, al.title LIMIT ?1 OFFSET ?2",
+            ALBUM_QUERY
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(
+            params![page.limit as i64, page.offset as i64],
+            album_from_row,
+        )?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Albums whose title or album artist contains `text`, ignoring case
+    pub fn find_albums(&self, text: &str, page: Page) -> Result<Vec<Album>> {
+        let sql = format!(
+            "{} WHERE al.title LIKE ?1 ESCAPE '\\' OR ar.name LIKE ?1 ESCAPE '\\'
+             GROUP BY al.id ORDER BY ar.name, al.year, al.title LIMIT ?2 OFFSET ?3",
+            ALBUM_QUERY
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(
+            params![
+                format!("%{}%", escape_like(text)),
+                page.limit as i64,
+                page.offset as i64
+            ],
+            album_from_row,
+        )?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Count the albums `album_list` pages through
+    pub fn count_album_list(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM albums", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Albums whose album artist is the given artist, oldest first
+    pub fn artist_albums(&self, artist_id: i64) -> Result<Vec<Album>> {
+        let sql = format!(
+            "{} WHERE al.artist_id = ?1 GROUP BY al.id ORDER BY al.year, al.title",
+            ALBUM_QUERY
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map([artist_id], album_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Tracks of an album in disc and track order
+    pub fn album_tracks(&self, album_id: i64) -> Result<Vec<Track>> {
+        let sql = format!(
+            "SELECT {} FROM tracks t WHERE t.album_id = ?1
+             ORDER BY COALESCE(t.disc_number, 1), t.track_number, t.title, t.path",
+            track_columns("t.")
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map([album_id], track_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Look up an artist by id
+    pub fn artist(&self, artist_id: i64) -> Result<Option<Artist>> {
+        let sql = format!("{} WHERE ar.id = ?1", ARTIST_QUERY);
+        Ok(self
+            .conn
+            .query_row(&sql, [artist_id], artist_from_row)
+            .optional()?)
+    }
+
+    /// List artists by name
+    pub fn artist_list(&self, page: Page) -> Result<Vec<Artist>> {
+        let sql = format!("{} ORDER BY ar.name LIMIT ?1 OFFSET ?2", ARTIST_QUERY);
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(
+            params![page.limit as i64, page.offset as i64],
+            artist_from_row,
+        )?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Artists whose name contains `text`, ignoring case
+    pub fn find_artists(&self, text: &str, page: Page) -> Result<Vec<Artist>> {
+        let sql = format!(
+            "{} WHERE ar.name LIKE ?1 ESCAPE '\\' ORDER BY ar.name LIMIT ?2 OFFSET ?3",
+            ARTIST_QUERY
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(
+            params![
+                format!("%{}%", escape_like(text)),
+                page.limit as i64,
+                page.offset as i64
+            ],
+            artist_from_row,
+        )?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Count the artists `artist_list` pages through
+    pub fn count_artist_list(&self) -> Result<usize> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM artists", [], |row| row.get(0))?;
+        Ok(count as usize)
+    }
+
+    /// Everyone credited on a track: main artists, then featured, then composers
+    pub fn track_credits(&self, track_id: TrackId) -> Result<Vec<Credit>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT ta.artist_id, ar.name, ta.role FROM track_artists ta
+             JOIN artists ar ON ar.id = ta.artist_id
+             WHERE ta.track_id = ?1
+             ORDER BY CASE ta.role WHEN 'main' THEN 0 WHEN 'featured' THEN 1 ELSE 2 END,
+                ta.position",
+        )?;
+        let rows = stmt.query_map([track_id], |row| {
+            Ok(Credit {
+                artist_id: row.get(0)?,
+                name: row.get(1)?,
+                role: Role::parse(&row.get::<_, String>(2)?),
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Delete albums without tracks and artists credited nowhere, left behind when
+    /// tracks are retagged or removed. Returns the number of rows removed.
+    pub fn prune_catalog(&self) -> Result<usize> {
+        let tx = self.write_transaction()?;
+        let albums = tx.execute(
+            "DELETE FROM albums WHERE id NOT IN (
+                SELECT album_id FROM tracks WHERE album_id IS NOT NULL)",
+            [],
+        )?;
+        let artists = tx.execute(
+            "DELETE FROM artists
+             WHERE id NOT IN (SELECT artist_id FROM track_artists)
+               AND id NOT IN (SELECT artist_id FROM albums)",
+            [],
+        )?;
+        tx.commit()?;
+        Ok(albums + artists)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_split_credit() {
+        assert_eq!(
+            split_credit("Simon & Garfunkel"),
+            (names(&["Simon & Garfunkel"]), vec![])
+        );
+        assert_eq!(
+            split_credit("Daft Punk feat. Pharrell Williams & Nile Rodgers"),
+            (
+                names(&["Daft Punk"]),
+                names(&["Pharrell Williams", "Nile Rodgers"])
+            )
+        );
+        assert_eq!(
+            split_credit("Massive Attack (Ft. Tracey Thorn)"),
+            (names(&["Massive Attack"]), names(&["Tracey Thorn"]))
+        );
+        assert_eq!(
+            split_names("Lennon / McCartney; Harrison"),
+            names(&["Lennon", "McCartney", "Harrison"])
+        );
+    }
+
+    #[test]
+    fn test_albums_and_credits() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let track = |path: &str, artist: &str, disc: i64, number: i64| NewTrack {
+            path: path.into(),
+            title: Some(path.into()),
+            artist: Some(artist.into()),
+            album: Some("Discovery".into()),
+            album_artist: Some("Daft Punk".into()),
+            disc_number: Some(disc),
+            track_number: Some(number),
+            year: Some(2001),
+            duration_seconds: Some(100),
+            ..Default::default()
+        };
+        let b = db.insert_track(&track("/m/b", "Daft Punk", 1, 2)).unwrap();
+        let c = db
+            .insert_track(&track("/m/c", "Daft Punk feat. Romanthony", 2, 1))
+            .unwrap();
+        let a = db.insert_track(&track("/m/a", "Daft Punk", 1, 1)).unwrap();
+
+        let albums = db.album_list(Page::default()).unwrap();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0].artist, "Daft Punk");
+        assert_eq!(albums[0].track_count, 3);
+        assert_eq!(albums[0].duration_seconds, 300);
+        let order: Vec<TrackId> = db
+            .album_tracks(albums[0].id)
+            .unwrap()
+            .iter()
+            .map(|t| t.id)
+            .collect();
+        assert_eq!(order, vec![a, b, c]);
+
+        let credits = db.track_credits(c).unwrap();
+        assert_eq!(
+            credits
+                .iter()
+                .map(|c| (c.name.as_str(), c.role))
+                .collect::<Vec<_>>(),
+            vec![("Daft Punk", Role::Main), ("Romanthony", Role::Featured)]
+        );
+
+        // Compilations without an album artist go under "Various Artists"
+        for (path, artist) in [("/v/1", "Autechre"), ("/v/2", "Aphex Twin")] {
+            db.insert_track(&NewTrack {
+                path: path.into(),
+                artist: Some(artist.into()),
+                album: Some("Warp20".into()),
+                compilation: true,
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let artists = db.artist_list(Page::default()).unwrap();
+        let various = artists.iter().find(|a| a.name == VARIOUS_ARTISTS).unwrap();
+        assert_eq!(various.album_count, 1);
+        assert_eq!(db.artist_albums(various.id).unwrap()[0].track_count, 2);
+
+        // Retagging moves the track; pruning drops what is left empty
+        db.insert_track(&NewTrack {
+            path: "/m/c".into(),
+            artist: Some("Romanthony".into()),
+            album: Some("Trust".into()),
+            ..Default::default()
+        })
+        .unwrap();
+        db.insert_track(&NewTrack {
+            path: "/v/2".into(),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(db.prune_catalog().unwrap(), 1);
+        let artists = db.artist_list(Page::default()).unwrap();
+        assert_eq!(
+            artists.iter().map(|a| a.name.as_str()).collect::<Vec<_>>(),
+            vec!["Autechre", "Daft Punk", "Romanthony", VARIOUS_ARTISTS]
+        );
+        assert!(db.artist(artists[1].id).unwrap().is_some());
+        assert_eq!(db.album(albums[0].id).unwrap().unwrap().track_count, 2);
+    }
+}
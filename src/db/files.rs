@@ -0,0 +1,191 @@
+//! Keeping `tracks` in step with the filesystem: finding what changed under a
+//! directory, following files and directories that were moved or deleted, and
+//! remembering how far a scan got. A path here means a file or a directory; a
+//! directory covers every track below it, and a file covers the tracks a cue
+//! sheet cut from it (see `crate::cue`).
+
+use anyhow::Result;
+use rusqlite::params;
+use std::collections::{HashMap, HashSet};
+use std::path::MAIN_SEPARATOR;
+
+use super::{DB, NewTrack, TrackId, upsert_track};
+use crate::cue;
+
+/// SQL condition matching `path` itself, its cue tracks or anything below it,
+/// given as `?1`
+const AT_OR_UNDER: &str = "(path = ?1 OR substr(path, 1, length(?1) + 1) IN (?1 || ?2, ?1 || '#'))";
+
+fn trimmed(path: &str) -> &str {
+    match path.trim_end_matches(MAIN_SEPARATOR) {
+        "" => path,
+        trimmed => trimmed,
+    }
+}
+
+impl DB {
+    /// Files with tracks at or under `path`, with the file modification time
+    /// recorded when each was last read. Cue tracks count as their file.
+    pub fn track_files(&self, path: &str) -> Result<HashMap<String, Option<i64>>> {
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT path, file_mtime FROM tracks WHERE {}",
+            AT_OR_UNDER
+        ))?;
+        let rows = stmt.query_map(params![trimmed(path), MAIN_SEPARATOR.to_string()], |row| {
+            Ok((row.get::<_, String>(0)?, row.get(1)?))
+        })?;
+        let mut files = HashMap::new();
+        for row in rows {
+            let (path, mtime) = row?;
+            files.insert(cue::file_of(&path).to_owned(), mtime);
+        }
+        Ok(files)
+    }
+
+    /// Follow a rename of a file or directory, keeping ids, ratings and play
+    /// history. Tracks the move overwrote are dropped. Returns how many tracks
+    /// moved.
+    pub fn move_tracks(&self, from: &str, to: &str) -> Result<usize> {
+        let (from, to) = (trimmed(from), trimmed(to));
+        let separator = MAIN_SEPARATOR.to_string();
+        let tx = self.write_transaction()?;
+        tx.execute(
+            &format!("DELETE FROM tracks WHERE {}", AT_OR_UNDER),
+            params![to, separator],
+        )?;
+        let moved = tx.execute(
+            &format!(
+                "UPDATE tracks SET path = ?3 || substr(path, length(?1) + 1) WHERE {}",
+                AT_OR_UNDER
+            ),
+            params![from, separator, to],
+        )?;
+        tx.commit()?;
+        Ok(moved)
+    }
+
+    /// Import the tracks read from a file, replacing whatever the file held
+    /// before: a file newly split by a cue sheet loses its single track, and
+    /// the other way round
+    pub fn import_file(&self, file: &str, tracks: &[NewTrack]) -> Result<()> {
+        let tx = self.write_transaction()?;
+        replace_file(&tx, file, tracks)?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Import a batch of files from a scan of `root` in one transaction,
+    /// together with the directories the batch completes
+    pub fn import_batch(
+        &self,
+        root: &str,
+        files: &[(String, Vec<NewTrack>)],
+        finished: &[String],
+    ) -> Result<()> {
+        let tx = self.write_transaction()?;
+        for (file, tracks) in files {
+            replace_file(&tx, file, tracks)?;
+        }
+        for directory in finished {
+            tx.execute(
+                "INSERT OR IGNORE INTO scan_progress (root, directory) VALUES (?1, ?2)",
+                params![root, directory],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Directories an unfinished scan of `root` has completed
+    pub fn scanned_directories(&self, root: &str) -> Result<HashSet<String>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT directory FROM scan_progress WHERE root = ?1")?;
+        let rows = stmt.query_map([root], |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Forget the progress of a scan of `root` once it has run to the end
+    pub fn finish_scan(&self, root: &str) -> Result<()> {
+        self.writer()?
+            .execute("DELETE FROM scan_progress WHERE root = ?1", [root])?;
+        Ok(())
+    }
+
+    /// Drop the tracks at or under a deleted path. Returns how many went.
+    pub fn remove_tracks(&self, path: &str) -> Result<usize> {
+        Ok(self.writer()?.execute(
+            &format!("DELETE FROM tracks WHERE {}", AT_OR_UNDER),
+            params![trimmed(path), MAIN_SEPARATOR.to_string()],
+        )?)
+    }
+}
+
+/// Upsert the tracks of `file` and drop its tracks that aren't among them
+fn replace_file(conn: &rusqlite::Connection, file: &str, tracks: &[NewTrack]) -> Result<()> {
+    let mut kept = Vec::with_capacity(tracks.len());
+    for track in tracks {
+        kept.push(upsert_track(conn, track)?);
+    }
+    let mut stmt = conn.prepare(
+        "SELECT id FROM tracks WHERE path = ?1 OR substr(path, 1, length(?1) + 1) = ?1 || '#'",
+    )?;
+    let stale: Vec<TrackId> = stmt
+        .query_map([file], |row| row.get(0))?
+        .collect::<rusqlite::Result<_>>()?;
+    for id in stale {
+        if !kept.contains(&id) {
+            conn.execute("DELETE FROM tracks WHERE id = ?1", [id])?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{NewTrack, Page, TrackFilter};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_moves_and_removals() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        for path in ["/music/a/1.flac", "/music/a/2.flac", "/music/ab/3.flac"] {
+            db.insert_track(&NewTrack {
+                path: path.into(),
+                title: Some(path.into()),
+                file_mtime: Some(100),
+                ..Default::default()
+            })
+            .unwrap();
+        }
+        let id = db.track_by_path("/music/a/1.flac").unwrap().unwrap().id;
+        db.record_play(id, 1_000).unwrap();
+
+        // `/music/a` must not match its sibling `/music/ab`
+        assert_eq!(db.track_files("/music/a/").unwrap().len(), 2);
+        assert_eq!(db.move_tracks("/music/a", "/music/b").unwrap(), 2);
+        let moved = db.track_by_path("/music/b/1.flac").unwrap().unwrap();
+        assert_eq!((moved.id, moved.play_count), (id, 1));
+        assert_eq!(db.search("1.flac", 10).unwrap()[0].path, "/music/b/1.flac");
+
+        // Renaming a file over another replaces it
+        assert_eq!(
+            db.move_tracks("/music/b/2.flac", "/music/ab/3.flac")
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            db.track_files("/music").unwrap(),
+            HashMap::from([
+                ("/music/b/1.flac".to_owned(), Some(100)),
+                ("/music/ab/3.flac".to_owned(), Some(100)),
+            ])
+        );
+
+        assert_eq!(db.remove_tracks("/music/b").unwrap(), 1);
+        let left = db.tracks(&TrackFilter::default(), Page::all()).unwrap();
+        assert_eq!(left.len(), 1);
+    }
+}
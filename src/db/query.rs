@@ -0,0 +1,1039 @@
+//! A small query language for filtering the library, e.g.
+//!
+//! ```text
+//! artist:"Boards of Canada" year:>=1998 rating:>=4 -genre:live played:<30d duration:>5m
+//! ```
+//!
+//! Terms are ANDed together; `OR` and parentheses combine them differently and a
+//! leading `-` negates a term. A bare word is a full-text search (see `DB::search`).
+//! Queries are parsed into an `Expr` tree and compiled to parameterised SQL.
+
+use anyhow::Result;
+use rusqlite::types::Value;
+use std::fmt;
+use std::num::{IntErrorKind, ParseIntError};
+use std::ops::Range;
+use std::str::FromStr;
+use thiserror::Error;
+
+use super::{DB, Page, TRACK_COLUMNS, Track, now, search, track_from_row};
+
+/// A track attribute that can be filtered, sorted on or displayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    Id,
+    Path,
+    Title,
+    Artist,
+    Album,
+    AlbumArtist,
+    Genre,
+    Comment,
+    Composer,
+    Year,
+    Track,
+    Disc,
+    Rating,
+    Plays,
+    Duration,
+    Added,
+    Played,
+    Starred,
+    Loved,
+    Banned,
+}
+
+/// How a field's values are written in a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Text,
+    Number,
+    Duration,
+    Date,
+    Flag,
+}
+
+impl Field {
+    pub const ALL: [Field; 20] = [
+        Field::Id,
+        Field::Path,
+        Field::Title,
+        Field::Artist,
+        Field::Album,
+        Field::AlbumArtist,
+        Field::Genre,
+        Field::Comment,
+        Field::Composer,
+        Field::Year,
+        Field::Track,
+        Field::Disc,
+        Field::Rating,
+        Field::Plays,
+        Field::Duration,
+        Field::Added,
+        Field::Played,
+        Field::Starred,
+        Field::Loved,
+        Field::Banned,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::Path => "path",
+            Field::Title => "title",
+            Field::Artist => "artist",
+            Field::Album => "album",
+            Field::AlbumArtist => "albumartist",
+            Field::Genre => "genre",
+            Field::Comment => "comment",
+            Field::Composer => "composer",
+            Field::Year => "year",
+            Field::Track => "track",
+            Field::Disc => "disc",
+            Field::Rating => "rating",
+            Field::Plays => "plays",
+            Field::Duration => "duration",
+            Field::Added => "added",
+            Field::Played => "played",
+            Field::Starred => "starred",
+            Field::Loved => "loved",
+            Field::Banned => "banned",
+        }
+    }
+
+    /// SQL expression for this field over the `tracks` table
+    fn column(self) -> &'static str {
+        match self {
+            Field::Id => "id",
+            Field::Path => "path",
+            Field::Title => "title",
+            Field::Artist => "artist",
+            Field::Album => "album",
+            Field::AlbumArtist => "album_artist",
+            Field::Genre => "genre",
+            Field::Comment => "comment",
+            Field::Composer => "composer",
+            Field::Year => "year",
+            Field::Track => "track_number",
+            Field::Disc => "disc_number",
+            Field::Rating => "rating",
+            Field::Plays => "COALESCE(play_count, 0)",
+            Field::Duration => "duration_seconds",
+            Field::Added => "added_at",
+            Field::Played => "last_played",
+            Field::Starred => "starred_at",
+            Field::Loved => "NULLIF(loved > 0, 0)",
+            Field::Banned => "NULLIF(loved < 0, 0)",
+        }
+    }
+
+    fn kind(self) -> Kind {
+        match self {
+            Field::Path
+            | Field::Title
+            | Field::Artist
+            | Field::Album
+            | Field::AlbumArtist
+            | Field::Genre
+            | Field::Comment
+            | Field::Composer => Kind::Text,
+            Field::Id | Field::Year | Field::Track | Field::Disc | Field::Rating | Field::Plays => {
+                Kind::Number
+            }
+            Field::Duration => Kind::Duration,
+            Field::Added | Field::Played => Kind::Date,
+            Field::Starred | Field::Loved | Field::Banned => Kind::Flag,
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Field {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.to_ascii_lowercase();
+        if name == "album_artist" {
+            return Ok(Field::AlbumArtist);
+        }
+        Field::ALL
+            .into_iter()
+            .find(|field| field.name() == name)
+            .ok_or_else(|| format!("unknown field `{}` (expected one of {})", s, field_list()))
+    }
+}
+
+fn field_list() -> String {
+    Field::ALL.map(Field::name).join(", ")
+}
+
+/// Comparison written between a field and its value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// Case-insensitive substring match, the default for text fields
+    Contains,
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Op {
+    fn sql(self) -> &'static str {
+        match self {
+            Op::Contains => "LIKE",
+            Op::Eq => "=",
+            Op::Lt => "<",
+            Op::Le => "<=",
+            Op::Gt => ">",
+            Op::Ge => ">=",
+        }
+    }
+
+    /// The comparison with its sides swapped: `a < b` is `b > a`
+    fn flipped(self) -> Op {
+        match self {
+            Op::Lt => Op::Gt,
+            Op::Le => Op::Ge,
+            Op::Gt => Op::Lt,
+            Op::Ge => Op::Le,
+            op => op,
+        }
+    }
+}
+
+/// The value side of a condition
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operand {
+    Text(String),
+    Number(f64),
+    /// Midnight UTC at the start of a calendar day, in unix seconds
+    Date(i64),
+    /// A span of time before now, in seconds (`30d`)
+    Age(i64),
+    Flag(bool),
+}
+
+/// A parsed query
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// Every sub-expression matches; the empty list matches everything
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    /// Full-text search over the indexed columns
+    Text(String),
+    Condition {
+        field: Field,
+        op: Op,
+        operand: Operand,
+    },
+}
+
+/// A query that failed to parse, pointing at the offending part of the input
+#[derive(Debug, Clone, PartialEq, Error)]
+pub struct ParseError {
+    pub message: String,
+    pub query: String,
+    /// Byte range of the offending token in `query`
+    pub span: Range<usize>,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let indent = self.query[..self.span.start].chars().count();
+        let width = self.query[self.span.clone()].chars().count().max(1);
+        writeln!(f, "{}", self.message)?;
+        writeln!(f, "    {}", self.query)?;
+        write!(f, "    {}{}", " ".repeat(indent), "^".repeat(width))
+    }
+}
+
+impl Expr {
+    /// Parse a query string
+    pub fn parse(query: &str) -> Result<Expr, ParseError> {
+        let mut parser = Parser {
+            input: query,
+            pos: 0,
+        };
+        parser.parse_or(false)
+    }
+
+    /// Compile to an SQL boolean expression over `tracks`, with `?` placeholders
+    /// for `args`. Relative dates are resolved against `now` (unix seconds).
+    pub(crate) fn to_sql(&self, now: i64, args: &mut Vec<Value>) -> String {
+        match self {
+            Expr::And(exprs) if exprs.is_empty() => "1".into(),
+            Expr::And(exprs) => join(exprs, " AND ", now, args),
+            Expr::Or(exprs) => join(exprs, " OR ", now, args),
+            // NULL columns make a condition unknown rather than false; negating
+            // `genre:live` should still match tracks without a genre
+            Expr::Not(expr) => format!("NOT COALESCE({}, 0)", expr.to_sql(now, args)),
+            Expr::Text(text) => {
+                // The parser only builds this from text containing a searchable word
+                args.push(Value::Text(search::fts_query(text).unwrap_or_default()));
+                "id IN (SELECT rowid FROM tracks_fts WHERE tracks_fts MATCH ?)".into()
+            }
+            Expr::Condition { field, op, operand } => {
+                condition_sql(*field, *op, operand, now, args)
+            }
+        }
+    }
+}
+
+impl FromStr for Expr {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Expr::parse(s)
+    }
+}
+
+fn join(exprs: &[Expr], separator: &str, now: i64, args: &mut Vec<Value>) -> String {
+    let parts: Vec<String> = exprs.iter().map(|e| e.to_sql(now, args)).collect();
+    format!("({})", parts.join(separator))
+}
+
+fn condition_sql(
+    field: Field,
+    op: Op,
+    operand: &Operand,
+    now: i64,
+    args: &mut Vec<Value>,
+) -> String {
+    let column = field.column();
+    match operand {
+        Operand::Text(text) if op == Op::Contains => {
+            args.push(Value::Text(format!("%{}%", escape_like(text))));
+            format!("{} LIKE ? ESCAPE '\\'", column)
+        }
+        Operand::Text(text) => {
+            args.push(Value::Text(text.clone()));
+            format!("{} {} ? COLLATE NOCASE", column, op.sql())
+        }
+        Operand::Number(n) => {
+            args.push(Value::Real(*n));
+            format!("{} {} ?", column, op.sql())
+        }
+        // A bare date means anywhere within that day
+        Operand::Date(day) if op == Op::Eq => {
+            args.push(Value::Integer(*day));
+            args.push(Value::Integer(day.saturating_add(86_400)));
+            format!("({0} >= ? AND {0} < ?)", column)
+        }
+        Operand::Date(day) => {
+            // `<=` a day includes all of it
+            let (op, at) = match op {
+                Op::Le => (Op::Lt, day.saturating_add(86_400)),
+                Op::Gt => (Op::Ge, day.saturating_add(86_400)),
+                op => (op, *day),
+            };
+            args.push(Value::Integer(at));
+            format!("{} {} ?", column, op.sql())
+        }
+        // Less than 30 days ago is after the instant 30 days ago
+        Operand::Age(seconds) => {
+            args.push(Value::Integer(now.saturating_sub(*seconds)));
+            format!("{} {} ?", column, op.flipped().sql())
+        }
+        Operand::Flag(true) => format!("{} IS NOT NULL", column),
+        Operand::Flag(false) => format!("{} IS NULL", column),
+    }
+}
+
+pub(super) fn escape_like(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: impl Into<String>, span: Range<usize>) -> ParseError {
+        ParseError {
+            message: message.into(),
+            query: self.input.to_owned(),
+            span,
+        }
+    }
+
+    fn rest(&self) -> &str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        let trimmed = self.rest().trim_start();
+        self.pos = self.input.len() - trimmed.len();
+    }
+
+    /// Whether the next word is the `OR` keyword
+    fn at_or(&self) -> bool {
+        self.rest()
+            .strip_prefix("OR")
+            .is_some_and(|after| after.is_empty() || after.starts_with([' ', '\t', '(']))
+    }
+
+    /// `or := and ("OR" and)*`; stops at a `)` or the end of input
+    fn parse_or(&mut self, nested: bool) -> Result<Expr, ParseError> {
+        let mut alternatives = vec![self.parse_and(nested)?];
+        loop {
+            self.skip_whitespace();
+            if !self.at_or() {
+                break;
+            }
+            let keyword = self.pos..self.pos + 2;
+            self.pos += 2;
+            let expr = self.parse_and(true)?;
+            if expr == Expr::And(Vec::new()) {
+                return Err(self.error("expected a search term after `OR`", keyword));
+            }
+            alternatives.push(expr);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.remove(0)
+        } else {
+            Expr::Or(alternatives)
+        })
+    }
+
+    /// `and := unary*`
+    fn parse_and(&mut self, nested: bool) -> Result<Expr, ParseError> {
+        let mut terms = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None => break,
+                Some(')') if nested => break,
+                Some(')') => return Err(self.error("unmatched `)`", self.pos..self.pos + 1)),
+                _ if self.at_or() => break,
+                _ => terms.push(self.parse_unary()?),
+            }
+        }
+        Ok(if terms.len() == 1 {
+            terms.remove(0)
+        } else {
+            Expr::And(terms)
+        })
+    }
+
+    /// `unary := "-" unary | "(" or ")" | term`
+    fn parse_unary(&mut self) -> Result<Expr, ParseError> {
+        let start = self.pos;
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                match self.peek() {
+                    Some(c) if !c.is_whitespace() && c != ')' => {
+                        Ok(Expr::Not(Box::new(self.parse_unary()?)))
+                    }
+                    _ => Err(self.error("expected a term to negate after `-`", start..start + 1)),
+                }
+            }
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.parse_or(true)?;
+                self.skip_whitespace();
+                if self.peek() != Some(')') {
+                    return Err(self.error("unclosed `(`", start..start + 1));
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            _ => self.parse_term(),
+        }
+    }
+
+    /// `term := field ":" op? value | value`
+    fn parse_term(&mut self) -> Result<Expr, ParseError> {
+        let start = self.pos;
+        let name_len = self
+            .rest()
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(self.rest().len());
+        if name_len == 0 || !self.rest()[name_len..].starts_with(':') {
+            let (text, span) = self.parse_value()?;
+            if search::fts_query(&text).is_none() {
+                return Err(self.error(format!("nothing to search for in `{}`", text), span));
+            }
+            return Ok(Expr::Text(text));
+        }
+
+        let name_span = start..start + name_len;
+        let field: Field = self.input[name_span.clone()]
+            .parse()
+            .map_err(|message| self.error(message, name_span.clone()))?;
+        self.pos += name_len + 1;
+
+        let op_start = self.pos;
+        let op = [
+            (">=", Op::Ge),
+            ("<=", Op::Le),
+            (">", Op::Gt),
+            ("<", Op::Lt),
+            ("=", Op::Eq),
+        ]
+        .into_iter()
+        .find(|(symbol, _)| self.rest().starts_with(symbol));
+        if let Some((symbol, _)) = op {
+            self.pos += symbol.len();
+        }
+        let op_span = op_start..self.pos;
+        let prefix = &self.input[start..self.pos];
+
+        let (value, span) = self.parse_value()?;
+        if value.is_empty() {
+            return Err(self.error(format!("missing value after `{}`", prefix), start..self.pos));
+        }
+        let invalid = |what: &str| {
+            self.error(
+                format!("expected {} after `{}`, found `{}`", what, prefix, value),
+                span.clone(),
+            )
+        };
+        let out_of_range = || {
+            self.error(
+                format!("`{}` is out of range after `{}`", value, prefix),
+                span.clone(),
+            )
+        };
+
+        let (op, operand) = match field.kind() {
+            Kind::Text => {
+                let op = op.map_or(Op::Contains, |(_, op)| op);
+                (op, Operand::Text(value.clone()))
+            }
+            Kind::Number => {
+                let n = value.parse().map_err(|_| invalid("a number"))?;
+                (op.map_or(Op::Eq, |(_, op)| op), Operand::Number(n))
+            }
+            Kind::Duration => {
+                let seconds = parse_duration(&value).map_err(|e| match e {
+                    Invalid::OutOfRange => out_of_range(),
+                    Invalid::Malformed => {
+                        invalid("a duration such as `90`, `5m`, `1h30m` or `3:20`")
+                    }
+                })?;
+                (
+                    op.map_or(Op::Eq, |(_, op)| op),
+                    Operand::Number(seconds as f64),
+                )
+            }
+            Kind::Date => {
+                let date = parse_date(&value);
+                let age = parse_age(&value);
+                if date == Err(Invalid::OutOfRange) || age == Err(Invalid::OutOfRange) {
+                    return Err(out_of_range());
+                }
+                if let Ok(day) = date {
+                    (op.map_or(Op::Eq, |(_, op)| op), Operand::Date(day))
+                } else if let Ok(age) = age {
+                    // `played:30d` reads as "played within the last 30 days"
+                    let op = match op {
+                        None => Op::Lt,
+                        Some((_, Op::Eq)) => {
+                            return Err(self.error(
+                                format!(
+                                    "use `<` or `>` with a relative time, e.g. `{}<{}`",
+                                    prefix, value
+                                ),
+                                op_span,
+                            ));
+                        }
+                        Some((_, op)) => op,
+                    };
+                    (op, Operand::Age(age))
+                } else {
+                    return Err(invalid(
+                        "a date such as `2024-01-31` or an age such as `30d`",
+                    ));
+                }
+            }
+            Kind::Flag => {
+                if let Some((symbol, _)) = op {
+                    return Err(self.error(
+                        format!("`{}` can't be compared with `{}`", field, symbol),
+                        op_span,
+                    ));
+                }
+                let flag = match value.to_ascii_lowercase().as_str() {
+                    "yes" | "true" | "1" => true,
+                    "no" | "false" | "0" => false,
+                    _ => return Err(invalid("`yes` or `no`")),
+                };
+                (Op::Eq, Operand::Flag(flag))
+            }
+        };
+        Ok(Expr::Condition { field, op, operand })
+    }
+
+    /// A `"quoted string"` (with `\"` and `\\` escapes) or a bare word, which runs
+    /// until whitespace or a closing parenthesis
+    fn parse_value(&mut self) -> Result<(String, Range<usize>), ParseError> {
+        let start = self.pos;
+        if self.peek() != Some('"') {
+            let len = self
+                .rest()
+                .find(|c: char| c.is_whitespace() || c == ')')
+                .unwrap_or(self.rest().len());
+            self.pos += len;
+            return Ok((self.input[start..self.pos].to_owned(), start..self.pos));
+        }
+
+        let mut value = String::new();
+        let mut chars = self.rest().char_indices().skip(1);
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.pos += i + 1;
+                    return Ok((value, start..self.pos));
+                }
+                '\\' => {
+                    if let Some((_, escaped)) = chars.next() {
+                        value.push(escaped);
+                    }
+                }
+                c => value.push(c),
+            }
+        }
+        Err(self.error("unterminated quote", start..self.input.len()))
+    }
+}
+
+/// Longest duration or age a query can give, which keeps the arithmetic on
+/// them and on timestamps from overflowing
+const MAX_SECONDS: i64 = 10_000 * 365 * 86_400;
+
+/// Last year a date can be in
+const MAX_YEAR: i64 = 9999;
+
+/// Why a duration, age or date could not be read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Invalid {
+    Malformed,
+    /// Well formed, but too large to work with
+    OutOfRange,
+}
+
+fn parse_int(text: &str) -> Result<i64, Invalid> {
+    text.parse().map_err(|e: ParseIntError| match e.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => Invalid::OutOfRange,
+        _ => Invalid::Malformed,
+    })
+}
+
+/// Seconds from `90`, `5m`, `1h30m`, `2m30s` or `3:20` / `1:02:03`
+fn parse_duration(text: &str) -> Result<i64, Invalid> {
+    if text.contains(':') {
+        return text.split(':').try_fold(0i64, |total, part| {
+            let n = parse_int(part)?;
+            total
+                .checked_mul(60)
+                .and_then(|total| total.checked_add(n))
+                .filter(|total| *total <= MAX_SECONDS)
+                .ok_or(Invalid::OutOfRange)
+        });
+    }
+    match parse_int(text) {
+        Ok(seconds) if seconds > MAX_SECONDS => Err(Invalid::OutOfRange),
+        Ok(seconds) => Ok(seconds),
+        Err(Invalid::OutOfRange) => Err(Invalid::OutOfRange),
+        Err(Invalid::Malformed) => parse_units(text, |unit| match unit {
+            "h" => Some(3600),
+            "m" => Some(60),
+            "s" => Some(1),
+            _ => None,
+        }),
+    }
+}
+
+/// Seconds from an age such as `12h`, `30d`, `2w`, `6m` (months) or `1y`
+pub(super) fn parse_age(text: &str) -> Result<i64, Invalid> {
+    parse_units(text, |unit| match unit {
+        "h" => Some(3600),
+        "d" => Some(86_400),
+        "w" => Some(7 * 86_400),
+        "m" => Some(30 * 86_400),
+        "y" => Some(365 * 86_400),
+        _ => None,
+    })
+}
+
+/// Sum of `<number><unit>` pairs, e.g. `1h30m`
+fn parse_units(text: &str, unit_seconds: impl Fn(&str) -> Option<i64>) -> Result<i64, Invalid> {
+    if text.is_empty() {
+        return Err(Invalid::Malformed);
+    }
+    let mut total: i64 = 0;
+    let mut rest = text;
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let n = parse_int(&rest[..digits])?;
+        let unit_len = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len() - digits);
+        let unit = unit_seconds(&rest[digits..digits + unit_len]).ok_or(Invalid::Malformed)?;
+        total = n
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds))
+            .filter(|total| *total <= MAX_SECONDS)
+            .ok_or(Invalid::OutOfRange)?;
+        rest = &rest[digits + unit_len..];
+    }
+    Ok(total)
+}
+
+/// Midnight UTC of a `YYYY-MM-DD` date, in unix seconds
+pub(super) fn parse_date(text: &str) -> Result<i64, Invalid> {
+    let mut parts = text.splitn(3, '-');
+    let mut part = || parts.next().ok_or(Invalid::Malformed).and_then(parse_int);
+    let (year, month, day) = (part()?, part()?, part()?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(Invalid::Malformed);
+    }
+    if !(0..=MAX_YEAR).contains(&year) {
+        return Err(Invalid::OutOfRange);
+    }
+    Ok(days_from_civil(year, month, day) * 86_400)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, after Howard Hinnant's
+/// date algorithms
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// `(year, month, day)` of a count of days since 1970-01-01; the inverse of
+/// `days_from_civil`
+pub(crate) fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// One key of a sort order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SortKey {
+    Asc(Field),
+    Desc(Field),
+    Random,
+    /// Most plays in the last so many seconds first
+    PlaysWithin(i64),
+}
+
+impl SortKey {
+    fn sql(self, now: i64) -> String {
+        match self {
+            SortKey::Asc(field) => field.column().to_owned(),
+            SortKey::Desc(field) => format!("{} DESC", field.column()),
+            SortKey::Random => "RANDOM()".to_owned(),
+            SortKey::PlaysWithin(window) => format!(
+                "(SELECT COUNT(*) FROM play_events p
+                  WHERE p.track_id = tracks.id AND p.played_at >= {}) DESC",
+                now.saturating_sub(window)
+            ),
+        }
+    }
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    /// `year` sorts ascending, `-year` descending; `random` shuffles
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("random") {
+            return Ok(SortKey::Random);
+        }
+        match s.strip_prefix('-') {
+            Some(name) => name.parse().map(SortKey::Desc),
+            None => s.parse().map(SortKey::Asc),
+        }
+    }
+}
+
+/// Ordering used when no sort keys are given
+const DEFAULT_ORDER: &str = "artist, album, disc_number, track_number, title, path";
+
+impl DB {
+    /// List the tracks matching a parsed query. Without sort keys tracks come in
+    /// artist, album, disc and track order.
+    pub fn query_tracks(&self, query: &Expr, sort: &[SortKey], page: Page) -> Result<Vec<Track>> {
+        let now = now();
+        let mut args = Vec::new();
+        let condition = query.to_sql(now, &mut args);
+        let mut order: Vec<String> = sort.iter().map(|key| key.sql(now)).collect();
+        order.push(DEFAULT_ORDER.to_owned());
+        let sql = format!(
+            "SELECT {} FROM tracks WHERE {} ORDER BY {} LIMIT ? OFFSET ?",
+            TRACK_COLUMNS,
+            condition,
+            order.join(", ")
+        );
+        args.push(Value::Integer(page.limit as i64));
+        args.push(Value::Integer(page.offset as i64));
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(rusqlite::params_from_iter(args), track_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Count the tracks matching a parsed query
+    pub fn count_query_tracks(&self, query: &Expr) -> Result<usize> {
+        let mut args = Vec::new();
+        let sql = format!(
+            "SELECT COUNT(*) FROM tracks WHERE {}",
+            query.to_sql(now(), &mut args)
+        );
+        let count: i64 = self
+            .conn
+            .query_row(&sql, rusqlite::params_from_iter(args), |row| row.get(0))?;
+        Ok(count as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{NewTrack, TrackId};
+    use tempfile::NamedTempFile;
+
+    fn condition(field: Field, op: Op, operand: Operand) -> Expr {
+        Expr::Condition { field, op, operand }
+    }
+
+    #[test]
+    fn test_parse() {
+        let expr = Expr::parse(
+            r#"artist:"Boards of Canada" year:>=1998 rating:>=4 -genre:live played:<30d duration:>5m"#,
+        )
+        .unwrap();
+        assert_eq!(
+            expr,
+            Expr::And(vec![
+                condition(
+                    Field::Artist,
+                    Op::Contains,
+                    Operand::Text("Boards of Canada".into())
+                ),
+                condition(Field::Year, Op::Ge, Operand::Number(1998.0)),
+                condition(Field::Rating, Op::Ge, Operand::Number(4.0)),
+                Expr::Not(Box::new(condition(
+                    Field::Genre,
+                    Op::Contains,
+                    Operand::Text("live".into())
+                ))),
+                condition(Field::Played, Op::Lt, Operand::Age(30 * 86_400)),
+                condition(Field::Duration, Op::Gt, Operand::Number(300.0)),
+            ])
+        );
+
+        assert_eq!(Expr::parse("  ").unwrap(), Expr::And(vec![]));
+        assert_eq!(
+            Expr::parse("(autechre OR aphex) starred:yes").unwrap(),
+            Expr::And(vec![
+                Expr::Or(vec![
+                    Expr::Text("autechre".into()),
+                    Expr::Text("aphex".into())
+                ]),
+                condition(Field::Starred, Op::Eq, Operand::Flag(true)),
+            ])
+        );
+        assert_eq!(
+            Expr::parse("added:2024-02-29").unwrap(),
+            condition(Field::Added, Op::Eq, Operand::Date(1_709_164_800))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors_point_at_token() {
+        let cases = [
+            (
+                "year:>=abc",
+                "expected a number after `year:>=`, found `abc`",
+                7..10,
+            ),
+            ("colour:red rating:5", "unknown field `colour`", 0..6),
+            ("artist:", "missing value after `artist:`", 0..7),
+            ("(aphex OR", "expected a search term after `OR`", 7..9),
+            ("(aphex", "unclosed `(`", 0..1),
+            ("aphex )", "unmatched `)`", 6..7),
+            (r#"title:"unterminated"#, "unterminated quote", 6..19),
+            ("played:=30d", "use `<` or `>` with a relative time", 7..8),
+            ("duration:>long", "expected a duration", 10..14),
+            ("starred:>1", "`starred` can't be compared with `>`", 8..9),
+            // Values too large to add up or turn into a time
+            (
+                "played:<999999999999999d",
+                "`999999999999999d` is out of range",
+                8..24,
+            ),
+            (
+                "added:>99999999999999-01-01",
+                "`99999999999999-01-01` is out of range",
+                7..27,
+            ),
+            (
+                "duration:>9999999999:00:00",
+                "`9999999999:00:00` is out of range",
+                10..26,
+            ),
+            (
+                "duration:<99999999999999999999",
+                "`99999999999999999999` is out of range",
+                10..30,
+            ),
+        ];
+        for (query, message, span) in cases {
+            let err = Expr::parse(query).unwrap_err();
+            assert!(
+                err.message.starts_with(message),
+                "{}: {}",
+                query,
+                err.message
+            );
+            assert_eq!(err.span, span, "{}", query);
+        }
+
+        let err = Expr::parse("rating:>=4 year:>=nineties").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "expected a number after `year:>=`, found `nineties`\n    \
+             rating:>=4 year:>=nineties\n                      ^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn test_values() {
+        assert_eq!(parse_duration("90"), Ok(90));
+        assert_eq!(parse_duration("5m"), Ok(300));
+        assert_eq!(parse_duration("1h30m"), Ok(5400));
+        assert_eq!(parse_duration("3:20"), Ok(200));
+        assert_eq!(parse_duration("1:02:03"), Ok(3723));
+        assert_eq!(parse_duration("5x"), Err(Invalid::Malformed));
+        assert_eq!(parse_age("2w"), Ok(14 * 86_400));
+        assert_eq!(parse_age("30"), Err(Invalid::Malformed));
+        assert_eq!(parse_date("1970-01-02"), Ok(86_400));
+        assert_eq!(parse_date("2024-13-01"), Err(Invalid::Malformed));
+        assert_eq!(civil_from_days(19_782), (2024, 2, 29));
+        assert_eq!(days_from_civil(2024, 2, 29), 19_782);
+    }
+
+    #[test]
+    fn test_query_tracks() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let add = |path: &str, artist: &str, title: &str, year: i64, duration: i64, genre: &str| {
+            db.insert_track(&NewTrack {
+                path: path.into(),
+                title: Some(title.into()),
+                artist: Some(artist.into()),
+                album: Some("Album".into()),
+                genre: (!genre.is_empty()).then(|| genre.into()),
+                year: Some(year),
+                duration_seconds: Some(duration),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let roygbiv = add(
+            "/m/1.flac",
+            "Boards of Canada",
+            "Roygbiv",
+            1998,
+            150,
+            "electronic",
+        );
+        let dayvan = add(
+            "/m/2.flac",
+            "Boards of Canada",
+            "Dayvan Cowboy",
+            2005,
+            300,
+            "",
+        );
+        let live = add(
+            "/m/3.flac",
+            "Boards of Canada",
+            "Live at Warp",
+            2001,
+            600,
+            "Live",
+        );
+        let flim = add("/m/4.flac", "Aphex Twin", "Flim", 1997, 177, "electronic");
+        db.set_rating(roygbiv, Some(5.0)).unwrap();
+        db.set_rating(dayvan, Some(4.5)).unwrap();
+        db.set_rating(live, Some(4.0)).unwrap();
+        db.record_play(dayvan, now() - 86_400).unwrap();
+        db.record_play(roygbiv, now() - 90 * 86_400).unwrap();
+        db.set_starred(flim, true).unwrap();
+
+        let ids = |query: &str, sort: &[SortKey]| -> Vec<TrackId> {
+            let expr = Expr::parse(query).unwrap();
+            let tracks = db.query_tracks(&expr, sort, Page::all()).unwrap();
+            assert_eq!(db.count_query_tracks(&expr).unwrap(), tracks.len());
+            tracks.iter().map(|t| t.id).collect()
+        };
+
+        let by_year = [SortKey::Asc(Field::Year)];
+        assert_eq!(ids("", &by_year), vec![flim, roygbiv, live, dayvan]);
+        assert_eq!(
+            ids(
+                r#"artist:"boards of" year:>=1998 rating:>=4 -genre:live"#,
+                &by_year
+            ),
+            vec![roygbiv, dayvan]
+        );
+        assert_eq!(ids("played:<30d", &[]), vec![dayvan]);
+        assert_eq!(ids("-played:<30d", &by_year), vec![flim, roygbiv, live]);
+        assert_eq!(ids("duration:>5m", &[]), vec![live]);
+        assert_eq!(ids("duration:>=5m", &by_year), vec![live, dayvan]);
+        assert_eq!(
+            ids("starred:yes OR title:=roygbiv", &by_year),
+            vec![flim, roygbiv]
+        );
+        assert_eq!(ids("cowboy", &[]), vec![dayvan]);
+        assert_eq!(ids("title:%", &[]), Vec::<TrackId>::new());
+        assert_eq!(
+            ids("-(artist:boards plays:0)", &by_year),
+            vec![flim, roygbiv, dayvan]
+        );
+        assert_eq!(ids("", &[SortKey::Desc(Field::Rating)])[0], roygbiv);
+
+        let today = now().div_euclid(86_400);
+        let (y, m, d) = civil_from_days(today);
+        assert_eq!(
+            ids(&format!("added:{:04}-{:02}-{:02}", y, m, d), &[]).len(),
+            4
+        );
+        assert_eq!(
+            ids(&format!("added:<{:04}-{:02}-{:02}", y, m, d), &[]).len(),
+            0
+        );
+    }
+}
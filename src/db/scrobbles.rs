@@ -0,0 +1,204 @@
+//! The scrobble queue: listens waiting to be submitted to Last.fm or
+//! ListenBrainz. Rows stay until a service accepts them, so listens survive
+//! restarts and offline periods.
+
+use anyhow::Result;
+use rusqlite::params;
+
+use super::{DB, Track};
+
+/// A play to report to a scrobbling service
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Listen {
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    pub track_number: Option<i64>,
+    pub duration_seconds: Option<i64>,
+    /// MusicBrainz recording id
+    pub mbid: Option<String>,
+    /// When the track started playing, in unix seconds
+    pub played_at: i64,
+}
+
+impl Listen {
+    /// A listen of a library track; services need at least an artist and a title
+    pub fn from_track(track: &Track, played_at: i64) -> Option<Listen> {
+        Some(Listen {
+            artist: track.artist.clone()?,
+            title: track.title.clone()?,
+            album: track.album.clone(),
+            album_artist: track.album_artist.clone(),
+            track_number: track.track_number,
+            duration_seconds: track.duration_seconds,
+            mbid: track.mbid.clone(),
+            played_at,
+        })
+    }
+}
+
+/// A listen in the queue
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedListen {
+    pub id: i64,
+    pub listen: Listen,
+    /// Failed submission rounds so far
+    pub attempts: u32,
+}
+
+/// Summary of a service's queue
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct QueueStatus {
+    pub pending: usize,
+    /// Earliest time a failed submission will be retried
+    pub retry_at: Option<i64>,
+    pub last_error: Option<String>,
+}
+
+impl DB {
+    /// Queue a listen for a service. Queuing the same listen twice is a no-op.
+    pub fn queue_listen(&self, service: &str, listen: &Listen) -> Result<()> {
+        self.writer()?.execute(
+            "INSERT OR IGNORE INTO scrobbles (service, artist, title, album, album_artist,
+                track_number, duration_seconds, mbid, played_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                service,
+                listen.artist,
+                listen.title,
+                listen.album,
+                listen.album_artist,
+                listen.track_number,
+                listen.duration_seconds,
+                listen.mbid,
+                listen.played_at
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Oldest listens for a service that are due for (re)submission at `now`
+    pub fn due_listens(&self, service: &str, now: i64, limit: usize) -> Result<Vec<QueuedListen>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT id, artist, title, album, album_artist, track_number, duration_seconds,
+                mbid, played_at, attempts
+             FROM scrobbles WHERE service = ?1 AND next_attempt_at <= ?2
+             ORDER BY played_at, id LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(params![service, now, limit as i64], |row| {
+            Ok(QueuedListen {
+                id: row.get(0)?,
+                listen: Listen {
+                    artist: row.get(1)?,
+                    title: row.get(2)?,
+                    album: row.get(3)?,
+                    album_artist: row.get(4)?,
+                    track_number: row.get(5)?,
+                    duration_seconds: row.get(6)?,
+                    mbid: row.get(7)?,
+                    played_at: row.get(8)?,
+                },
+                attempts: row.get(9)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Drop listens from the queue once they are submitted or rejected
+    pub fn remove_listens(&self, ids: &[i64]) -> Result<()> {
+        let tx = self.write_transaction()?;
+        for id in ids {
+            tx.execute("DELETE FROM scrobbles WHERE id = ?1", [id])?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Hold back every queued listen of a service until `retry_at` after a failed
+    /// submission
+    pub fn defer_listens(&self, service: &str, retry_at: i64, error: &str) -> Result<()> {
+        self.writer()?.execute(
+            "UPDATE scrobbles SET attempts = attempts + 1, next_attempt_at = ?2, last_error = ?3
+             WHERE service = ?1",
+            params![service, retry_at, error],
+        )?;
+        Ok(())
+    }
+
+    /// Make every queued listen of a service due again, cutting its backoff short
+    pub fn retry_listens(&self, service: &str) -> Result<()> {
+        self.writer()?.execute(
+            "UPDATE scrobbles SET next_attempt_at = 0 WHERE service = ?1",
+            [service],
+        )?;
+        Ok(())
+    }
+
+    pub fn listen_queue(&self, service: &str) -> Result<QueueStatus> {
+        Ok(self.conn.query_row(
+            "SELECT COUNT(*), MIN(NULLIF(next_attempt_at, 0)),
+                (SELECT last_error FROM scrobbles WHERE service = ?1 AND last_error IS NOT NULL
+                 ORDER BY next_attempt_at DESC LIMIT 1)
+             FROM scrobbles WHERE service = ?1",
+            [service],
+            |row| {
+                Ok(QueueStatus {
+                    pending: row.get::<_, i64>(0)? as usize,
+                    retry_at: row.get(1)?,
+                    last_error: row.get(2)?,
+                })
+            },
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_queue() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let listen = |title: &str, played_at| Listen {
+            artist: "Burial".into(),
+            title: title.into(),
+            played_at,
+            ..Default::default()
+        };
+        db.queue_listen("lastfm", &listen("Archangel", 20)).unwrap();
+        db.queue_listen("lastfm", &listen("Etched Headplate", 10))
+            .unwrap();
+        db.queue_listen("lastfm", &listen("Archangel", 20)).unwrap();
+        db.queue_listen("listenbrainz", &listen("Archangel", 20))
+            .unwrap();
+
+        let due = db.due_listens("lastfm", 100, 10).unwrap();
+        let titles: Vec<_> = due.iter().map(|q| q.listen.title.as_str()).collect();
+        assert_eq!(titles, vec!["Etched Headplate", "Archangel"]);
+
+        db.defer_listens("lastfm", 160, "offline").unwrap();
+        assert!(db.due_listens("lastfm", 100, 10).unwrap().is_empty());
+        assert_eq!(db.due_listens("listenbrainz", 100, 10).unwrap().len(), 1);
+        let status = db.listen_queue("lastfm").unwrap();
+        assert_eq!(
+            status,
+            QueueStatus {
+                pending: 2,
+                retry_at: Some(160),
+                last_error: Some("offline".into())
+            }
+        );
+
+        let due = db.due_listens("lastfm", 160, 1).unwrap();
+        assert_eq!(due[0].attempts, 1);
+        db.remove_listens(&[due[0].id]).unwrap();
+        assert_eq!(db.listen_queue("lastfm").unwrap().pending, 1);
+
+        db.defer_listens("lastfm", 500, "offline").unwrap();
+        db.retry_listens("lastfm").unwrap();
+        assert_eq!(db.due_listens("lastfm", 100, 10).unwrap().len(), 1);
+    }
+}
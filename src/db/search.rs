@@ -0,0 +1,169 @@
+use anyhow::Result;
+use rusqlite::params;
+
+use super::{DB, Track, track_columns, track_from_row};
+
+/// Turn free text into an FTS5 query in which every word must match, as a prefix,
+/// in some indexed column. Words are quoted so that FTS5 operators and punctuation
+/// typed by the user are matched literally rather than parsed.
+pub(super) fn fts_query(text: &str) -> Option<String> {
+    let terms = fts_terms(text);
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// Like `fts_query`, but any one word is enough to match
+fn fts_query_any(text: &str) -> Option<String> {
+    let terms = fts_terms(text);
+    (!terms.is_empty()).then(|| terms.join(" OR "))
+}
+
+fn fts_terms(text: &str) -> Vec<String> {
+    text.split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .map(|word| format!("\"{}\"*", word.replace('"', "\"\"")))
+        .collect()
+}
+
+impl DB {
+    /// Full-text search over title, artist, album, album artist, genre, comment
+    /// and file name, best matches first.
+    ///
+    /// Matching ignores case and diacritics ("sigur ros" finds "Sigur Rós") and
+    /// treats every word as a prefix ("boa can" finds "Boards of Canada").
+    pub fn search(&self, query: &str, limit: usize) -> Result<Vec<Track>> {
+        match fts_query(query) {
+            Some(fts) => self.search_fts(&fts, limit),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Tracks matching any word of `query`, those matching most words first.
+    /// Used to find candidates for fuzzy matching.
+    pub(crate) fn search_any(&self, query: &str, limit: usize) -> Result<Vec<Track>> {
+        match fts_query_any(query) {
+            Some(fts) => self.search_fts(&fts, limit),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn search_fts(&self, fts: &str, limit: usize) -> Result<Vec<Track>> {
+        let sql = format!(
+            "SELECT {} FROM tracks_fts JOIN tracks t ON t.id = tracks_fts.rowid
+             WHERE tracks_fts MATCH ?1
+             ORDER BY tracks_fts.rank
+             LIMIT ?2",
+            track_columns("t.")
+        );
+        let mut stmt = self.conn.prepare_cached(&sql)?;
+        let rows = stmt.query_map(params![fts, limit as i64], track_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{NewTrack, TrackId};
+    use tempfile::NamedTempFile;
+
+    fn insert(db: &DB, path: &str, title: &str, artist: &str, comment: Option<&str>) -> TrackId {
+        db.insert_track(&NewTrack {
+            path: path.into(),
+            title: Some(title.into()),
+            artist: Some(artist.into()),
+            album: Some("Album".into()),
+            comment: comment.map(String::from),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn titles(tracks: &[Track]) -> Vec<&str> {
+        tracks.iter().filter_map(|t| t.title.as_deref()).collect()
+    }
+
+    #[test]
+    fn test_fts_query_quotes_words() {
+        assert_eq!(fts_query("  "), None);
+        assert_eq!(fts_query("- ()"), None);
+        assert_eq!(fts_query("boa can"), Some(r#""boa"* "can"*"#.into()));
+        assert_eq!(
+            fts_query(r#"AC/DC "NOT""#),
+            Some(r#""AC/DC"* """NOT"""*"#.into())
+        );
+    }
+
+    #[test]
+    fn test_search_matching_and_ranking() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        insert(
+            &db,
+            "/music/hoppipolla.flac",
+            "Hoppípolla",
+            "Sigur Rós",
+            None,
+        );
+        insert(
+            &db,
+            "/music/roygbiv.flac",
+            "Roygbiv",
+            "Boards of Canada",
+            None,
+        );
+        insert(
+            &db,
+            "/music/other.flac",
+            "Other",
+            "Someone",
+            Some("sounds like roygbiv"),
+        );
+        insert(&db, "/music/Rain Dance.mp3", "Untitled", "Nobody", None);
+
+        // Diacritics and case are ignored, words match as prefixes
+        assert_eq!(
+            titles(&db.search("sigur ros", 10).unwrap()),
+            vec!["Hoppípolla"]
+        );
+        assert_eq!(
+            titles(&db.search("HOPPIP", 10).unwrap()),
+            vec!["Hoppípolla"]
+        );
+        assert_eq!(titles(&db.search("boa can", 10).unwrap()), vec!["Roygbiv"]);
+
+        // A title match outranks a comment match
+        assert_eq!(
+            titles(&db.search("roygbiv", 10).unwrap()),
+            vec!["Roygbiv", "Other"]
+        );
+        assert_eq!(db.search("roygbiv", 1).unwrap().len(), 1);
+
+        // File names are indexed, directories are not
+        assert_eq!(titles(&db.search("dance", 10).unwrap()), vec!["Untitled"]);
+        assert!(db.search("music", 10).unwrap().is_empty());
+
+        // Syntax characters are not interpreted
+        assert!(db.search("NOT OR AND \"", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_index_follows_updates_and_deletes() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let id = insert(&db, "/music/a.mp3", "Windowlicker", "Aphex Twin", None);
+        assert_eq!(db.search("window", 10).unwrap().len(), 1);
+
+        // Re-importing with new tags replaces the indexed text
+        insert(&db, "/music/a.mp3", "Flim", "Aphex Twin", None);
+        assert!(db.search("window", 10).unwrap().is_empty());
+        assert_eq!(db.search("flim", 10).unwrap()[0].id, id);
+
+        // Play statistics don't touch the index, deletes remove the row
+        db.record_play(id, 1).unwrap();
+        assert_eq!(db.search("flim", 10).unwrap().len(), 1);
+        db.conn
+            .execute("DELETE FROM tracks WHERE id = ?1", [id])
+            .unwrap();
+        assert!(db.search("flim", 10).unwrap().is_empty());
+    }
+}
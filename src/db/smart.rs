@@ -0,0 +1,301 @@
+//! Smart playlists: playlists defined by a saved query rather than fixed entries.
+//!
+//! The query, ordering and limit are stored on the `playlists` row and evaluated
+//! every time the playlist is read, so its contents follow library and play
+//! statistics changes without any bookkeeping.
+
+use anyhow::Result;
+use rusqlite::{OptionalExtension, params};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::str::FromStr;
+
+use super::query::parse_age;
+use super::{DB, Expr, Field, Page, SortKey, Track};
+
+/// How the tracks of a smart playlist are ordered before its limit applies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PlaylistOrder {
+    /// Artist, album and title
+    #[default]
+    Library,
+    /// Shuffled afresh on every evaluation
+    Random,
+    /// Most plays first, counting only plays in the last so many seconds if given
+    MostPlayed(Option<i64>),
+    RecentlyAdded,
+    RecentlyPlayed,
+}
+
+impl PlaylistOrder {
+    fn sort_keys(self) -> Vec<SortKey> {
+        match self {
+            PlaylistOrder::Library => Vec::new(),
+            PlaylistOrder::Random => vec![SortKey::Random],
+            PlaylistOrder::MostPlayed(None) => vec![SortKey::Desc(Field::Plays)],
+            PlaylistOrder::MostPlayed(Some(window)) => vec![SortKey::PlaysWithin(window)],
+            PlaylistOrder::RecentlyAdded => vec![SortKey::Desc(Field::Added)],
+            PlaylistOrder::RecentlyPlayed => vec![SortKey::Desc(Field::Played)],
+        }
+    }
+}
+
+impl fmt::Display for PlaylistOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PlaylistOrder::Library => f.write_str("library"),
+            PlaylistOrder::Random => f.write_str("random"),
+            PlaylistOrder::MostPlayed(None) => f.write_str("most-played"),
+            PlaylistOrder::MostPlayed(Some(window)) if window % 86_400 == 0 => {
+                write!(f, "most-played:{}d", window / 86_400)
+            }
+            PlaylistOrder::MostPlayed(Some(window)) => write!(f, "most-played:{}h", window / 3600),
+            PlaylistOrder::RecentlyAdded => f.write_str("recently-added"),
+            PlaylistOrder::RecentlyPlayed => f.write_str("recently-played"),
+        }
+    }
+}
+
+impl FromStr for PlaylistOrder {
+    type Err = String;
+
+    /// `library`, `random`, `most-played`, `most-played:<age>` (e.g. `most-played:1y`),
+    /// `recently-added` or `recently-played`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "library" => Ok(PlaylistOrder::Library),
+            "random" => Ok(PlaylistOrder::Random),
+            "most-played" => Ok(PlaylistOrder::MostPlayed(None)),
+            "recently-added" => Ok(PlaylistOrder::RecentlyAdded),
+            "recently-played" => Ok(PlaylistOrder::RecentlyPlayed),
+            _ => s
+                .strip_prefix("most-played:")
+                .and_then(|age| parse_age(age).ok())
+                .map(|window| PlaylistOrder::MostPlayed(Some(window)))
+                .ok_or_else(|| {
+                    format!(
+                        "unknown order `{}` (expected library, random, most-played[:<age>], \
+                         recently-added or recently-played)",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+impl Serialize for PlaylistOrder {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The rules of a smart playlist
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SmartRules {
+    /// Query in the `library` language, e.g. `rating:>=4 -played:<60d`
+    pub query: String,
+    pub order: PlaylistOrder,
+    pub limit: Option<usize>,
+}
+
+impl DB {
+    /// Create a smart playlist and return its id. The query is checked here so a
+    /// broken playlist can't be saved.
+    pub fn create_smart_playlist(&self, name: &str, rules: &SmartRules) -> Result<i64> {
+        Expr::parse(&rules.query)?;
+        let id = self.create_playlist(name)?;
+        self.store_rules(id, rules)?;
+        Ok(id)
+    }
+
+    /// Replace the rules of an existing smart playlist
+    pub fn update_smart_playlist(&self, playlist_id: i64, rules: &SmartRules) -> Result<()> {
+        Expr::parse(&rules.query)?;
+        if self.smart_rules(playlist_id)?.is_none() {
+            anyhow::bail!("Playlist {} is not a smart playlist", playlist_id);
+        }
+        self.store_rules(playlist_id, rules)
+    }
+
+    fn store_rules(&self, playlist_id: i64, rules: &SmartRules) -> Result<()> {
+        self.writer()?.execute(
+            "UPDATE playlists SET query = ?2, ordering = ?3, track_limit = ?4 WHERE id = ?1",
+            params![
+                playlist_id,
+                rules.query,
+                rules.order.to_string(),
+                rules.limit.map(|l| l as i64)
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The rules of a playlist, or `None` for a static playlist or unknown id
+    pub(super) fn smart_rules(&self, playlist_id: i64) -> Result<Option<SmartRules>> {
+        let row: Option<(Option<String>, Option<String>, Option<i64>)> = self
+            .conn
+            .query_row(
+                "SELECT query, ordering, track_limit FROM playlists WHERE id = ?1",
+                [playlist_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let Some((Some(query), ordering, limit)) = row else {
+            return Ok(None);
+        };
+        let order = ordering
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(anyhow::Error::msg)?
+            .unwrap_or_default();
+        Ok(Some(SmartRules {
+            query,
+            order,
+            limit: limit.map(|l| l as usize),
+        }))
+    }
+
+    /// Evaluate smart playlist rules against the current library
+    pub(super) fn smart_tracks(&self, rules: &SmartRules) -> Result<Vec<Track>> {
+        let page = Page {
+            limit: rules.limit.unwrap_or(Page::all().limit),
+            offset: 0,
+        };
+        self.query_tracks(&Expr::parse(&rules.query)?, &rules.order.sort_keys(), page)
+    }
+
+    /// Number of tracks smart playlist rules currently select
+    pub(super) fn count_smart_tracks(&self, rules: &SmartRules) -> Result<usize> {
+        let count = self.count_query_tracks(&Expr::parse(&rules.query)?)?;
+        Ok(rules.limit.map_or(count, |limit| count.min(limit)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{NewTrack, now};
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_order_round_trips() {
+        for order in [
+            PlaylistOrder::Library,
+            PlaylistOrder::Random,
+            PlaylistOrder::MostPlayed(None),
+            PlaylistOrder::MostPlayed(Some(365 * 86_400)),
+            PlaylistOrder::MostPlayed(Some(12 * 3600)),
+            PlaylistOrder::RecentlyAdded,
+            PlaylistOrder::RecentlyPlayed,
+        ] {
+            assert_eq!(order.to_string().parse(), Ok(order));
+        }
+        assert_eq!(
+            "most-played:1y".parse(),
+            Ok(PlaylistOrder::MostPlayed(Some(365 * 86_400)))
+        );
+        assert!("most-played:soon".parse::<PlaylistOrder>().is_err());
+        assert!(
+            "most-played:999999999999999y"
+                .parse::<PlaylistOrder>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_smart_playlists_follow_the_library() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let add = |path: &str, rating: f64| {
+            let id = db
+                .insert_track(&NewTrack {
+                    path: path.into(),
+                    title: Some(path.into()),
+                    ..Default::default()
+                })
+                .unwrap();
+            db.set_rating(id, Some(rating)).unwrap();
+            id
+        };
+        let a = add("/m/a.mp3", 5.0);
+        let b = add("/m/b.mp3", 4.0);
+        let c = add("/m/c.mp3", 2.0);
+        let ids = |tracks: Vec<Track>| tracks.iter().map(|t| t.id).collect::<Vec<_>>();
+
+        let forgotten = db
+            .create_smart_playlist(
+                "Forgotten favourites",
+                &SmartRules {
+                    query: "rating:>=4 -played:<60d".into(),
+                    order: PlaylistOrder::Library,
+                    limit: None,
+                },
+            )
+            .unwrap();
+        let top = db
+            .create_smart_playlist(
+                "Most played this year",
+                &SmartRules {
+                    query: String::new(),
+                    order: "most-played:1y".parse().unwrap(),
+                    limit: Some(2),
+                },
+            )
+            .unwrap();
+        assert!(
+            db.create_smart_playlist(
+                "Broken",
+                &SmartRules {
+                    query: "rating:>=high".into(),
+                    order: PlaylistOrder::Random,
+                    limit: None,
+                },
+            )
+            .is_err()
+        );
+
+        assert_eq!(ids(db.playlist_tracks(forgotten).unwrap()), vec![a, b]);
+        assert_eq!(db.playlist(forgotten).unwrap().unwrap().track_count, 2);
+
+        // Plays move tracks out of one playlist and up the other
+        db.record_play(a, now()).unwrap();
+        db.record_play(c, now()).unwrap();
+        db.record_play(c, now()).unwrap();
+        db.record_play(b, now() - 2 * 365 * 86_400).unwrap();
+        db.record_play(b, now() - 2 * 365 * 86_400).unwrap();
+        db.record_play(b, now() - 2 * 365 * 86_400).unwrap();
+        assert_eq!(ids(db.playlist_tracks(forgotten).unwrap()), vec![b]);
+        assert_eq!(ids(db.playlist_tracks(top).unwrap()), vec![c, a]);
+
+        // So do library changes
+        let d = add("/m/d.mp3", 4.5);
+        assert_eq!(ids(db.playlist_tracks(forgotten).unwrap()), vec![b, d]);
+
+        let listed = db.playlists(Page::default()).unwrap();
+        assert_eq!(listed[0].name, "Forgotten favourites");
+        assert_eq!(listed[0].track_count, 2);
+        assert_eq!(listed[1].track_count, 2);
+        assert!(listed[1].rules.is_some());
+
+        // Entries belong to static playlists only
+        assert!(db.add_to_playlist(top, a).is_err());
+        assert!(db.clear_playlist(top).is_err());
+
+        db.update_smart_playlist(
+            top,
+            &SmartRules {
+                query: "rating:<3".into(),
+                order: PlaylistOrder::RecentlyAdded,
+                limit: None,
+            },
+        )
+        .unwrap();
+        assert_eq!(ids(db.playlist_tracks(top).unwrap()), vec![c]);
+        let mix = db.create_playlist("mix").unwrap();
+        assert!(
+            db.update_smart_playlist(mix, &db.playlist(top).unwrap().unwrap().rules.unwrap())
+                .is_err()
+        );
+    }
+}
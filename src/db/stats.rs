@@ -0,0 +1,450 @@
+//! Listening statistics computed from `play_events`.
+//!
+//! Everything is aggregated in SQL over a time window. Hours, weekdays and days
+//! are taken in the window's UTC offset so that reports follow the listener's
+//! clock rather than UTC.
+
+use anyhow::Result;
+use rusqlite::{Row, params};
+use serde::Serialize;
+
+use super::query::{parse_age, parse_date};
+use super::{DB, Track, now, track_columns, track_from_row};
+
+/// The span of time statistics are computed over
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct Window {
+    /// Start, inclusive, in unix seconds; `None` for the beginning of history
+    pub since: Option<i64>,
+    /// End, exclusive; `None` for now
+    pub until: Option<i64>,
+    /// Seconds east of UTC used for hours of the day, weekdays and streaks
+    pub utc_offset: i64,
+}
+
+impl Window {
+    /// A window bound given as an age (`30d` means thirty days ago) or a
+    /// `YYYY-MM-DD` date, which starts at local midnight
+    pub fn parse_bound(text: &str, utc_offset: i64) -> Option<i64> {
+        parse_age(text)
+            .map(|age| now() - age)
+            .or_else(|_| parse_date(text).map(|midnight| midnight.saturating_sub(utc_offset)))
+            .ok()
+    }
+
+    fn bounds(&self) -> (i64, i64) {
+        (
+            self.since.unwrap_or(i64::MIN),
+            self.until.unwrap_or(i64::MAX),
+        )
+    }
+}
+
+/// Plays and listening time of a track, artist, album or genre
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Ranked {
+    pub name: String,
+    /// Album artist, for albums
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    pub plays: u64,
+    pub seconds: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct TopTrack {
+    pub track: Track,
+    pub plays: u64,
+}
+
+/// Overall listening in a window. Listening time counts each play as the full
+/// length of the track.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Totals {
+    pub plays: u64,
+    pub seconds: i64,
+    pub tracks: u64,
+    pub first_play: Option<i64>,
+    pub last_play: Option<i64>,
+}
+
+/// Runs of consecutive days with at least one play
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Streaks {
+    /// Length in days of the run that includes today or yesterday
+    pub current: u64,
+    pub longest: u64,
+    /// First day of the longest run, as a local-midnight unix time
+    pub longest_start: Option<i64>,
+}
+
+/// When an artist was first played
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FirstListen {
+    pub artist: String,
+    pub played_at: i64,
+}
+
+/// Every report of `stats` in one
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StatsReport {
+    pub window: Window,
+    pub totals: Totals,
+    pub top_tracks: Vec<TopTrack>,
+    pub top_artists: Vec<Ranked>,
+    pub top_albums: Vec<Ranked>,
+    pub top_genres: Vec<Ranked>,
+    pub streaks: Streaks,
+    /// Plays per hour of the day, from midnight
+    pub by_hour: [u64; 24],
+    /// Plays per weekday, from Monday
+    pub by_weekday: [u64; 7],
+    /// Artists first played within the window, newest first
+    pub first_listens: Vec<FirstListen>,
+    pub forgotten_favourites: Vec<Track>,
+}
+
+/// Tracks count as forgotten favourites after this long without a play
+pub const FORGOTTEN_AFTER: i64 = 180 * 86_400;
+
+fn ranked_from_row(row: &Row) -> rusqlite::Result<Ranked> {
+    Ok(Ranked {
+        name: row.get(0)?,
+        artist: row.get(1)?,
+        plays: row.get::<_, i64>(2)? as u64,
+        seconds: row.get(3)?,
+    })
+}
+
+impl DB {
+    /// Seconds east of UTC of the local time zone, as SQLite sees it
+    pub fn utc_offset(&self) -> Result<i64> {
+        Ok(self.conn.query_row(
+            "SELECT CAST(strftime('%s', 'now', 'localtime') AS INTEGER)
+                  - CAST(strftime('%s', 'now') AS INTEGER)",
+            [],
+            |row| row.get(0),
+        )?)
+    }
+
+    pub fn listening_totals(&self, window: Window) -> Result<Totals> {
+        let (since, until) = window.bounds();
+        Ok(self.conn.query_row(
+            "SELECT COUNT(*), COALESCE(SUM(t.duration_seconds), 0),
+                COUNT(DISTINCT pe.track_id), MIN(pe.played_at), MAX(pe.played_at)
+             FROM play_events pe JOIN tracks t ON t.id = pe.track_id
+             WHERE pe.played_at >= ?1 AND pe.played_at < ?2",
+            params![since, until],
+            |row| {
+                Ok(Totals {
+                    plays: row.get::<_, i64>(0)? as u64,
+                    seconds: row.get(1)?,
+                    tracks: row.get::<_, i64>(2)? as u64,
+                    first_play: row.get(3)?,
+                    last_play: row.get(4)?,
+                })
+            },
+        )?)
+    }
+
+    /// Most played tracks in a window, ties going to the most recently played
+    pub fn top_tracks(&self, window: Window, limit: usize) -> Result<Vec<TopTrack>> {
+        let (since, until) = window.bounds();
+        let sql = format!(
+            "SELECT {}, COUNT(*) AS plays FROM play_events pe
+             JOIN tracks t ON t.id = pe.track_id
+             WHERE pe.played_at >= ?1 AND pe.played_at < ?2
+             GROUP BY t.id ORDER BY plays DESC, MAX(pe.played_at) DESC LIMIT ?3",
+            track_columns("t.")
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(params![since, until, limit as i64], |row| {
+            Ok(TopTrack {
+                track: track_from_row(row)?,
+                plays: row.get::<_, i64>("plays")? as u64,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Most played artists, crediting each main artist of a track with its plays
+    pub fn top_artists(&self, window: Window, limit: usize) -> Result<Vec<Ranked>> {
+        self.ranked(
+            "SELECT ar.name, NULL, COUNT(*) AS plays, COALESCE(SUM(t.duration_seconds), 0)
+             FROM play_events pe
+             JOIN tracks t ON t.id = pe.track_id
+             JOIN track_artists ta ON ta.track_id = t.id AND ta.role = 'main'
+             JOIN artists ar ON ar.id = ta.artist_id
+             WHERE pe.played_at >= ?1 AND pe.played_at < ?2
+             GROUP BY ar.id ORDER BY plays DESC, ar.name LIMIT ?3",
+            window,
+            limit,
+        )
+    }
+
+    pub fn top_albums(&self, window: Window, limit: usize) -> Result<Vec<Ranked>> {
+        self.ranked(
+            "SELECT al.title, ar.name, COUNT(*) AS plays, COALESCE(SUM(t.duration_seconds), 0)
+             FROM play_events pe
+             JOIN tracks t ON t.id = pe.track_id
+             JOIN albums al ON al.id = t.album_id
+             JOIN artists ar ON ar.id = al.artist_id
+             WHERE pe.played_at >= ?1 AND pe.played_at < ?2
+             GROUP BY al.id ORDER BY plays DESC, al.title LIMIT ?3",
+            window,
+            limit,
+        )
+    }
+
+    pub fn top_genres(&self, window: Window, limit: usize) -> Result<Vec<Ranked>> {
+        self.ranked(
+            "SELECT MIN(t.genre), NULL, COUNT(*) AS plays, COALESCE(SUM(t.duration_seconds), 0)
+             FROM play_events pe JOIN tracks t ON t.id = pe.track_id
+             WHERE pe.played_at >= ?1 AND pe.played_at < ?2 AND t.genre IS NOT NULL
+             GROUP BY t.genre COLLATE NOCASE ORDER BY plays DESC, MIN(t.genre) LIMIT ?3",
+            window,
+            limit,
+        )
+    }
+
+    fn ranked(&self, sql: &str, window: Window, limit: usize) -> Result<Vec<Ranked>> {
+        let (since, until) = window.bounds();
+        let mut stmt = self.conn.prepare(sql)?;
+        let rows = stmt.query_map(params![since, until, limit as i64], ranked_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Listening streaks, found by grouping consecutive play days
+    pub fn streaks(&self, window: Window) -> Result<Streaks> {
+        let (since, until) = window.bounds();
+        let mut stmt = self.conn.prepare(
+            "WITH days AS (
+                SELECT DISTINCT (played_at + ?3) / 86400 AS day FROM play_events
+                WHERE played_at >= ?1 AND played_at < ?2
+             ), runs AS (
+                SELECT day, day - ROW_NUMBER() OVER (ORDER BY day) AS run FROM days
+             )
+             SELECT MIN(day), MAX(day), COUNT(*) FROM runs GROUP BY run
+             ORDER BY MAX(day) DESC",
+        )?;
+        let runs = stmt.query_map(params![since, until, window.utc_offset], |row| {
+            Ok((
+                row.get::<_, i64>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, i64>(2)? as u64,
+            ))
+        })?;
+
+        let today = window.until.unwrap_or_else(now).saturating_sub(1) + window.utc_offset;
+        let today = today.div_euclid(86_400);
+        let mut streaks = Streaks::default();
+        for (i, run) in runs.enumerate() {
+            let (first, last, days) = run?;
+            if i == 0 && last >= today - 1 {
+                streaks.current = days;
+            }
+            if days > streaks.longest {
+                streaks.longest = days;
+                streaks.longest_start = Some(first * 86_400 - window.utc_offset);
+            }
+        }
+        Ok(streaks)
+    }
+
+    /// Plays per hour of the day and per weekday (Monday first)
+    pub fn listening_clock(&self, window: Window) -> Result<([u64; 24], [u64; 7])> {
+        let (since, until) = window.bounds();
+        let mut by_hour = [0; 24];
+        let mut by_weekday = [0; 7];
+        let mut stmt = self.conn.prepare(
+            "SELECT ((played_at + ?3) % 86400) / 3600 AS hour,
+                -- 1970-01-01 was a Thursday
+                ((played_at + ?3) / 86400 + 3) % 7 AS weekday,
+                COUNT(*)
+             FROM play_events WHERE played_at >= ?1 AND played_at < ?2
+             GROUP BY hour, weekday",
+        )?;
+        let rows = stmt.query_map(params![since, until, window.utc_offset], |row| {
+            Ok((
+                row.get::<_, i64>(0)? as usize,
+                row.get::<_, i64>(1)? as usize,
+                row.get::<_, i64>(2)? as u64,
+            ))
+        })?;
+        for row in rows {
+            let (hour, weekday, plays) = row?;
+            by_hour[hour % 24] += plays;
+            by_weekday[weekday % 7] += plays;
+        }
+        Ok((by_hour, by_weekday))
+    }
+
+    /// Artists whose first ever play falls within the window, newest first
+    pub fn first_listens(&self, window: Window, limit: usize) -> Result<Vec<FirstListen>> {
+        let (since, until) = window.bounds();
+        let mut stmt = self.conn.prepare(
+            "SELECT ar.name, MIN(pe.played_at) AS first FROM play_events pe
+             JOIN track_artists ta ON ta.track_id = pe.track_id AND ta.role = 'main'
+             JOIN artists ar ON ar.id = ta.artist_id
+             GROUP BY ar.id HAVING first >= ?1 AND first < ?2
+             ORDER BY first DESC LIMIT ?3",
+        )?;
+        let rows = stmt.query_map(params![since, until, limit as i64], |row| {
+            Ok(FirstListen {
+                artist: row.get(0)?,
+                played_at: row.get(1)?,
+            })
+        })?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// Loved, highly rated or often played tracks that haven't been played since
+    /// `before`, most played first
+    pub fn forgotten_favourites(&self, before: i64, limit: usize) -> Result<Vec<Track>> {
+        let sql = format!(
+            "SELECT {} FROM tracks
+             WHERE last_played < ?1
+               AND (play_count >= 3 OR rating >= 4 OR loved > 0)
+               AND COALESCE(loved, 0) >= 0
+             ORDER BY play_count DESC, rating DESC, last_played LIMIT ?2",
+            track_columns("")
+        );
+        let mut stmt = self.conn.prepare(&sql)?;
+        let rows = stmt.query_map(params![before, limit as i64], track_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<_>>()?)
+    }
+
+    /// All reports for a window, with `limit` entries per list
+    pub fn stats(&self, window: Window, limit: usize) -> Result<StatsReport> {
+        let (by_hour, by_weekday) = self.listening_clock(window)?;
+        Ok(StatsReport {
+            window,
+            totals: self.listening_totals(window)?,
+            top_tracks: self.top_tracks(window, limit)?,
+            top_artists: self.top_artists(window, limit)?,
+            top_albums: self.top_albums(window, limit)?,
+            top_genres: self.top_genres(window, limit)?,
+            streaks: self.streaks(window)?,
+            by_hour,
+            by_weekday,
+            first_listens: self.first_listens(window, limit)?,
+            forgotten_favourites: self
+                .forgotten_favourites(window.until.unwrap_or_else(now) - FORGOTTEN_AFTER, limit)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Love, NewTrack};
+    use tempfile::NamedTempFile;
+
+    const DAY: i64 = 86_400;
+    /// Monday 2024-01-01 00:00 UTC
+    const MONDAY: i64 = 1_704_067_200;
+
+    #[test]
+    fn test_stats() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let add = |path: &str, artist: &str, album: &str, genre: &str| {
+            db.insert_track(&NewTrack {
+                path: path.into(),
+                title: Some(path.into()),
+                artist: Some(artist.into()),
+                album: Some(album.into()),
+                genre: Some(genre.into()),
+                duration_seconds: Some(100),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let a = add("a", "Autechre", "Amber", "IDM");
+        let b = add("b", "Autechre feat. Gescom", "Amber", "idm");
+        let c = add("c", "Burial", "Untrue", "Dubstep");
+        let old = add("old", "Coil", "Love's Secret Domain", "Industrial");
+
+        for _ in 0..5 {
+            db.record_play(old, MONDAY - 400 * DAY).unwrap();
+        }
+        // Monday to Wednesday, a gap, then Friday
+        db.record_play(a, MONDAY + 9 * 3600).unwrap();
+        db.record_play(a, MONDAY + DAY + 9 * 3600).unwrap();
+        db.record_play(b, MONDAY + 2 * DAY + 22 * 3600).unwrap();
+        db.record_play(c, MONDAY + 4 * DAY + 22 * 3600).unwrap();
+        db.record_play(a, MONDAY + 4 * DAY + 23 * 3600).unwrap();
+
+        let week = Window {
+            since: Some(MONDAY),
+            until: Some(MONDAY + 7 * DAY),
+            utc_offset: 0,
+        };
+        let totals = db.listening_totals(week).unwrap();
+        assert_eq!((totals.plays, totals.seconds, totals.tracks), (5, 500, 3));
+
+        let top = db.top_tracks(week, 2).unwrap();
+        assert_eq!((top[0].track.id, top[0].plays), (a, 3));
+        assert_eq!(top.len(), 2);
+
+        let names = |ranked: Vec<Ranked>| -> Vec<(String, u64)> {
+            ranked.into_iter().map(|r| (r.name, r.plays)).collect()
+        };
+        assert_eq!(
+            names(db.top_artists(week, 10).unwrap()),
+            vec![("Autechre".into(), 4), ("Burial".into(), 1)]
+        );
+        let albums = db.top_albums(week, 10).unwrap();
+        assert_eq!((albums[0].name.as_str(), albums[0].plays), ("Amber", 4));
+        assert_eq!(albums[0].artist.as_deref(), Some("Autechre"));
+        assert_eq!(
+            names(db.top_genres(week, 10).unwrap()),
+            vec![("IDM".into(), 4), ("Dubstep".into(), 1)]
+        );
+
+        let streaks = db.streaks(week).unwrap();
+        assert_eq!((streaks.current, streaks.longest), (0, 3));
+        assert_eq!(streaks.longest_start, Some(MONDAY));
+        let friday = Window {
+            until: Some(MONDAY + 5 * DAY + 3600),
+            ..week
+        };
+        assert_eq!(db.streaks(friday).unwrap().current, 1);
+
+        let (by_hour, by_weekday) = db.listening_clock(week).unwrap();
+        assert_eq!((by_hour[9], by_hour[22], by_hour[23]), (2, 2, 1));
+        assert_eq!(by_weekday, [1, 1, 1, 0, 2, 0, 0]);
+        // Nine hours ahead of UTC, the late plays fall on the next morning
+        let tokyo = Window {
+            utc_offset: 9 * 3600,
+            ..week
+        };
+        let (by_hour, by_weekday) = db.listening_clock(tokyo).unwrap();
+        assert_eq!((by_hour[18], by_hour[7], by_hour[8]), (2, 2, 1));
+        assert_eq!(by_weekday, [1, 1, 0, 1, 0, 2, 0]);
+
+        let first: Vec<String> = db
+            .first_listens(week, 10)
+            .unwrap()
+            .into_iter()
+            .map(|f| f.artist)
+            .collect();
+        assert_eq!(first, vec!["Burial", "Autechre"]);
+
+        let forgotten = db.forgotten_favourites(MONDAY - 30 * DAY, 10).unwrap();
+        assert_eq!(
+            forgotten.iter().map(|t| t.id).collect::<Vec<_>>(),
+            vec![old]
+        );
+        db.set_loved(old, Love::Banned).unwrap();
+        assert!(db.forgotten_favourites(MONDAY, 10).unwrap().is_empty());
+
+        let report = db.stats(week, 1).unwrap();
+        assert_eq!(report.top_artists.len(), 1);
+        assert_eq!(report.totals, totals);
+        assert_eq!(Window::parse_bound("2024-01-01", 3600), Some(MONDAY - 3600));
+        assert!(Window::parse_bound("yesterday", 0).is_none());
+        assert!(Window::parse_bound("999999999999999d", 0).is_none());
+        assert!(Window::parse_bound("99999999999999-01-01", 0).is_none());
+    }
+}
@@ -0,0 +1,73 @@
+//! The audio stream chosen for each file with several, so it plays the same
+//! one next time.
+
+use anyhow::Result;
+use rusqlite::{OptionalExtension, params};
+
+use super::{DB, TrackId};
+use crate::streams::StreamChoice;
+
+impl DB {
+    pub fn stream_choice(&self, track_id: TrackId) -> Result<Option<StreamChoice>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT stream_index, language FROM stream_choices WHERE track_id = ?1",
+                [track_id],
+                |row| {
+                    Ok(StreamChoice {
+                        index: row.get::<_, Option<i64>>(0)?.map(|index| index as usize),
+                        language: row.get(1)?,
+                    })
+                },
+            )
+            .optional()?)
+    }
+
+    pub fn set_stream_choice(&self, track_id: TrackId, choice: &StreamChoice) -> Result<()> {
+        self.writer()?.execute(
+            "INSERT INTO stream_choices (track_id, stream_index, language) VALUES (?1, ?2, ?3)
+             ON CONFLICT(track_id) DO UPDATE SET
+                stream_index = excluded.stream_index,
+                language = excluded.language",
+            params![
+                track_id,
+                choice.index.map(|index| index as i64),
+                choice.language
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::NewTrack;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_stream_choices() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let id = db
+            .insert_track(&NewTrack {
+                path: "/films/amelie.mkv".into(),
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(db.stream_choice(id).unwrap(), None);
+        let french = StreamChoice {
+            index: None,
+            language: Some("fre".into()),
+        };
+        db.set_stream_choice(id, &french).unwrap();
+        assert_eq!(db.stream_choice(id).unwrap(), Some(french));
+        let second = StreamChoice {
+            index: Some(1),
+            language: None,
+        };
+        db.set_stream_choice(id, &second).unwrap();
+        assert_eq!(db.stream_choice(id).unwrap(), Some(second));
+    }
+}
@@ -0,0 +1,338 @@
+//! The containers the player, scanner and `info` can open: everything
+//! symphonia was built with, plus formats read here and turned into PCM
+//! (Standard MIDI Files through a SoundFont, DSD in DSF and DSDIFF files
+//! through a decimation filter, and tracker modules through a mixer).
+
+use std::sync::OnceLock;
+use symphonia::core::audio::Channels;
+use symphonia::core::codecs::{CODEC_TYPE_PCM_F32LE, CodecParameters};
+use symphonia::core::formats::Packet;
+use symphonia::core::probe::Probe;
+use symphonia::core::units::TimeBase;
+
+pub mod dsd;
+#[cfg(test)]
+mod fixtures;
+pub mod midi;
+pub mod tracker;
+
+pub use dsd::set_output_rate as set_dsd_rate;
+pub use midi::set_soundfont;
+
+/// The probe to open files with, in place of `symphonia::default::get_probe`
+pub fn get() -> &'static Probe {
+    static PROBE: OnceLock<Probe> = OnceLock::new();
+    PROBE.get_or_init(|| {
+        let mut probe = Probe::default();
+        symphonia::default::register_enabled_formats(&mut probe);
+        probe.register_all::<midi::MidiReader>();
+        probe.register_all::<dsd::DsdReader>();
+        probe.register_all::<tracker::TrackerReader>();
+        probe
+    })
+}
+
+/// Parameters for a track of interleaved 32-bit float PCM, which is what
+/// the readers here hand on to symphonia's PCM decoder
+fn pcm_params(
+    sample_rate: u32,
+    channels: Channels,
+    frames: u64,
+    packet_frames: u64,
+) -> CodecParameters {
+    let mut params = CodecParameters::new();
+    params
+        .for_codec(CODEC_TYPE_PCM_F32LE)
+        .with_sample_rate(sample_rate)
+        .with_time_base(TimeBase::new(1, sample_rate))
+        .with_n_frames(frames)
+        .with_channels(channels)
+        .with_max_frames_per_packet(packet_frames);
+    params
+}
+
+/// A packet of `frames` frames of interleaved `samples` on track 0
+fn pcm_packet(ts: u64, frames: u64, samples: &[f32]) -> Packet {
+    let data: Vec<u8> = samples
+        .iter()
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect();
+    Packet::new_from_boxed_slice(0, ts, frames, data.into_boxed_slice())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fixtures;
+    use crate::probe;
+    use crate::scanner;
+    use std::fs::File;
+    use std::io::Cursor;
+    use std::path::Path;
+    use symphonia::core::errors::Error;
+    use symphonia::core::formats::{FormatReader, Packet, SeekMode, SeekTo};
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::probe::Hint;
+    use tempfile::tempdir;
+
+    fn open(path: &Path) -> Box<dyn FormatReader> {
+        let file = File::open(path).unwrap();
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+        super::get()
+            .format(&Hint::new(), mss, &Default::default(), &Default::default())
+            .unwrap()
+            .format
+    }
+
+    /// The samples of one channel of a packet of interleaved float PCM
+    fn channel(packet: &Packet, channel: usize, channels: usize) -> Vec<f32> {
+        packet
+            .buf()
+            .chunks_exact(4)
+            .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+            .skip(channel)
+            .step_by(channels)
+            .collect()
+    }
+
+    /// Loudest sample of the next packet
+    fn peak(reader: &mut dyn FormatReader) -> f32 {
+        let packet = reader.next_packet().unwrap();
+        channel(&packet, 0, 1)
+            .into_iter()
+            .map(f32::abs)
+            .fold(0.0, f32::max)
+    }
+
+    fn rms(samples: &[f32]) -> f32 {
+        (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+    }
+
+    fn seek(reader: &mut dyn FormatReader, seconds: f64) {
+        let rate = reader.tracks()[0].codec_params.sample_rate.unwrap();
+        let ts = (seconds * rate as f64) as u64;
+        let seeked = reader
+            .seek(SeekMode::Accurate, SeekTo::TimeStamp { ts, track_id: 0 })
+            .unwrap();
+        assert_eq!(seeked.actual_ts, ts);
+    }
+
+    #[test]
+    fn test_scan_midi() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("song.mid");
+        std::fs::write(&path, fixtures::song()).unwrap();
+        assert!(scanner::is_audio_file(&path));
+        let tracks = scanner::read_tracks(&path).unwrap();
+        assert_eq!(tracks[0].title.as_deref(), Some("Sine Song"));
+        assert_eq!(tracks[0].comment.as_deref(), Some("Made for tests"));
+        // One second of notes and one to ring out
+        assert_eq!(tracks[0].duration_seconds, Some(2));
+    }
+
+    // The SoundFont is process-wide, so everything that decodes MIDI is in
+    // this one test
+    #[test]
+    fn test_render_midi() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("song.mid");
+        std::fs::write(&path, fixtures::song()).unwrap();
+
+        super::set_soundfont(Some(dir.path().join("missing.sf2")));
+        let info = probe::probe(&path).unwrap();
+        assert_eq!(info.format.unwrap().short, "midi");
+        assert!(info.demux_error.unwrap().contains("missing.sf2"));
+
+        let font = dir.path().join("sine.sf2");
+        std::fs::write(&font, fixtures::soundfont()).unwrap();
+        super::set_soundfont(Some(font));
+        let info = probe::probe(&path).unwrap();
+        assert_eq!(info.demux_error, None);
+        assert_eq!(info.tracks[0].codec.short, "pcm_f32le");
+        assert_eq!(info.decode[0].frames, 2 * 44_100);
+        assert_eq!(info.decode[0].error_count, 0);
+
+        // A seek before anything is rendered still plays what came before
+        let mut reader = open(&path);
+        seek(reader.as_mut(), 0.5);
+        assert!(peak(reader.as_mut()) > 0.05);
+
+        let mut reader = open(&path);
+        assert!(peak(reader.as_mut()) > 0.05);
+        // The held note is rendered again from where it started
+        seek(reader.as_mut(), 0.5);
+        assert!(peak(reader.as_mut()) > 0.05);
+        seek(reader.as_mut(), 1.5);
+        assert_eq!(peak(reader.as_mut()), 0.0);
+        seek(reader.as_mut(), 0.9);
+        assert!(peak(reader.as_mut()) > 0.05);
+    }
+
+    // The output rate is process-wide, so everything that decodes DSD is in
+    // this one test
+    #[test]
+    fn test_decode_dsd() {
+        let dir = tempdir().unwrap();
+        for (name, bytes, format) in [
+            ("sine.dsf", fixtures::dsf(), "dsf"),
+            ("sine.dff", fixtures::dff(), "dsdiff"),
+        ] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, bytes).unwrap();
+            assert!(scanner::is_audio_file(&path));
+            let tracks = scanner::read_tracks(&path).unwrap();
+            assert_eq!(tracks[0].title.as_deref(), Some("Sine Wave"), "{}", name);
+            assert_eq!(tracks[0].artist.as_deref(), Some("Test Tone"), "{}", name);
+
+            // DSD64 is decimated by 32 to 88.2 kHz
+            super::set_dsd_rate(None);
+            let info = probe::probe(&path).unwrap();
+            assert_eq!(info.format.unwrap().short, format);
+            assert_eq!(info.tracks[0].sample_rate, Some(88_200));
+            assert_eq!(info.decode[0].frames, 8820);
+            assert_eq!(info.decode[0].error_count, 0);
+
+            // Once the filter has filled, the left channel is the sine at
+            // half of full scale, and the right is quiet
+            let mut reader = open(&path);
+            let mut left = Vec::new();
+            let mut right = Vec::new();
+            while let Ok(packet) = reader.next_packet() {
+                left.extend(channel(&packet, 0, 2));
+                right.extend(channel(&packet, 1, 2));
+            }
+            assert_eq!(left.len(), 8820);
+            let sine = 0.5 / 2f32.sqrt();
+            assert!((rms(&left[1024..]) - sine).abs() < 0.03, "{}", name);
+            assert!(rms(&right[1024..]) < 0.01, "{}", name);
+
+            // A seek primes the filter, so the first packet is already clean
+            seek(reader.as_mut(), 0.05);
+            let packet = reader.next_packet().unwrap();
+            assert_eq!(packet.ts(), 4410);
+            assert!(
+                (rms(&channel(&packet, 0, 2)) - sine).abs() < 0.03,
+                "{}",
+                name
+            );
+
+            super::set_dsd_rate(Some(176_400));
+            let info = probe::probe(&path).unwrap();
+            assert_eq!(info.tracks[0].sample_rate, Some(176_400));
+            assert_eq!(info.decode[0].frames, 17_640);
+        }
+        super::set_dsd_rate(None);
+    }
+
+    #[test]
+    fn test_dff_chunk_lengths_out_of_range() {
+        let chunk = |id: &[u8], length: u64| [id, &length.to_be_bytes()].concat();
+        for file in [
+            // A form so long its end is past any offset
+            [chunk(b"FRM8", u64::MAX - 3), b"DSD ".to_vec()].concat(),
+            // A chunk that runs past the form it is in
+            [
+                chunk(b"FRM8", 16),
+                b"DSD ".to_vec(),
+                chunk(b"FVER", u64::MAX - 8),
+            ]
+            .concat(),
+            [
+                chunk(b"FRM8", 32),
+                b"DSD ".to_vec(),
+                chunk(b"PROP", 16),
+                b"SND ".to_vec(),
+                chunk(b"FS  ", u64::MAX),
+            ]
+            .concat(),
+        ] {
+            let source = MediaSourceStream::new(Box::new(Cursor::new(file)), Default::default());
+            let result = super::dsd::DsdReader::try_new(source, &Default::default());
+            assert!(matches!(result, Err(Error::DecodeError(_))));
+        }
+    }
+
+    fn modules() -> [(&'static str, Vec<u8>); 4] {
+        [
+            ("mod", fixtures::protracker()),
+            ("s3m", fixtures::scream_tracker()),
+            ("xm", fixtures::fasttracker()),
+            ("it", fixtures::impulse_tracker()),
+        ]
+    }
+
+    #[test]
+    fn test_scan_tracker() {
+        let dir = tempdir().unwrap();
+        for (format, bytes) in modules() {
+            let path = dir.path().join(format!("tune.{}", format));
+            std::fs::write(&path, bytes).unwrap();
+            assert!(scanner::is_audio_file(&path));
+            let tracks = scanner::read_tracks(&path).unwrap();
+            assert_eq!(tracks[0].title.as_deref(), Some("Sine Tune"), "{}", format);
+            let comment = tracks[0].comment.as_deref().unwrap_or_default();
+            let expected = match format {
+                "xm" => "Lead\nSine",
+                "it" => "Made for tests\nLead\nSine",
+                _ => "Sine",
+            };
+            assert_eq!(comment, expected, "{}", format);
+            // 32 rows of 0.12 seconds, then the song comes back round
+            assert_eq!(tracks[0].duration_seconds, Some(4), "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_render_tracker() {
+        let dir = tempdir().unwrap();
+        let row = fixtures::TRACKER_ROW;
+        for (format, bytes) in modules() {
+            let path = dir.path().join(format!("tune.{}", format));
+            std::fs::write(&path, bytes).unwrap();
+            let info = probe::probe(&path).unwrap();
+            assert_eq!(info.format.unwrap().short, format);
+            assert_eq!(info.demux_error, None, "{}", format);
+            assert_eq!(
+                info.decode[0].frames,
+                fixtures::TRACKER_ROWS * row,
+                "{}",
+                format
+            );
+            assert_eq!(info.decode[0].error_count, 0);
+
+            let mut reader = open(&path);
+            assert!(peak(reader.as_mut()) > 0.1, "{}", format);
+
+            // Seeks land on the start of the row the time is in
+            let seeked = reader
+                .seek(
+                    SeekMode::Accurate,
+                    SeekTo::TimeStamp {
+                        ts: 44_100,
+                        track_id: 0,
+                    },
+                )
+                .unwrap();
+            assert_eq!(seeked.actual_ts, 8 * row, "{}", format);
+            let packet = reader.next_packet().unwrap();
+            assert_eq!(packet.ts(), 8 * row);
+            assert!(channel(&packet, 0, 1).iter().any(|s| s.abs() > 0.1));
+
+            // The second order position cuts the note
+            let seeked = reader
+                .seek(
+                    SeekMode::Accurate,
+                    SeekTo::TimeStamp {
+                        ts: 110_250,
+                        track_id: 0,
+                    },
+                )
+                .unwrap();
+            assert_eq!(seeked.actual_ts, 20 * row, "{}", format);
+            assert_eq!(peak(reader.as_mut()), 0.0, "{}", format);
+
+            // And back into the first
+            seek(reader.as_mut(), 0.0);
+            assert!(peak(reader.as_mut()) > 0.1, "{}", format);
+        }
+    }
+}
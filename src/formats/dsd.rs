@@ -0,0 +1,647 @@
+//! DSD audio from Sony's DSF files and Philips' DSDIFF (`.dff`) files,
+//! turned into PCM by a low-pass FIR filter and decimation as it is read.
+//!
+//! The output rate is `playback.dsd_rate` from the settings file, moved to
+//! the nearest rate the DSD rate divides into by a power of two: DSD64 at
+//! 88.2 kHz by default, DSD128 the same rate by twice the decimation.
+//! DST-compressed DSDIFF is not supported.
+
+use std::f64::consts::PI;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+use symphonia::core::audio::Channels;
+use symphonia::core::errors::SeekErrorKind;
+use symphonia::core::errors::{Error, Result, decode_error, seek_error, unsupported_error};
+use symphonia::core::formats::prelude::*;
+use symphonia::core::io::{BufReader, MediaSource, MediaSourceStream, ReadBytes};
+use symphonia::core::meta::{Metadata, MetadataBuilder, MetadataLog, StandardTagKey, Tag, Value};
+use symphonia::core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia::core::support_format;
+use symphonia_metadata::id3v2::read_id3v2;
+
+/// Output rate when none is set
+const DEFAULT_RATE: u32 = 88_200;
+
+/// Rates `playback.dsd_rate` may be set to
+pub const OUTPUT_RATES: &[u32] = &[
+    44_100, 48_000, 88_200, 96_000, 176_400, 192_000, 352_800, 384_000,
+];
+
+/// Output frames per packet
+const PACKET_FRAMES: u64 = 1024;
+
+/// Filter taps per DSD bit the output is decimated by
+const TAPS_PER_BIT: usize = 32;
+
+/// Passband edge cap: above it DSD's shaped noise rises steeply
+const MAX_CUTOFF: f64 = 30_000.0;
+
+/// A byte of DSD silence, as many ones as zeros
+const SILENCE: u8 = 0x69;
+
+static OUTPUT_RATE: Mutex<Option<u32>> = Mutex::new(None);
+
+/// Convert DSD to PCM at about `rate`, or at 88.2 kHz if `None`
+pub fn set_output_rate(rate: Option<u32>) {
+    *OUTPUT_RATE.lock().unwrap() = rate;
+}
+
+/// How many DSD bits go into each PCM frame to get as close to the
+/// configured rate as a power of two allows, from 8 (one byte) up
+fn decimation(dsd_rate: u32) -> usize {
+    let wanted = OUTPUT_RATE.lock().unwrap().unwrap_or(DEFAULT_RATE);
+    let ratio = dsd_rate as f64 / wanted as f64;
+    let power = ratio.log2().round().clamp(3.0, 10.0);
+    1 << power as u32
+}
+
+/// A windowed-sinc low-pass filter over DSD bits, applied a byte at a time:
+/// `tables[g][byte]` is the filter's response to `byte` sitting `g` bytes
+/// back from the newest
+struct Filter {
+    tables: Vec<[f32; 256]>,
+}
+
+impl Filter {
+    fn new(decimation: usize, dsd_rate: u32) -> Self {
+        let taps = decimation * TAPS_PER_BIT;
+        let output_rate = dsd_rate as f64 / decimation as f64;
+        let cutoff = (output_rate * 0.45).min(MAX_CUTOFF) / dsd_rate as f64;
+        let centre = (taps - 1) as f64 / 2.0;
+        let mut coefficients: Vec<f64> = (0..taps)
+            .map(|n| {
+                let x = n as f64 - centre;
+                let sinc = if x == 0.0 {
+                    2.0 * cutoff
+                } else {
+                    (2.0 * PI * cutoff * x).sin() / (PI * x)
+                };
+                // Blackman window
+                let phase = 2.0 * PI * n as f64 / (taps - 1) as f64;
+                sinc * (0.42 - 0.5 * phase.cos() + 0.08 * (2.0 * phase).cos())
+            })
+            .collect();
+        let sum: f64 = coefficients.iter().sum();
+        coefficients.iter_mut().for_each(|c| *c /= sum);
+
+        // Tap 0 is the newest bit, the last of the newest byte
+        let tables = coefficients
+            .chunks_exact(8)
+            .map(|group| {
+                let mut table = [0f32; 256];
+                for (byte, response) in table.iter_mut().enumerate() {
+                    *response = (0..8)
+                        .map(|bit| {
+                            let coefficient = group[7 - bit];
+                            match byte >> (7 - bit) & 1 {
+                                1 => coefficient,
+                                _ => -coefficient,
+                            }
+                        })
+                        .sum::<f64>() as f32;
+                }
+                table
+            })
+            .collect();
+        Self { tables }
+    }
+
+    /// Bytes of history each output sample looks at
+    fn len(&self) -> usize {
+        self.tables.len()
+    }
+
+    /// The output for the window of bytes ending with the newest
+    fn apply(&self, window: &[u8]) -> f32 {
+        self.tables
+            .iter()
+            .zip(window.iter().rev())
+            .map(|(table, &byte)| table[byte as usize])
+            .sum()
+    }
+}
+
+/// How the channels' bytes are laid out in the sound data
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Layout {
+    /// DSF: blocks of this many bytes of each channel in turn, bits least
+    /// significant first
+    Blocks(u64),
+    /// DSDIFF: a byte of each channel in turn, most significant bit first
+    Interleaved,
+}
+
+/// What the container headers say
+struct Header {
+    layout: Layout,
+    dsd_rate: u32,
+    /// Speaker of each channel in the file, in file order
+    positions: Vec<Channels>,
+    /// Where the sound data starts
+    data_start: u64,
+    /// DSD bytes per channel
+    bytes: u64,
+    tags: MetadataBuilder,
+}
+
+/// A fixed-size little- or big-endian header field reader
+struct Fields<'a>(&'a [u8]);
+
+impl Fields<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        if self.0.len() < N {
+            return decode_error("dsd: header too short");
+        }
+        let (field, rest) = self.0.split_at(N);
+        self.0 = rest;
+        Ok(field.try_into().unwrap())
+    }
+
+    fn u16_be(&mut self) -> Result<u16> {
+        Ok(u16::from_be_bytes(self.take()?))
+    }
+
+    fn u32_le(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    fn u32_be(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take()?))
+    }
+
+    fn u64_le(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    fn u64_be(&mut self) -> Result<u64> {
+        Ok(u64::from_be_bytes(self.take()?))
+    }
+}
+
+fn read_exact(source: &mut MediaSourceStream, count: usize) -> Result<Vec<u8>> {
+    let mut buf = vec![0; count];
+    source.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// ID3v2 tags, as DSF files carry and many DSDIFF files do too
+fn read_id3(data: &[u8], tags: &mut MetadataBuilder) {
+    // A broken tag leaves the audio playable
+    let _ = read_id3v2(&mut BufReader::new(data), tags);
+}
+
+/// DSF speaker layouts by channel type
+fn dsf_positions(channel_type: u32, count: u32) -> Result<Vec<Channels>> {
+    use Channels as C;
+    let positions = match channel_type {
+        1 => vec![C::FRONT_LEFT],
+        2 => vec![C::FRONT_LEFT, C::FRONT_RIGHT],
+        3 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::FRONT_CENTRE],
+        4 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::REAR_LEFT, C::REAR_RIGHT],
+        5 => vec![C::FRONT_LEFT, C::FRONT_RIGHT, C::FRONT_CENTRE, C::LFE1],
+        6 => vec![
+            C::FRONT_LEFT,
+            C::FRONT_RIGHT,
+            C::FRONT_CENTRE,
+            C::REAR_LEFT,
+            C::REAR_RIGHT,
+        ],
+        7 => vec![
+            C::FRONT_LEFT,
+            C::FRONT_RIGHT,
+            C::FRONT_CENTRE,
+            C::LFE1,
+            C::REAR_LEFT,
+            C::REAR_RIGHT,
+        ],
+        _ => return unsupported_error("dsd: unknown DSF channel type"),
+    };
+    if positions.len() != count as usize {
+        return decode_error("dsd: channel count does not match channel type");
+    }
+    Ok(positions)
+}
+
+fn read_dsf(source: &mut MediaSourceStream, base: u64) -> Result<Header> {
+    let head = read_exact(source, 28)?;
+    let mut fields = Fields(&head[4..]);
+    let _size = fields.u64_le()?;
+    let _file_size = fields.u64_le()?;
+    let metadata_offset = fields.u64_le()?;
+
+    let fmt = read_exact(source, 52)?;
+    if &fmt[..4] != b"fmt " {
+        return decode_error("dsd: missing fmt chunk");
+    }
+    let mut fields = Fields(&fmt[12..]);
+    let _version = fields.u32_le()?;
+    if fields.u32_le()? != 0 {
+        return unsupported_error("dsd: only raw DSF is supported");
+    }
+    let channel_type = fields.u32_le()?;
+    let channels = fields.u32_le()?;
+    let dsd_rate = fields.u32_le()?;
+    let bits_per_sample = fields.u32_le()?;
+    let samples = fields.u64_le()?;
+    let block = fields.u32_le()? as u64;
+    // Eight bits per sample is the rare most-significant-first variant
+    if bits_per_sample != 1 || block == 0 {
+        return unsupported_error("dsd: only 1-bit DSF is supported");
+    }
+    let positions = dsf_positions(channel_type, channels)?;
+
+    let data = read_exact(source, 12)?;
+    if &data[..4] != b"data" {
+        return decode_error("dsd: missing data chunk");
+    }
+    let data_start = base + 28 + 52 + 12;
+
+    let mut tags = MetadataBuilder::new();
+    if metadata_offset != 0 {
+        let Some(offset) = base.checked_add(metadata_offset) else {
+            return decode_error("dsd: metadata offset out of range");
+        };
+        source.seek(SeekFrom::Start(offset))?;
+        let mut tag = Vec::new();
+        source.read_to_end(&mut tag)?;
+        read_id3(&tag, &mut tags);
+    }
+
+    Ok(Header {
+        layout: Layout::Blocks(block),
+        dsd_rate,
+        positions,
+        data_start,
+        bytes: samples.div_ceil(8),
+        tags,
+    })
+}
+
+/// The body of a chunk that isn't sound data
+fn body(source: &mut MediaSourceStream, start: u64, length: u64) -> Result<Vec<u8>> {
+    source.seek(SeekFrom::Start(start))?;
+    read_exact(source, length.min(1 << 24) as usize)
+}
+
+/// DSDIFF speaker names
+fn dff_position(id: &[u8]) -> Option<Channels> {
+    Some(match id {
+        b"SLFT" | b"MLFT" => Channels::FRONT_LEFT,
+        b"SRGT" | b"MRGT" => Channels::FRONT_RIGHT,
+        b"C   " => Channels::FRONT_CENTRE,
+        b"LFE " => Channels::LFE1,
+        b"LS  " => Channels::REAR_LEFT,
+        b"RS  " => Channels::REAR_RIGHT,
+        _ => return None,
+    })
+}
+
+/// A DSDIFF text: a 32-bit length and the text
+fn dff_text(body: &[u8]) -> Option<String> {
+    let length = u32::from_be_bytes(body.get(..4)?.try_into().unwrap()) as usize;
+    let text = body.get(4..4 + length)?;
+    let text = String::from_utf8_lossy(text).trim().to_owned();
+    (!text.is_empty()).then_some(text)
+}
+
+/// The chunks of a DSDIFF container, as (id, start of body, length), with
+/// the bodies left unread. Every body ends by `end`, so a chunk that claims
+/// to run past its container is an error.
+fn dff_chunks(
+    source: &mut MediaSourceStream,
+    mut position: u64,
+    end: u64,
+) -> Result<Vec<([u8; 4], u64, u64)>> {
+    let mut chunks = Vec::new();
+    while end.saturating_sub(position) >= 12 {
+        source.seek(SeekFrom::Start(position))?;
+        let head = read_exact(source, 12)?;
+        let id: [u8; 4] = head[..4].try_into().unwrap();
+        let length = Fields(&head[4..]).u64_be()?;
+        let start = position + 12;
+        let Some(body_end) = start
+            .checked_add(length)
+            .filter(|&body_end| body_end <= end)
+        else {
+            return decode_error("dsd: chunk runs past its container");
+        };
+        chunks.push((id, start, length));
+        position = body_end.saturating_add(length % 2);
+    }
+    Ok(chunks)
+}
+
+fn read_dff(source: &mut MediaSourceStream, base: u64) -> Result<Header> {
+    let head = read_exact(source, 16)?;
+    let length = Fields(&head[4..12]).u64_be()?;
+    if &head[12..] != b"DSD " {
+        return unsupported_error("dsd: not a DSD form");
+    }
+    let (mut dsd_rate, mut positions, mut data) = (None, None, None);
+    let mut tags = MetadataBuilder::new();
+    let Some(end) = (base + 12).checked_add(length) else {
+        return decode_error("dsd: form runs past the end of the file");
+    };
+    for (id, start, length) in dff_chunks(source, base + 16, end)? {
+        match &id {
+            b"PROP" => {
+                let form = body(source, start, 4)?;
+                if form != b"SND " {
+                    continue;
+                }
+                for (id, start, length) in dff_chunks(source, start + 4, start + length)? {
+                    let property = body(source, start, length)?;
+                    let mut fields = Fields(&property);
+                    match &id {
+                        b"FS  " => dsd_rate = Some(fields.u32_be()?),
+                        b"CHNL" => {
+                            let count = fields.u16_be()? as usize;
+                            let ids = property.get(2..2 + count * 4).unwrap_or_default();
+                            positions = Some(
+                                ids.chunks_exact(4)
+                                    .map(dff_position)
+                                    .collect::<Option<Vec<_>>>()
+                                    .unwrap_or_else(|| fallback_positions(count)),
+                            );
+                        }
+                        b"CMPR" if property.get(..4) != Some(b"DSD ") => {
+                            return unsupported_error(
+                                "dsd: DST-compressed DSDIFF is not supported",
+                            );
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            b"DSD " => data = Some((start, length)),
+            b"DST " => return unsupported_error("dsd: DST-compressed DSDIFF is not supported"),
+            b"DIIN" => {
+                for (id, start, length) in dff_chunks(source, start, start + length)? {
+                    let key = match &id {
+                        b"DITI" => (StandardTagKey::TrackTitle, "TITLE"),
+                        b"DIAR" => (StandardTagKey::Artist, "ARTIST"),
+                        _ => continue,
+                    };
+                    if let Some(text) = dff_text(&body(source, start, length)?) {
+                        tags.add_tag(Tag::new(Some(key.0), key.1, Value::from(text)));
+                    }
+                }
+            }
+            b"ID3 " => read_id3(&body(source, start, length)?, &mut tags),
+            _ => {}
+        }
+    }
+
+    let dsd_rate = dsd_rate.ok_or(Error::DecodeError("dsd: no sample rate"))?;
+    let positions = positions.ok_or(Error::DecodeError("dsd: no channels"))?;
+    let (data_start, length) = data.ok_or(Error::DecodeError("dsd: no sound data"))?;
+    if positions.is_empty() {
+        return decode_error("dsd: no channels");
+    }
+    Ok(Header {
+        layout: Layout::Interleaved,
+        dsd_rate,
+        bytes: length / positions.len() as u64,
+        positions,
+        data_start,
+        tags,
+    })
+}
+
+/// Speakers for channels a DSDIFF file names in some other way
+fn fallback_positions(count: usize) -> Vec<Channels> {
+    let all = [
+        Channels::FRONT_LEFT,
+        Channels::FRONT_RIGHT,
+        Channels::FRONT_CENTRE,
+        Channels::LFE1,
+        Channels::REAR_LEFT,
+        Channels::REAR_RIGHT,
+    ];
+    match count {
+        1 => vec![Channels::FRONT_LEFT],
+        5 => vec![all[0], all[1], all[2], all[4], all[5]],
+        _ => all.into_iter().take(count).collect(),
+    }
+}
+
+pub struct DsdReader {
+    source: MediaSourceStream,
+    tracks: Vec<Track>,
+    metadata: MetadataLog,
+    layout: Layout,
+    data_start: u64,
+    /// DSD bytes per channel
+    bytes: u64,
+    /// Channels of the file, and the file channel for each output channel
+    channels: usize,
+    order: Vec<usize>,
+    filter: Filter,
+    /// DSD bytes per output frame
+    step: usize,
+    /// The last `filter.len()` bytes of each file channel
+    history: Vec<Vec<u8>>,
+    frame: u64,
+    frames: u64,
+    buf: Vec<f32>,
+}
+
+impl QueryDescriptor for DsdReader {
+    fn query() -> &'static [Descriptor] {
+        &[
+            support_format!(
+                "dsf",
+                "DSD Stream File",
+                &["dsf"],
+                &["audio/dsf", "audio/x-dsf"],
+                &[b"DSD "]
+            ),
+            support_format!(
+                "dsdiff",
+                "Direct Stream Digital Interchange File Format",
+                &["dff"],
+                &["audio/dff", "audio/x-dff"],
+                &[b"FRM8"]
+            ),
+        ]
+    }
+
+    fn score(_context: &[u8]) -> u8 {
+        255
+    }
+}
+
+impl DsdReader {
+    /// `count` bytes of each file channel from `start`, bits in time order
+    /// from the most significant; past the end of the data is silence
+    fn read(&mut self, start: u64, count: usize) -> Result<Vec<Vec<u8>>> {
+        let mut channels = vec![vec![SILENCE; count]; self.channels];
+        let end = (start + count as u64).min(self.bytes);
+        if start >= end {
+            return Ok(channels);
+        }
+        let wanted = (end - start) as usize;
+        match self.layout {
+            Layout::Interleaved => {
+                let at = self.data_start + start * self.channels as u64;
+                self.source.seek(SeekFrom::Start(at))?;
+                let data = read_exact(&mut self.source, wanted * self.channels)?;
+                for (i, frame) in data.chunks_exact(self.channels).enumerate() {
+                    for (channel, &byte) in channels.iter_mut().zip(frame) {
+                        channel[i] = byte;
+                    }
+                }
+            }
+            Layout::Blocks(block) => {
+                // Blocks of every channel for the span are next to each other
+                let (first, last) = (start / block, (end - 1) / block);
+                let group = block * self.channels as u64;
+                self.source
+                    .seek(SeekFrom::Start(self.data_start + first * group))?;
+                let data = read_exact(&mut self.source, ((last - first + 1) * group) as usize)?;
+                for (c, channel) in channels.iter_mut().enumerate() {
+                    for (i, out) in channel[..wanted].iter_mut().enumerate() {
+                        let position = start + i as u64;
+                        let index = (position / block - first) * group
+                            + c as u64 * block
+                            + position % block;
+                        *out = data[index as usize].reverse_bits();
+                    }
+                }
+            }
+        }
+        Ok(channels)
+    }
+
+    /// Start converting at `frame`, with the filter primed on the bytes
+    /// before it
+    fn restart(&mut self, frame: u64) -> Result<()> {
+        let start = frame * self.step as u64;
+        let primer = start.min(self.filter.len() as u64);
+        let mut history = self.read(start - primer, primer as usize)?;
+        for channel in &mut history {
+            let mut primed = vec![SILENCE; self.filter.len() - primer as usize];
+            primed.append(channel);
+            *channel = primed;
+        }
+        self.history = history;
+        self.frame = frame;
+        Ok(())
+    }
+}
+
+impl FormatReader for DsdReader {
+    fn try_new(mut source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        if !source.is_seekable() {
+            return unsupported_error("dsd: the file must be seekable");
+        }
+        let base = source.pos();
+        let mut marker = [0; 4];
+        source.read_exact(&mut marker)?;
+        source.seek(SeekFrom::Start(base))?;
+        let header = match &marker {
+            b"DSD " => read_dsf(&mut source, base)?,
+            _ => read_dff(&mut source, base)?,
+        };
+        if header.dsd_rate == 0 {
+            return decode_error("dsd: zero sample rate");
+        }
+
+        let decimation = decimation(header.dsd_rate);
+        let step = decimation / 8;
+        let frames = header.bytes / step as u64;
+        let rate = header.dsd_rate / decimation as u32;
+        let mut order: Vec<usize> = (0..header.positions.len()).collect();
+        order.sort_by_key(|&channel| header.positions[channel].bits());
+        let positions = header
+            .positions
+            .iter()
+            .fold(Channels::empty(), |all, &position| all | position);
+        if positions.count() != header.positions.len() {
+            return decode_error("dsd: a speaker is named twice");
+        }
+
+        let mut metadata = MetadataLog::default();
+        metadata.push(header.tags.metadata());
+        let mut reader = Self {
+            source,
+            tracks: vec![Track::new(
+                0,
+                super::pcm_params(rate, positions, frames, PACKET_FRAMES),
+            )],
+            metadata,
+            layout: header.layout,
+            data_start: header.data_start,
+            bytes: header.bytes,
+            channels: header.positions.len(),
+            order,
+            filter: Filter::new(decimation, header.dsd_rate),
+            step,
+            history: Vec::new(),
+            frame: 0,
+            frames,
+            buf: Vec::new(),
+        };
+        reader.restart(0)?;
+        Ok(reader)
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &[]
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Every frame starts on a byte, so seeks land exactly
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        let rate = self.tracks[0].codec_params.sample_rate.unwrap_or(1) as u64;
+        let target = match to {
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => time.seconds * rate + (time.frac * rate as f64) as u64,
+        };
+        if target > self.frames {
+            return seek_error(SeekErrorKind::OutOfRange);
+        }
+        self.restart(target)?;
+        Ok(SeekedTo {
+            track_id: 0,
+            required_ts: target,
+            actual_ts: target,
+        })
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        if self.frame >= self.frames {
+            return Err(Error::IoError(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let ts = self.frame;
+        let frames = PACKET_FRAMES.min(self.frames - self.frame) as usize;
+        let fresh = self.read(ts * self.step as u64, frames * self.step)?;
+        let taps = self.filter.len();
+        self.buf.resize(frames * self.channels, 0.0);
+        for (channel, fresh) in fresh.into_iter().enumerate() {
+            let history = &mut self.history[channel];
+            history.extend(fresh);
+            let output = self.order.iter().position(|&c| c == channel).unwrap();
+            for frame in 0..frames {
+                let end = taps + (frame + 1) * self.step;
+                self.buf[frame * self.channels + output] =
+                    self.filter.apply(&history[end - taps..end]);
+            }
+            history.drain(..history.len() - taps);
+        }
+        self.frame += frames as u64;
+        Ok(super::pcm_packet(ts, frames as u64, &self.buf))
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.source
+    }
+}
@@ -0,0 +1,547 @@
+//! Tiny files in the formats turned into PCM here, built rather than
+//! checked in: a one-note MIDI song, a SoundFont of one looped sine wave,
+//! a tenth of a second of a DSD64 sine wave in DSF and DSDIFF, and a
+//! two-pattern song of one looped sine in each tracker format.
+
+use std::f32::consts::TAU;
+
+/// Length of the SoundFont's sine wave: a cycle at 441 Hz
+const SINE_FRAMES: usize = 100;
+
+/// A MIDI variable-length quantity
+fn quantity(mut value: u32) -> Vec<u8> {
+    let mut bytes = vec![(value & 0x7f) as u8];
+    value >>= 7;
+    while value > 0 {
+        bytes.insert(0, (value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    bytes
+}
+
+fn midi_chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut chunk = id.to_vec();
+    chunk.extend((body.len() as u32).to_be_bytes());
+    chunk.extend(body);
+    chunk
+}
+
+/// A track of (delta ticks, event bytes), ended for it
+fn track(events: &[(u32, &[u8])]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (delta, event) in events {
+        body.extend(quantity(*delta));
+        body.extend(*event);
+    }
+    body.extend([0x00, 0xff, 0x2f, 0x00]);
+    midi_chunk(b"MTrk", &body)
+}
+
+fn meta(kind: u8, text: &str) -> Vec<u8> {
+    let mut event = vec![0xff, kind];
+    event.extend(quantity(text.len() as u32));
+    event.extend(text.as_bytes());
+    event
+}
+
+/// A format 1 file at 480 ticks per quarter and 120 bpm: a title,
+/// copyright and comment on the first track, and A4 held for one second
+/// from the start on the second, its note-off sent as a running status
+/// note-on
+pub fn song() -> Vec<u8> {
+    let mut file = midi_chunk(b"MThd", &[0, 1, 0, 2, 0x01, 0xe0]);
+    file.extend(track(&[
+        (0, &meta(0x03, "Sine Song")),
+        (0, &meta(0x02, "(c) Nobody")),
+        (0, &meta(0x01, "Made for tests")),
+        (0, &[0xff, 0x51, 0x03, 0x07, 0xa1, 0x20]),
+    ]));
+    file.extend(track(&[
+        (0, &[0xc0, 0x00]),
+        (0, &[0x90, 69, 100]),
+        (960, &[69, 0]),
+    ]));
+    file
+}
+
+fn riff_chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut chunk = id.to_vec();
+    chunk.extend((body.len() as u32).to_le_bytes());
+    chunk.extend(body);
+    if body.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+    let mut body = kind.to_vec();
+    body.extend(chunks.concat());
+    riff_chunk(b"LIST", &body)
+}
+
+/// A 20-byte name field
+fn name(name: &str) -> Vec<u8> {
+    let mut field = name.as_bytes().to_vec();
+    field.resize(20, 0);
+    field
+}
+
+fn words(values: &[u16]) -> Vec<u8> {
+    values
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+/// One preset, "Sine", playing one instrument of one looped sine cycle
+/// sampled at 44.1 kHz with A4 as its root key
+pub fn soundfont() -> Vec<u8> {
+    let mut smpl: Vec<u8> = (0..SINE_FRAMES)
+        .map(|i| ((i as f32 / SINE_FRAMES as f32 * TAU).sin() * 16000.0) as i16)
+        .flat_map(|sample| sample.to_le_bytes())
+        .collect();
+    // The specification wants 46 zero samples after each sample
+    smpl.resize(smpl.len() + 46 * 2, 0);
+
+    let mut phdr = [name("Sine"), words(&[0, 0, 0]), vec![0; 12]].concat();
+    phdr.extend([name("EOP"), words(&[0, 0, 1]), vec![0; 12]].concat());
+    let mut inst = [name("Sine"), words(&[0])].concat();
+    inst.extend([name("EOI"), words(&[1])].concat());
+    let frames = SINE_FRAMES as u32;
+    let mut shdr = name("Sine");
+    for value in [0, frames, 0, frames, 44_100] {
+        shdr.extend(u32::to_le_bytes(value));
+    }
+    shdr.extend([69, 0]);
+    shdr.extend(words(&[0, 1]));
+    shdr.extend([name("EOS"), vec![0; 26]].concat());
+
+    let body = [
+        b"sfbk".to_vec(),
+        list(b"INFO", &[riff_chunk(b"ifil", &words(&[2, 1]))]),
+        list(b"sdta", &[riff_chunk(b"smpl", &smpl)]),
+        list(
+            b"pdta",
+            &[
+                riff_chunk(b"phdr", &phdr),
+                riff_chunk(b"pbag", &words(&[0, 0, 1, 0])),
+                riff_chunk(b"pmod", &[0; 10]),
+                // Instrument 0, then the terminal record
+                riff_chunk(b"pgen", &words(&[41, 0, 0, 0])),
+                riff_chunk(b"inst", &inst),
+                riff_chunk(b"ibag", &words(&[0, 0, 2, 0])),
+                riff_chunk(b"imod", &[0; 10]),
+                // Loop continuously, sample 0, then the terminal record
+                riff_chunk(b"igen", &words(&[54, 1, 53, 0, 0, 0])),
+                riff_chunk(b"shdr", &shdr),
+            ],
+        ),
+    ]
+    .concat();
+    riff_chunk(b"RIFF", &body)
+}
+
+/// Bit rate of DSD64
+pub const DSD64: u32 = 2_822_400;
+
+/// Frequency of the DSD sine wave
+pub const DSD_TONE: f64 = 1000.0;
+
+/// A tenth of a second of DSD64 per channel: a 1 kHz sine at half of full
+/// scale on the left, silence on the right, through a second-order
+/// delta-sigma modulator. Earliest bit in the high bit of each byte.
+fn dsd_channels() -> [Vec<u8>; 2] {
+    let bits = DSD64 as usize / 10;
+    [0.5, 0.0].map(|amplitude| {
+        let (mut first, mut second, mut last) = (0.0, 0.0, 0.0);
+        let mut bytes = vec![0u8; bits / 8];
+        for i in 0..bits {
+            let phase = std::f64::consts::TAU * DSD_TONE * i as f64 / DSD64 as f64;
+            first += amplitude * phase.sin() - last;
+            second += first - last;
+            last = if second >= 0.0 { 1.0 } else { -1.0 };
+            if last > 0.0 {
+                bytes[i / 8] |= 0x80 >> (i % 8);
+            }
+        }
+        bytes
+    })
+}
+
+/// An ID3v2.3 tag of text frames
+fn id3(frames: &[(&[u8; 4], &str)]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (id, text) in frames {
+        body.extend(*id);
+        body.extend((text.len() as u32 + 1).to_be_bytes());
+        body.extend([0, 0, 0]);
+        body.extend(text.as_bytes());
+    }
+    let size = body.len() as u32;
+    let mut tag = b"ID3\x03\x00\x00".to_vec();
+    tag.extend([21, 14, 7, 0].map(|shift| (size >> shift & 0x7f) as u8));
+    tag.extend(body);
+    tag
+}
+
+/// The DSD sine in a DSF file with an ID3 title and artist
+pub fn dsf() -> Vec<u8> {
+    const BLOCK: usize = 4096;
+    let channels = dsd_channels();
+    let bytes = channels[0].len();
+    let mut data = Vec::new();
+    for block in 0..bytes.div_ceil(BLOCK) {
+        for channel in &channels {
+            let mut chunk: Vec<u8> = channel[block * BLOCK..bytes.min((block + 1) * BLOCK)]
+                .iter()
+                .map(|byte| byte.reverse_bits())
+                .collect();
+            chunk.resize(BLOCK, 0);
+            data.extend(chunk);
+        }
+    }
+    let tag = id3(&[(b"TIT2", "Sine Wave"), (b"TPE1", "Test Tone")]);
+    let metadata = (28 + 52 + 12 + data.len()) as u64;
+
+    let mut file = b"DSD ".to_vec();
+    file.extend(28u64.to_le_bytes());
+    file.extend((metadata + tag.len() as u64).to_le_bytes());
+    file.extend(metadata.to_le_bytes());
+    file.extend(b"fmt ");
+    file.extend(52u64.to_le_bytes());
+    // Version 1, raw DSD, stereo, two channels
+    for value in [1, 0, 2, 2, DSD64, 1] {
+        file.extend(u32::to_le_bytes(value));
+    }
+    file.extend((bytes as u64 * 8).to_le_bytes());
+    file.extend((BLOCK as u32).to_le_bytes());
+    file.extend([0; 4]);
+    file.extend(b"data");
+    file.extend((12 + data.len() as u64).to_le_bytes());
+    file.extend(data);
+    file.extend(tag);
+    file
+}
+
+fn dff_chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+    let mut chunk = id.to_vec();
+    chunk.extend((body.len() as u64).to_be_bytes());
+    chunk.extend(body);
+    if body.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+fn dff_text(text: &str) -> Vec<u8> {
+    let mut body = (text.len() as u32).to_be_bytes().to_vec();
+    body.extend(text.as_bytes());
+    body
+}
+
+/// The DSD sine in a DSDIFF file with an edited master title and artist
+pub fn dff() -> Vec<u8> {
+    let [left, right] = dsd_channels();
+    let data: Vec<u8> = left
+        .iter()
+        .zip(&right)
+        .flat_map(|(l, r)| [*l, *r])
+        .collect();
+    let mut channels = 2u16.to_be_bytes().to_vec();
+    channels.extend(b"SLFTSRGT");
+    let mut compression = b"DSD \x0enot compressed".to_vec();
+    compression.push(0);
+    let prop = [
+        b"SND ".to_vec(),
+        dff_chunk(b"FS  ", &DSD64.to_be_bytes()),
+        dff_chunk(b"CHNL", &channels),
+        dff_chunk(b"CMPR", &compression),
+    ]
+    .concat();
+    let body = [
+        b"DSD ".to_vec(),
+        dff_chunk(b"FVER", &[1, 5, 0, 0]),
+        dff_chunk(b"PROP", &prop),
+        dff_chunk(b"DSD ", &data),
+        dff_chunk(
+            b"DIIN",
+            &[
+                dff_chunk(b"DIAR", &dff_text("Test Tone")),
+                dff_chunk(b"DITI", &dff_text("Sine Wave")),
+            ]
+            .concat(),
+        ),
+    ]
+    .concat();
+    dff_chunk(b"FRM8", &body)
+}
+
+/// Frames of a row of the tracker song: six ticks at 125 bpm
+pub const TRACKER_ROW: u64 = 6 * 882;
+
+/// Rows the tracker song plays before it comes back round
+pub const TRACKER_ROWS: u64 = 32;
+
+/// The tracker song's looped sample: one cycle of a sine in 64 bytes,
+/// which plays at 130 Hz for C-5
+fn tracker_sine() -> Vec<u8> {
+    (0..64)
+        .map(|i| ((i as f32 / 64.0 * TAU).sin() * 127.0) as i8 as u8)
+        .collect()
+}
+
+/// A fixed-length text field
+fn field(text: &str, length: usize) -> Vec<u8> {
+    let mut field = text.as_bytes().to_vec();
+    field.resize(length, 0);
+    field
+}
+
+fn pad(data: &mut Vec<u8>, to: usize) {
+    data.resize(data.len().div_ceil(to) * to, 0);
+}
+
+/// "Sine Tune", its sample "Sine" in a ProTracker module: C-5 from the
+/// start of order 0, a break from row 15 to order 1, which cuts the note
+/// on its first row and jumps back to order 0 from row 15
+pub fn protracker() -> Vec<u8> {
+    let mut file = field("Sine Tune", 20);
+    file.extend(field("Sine", 22));
+    file.extend([0, 32, 0, 64, 0, 0, 0, 32]);
+    file.resize(950, 0);
+    file.extend([2, 127, 0, 1]);
+    file.resize(1080, 0);
+    file.extend(b"M.K.");
+    let cell = |row: usize, bytes: [u8; 4]| (row * 4 * 4, bytes);
+    for cells in [
+        [
+            cell(0, [0x01, 0xac, 0x10, 0x00]),
+            cell(15, [0, 0, 0x0d, 0x00]),
+        ],
+        [cell(0, [0, 0, 0x0e, 0xc0]), cell(15, [0, 0, 0x0b, 0x00])],
+    ] {
+        let mut pattern = vec![0; 64 * 4 * 4];
+        for (at, bytes) in cells {
+            pattern[at..at + 4].copy_from_slice(&bytes);
+        }
+        file.extend(pattern);
+    }
+    file.extend(tracker_sine());
+    file
+}
+
+/// The song as a Scream Tracker 3 module, on two channels
+pub fn scream_tracker() -> Vec<u8> {
+    let mut file = field("Sine Tune", 28);
+    file.extend([0x1a, 16, 0, 0]);
+    // Orders, instruments, patterns, flags, version, signed samples
+    file.extend(words(&[2, 1, 2, 0, 0x1320, 1]));
+    file.extend(b"SCRM");
+    file.extend([64, 6, 125, 0xb0, 0, 0]);
+    file.resize(64, 0);
+    let mut channels = [255; 32];
+    channels[..2].copy_from_slice(&[0, 8]);
+    file.extend(channels);
+    file.extend([0, 1]);
+    // The instrument at paragraph 7, the patterns after it
+    file.extend(words(&[7]));
+    let pattern_pointers = file.len();
+    file.extend([0; 4]);
+    file.resize(7 * 16, 0);
+
+    let instrument = file.len();
+    file.extend([1]);
+    file.extend(field("sine.raw", 12));
+    file.extend([0; 3]);
+    for value in [64, 0, 64] {
+        file.extend(u32::to_le_bytes(value));
+    }
+    file.extend([64, 0, 0, 1]);
+    file.extend(8363u32.to_le_bytes());
+    file.resize(instrument + 48, 0);
+    file.extend(field("Sine", 28));
+    file.extend(b"SCRS");
+
+    let rows = |first: [u8; 3], last: [u8; 3]| {
+        let mut rows = [first.to_vec(), vec![0]].concat();
+        rows.extend([0; 14]);
+        rows.extend([last.to_vec(), vec![0]].concat());
+        rows.extend([0; 48]);
+        let mut pattern = ((rows.len() + 2) as u16).to_le_bytes().to_vec();
+        pattern.extend(rows);
+        pattern
+    };
+    for (index, pattern) in [
+        rows([0x20, 0x40, 1], [0x80, 3, 0]),
+        rows([0x80, 19, 0xc0], [0x80, 2, 0]),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        pad(&mut file, 16);
+        let at = (file.len() / 16) as u16;
+        file[pattern_pointers + index * 2..][..2].copy_from_slice(&at.to_le_bytes());
+        file.extend(pattern);
+    }
+
+    pad(&mut file, 16);
+    let paragraph = file.len() / 16;
+    file[instrument + 13] = (paragraph >> 16) as u8;
+    file[instrument + 14..instrument + 16].copy_from_slice(&(paragraph as u16).to_le_bytes());
+    file.extend(tracker_sine());
+    file
+}
+
+/// The song as a FastTracker 2 module, its sample in the instrument "Lead"
+pub fn fasttracker() -> Vec<u8> {
+    let mut file = b"Extended Module: ".to_vec();
+    file.extend(field("Sine Tune", 20));
+    file.push(0x1a);
+    file.extend(field("FastTracker v2.00", 20));
+    file.extend(words(&[0x0104]));
+    file.extend(276u32.to_le_bytes());
+    // Orders, restart, channels, patterns, instruments, linear, speed, tempo
+    file.extend(words(&[2, 0, 2, 2, 1, 1, 6, 125]));
+    file.extend(field("\x00\x01", 256));
+
+    let rows = |first: [u8; 3], last: [u8; 3]| {
+        let mut cells = Vec::new();
+        for row in 0..64 {
+            match row {
+                0 => cells.extend(first),
+                15 => cells.extend(last),
+                _ => cells.push(0x80),
+            }
+            cells.push(0x80);
+        }
+        let mut pattern = 9u32.to_le_bytes().to_vec();
+        pattern.push(0);
+        pattern.extend(words(&[64, cells.len() as u16]));
+        pattern.extend(cells);
+        pattern
+    };
+    file.extend(rows([0x83, 49, 1], [0x98, 0x0d, 0x00]));
+    file.extend(rows([0x98, 0x0e, 0xc0], [0x98, 0x0b, 0x00]));
+
+    let instrument = file.len();
+    file.extend(263u32.to_le_bytes());
+    file.extend(field("Lead", 22));
+    file.push(0);
+    file.extend(words(&[1]));
+    file.extend(40u32.to_le_bytes());
+    file.resize(instrument + 263, 0);
+    for value in [64, 0, 64] {
+        file.extend(u32::to_le_bytes(value));
+    }
+    // Volume, finetune, forward loop, pan, relative note
+    file.extend([64, 0, 1, 128, 0, 0]);
+    file.extend(field("Sine", 22));
+    let mut last = 0u8;
+    for sample in tracker_sine() {
+        file.push(sample.wrapping_sub(last));
+        last = sample;
+    }
+    file
+}
+
+/// The song as an Impulse Tracker module with a message, its sample in the
+/// instrument "Lead" and IT214-compressed
+pub fn impulse_tracker() -> Vec<u8> {
+    let message = b"Made for tests\r\0";
+    let mut file = b"IMPM".to_vec();
+    file.extend(field("Sine Tune", 26));
+    file.extend([4, 16]);
+    // Orders, instruments, samples, patterns, versions, flags (stereo,
+    // instruments, linear slides), message
+    file.extend(words(&[2, 1, 1, 2, 0x0214, 0x0214, 0x0d, 1]));
+    file.extend([128, 48, 6, 125, 128, 0]);
+    file.extend(words(&[message.len() as u16]));
+    let message_pointer = file.len();
+    file.extend([0; 8]);
+    let mut pans = [0xa0; 64];
+    pans[..2].copy_from_slice(&[32, 32]);
+    file.extend(pans);
+    file.extend([64; 64]);
+    file.extend([0, 1]);
+    let pointers = file.len();
+    file.extend([0; 16]);
+    let point = |file: &mut Vec<u8>, index: usize| {
+        let at = file.len() as u32;
+        file[pointers + index * 4..][..4].copy_from_slice(&at.to_le_bytes());
+    };
+
+    let at = file.len() as u32;
+    file[message_pointer..message_pointer + 4].copy_from_slice(&at.to_le_bytes());
+    file.extend(message);
+
+    point(&mut file, 0);
+    let instrument = file.len();
+    file.extend(b"IMPI");
+    file.extend(field("lead.iti", 12));
+    file.resize(instrument + 24, 0);
+    file.extend([128, 0x80]);
+    file.resize(instrument + 30, 0);
+    file.extend([1, 0]);
+    file.extend(field("Lead", 26));
+    file.resize(instrument + 64, 0);
+    for note in 0..120 {
+        file.extend([note, 1]);
+    }
+    file.resize(instrument + 554, 0);
+
+    point(&mut file, 1);
+    let sample = file.len();
+    file.extend(b"IMPS");
+    file.extend(field("sine.raw", 12));
+    // Global volume, present and compressed and looped, volume
+    file.extend([0, 64, 0x19, 64]);
+    file.extend(field("Sine", 26));
+    file.extend([1, 0]);
+    for value in [64, 0, 64, 8363, 0, 0, 0, 0] {
+        file.extend(u32::to_le_bytes(value));
+    }
+
+    let rows = |first: [u8; 4], last: [u8; 4]| {
+        let mut rows = [first.to_vec(), vec![0]].concat();
+        rows.extend([0; 14]);
+        rows.extend([last.to_vec(), vec![0]].concat());
+        rows.extend([0; 48]);
+        let mut pattern = words(&[rows.len() as u16, 64, 0, 0]);
+        pattern.extend(rows);
+        pattern
+    };
+    for (index, pattern) in [
+        rows([0x81, 0x03, 60, 1], [0x81, 0x08, 3, 0]),
+        rows([0x81, 0x08, 19, 0xc0], [0x81, 0x08, 2, 0]),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        point(&mut file, 2 + index);
+        file.extend(pattern);
+    }
+
+    // One block of 9-bit differences, never wide enough to change width
+    let at = file.len() as u32;
+    file[sample + 72..sample + 76].copy_from_slice(&at.to_le_bytes());
+    let mut bits = Vec::new();
+    let mut last = 0u8;
+    for value in tracker_sine() {
+        let delta = value.wrapping_sub(last);
+        bits.extend((0..9).map(|bit| (delta as u16 >> bit) & 1));
+        last = value;
+    }
+    let block: Vec<u8> = bits
+        .chunks(8)
+        .map(|byte| {
+            byte.iter()
+                .enumerate()
+                .map(|(i, &bit)| (bit as u8) << i)
+                .sum()
+        })
+        .collect();
+    file.extend(words(&[block.len() as u16]));
+    file.extend(block);
+    file
+}
@@ -0,0 +1,533 @@
+//! Standard MIDI Files, rendered to PCM through a SoundFont as they are
+//! read, so they play, seek and scan like any other track.
+//!
+//! The SoundFont is `playback.soundfont` from the settings file, or a
+//! General MIDI one where distributions install them. It is only loaded
+//! once a file is decoded: scanning reads the text events and length alone.
+
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use symphonia::core::audio::Channels;
+use symphonia::core::errors::{Error, Result, decode_error, unsupported_error};
+use symphonia::core::formats::prelude::*;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{Metadata, MetadataBuilder, MetadataLog, StandardTagKey, Tag, Value};
+use symphonia::core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia::core::support_format;
+
+mod soundfont;
+mod synth;
+
+use soundfont::SoundFont;
+use synth::{Message, Synth};
+
+/// Rate the synthesizer renders at
+const SAMPLE_RATE: u32 = 44_100;
+
+/// Frames per packet
+const PACKET_FRAMES: u64 = 1024;
+
+/// Played after the last event so that notes can ring out
+const TAIL_FRAMES: u64 = SAMPLE_RATE as u64;
+
+/// Where distributions put a General MIDI SoundFont
+const SYSTEM_SOUNDFONTS: &[&str] = &[
+    "/usr/share/sounds/sf2/FluidR3_GM.sf2",
+    "/usr/share/sounds/sf2/default-GM.sf2",
+    "/usr/share/soundfonts/default.sf2",
+    "/usr/share/soundfonts/FluidR3_GM.sf2",
+    "/usr/share/soundfonts/FluidR3_GM2-2.sf2",
+];
+
+static SOUNDFONT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Play MIDI files with the SoundFont at `path`, or with a system one if
+/// `None`
+pub fn set_soundfont(path: Option<PathBuf>) {
+    *SOUNDFONT.lock().unwrap() = path;
+}
+
+/// The SoundFont to play with, parsed once and kept while it stays the one
+/// configured
+fn load_soundfont() -> std::result::Result<Arc<SoundFont>, String> {
+    static LOADED: Mutex<Option<(PathBuf, Arc<SoundFont>)>> = Mutex::new(None);
+    let path = match SOUNDFONT.lock().unwrap().clone() {
+        Some(path) => path,
+        None => SYSTEM_SOUNDFONTS
+            .iter()
+            .map(PathBuf::from)
+            .find(|path| path.exists())
+            .ok_or("no SoundFont found; set playback.soundfont to an .sf2 file")?,
+    };
+    let mut loaded = LOADED.lock().unwrap();
+    if let Some((loaded_path, font)) = loaded.as_ref()
+        && *loaded_path == path
+    {
+        return Ok(font.clone());
+    }
+    let font = std::fs::read(&path)
+        .map_err(|e| e.to_string())
+        .and_then(|data| SoundFont::parse(&data).map_err(|e| format!("{:#}", e)))
+        .map(Arc::new)
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    *loaded = Some((path, font.clone()));
+    Ok(font)
+}
+
+/// A channel message at the frame it sounds
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Event {
+    frame: u64,
+    channel: u8,
+    message: Message,
+}
+
+/// What a file holds once its tracks are merged and timed
+#[derive(Debug, Default)]
+struct Song {
+    events: Vec<Event>,
+    /// Frame of the last event of any kind
+    end: u64,
+    tags: Vec<Tag>,
+}
+
+/// Reads big-endian numbers and variable-length quantities from a chunk
+struct Cursor<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn byte(&mut self) -> Result<u8> {
+        let byte = *self
+            .data
+            .get(self.position)
+            .ok_or(Error::DecodeError("midi: track ends mid-event"))?;
+        self.position += 1;
+        Ok(byte)
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + count)
+            .ok_or(Error::DecodeError("midi: track ends mid-event"))?;
+        self.position += count;
+        Ok(bytes)
+    }
+
+    fn quantity(&mut self) -> Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.byte()?;
+            value = (value << 7) | (byte & 0x7f) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        decode_error("midi: variable-length number too long")
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.data.len()
+    }
+}
+
+/// An event of a track at its tick
+enum TrackEvent<'a> {
+    Channel(u8, Message),
+    /// Microseconds per quarter note
+    Tempo(u32),
+    Text(u8, &'a [u8]),
+}
+
+/// The events of one `MTrk` chunk, as (tick, event)
+fn track_events(data: &[u8]) -> Result<Vec<(u64, TrackEvent<'_>)>> {
+    let mut cursor = Cursor { data, position: 0 };
+    let mut events = Vec::new();
+    let mut tick = 0u64;
+    let mut running = None;
+    while !cursor.is_done() {
+        tick += cursor.quantity()? as u64;
+        let mut status = cursor.byte()?;
+        let first = if status < 0x80 {
+            // Running status: the byte was the first data byte
+            let data = status;
+            status = running.ok_or(Error::DecodeError("midi: data byte without a status"))?;
+            Some(data)
+        } else {
+            None
+        };
+        match status {
+            0xff => {
+                let kind = cursor.byte()?;
+                let length = cursor.quantity()? as usize;
+                let data = cursor.bytes(length)?;
+                match kind {
+                    0x01..=0x05 => events.push((tick, TrackEvent::Text(kind, data))),
+                    0x51 if length == 3 => events.push((
+                        tick,
+                        TrackEvent::Tempo(u32::from_be_bytes([0, data[0], data[1], data[2]])),
+                    )),
+                    0x2f => break,
+                    _ => {}
+                }
+            }
+            0xf0 | 0xf7 => {
+                let length = cursor.quantity()? as usize;
+                cursor.bytes(length)?;
+            }
+            0x80..=0xef => {
+                running = Some(status);
+                let a = match first {
+                    Some(byte) => byte,
+                    None => cursor.byte()?,
+                };
+                let channel = status & 0x0f;
+                let message = match status & 0xf0 {
+                    0x80 => {
+                        cursor.byte()?;
+                        Some(Message::NoteOff { key: a & 0x7f })
+                    }
+                    0x90 => Some(Message::NoteOn {
+                        key: a & 0x7f,
+                        velocity: cursor.byte()? & 0x7f,
+                    }),
+                    0xa0 => {
+                        cursor.byte()?;
+                        None
+                    }
+                    0xb0 => Some(Message::Controller {
+                        number: a & 0x7f,
+                        value: cursor.byte()? & 0x7f,
+                    }),
+                    0xc0 => Some(Message::Program(a & 0x7f)),
+                    0xd0 => None,
+                    _ => {
+                        let high = cursor.byte()? as i16 & 0x7f;
+                        Some(Message::PitchBend(((high << 7) | (a as i16 & 0x7f)) - 8192))
+                    }
+                };
+                if let Some(message) = message {
+                    events.push((tick, TrackEvent::Channel(channel, message)));
+                }
+            }
+            // System common and real-time messages don't belong in files
+            _ => return decode_error("midi: unexpected system message"),
+        }
+    }
+    Ok(events)
+}
+
+fn text(data: &[u8]) -> String {
+    // Text events have no set encoding; most are ASCII or Latin-1
+    match std::str::from_utf8(data) {
+        Ok(text) => text.trim().to_owned(),
+        Err(_) => data
+            .iter()
+            .map(|&b| b as char)
+            .collect::<String>()
+            .trim()
+            .to_owned(),
+    }
+}
+
+impl Song {
+    fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 14 || &data[..4] != b"MThd" {
+            return unsupported_error("midi: missing MThd header");
+        }
+        let header_length = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+        if header_length < 6 {
+            return decode_error("midi: header too short");
+        }
+        let format = u16::from_be_bytes([data[8], data[9]]);
+        let division = u16::from_be_bytes([data[12], data[13]]);
+        if division == 0 {
+            return decode_error("midi: zero ticks per quarter note");
+        }
+
+        // Merge the tracks by tick; a format 2 file's tracks play in turn
+        let mut merged = Vec::new();
+        let mut offset = 0;
+        let mut rest = data.get(8 + header_length..).unwrap_or_default();
+        let mut tracks = 0;
+        while rest.len() >= 8 {
+            let length = u32::from_be_bytes(rest[4..8].try_into().unwrap()) as usize;
+            let body = rest.get(8..8 + length).unwrap_or(&rest[8..]);
+            if &rest[..4] == b"MTrk" {
+                let events = track_events(body)?;
+                let last = events.last().map_or(0, |(tick, _)| *tick);
+                merged.extend(
+                    events
+                        .into_iter()
+                        .map(|(tick, event)| (tick + offset, tracks, event)),
+                );
+                if format == 2 {
+                    offset += last;
+                }
+                tracks += 1;
+            }
+            rest = rest.get(8 + length..).unwrap_or_default();
+        }
+        if tracks == 0 {
+            return decode_error("midi: no tracks");
+        }
+        merged.sort_by_key(|(tick, track, _)| (*tick, *track));
+
+        // Ticks to frames through the tempo map, or at a fixed rate for
+        // SMPTE time
+        let seconds_per_tick = |tempo: u32| match division & 0x8000 {
+            0 => tempo as f64 / 1e6 / division as f64,
+            _ => {
+                let fps = match -((division >> 8) as i8) {
+                    29 => 29.97,
+                    fps => fps as f64,
+                };
+                1.0 / (fps * (division & 0xff).max(1) as f64)
+            }
+        };
+        let mut song = Song::default();
+        let mut tempo = 500_000;
+        let (mut last_tick, mut seconds) = (0, 0.0);
+        let mut comments = Vec::new();
+        for (tick, track, event) in merged {
+            seconds += (tick - last_tick) as f64 * seconds_per_tick(tempo);
+            last_tick = tick;
+            let frame = (seconds * SAMPLE_RATE as f64).round() as u64;
+            song.end = frame;
+            match event {
+                TrackEvent::Channel(channel, message) => song.events.push(Event {
+                    frame,
+                    channel,
+                    message,
+                }),
+                TrackEvent::Tempo(value) => tempo = value.max(1),
+                TrackEvent::Text(kind, data) => {
+                    let value = text(data);
+                    if value.is_empty() {
+                        continue;
+                    }
+                    let (key, name) = match kind {
+                        // The first track's name is the song's
+                        0x03 if track == 0 && tick == 0 => {
+                            (Some(StandardTagKey::TrackTitle), "TITLE")
+                        }
+                        0x02 => (Some(StandardTagKey::Copyright), "COPYRIGHT"),
+                        0x01 => {
+                            comments.push(value);
+                            continue;
+                        }
+                        0x05 => (Some(StandardTagKey::Lyrics), "LYRICS"),
+                        0x03 => (None, "TRACKNAME"),
+                        _ => (None, "INSTRUMENT"),
+                    };
+                    song.tags.push(Tag::new(key, name, Value::from(value)));
+                }
+            }
+        }
+        if !comments.is_empty() {
+            song.tags.push(Tag::new(
+                Some(StandardTagKey::Comment),
+                "COMMENT",
+                Value::from(comments.join("\n")),
+            ));
+        }
+        Ok(song)
+    }
+}
+
+pub struct MidiReader {
+    source: MediaSourceStream,
+    tracks: Vec<Track>,
+    metadata: MetadataLog,
+    song: Song,
+    /// Loaded on the first packet or seek
+    synth: Option<Synth>,
+    /// Next frame to render, and the index of the first event not yet played
+    frame: u64,
+    next_event: usize,
+    frames: u64,
+    buf: Vec<f32>,
+}
+
+impl QueryDescriptor for MidiReader {
+    fn query() -> &'static [Descriptor] {
+        &[support_format!(
+            "midi",
+            "Standard MIDI File",
+            &["mid", "midi", "kar"],
+            &["audio/midi", "audio/x-midi"],
+            &[b"MThd"]
+        )]
+    }
+
+    fn score(_context: &[u8]) -> u8 {
+        255
+    }
+}
+
+impl MidiReader {
+    /// Load the SoundFont and set up the synthesizer, unless that's done
+    fn load_synth(&mut self) -> Result<()> {
+        if self.synth.is_none() {
+            let font = load_soundfont()
+                .map_err(|e| Error::IoError(std::io::Error::other(format!("midi: {}", e))))?;
+            self.synth = Some(Synth::new(font, SAMPLE_RATE));
+        }
+        Ok(())
+    }
+
+    /// Render `frames` frames from where the reader is into `buf`, playing
+    /// events as their frames come up
+    fn render(&mut self, frames: u64) -> Result<()> {
+        self.load_synth()?;
+        let synth = self.synth.as_mut().unwrap();
+        self.buf.resize(frames as usize * 2, 0.0);
+        let end = self.frame + frames;
+        let mut done = 0;
+        while self.frame < end {
+            while let Some(event) = self.song.events.get(self.next_event)
+                && event.frame <= self.frame
+            {
+                synth.handle(event.channel, event.message, true);
+                self.next_event += 1;
+            }
+            let until = self
+                .song
+                .events
+                .get(self.next_event)
+                .map_or(end, |event| event.frame.min(end));
+            let count = (until - self.frame) as usize;
+            synth.render(&mut self.buf[done * 2..], count);
+            done += count;
+            self.frame = until;
+        }
+        Ok(())
+    }
+}
+
+impl FormatReader for MidiReader {
+    fn try_new(mut source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+        let song = Song::parse(&data)?;
+        let frames = song.end + TAIL_FRAMES;
+
+        let params = super::pcm_params(
+            SAMPLE_RATE,
+            Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+            frames,
+            PACKET_FRAMES,
+        );
+
+        let mut metadata = MetadataLog::default();
+        if !song.tags.is_empty() {
+            let mut builder = MetadataBuilder::new();
+            for tag in &song.tags {
+                builder.add_tag(tag.clone());
+            }
+            metadata.push(builder.metadata());
+        }
+
+        Ok(Self {
+            source,
+            tracks: vec![Track::new(0, params)],
+            metadata,
+            song,
+            synth: None,
+            frame: 0,
+            next_event: 0,
+            frames,
+            buf: Vec::new(),
+        })
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &[]
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Start over from the earliest note still held at the target, with
+    /// the controllers and programs set before it, and render up to the
+    /// target, so that held notes sound as they would have
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        let target = match to {
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => {
+                time.seconds * SAMPLE_RATE as u64 + (time.frac * SAMPLE_RATE as f64) as u64
+            }
+        }
+        .min(self.frames);
+
+        let before = self
+            .song
+            .events
+            .partition_point(|event| event.frame < target);
+        let mut held: Vec<(u8, u8, u64)> = Vec::new();
+        for event in &self.song.events[..before] {
+            match event.message {
+                Message::NoteOn { key, velocity } if velocity > 0 => {
+                    held.push((event.channel, key, event.frame))
+                }
+                Message::NoteOn { key, .. } | Message::NoteOff { key } => {
+                    held.retain(|&(channel, held, _)| (channel, held) != (event.channel, key))
+                }
+                _ => {}
+            }
+        }
+        let start = held
+            .iter()
+            .map(|&(_, _, frame)| frame)
+            .min()
+            .unwrap_or(target);
+        let first = self
+            .song
+            .events
+            .partition_point(|event| event.frame < start);
+
+        self.load_synth()?;
+        let synth = self.synth.as_mut().unwrap();
+        synth.reset();
+        for event in &self.song.events[..first] {
+            synth.handle(event.channel, event.message, false);
+        }
+        self.frame = start;
+        self.next_event = first;
+        if target > start {
+            self.render(target - start)?;
+        }
+        Ok(SeekedTo {
+            track_id: 0,
+            required_ts: target,
+            actual_ts: target,
+        })
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        if self.frame >= self.frames {
+            return Err(Error::IoError(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let ts = self.frame;
+        let frames = PACKET_FRAMES.min(self.frames - self.frame);
+        self.render(frames)?;
+        Ok(super::pcm_packet(
+            ts,
+            frames,
+            &self.buf[..frames as usize * 2],
+        ))
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.source
+    }
+}
@@ -0,0 +1,468 @@
+//! SoundFont 2 banks: the samples, and each preset flattened into regions
+//! that say which sample plays for a key and velocity, and how.
+
+use anyhow::{Context, Result, bail};
+use std::ops::RangeInclusive;
+
+/// A loaded `.sf2` bank
+pub struct SoundFont {
+    /// Every sample in the bank, as one run of mono audio
+    pub samples: Vec<f32>,
+    pub presets: Vec<Preset>,
+}
+
+pub struct Preset {
+    pub bank: u16,
+    pub program: u16,
+    pub regions: Vec<Region>,
+}
+
+/// A preset zone layered over an instrument zone, with its generators
+/// resolved into what the synthesizer needs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Region {
+    pub keys: RangeInclusive<u8>,
+    pub velocities: RangeInclusive<u8>,
+    /// Offsets into `SoundFont::samples`
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub looping: Looping,
+    pub sample_rate: u32,
+    /// The key at which the sample plays at its own pitch
+    pub root_key: u8,
+    /// Cents of pitch change per key
+    pub scale_tuning: i32,
+    /// Cents added to the pitch, including the sample's own correction
+    pub tune: i32,
+    /// Centibels
+    pub attenuation: f32,
+    /// -0.5 (left) to 0.5 (right)
+    pub pan: f32,
+    pub envelope: Envelope,
+    /// Notes of the same class on a channel cut each other off, like open
+    /// and closed hi-hats; 0 for none
+    pub exclusive_class: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Looping {
+    None,
+    Continuous,
+    /// Loop while the key is held, then play out the rest of the sample
+    UntilRelease,
+}
+
+/// Volume envelope: times in seconds, sustain in centibels below full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Envelope {
+    pub delay: f32,
+    pub attack: f32,
+    pub hold: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+// Generator numbers from the SoundFont 2.04 specification
+const START_OFFSET: usize = 0;
+const END_OFFSET: usize = 1;
+const LOOP_START_OFFSET: usize = 2;
+const LOOP_END_OFFSET: usize = 3;
+const START_COARSE_OFFSET: usize = 4;
+const END_COARSE_OFFSET: usize = 12;
+const PAN: usize = 17;
+const DELAY_VOL_ENV: usize = 33;
+const ATTACK_VOL_ENV: usize = 34;
+const HOLD_VOL_ENV: usize = 35;
+const DECAY_VOL_ENV: usize = 36;
+const SUSTAIN_VOL_ENV: usize = 37;
+const RELEASE_VOL_ENV: usize = 38;
+const INSTRUMENT: usize = 41;
+const KEY_RANGE: usize = 43;
+const VEL_RANGE: usize = 44;
+const LOOP_START_COARSE_OFFSET: usize = 45;
+const KEYNUM: usize = 46;
+const VELOCITY: usize = 47;
+const INITIAL_ATTENUATION: usize = 48;
+const LOOP_END_COARSE_OFFSET: usize = 50;
+const COARSE_TUNE: usize = 51;
+const FINE_TUNE: usize = 52;
+const SAMPLE_ID: usize = 53;
+const SAMPLE_MODES: usize = 54;
+const SCALE_TUNING: usize = 56;
+const EXCLUSIVE_CLASS: usize = 57;
+const OVERRIDING_ROOT_KEY: usize = 58;
+const GENERATORS: usize = 61;
+
+/// Generators a preset zone sets outright rather than adding to the
+/// instrument's, or may not set at all
+const NOT_ADDITIVE: &[usize] = &[
+    START_OFFSET,
+    END_OFFSET,
+    LOOP_START_OFFSET,
+    LOOP_END_OFFSET,
+    START_COARSE_OFFSET,
+    END_COARSE_OFFSET,
+    INSTRUMENT,
+    KEY_RANGE,
+    VEL_RANGE,
+    LOOP_START_COARSE_OFFSET,
+    KEYNUM,
+    VELOCITY,
+    LOOP_END_COARSE_OFFSET,
+    SAMPLE_ID,
+    SAMPLE_MODES,
+    EXCLUSIVE_CLASS,
+    OVERRIDING_ROOT_KEY,
+];
+
+/// Generator amounts, raw: ranges are two bytes, everything else an `i16`
+type Generators = [u16; GENERATORS];
+
+fn instrument_defaults() -> Generators {
+    let mut generators = [0; GENERATORS];
+    for envelope in [
+        DELAY_VOL_ENV,
+        ATTACK_VOL_ENV,
+        HOLD_VOL_ENV,
+        DECAY_VOL_ENV,
+        RELEASE_VOL_ENV,
+    ] {
+        generators[envelope] = -12000i16 as u16;
+    }
+    generators[KEY_RANGE] = 127 << 8;
+    generators[VEL_RANGE] = 127 << 8;
+    generators[KEYNUM] = u16::MAX;
+    generators[VELOCITY] = u16::MAX;
+    generators[SCALE_TUNING] = 100;
+    generators[OVERRIDING_ROOT_KEY] = u16::MAX;
+    generators
+}
+
+fn preset_defaults() -> Generators {
+    let mut generators = [0; GENERATORS];
+    generators[KEY_RANGE] = 127 << 8;
+    generators[VEL_RANGE] = 127 << 8;
+    generators
+}
+
+fn signed(generators: &Generators, generator: usize) -> i32 {
+    generators[generator] as i16 as i32
+}
+
+fn range(generators: &Generators, generator: usize) -> RangeInclusive<u8> {
+    let [low, high] = generators[generator].to_le_bytes();
+    low..=high
+}
+
+fn intersect(a: &RangeInclusive<u8>, b: &RangeInclusive<u8>) -> RangeInclusive<u8> {
+    *a.start().max(b.start())..=*a.end().min(b.end())
+}
+
+/// Timecents to seconds; the specification's minimum means "instantly"
+fn seconds(timecents: i32) -> f32 {
+    if timecents <= -12000 {
+        0.0
+    } else {
+        2f32.powf(timecents as f32 / 1200.0)
+    }
+}
+
+/// A zone's generators, in file order
+struct Zone(Vec<(u16, u16)>);
+
+impl Zone {
+    /// The generator that ends a non-global zone, if this zone has one
+    fn last_is(&self, generator: usize) -> bool {
+        self.0
+            .last()
+            .is_some_and(|&(oper, _)| oper as usize == generator)
+    }
+
+    fn apply(&self, generators: &mut Generators) {
+        for &(oper, amount) in &self.0 {
+            if let Some(slot) = generators.get_mut(oper as usize) {
+                *slot = amount;
+            }
+        }
+    }
+}
+
+struct SampleHeader {
+    start: u32,
+    end: u32,
+    loop_start: u32,
+    loop_end: u32,
+    sample_rate: u32,
+    original_pitch: u8,
+    pitch_correction: i8,
+}
+
+/// The chunks of a RIFF list, as (id, body)
+fn chunks(mut data: &[u8]) -> Result<Vec<([u8; 4], &[u8])>> {
+    let mut chunks = Vec::new();
+    while data.len() >= 8 {
+        let id: [u8; 4] = data[..4].try_into().unwrap();
+        let size = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        let body = data
+            .get(8..8 + size)
+            .with_context(|| format!("{} chunk runs past the end", String::from_utf8_lossy(&id)))?;
+        chunks.push((id, body));
+        // Chunks are padded to an even length
+        data = data.get(8 + size + size % 2..).unwrap_or_default();
+    }
+    Ok(chunks)
+}
+
+/// The fixed-size records of a `pdta` sub-chunk
+fn records<'a>(pdta: &[([u8; 4], &'a [u8])], id: &[u8; 4], size: usize) -> Result<Vec<&'a [u8]>> {
+    let (_, body) = pdta
+        .iter()
+        .find(|(chunk, _)| chunk == id)
+        .with_context(|| format!("no {} chunk", String::from_utf8_lossy(id)))?;
+    if body.len() % size != 0 {
+        bail!(
+            "{} chunk is not a whole number of records",
+            String::from_utf8_lossy(id)
+        );
+    }
+    Ok(body.chunks_exact(size).collect())
+}
+
+fn u16_at(record: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([record[offset], record[offset + 1]])
+}
+
+fn u32_at(record: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(record[offset..offset + 4].try_into().unwrap())
+}
+
+/// The zones of each preset or instrument: `headers` are their records,
+/// whose bag index sits at `bag_offset`, ending with the terminal record
+fn zones(
+    headers: &[&[u8]],
+    bag_offset: usize,
+    bags: &[&[u8]],
+    generators: &[&[u8]],
+) -> Result<Vec<Vec<Zone>>> {
+    let bag = |index: usize| -> Result<usize> {
+        Ok(u16_at(bags.get(index).context("bag index out of range")?, 0) as usize)
+    };
+    headers
+        .windows(2)
+        .map(|pair| {
+            let (first, last) = (
+                u16_at(pair[0], bag_offset) as usize,
+                u16_at(pair[1], bag_offset) as usize,
+            );
+            (first..last.max(first))
+                .map(|index| {
+                    let range = bag(index)?..bag(index + 1)?;
+                    let generators = generators
+                        .get(range)
+                        .context("generator index out of range")?;
+                    Ok(Zone(
+                        generators
+                            .iter()
+                            .map(|record| (u16_at(record, 0), u16_at(record, 2)))
+                            .collect(),
+                    ))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+impl SoundFont {
+    pub fn parse(data: &[u8]) -> Result<Self> {
+        if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"sfbk" {
+            bail!("not a SoundFont 2 file");
+        }
+        let mut samples = None;
+        let mut pdta = None;
+        for (id, body) in chunks(&data[12..])? {
+            if &id != b"LIST" || body.len() < 4 {
+                continue;
+            }
+            let list = chunks(&body[4..])?;
+            match &body[..4] {
+                b"sdta" => {
+                    samples = list
+                        .into_iter()
+                        .find(|(id, _)| id == b"smpl")
+                        .map(|(_, smpl)| {
+                            smpl.chunks_exact(2)
+                                .map(|s| i16::from_le_bytes([s[0], s[1]]) as f32 / 32768.0)
+                                .collect::<Vec<f32>>()
+                        })
+                }
+                b"pdta" => pdta = Some(list),
+                _ => {}
+            }
+        }
+        let samples = samples.context("no sample data")?;
+        let pdta = pdta.context("no preset data")?;
+
+        let preset_headers = records(&pdta, b"phdr", 38)?;
+        let preset_zones = zones(
+            &preset_headers,
+            24,
+            &records(&pdta, b"pbag", 4)?,
+            &records(&pdta, b"pgen", 4)?,
+        )?;
+        let instrument_zones = zones(
+            &records(&pdta, b"inst", 22)?,
+            20,
+            &records(&pdta, b"ibag", 4)?,
+            &records(&pdta, b"igen", 4)?,
+        )?;
+        let sample_headers: Vec<SampleHeader> = records(&pdta, b"shdr", 46)?
+            .into_iter()
+            .map(|record| SampleHeader {
+                start: u32_at(record, 20),
+                end: u32_at(record, 24),
+                loop_start: u32_at(record, 28),
+                loop_end: u32_at(record, 32),
+                sample_rate: u32_at(record, 36),
+                original_pitch: record[40],
+                pitch_correction: record[41] as i8,
+            })
+            .collect();
+
+        let presets = preset_headers
+            .iter()
+            .zip(preset_zones)
+            .map(|(header, zones)| Preset {
+                program: u16_at(header, 20),
+                bank: u16_at(header, 22),
+                regions: regions(&zones, &instrument_zones, &sample_headers, samples.len()),
+            })
+            .collect();
+        Ok(Self { samples, presets })
+    }
+
+    /// The preset for a bank and program, falling back to the same program
+    /// in bank 0, then to the first preset of the bank or of the font
+    pub fn preset(&self, bank: u16, program: u16) -> Option<&Preset> {
+        let find = |bank, program| {
+            self.presets
+                .iter()
+                .find(|preset| preset.bank == bank && preset.program == program)
+        };
+        find(bank, program)
+            .or_else(|| find(if bank == 128 { 128 } else { 0 }, program))
+            .or_else(|| find(bank, 0))
+            .or_else(|| self.presets.iter().find(|preset| preset.bank == bank))
+            .or_else(|| self.presets.first())
+    }
+}
+
+/// Layer a preset's zones over the zones of the instruments they name
+fn regions(
+    preset: &[Zone],
+    instruments: &[Vec<Zone>],
+    samples: &[SampleHeader],
+    sample_count: usize,
+) -> Vec<Region> {
+    let (global, preset) = match preset.split_first() {
+        Some((first, rest)) if !first.last_is(INSTRUMENT) => (Some(first), rest),
+        _ => (None, preset),
+    };
+    let mut regions = Vec::new();
+    for zone in preset.iter().filter(|zone| zone.last_is(INSTRUMENT)) {
+        let mut preset_generators = preset_defaults();
+        if let Some(global) = global {
+            global.apply(&mut preset_generators);
+        }
+        zone.apply(&mut preset_generators);
+        let Some(instrument) = instruments.get(preset_generators[INSTRUMENT] as usize) else {
+            continue;
+        };
+
+        let (global, instrument) = match instrument.split_first() {
+            Some((first, rest)) if !first.last_is(SAMPLE_ID) => (Some(first), rest),
+            _ => (None, &instrument[..]),
+        };
+        for zone in instrument.iter().filter(|zone| zone.last_is(SAMPLE_ID)) {
+            let mut generators = instrument_defaults();
+            if let Some(global) = global {
+                global.apply(&mut generators);
+            }
+            zone.apply(&mut generators);
+            for (generator, amount) in preset_generators.iter().enumerate() {
+                if !NOT_ADDITIVE.contains(&generator) {
+                    generators[generator] =
+                        (generators[generator] as i16).saturating_add(*amount as i16) as u16;
+                }
+            }
+            let Some(sample) = samples.get(generators[SAMPLE_ID] as usize) else {
+                continue;
+            };
+            if let Some(region) = region(&generators, &preset_generators, sample, sample_count) {
+                regions.push(region);
+            }
+        }
+    }
+    regions
+}
+
+fn region(
+    generators: &Generators,
+    preset: &Generators,
+    sample: &SampleHeader,
+    sample_count: usize,
+) -> Option<Region> {
+    let offset = |base: u32, fine: usize, coarse: usize| -> usize {
+        let offset = signed(generators, fine) + signed(generators, coarse) * 32768;
+        (base as i64 + offset as i64).clamp(0, sample_count as i64) as usize
+    };
+    let start = offset(sample.start, START_OFFSET, START_COARSE_OFFSET);
+    let end = offset(sample.end, END_OFFSET, END_COARSE_OFFSET);
+    if start >= end || sample.sample_rate == 0 {
+        return None;
+    }
+    let loop_start = offset(
+        sample.loop_start,
+        LOOP_START_OFFSET,
+        LOOP_START_COARSE_OFFSET,
+    );
+    let loop_end = offset(sample.loop_end, LOOP_END_OFFSET, LOOP_END_COARSE_OFFSET);
+    let looping = match generators[SAMPLE_MODES] & 3 {
+        _ if loop_start >= loop_end || loop_end > end => Looping::None,
+        1 => Looping::Continuous,
+        3 => Looping::UntilRelease,
+        _ => Looping::None,
+    };
+    let root_key = match signed(generators, OVERRIDING_ROOT_KEY) {
+        key @ 0..=127 => key as u8,
+        _ => sample.original_pitch.min(127),
+    };
+    Some(Region {
+        keys: intersect(&range(generators, KEY_RANGE), &range(preset, KEY_RANGE)),
+        velocities: intersect(&range(generators, VEL_RANGE), &range(preset, VEL_RANGE)),
+        start,
+        end,
+        loop_start,
+        loop_end,
+        looping,
+        sample_rate: sample.sample_rate,
+        root_key,
+        scale_tuning: signed(generators, SCALE_TUNING),
+        tune: signed(generators, COARSE_TUNE) * 100
+            + signed(generators, FINE_TUNE)
+            + sample.pitch_correction as i32,
+        attenuation: signed(generators, INITIAL_ATTENUATION).max(0) as f32,
+        pan: (signed(generators, PAN) as f32 / 1000.0).clamp(-0.5, 0.5),
+        envelope: Envelope {
+            delay: seconds(signed(generators, DELAY_VOL_ENV)),
+            attack: seconds(signed(generators, ATTACK_VOL_ENV)),
+            hold: seconds(signed(generators, HOLD_VOL_ENV)),
+            decay: seconds(signed(generators, DECAY_VOL_ENV)),
+            sustain: signed(generators, SUSTAIN_VOL_ENV).clamp(0, 1440) as f32,
+            release: seconds(signed(generators, RELEASE_VOL_ENV)),
+        },
+        exclusive_class: generators[EXCLUSIVE_CLASS],
+    })
+}
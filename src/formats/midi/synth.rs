@@ -0,0 +1,397 @@
+//! A General MIDI synthesizer over a SoundFont: sample playback with pitch,
+//! volume envelope, velocity, pan and the common channel controllers. No
+//! filters, modulators, LFOs or effects.
+
+use std::f32::consts::FRAC_PI_2;
+use std::sync::Arc;
+
+use super::soundfont::{Looping, Region, SoundFont};
+
+/// Voices beyond this steal the oldest
+const MAX_VOICES: usize = 128;
+
+/// Envelopes and controller changes move in steps this many frames long
+const STEP: usize = 64;
+
+/// Headroom for several notes at once
+const MASTER_GAIN: f32 = 0.5;
+
+/// Attenuation at which a releasing voice is inaudible, in centibels
+const SILENT: f32 = 960.0;
+
+/// The percussion channel, which plays from bank 128
+const DRUMS: u8 = 9;
+
+/// A channel message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Message {
+    NoteOn {
+        key: u8,
+        velocity: u8,
+    },
+    NoteOff {
+        key: u8,
+    },
+    Controller {
+        number: u8,
+        value: u8,
+    },
+    Program(u8),
+    /// -8192 to 8191
+    PitchBend(i16),
+}
+
+#[derive(Clone)]
+struct Channel {
+    bank: u16,
+    program: u8,
+    volume: u8,
+    expression: u8,
+    pan: u8,
+    bend: i16,
+    /// Semitones either way
+    bend_range: u8,
+    sustain: bool,
+    /// Registered parameter selected by controllers 101 and 100
+    rpn: (u8, u8),
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Self {
+            bank: 0,
+            program: 0,
+            volume: 100,
+            expression: 127,
+            pan: 64,
+            bend: 0,
+            bend_range: 2,
+            sustain: false,
+            rpn: (127, 127),
+        }
+    }
+}
+
+impl Channel {
+    fn gain(&self) -> f32 {
+        let volume = self.volume as f32 / 127.0;
+        let expression = self.expression as f32 / 127.0;
+        volume * volume * expression * expression
+    }
+
+    fn bend_cents(&self) -> f32 {
+        self.bend as f32 / 8192.0 * self.bend_range as f32 * 100.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Delay,
+    Attack,
+    Hold,
+    Decay,
+    Sustain,
+    Release,
+}
+
+struct Voice {
+    channel: u8,
+    key: u8,
+    region: Region,
+    /// Position in `SoundFont::samples`
+    position: f64,
+    /// Pitch relative to the sample's own, less pitch bend, in cents
+    cents: f32,
+    velocity_gain: f32,
+    stage: Stage,
+    /// Seconds into the stage
+    elapsed: f32,
+    /// Amplitude during the attack, 0 to 1
+    level: f32,
+    /// Attenuation from the decay onwards, in centibels
+    attenuation: f32,
+    /// Released while the sustain pedal was down
+    sustained: bool,
+    finished: bool,
+}
+
+impl Voice {
+    fn release(&mut self) {
+        if self.stage != Stage::Release {
+            // An unfinished attack releases from the level it got to
+            if matches!(self.stage, Stage::Delay | Stage::Attack) {
+                self.attenuation = -200.0 * self.level.max(1e-5).log10();
+            }
+            self.stage = Stage::Release;
+            self.elapsed = 0.0;
+        }
+    }
+
+    /// Move the envelope on by `seconds` and return its amplitude
+    fn envelope(&mut self, seconds: f32) -> f32 {
+        let envelope = self.region.envelope;
+        self.elapsed += seconds;
+        loop {
+            let length = match self.stage {
+                Stage::Delay => envelope.delay,
+                Stage::Attack => envelope.attack,
+                Stage::Hold => envelope.hold,
+                Stage::Decay => envelope.decay,
+                Stage::Sustain => f32::INFINITY,
+                Stage::Release => envelope.release,
+            };
+            if self.elapsed < length {
+                break;
+            }
+            self.elapsed -= length;
+            self.stage = match self.stage {
+                Stage::Delay => Stage::Attack,
+                Stage::Attack => {
+                    self.level = 1.0;
+                    self.attenuation = 0.0;
+                    Stage::Hold
+                }
+                Stage::Hold => Stage::Decay,
+                Stage::Decay => {
+                    self.attenuation = envelope.sustain;
+                    Stage::Sustain
+                }
+                Stage::Sustain => Stage::Sustain,
+                Stage::Release => {
+                    self.finished = true;
+                    return 0.0;
+                }
+            };
+        }
+        // Attack is linear in amplitude; decay and release are linear in
+        // decibels, taking their whole time to fall 96 dB
+        match self.stage {
+            Stage::Delay => 0.0,
+            Stage::Attack => {
+                self.level = self.elapsed / envelope.attack;
+                self.level
+            }
+            Stage::Hold => 1.0,
+            Stage::Decay => {
+                let fall = SILENT * self.elapsed / envelope.decay;
+                centibels(fall.min(envelope.sustain))
+            }
+            Stage::Sustain => centibels(envelope.sustain),
+            Stage::Release => {
+                let attenuation = self.attenuation + SILENT * self.elapsed / envelope.release;
+                if attenuation >= SILENT {
+                    self.finished = true;
+                }
+                centibels(attenuation)
+            }
+        }
+    }
+}
+
+fn centibels(attenuation: f32) -> f32 {
+    10f32.powf(-attenuation / 200.0)
+}
+
+pub struct Synth {
+    font: Arc<SoundFont>,
+    sample_rate: u32,
+    channels: Vec<Channel>,
+    voices: Vec<Voice>,
+}
+
+impl Synth {
+    pub fn new(font: Arc<SoundFont>, sample_rate: u32) -> Self {
+        Self {
+            font,
+            sample_rate,
+            channels: vec![Channel::default(); 16],
+            voices: Vec::new(),
+        }
+    }
+
+    /// Silence every voice and put the channels back as they start
+    pub fn reset(&mut self) {
+        self.voices.clear();
+        self.channels.fill(Channel::default());
+    }
+
+    /// Act on a message for `channel`. `notes` false applies only its effect
+    /// on the channel, for catching up before a seek.
+    pub fn handle(&mut self, channel: u8, message: Message, notes: bool) {
+        let index = channel as usize & 15;
+        match message {
+            Message::NoteOn { key, velocity: 0 } | Message::NoteOff { key } => {
+                let sustain = self.channels[index].sustain;
+                for voice in &mut self.voices {
+                    if voice.channel == channel && voice.key == key {
+                        if sustain {
+                            voice.sustained = true;
+                        } else {
+                            voice.release();
+                        }
+                    }
+                }
+            }
+            Message::NoteOn { key, velocity } if notes => self.note_on(channel, key, velocity),
+            Message::NoteOn { .. } => {}
+            Message::Program(program) => self.channels[index].program = program,
+            Message::PitchBend(bend) => self.channels[index].bend = bend,
+            Message::Controller { number, value } => self.controller(channel, number, value),
+        }
+    }
+
+    fn controller(&mut self, channel: u8, number: u8, value: u8) {
+        let state = &mut self.channels[channel as usize & 15];
+        match number {
+            0 => state.bank = value.into(),
+            6 if state.rpn == (0, 0) => state.bend_range = value,
+            7 => state.volume = value,
+            10 => state.pan = value,
+            11 => state.expression = value,
+            64 => {
+                state.sustain = value >= 64;
+                if !state.sustain {
+                    for voice in &mut self.voices {
+                        if voice.channel == channel && voice.sustained {
+                            voice.release();
+                        }
+                    }
+                }
+            }
+            100 => state.rpn.1 = value,
+            101 => state.rpn.0 = value,
+            // All sound off
+            120 => self.voices.retain(|voice| voice.channel != channel),
+            121 => {
+                *state = Channel {
+                    bank: state.bank,
+                    program: state.program,
+                    volume: state.volume,
+                    pan: state.pan,
+                    ..Channel::default()
+                };
+            }
+            // All notes off
+            123 => {
+                for voice in &mut self.voices {
+                    if voice.channel == channel {
+                        voice.release();
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn note_on(&mut self, channel: u8, key: u8, velocity: u8) {
+        let state = &self.channels[channel as usize & 15];
+        let bank = if channel == DRUMS { 128 } else { state.bank };
+        let Some(preset) = self.font.preset(bank, state.program.into()) else {
+            return;
+        };
+        let regions: Vec<Region> = preset
+            .regions
+            .iter()
+            .filter(|region| region.keys.contains(&key) && region.velocities.contains(&velocity))
+            .cloned()
+            .collect();
+        for region in regions {
+            if region.exclusive_class != 0 {
+                for voice in &mut self.voices {
+                    if voice.channel == channel
+                        && voice.region.exclusive_class == region.exclusive_class
+                    {
+                        voice.finished = true;
+                    }
+                }
+            }
+            if self.voices.len() >= MAX_VOICES {
+                // Releasing voices go first, then the oldest
+                let oldest = self
+                    .voices
+                    .iter()
+                    .position(|voice| voice.stage == Stage::Release)
+                    .unwrap_or(0);
+                self.voices.remove(oldest);
+            }
+            let cents = (key as f32 - region.root_key as f32) * region.scale_tuning as f32
+                + region.tune as f32;
+            let velocity = velocity as f32 / 127.0;
+            self.voices.push(Voice {
+                channel,
+                key,
+                position: region.start as f64,
+                cents,
+                velocity_gain: velocity * velocity * centibels(region.attenuation),
+                region,
+                stage: Stage::Delay,
+                elapsed: 0.0,
+                level: 0.0,
+                attenuation: 0.0,
+                sustained: false,
+                finished: false,
+            });
+        }
+    }
+
+    /// Mix `frames` frames of interleaved stereo into `out`, which must hold
+    /// at least twice as many samples
+    pub fn render(&mut self, out: &mut [f32], frames: usize) {
+        out[..frames * 2].fill(0.0);
+        let samples = &self.font.samples;
+        for voice in &mut self.voices {
+            let channel = &self.channels[voice.channel as usize & 15];
+            let ratio = 2f64.powf((voice.cents + channel.bend_cents()) as f64 / 1200.0)
+                * voice.region.sample_rate as f64
+                / self.sample_rate as f64;
+            let pan = (voice.region.pan + (channel.pan as f32 - 64.0) / 127.0).clamp(-0.5, 0.5);
+            let angle = (pan + 0.5) * FRAC_PI_2;
+            let gain = voice.velocity_gain * channel.gain() * MASTER_GAIN;
+            let (left, right) = (gain * angle.cos(), gain * angle.sin());
+
+            let Region {
+                end,
+                loop_start,
+                loop_end,
+                ..
+            } = voice.region;
+            let looping = match voice.region.looping {
+                Looping::Continuous => true,
+                Looping::UntilRelease => voice.stage != Stage::Release,
+                Looping::None => false,
+            };
+            let mut amplitude = voice.envelope(0.0);
+            for block in out[..frames * 2].chunks_mut(STEP * 2) {
+                let count = block.len() / 2;
+                let target = voice.envelope(count as f32 / self.sample_rate as f32);
+                let slope = (target - amplitude) / count as f32;
+                for frame in block.chunks_exact_mut(2) {
+                    if looping && voice.position >= loop_end as f64 {
+                        voice.position -= (loop_end - loop_start) as f64;
+                    }
+                    let index = voice.position as usize;
+                    if !looping && index + 1 >= end {
+                        voice.finished = true;
+                        break;
+                    }
+                    let fraction = (voice.position - index as f64) as f32;
+                    let next = if looping && index + 1 >= loop_end {
+                        samples[loop_start]
+                    } else {
+                        samples[index + 1]
+                    };
+                    let sample = samples[index] + (next - samples[index]) * fraction;
+                    frame[0] += sample * amplitude * left;
+                    frame[1] += sample * amplitude * right;
+                    amplitude += slope;
+                    voice.position += ratio;
+                }
+                amplitude = target;
+                if voice.finished {
+                    break;
+                }
+            }
+        }
+        self.voices.retain(|voice| !voice.finished);
+    }
+}
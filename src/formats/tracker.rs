@@ -0,0 +1,299 @@
+//! Tracker modules — ProTracker MOD, Scream Tracker 3 S3M, FastTracker 2
+//! XM and Impulse Tracker IT — rendered to PCM as they are read.
+//!
+//! Opening a module plays it through once without mixing to find where it
+//! ends: at the end of the order list, or at the first row it comes back
+//! to, as songs written to loop forever do. Seeks go by order position and
+//! row, landing on the start of the row the time falls in. The title is the
+//! module's, and the comment is its song message with the instrument and
+//! sample names, where trackers' users have always left their texts.
+//!
+//! Volume envelopes are played; filters, tremolo, tremor, panning slides
+//! and envelopes, and Impulse Tracker's new note actions are not.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use symphonia::core::audio::Channels;
+use symphonia::core::errors::{Error, Result, unsupported_error};
+use symphonia::core::formats::prelude::*;
+use symphonia::core::io::{MediaSourceStream, ReadBytes, SeekBuffered};
+use symphonia::core::meta::{Metadata, MetadataBuilder, MetadataLog, StandardTagKey, Tag, Value};
+use symphonia::core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia::core::support_format;
+
+mod it;
+mod module;
+mod protracker;
+mod render;
+mod s3m;
+mod xm;
+
+use module::Module;
+use render::Player;
+
+/// Rate modules are mixed at
+const SAMPLE_RATE: u32 = 44_100;
+
+/// Frames per packet
+const PACKET_FRAMES: u64 = 1024;
+
+/// Songs are cut off here if they haven't ended or come round again
+const MAX_FRAMES: u64 = 2 * 60 * 60 * SAMPLE_RATE as u64;
+
+/// The title, and the message and names of instruments and samples as the
+/// comment
+fn tags(module: &Module) -> Vec<Tag> {
+    let mut tags = Vec::new();
+    if !module.title.trim().is_empty() {
+        tags.push(Tag::new(
+            Some(StandardTagKey::TrackTitle),
+            "TITLE",
+            Value::from(module.title.trim()),
+        ));
+    }
+    let names = module
+        .instruments
+        .iter()
+        .map(|instrument| &instrument.name)
+        .chain(module.samples.iter().map(|sample| &sample.name))
+        .filter(|name| !name.trim().is_empty());
+    let lines: Vec<&str> = std::iter::once(&module.message)
+        .filter(|message| !message.is_empty())
+        .chain(names)
+        .map(String::as_str)
+        .collect();
+    if !lines.is_empty() {
+        tags.push(Tag::new(
+            Some(StandardTagKey::Comment),
+            "COMMENT",
+            Value::from(lines.join("\n")),
+        ));
+    }
+    tags
+}
+
+/// Play the song through without mixing, returning its length and where
+/// each order position first starts
+fn survey(mut player: Player) -> (u64, Vec<(u64, Player)>) {
+    let mut visited: Vec<Vec<bool>> = Vec::new();
+    let mut starts: Vec<(u64, Player)> = Vec::new();
+    let mut frame = 0;
+    while !player.ended() && frame < MAX_FRAMES {
+        if player.at_row_start() {
+            let (order, row) = player.position();
+            if visited.len() <= order {
+                visited.resize(order + 1, Vec::new());
+            }
+            let rows = &mut visited[order];
+            if rows.len() <= row {
+                rows.resize(row + 1, false);
+            }
+            if rows[row] {
+                break;
+            }
+            rows[row] = true;
+            if starts
+                .last()
+                .is_none_or(|(_, last)| last.position().0 != order)
+            {
+                starts.push((frame, player.clone()));
+            }
+        }
+        frame += player.tick() as u64;
+        // The rows a pattern loop goes back over are played again
+        if let Some(from) = player.take_looped_back() {
+            let (order, _) = player.position();
+            if let Some(rows) = visited.get_mut(order) {
+                rows.iter_mut().skip(from).for_each(|row| *row = false);
+            }
+        }
+    }
+    (frame.min(MAX_FRAMES), starts)
+}
+
+pub struct TrackerReader {
+    source: MediaSourceStream,
+    tracks: Vec<Track>,
+    metadata: MetadataLog,
+    player: Player,
+    /// Frame each order position starts at, and the player there
+    starts: Vec<(u64, Player)>,
+    frame: u64,
+    frames: u64,
+    buf: Vec<f32>,
+}
+
+impl QueryDescriptor for TrackerReader {
+    fn query() -> &'static [Descriptor] {
+        &[
+            support_format!(
+                "mod",
+                "ProTracker Module",
+                &["mod"],
+                &["audio/mod", "audio/x-mod"],
+                protracker::SIGNATURES
+            ),
+            support_format!(
+                "s3m",
+                "Scream Tracker 3 Module",
+                &["s3m"],
+                &["audio/s3m", "audio/x-s3m"],
+                &[b"SCRM"]
+            ),
+            // Markers can be no longer than 16 bytes
+            support_format!(
+                "xm",
+                "FastTracker 2 Extended Module",
+                &["xm"],
+                &["audio/xm", "audio/x-xm"],
+                &[b"Extended Module:"]
+            ),
+            support_format!(
+                "it",
+                "Impulse Tracker Module",
+                &["it"],
+                &["audio/it", "audio/x-it"],
+                &[it::SIGNATURE]
+            ),
+        ]
+    }
+
+    fn score(_context: &[u8]) -> u8 {
+        255
+    }
+}
+
+impl FormatReader for TrackerReader {
+    fn try_new(mut source: MediaSourceStream, _options: &FormatOptions) -> Result<Self> {
+        // The probe leaves the stream at the signature, which MOD and S3M
+        // files have some way in
+        let found = source.pos();
+        let mut marker = [0; 4];
+        source.read_exact(&mut marker)?;
+        let offset = match &marker {
+            b"SCRM" => s3m::SIGNATURE_OFFSET,
+            b"IMPM" | b"Exte" => 0,
+            _ => protracker::SIGNATURE_OFFSET,
+        };
+        let Some(start) = found.checked_sub(offset) else {
+            return unsupported_error("tracker: signature too early in the file");
+        };
+        if source.seek_buffered(start) != start {
+            source.seek(SeekFrom::Start(start))?;
+        }
+        let mut data = Vec::new();
+        source.read_to_end(&mut data)?;
+        let module = match &marker {
+            b"SCRM" => s3m::load(&data)?,
+            b"IMPM" => it::load(&data)?,
+            b"Exte" => xm::load(&data)?,
+            _ => protracker::load(&data)?,
+        };
+
+        let mut metadata = MetadataLog::default();
+        let tags = tags(&module);
+        if !tags.is_empty() {
+            let mut builder = MetadataBuilder::new();
+            for tag in tags {
+                builder.add_tag(tag);
+            }
+            metadata.push(builder.metadata());
+        }
+
+        let player = Player::new(Arc::new(module), SAMPLE_RATE);
+        let (frames, starts) = survey(player.clone());
+        let params = super::pcm_params(
+            SAMPLE_RATE,
+            Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+            frames,
+            PACKET_FRAMES,
+        );
+        Ok(Self {
+            source,
+            tracks: vec![Track::new(0, params)],
+            metadata,
+            player,
+            starts,
+            frame: 0,
+            frames,
+            buf: Vec::new(),
+        })
+    }
+
+    fn cues(&self) -> &[Cue] {
+        &[]
+    }
+
+    fn metadata(&mut self) -> Metadata<'_> {
+        self.metadata.metadata()
+    }
+
+    fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Go to the start of the order position the target is in, then on
+    /// row by row to the start of the row it is in
+    fn seek(&mut self, _mode: SeekMode, to: SeekTo) -> Result<SeekedTo> {
+        let target = match to {
+            SeekTo::TimeStamp { ts, .. } => ts,
+            SeekTo::Time { time, .. } => {
+                time.seconds * SAMPLE_RATE as u64 + (time.frac * SAMPLE_RATE as f64) as u64
+            }
+        }
+        .min(self.frames);
+
+        let index = self.starts.partition_point(|(frame, _)| *frame <= target);
+        let Some((mut frame, mut player)) = index
+            .checked_sub(1)
+            .and_then(|index| self.starts.get(index))
+            .cloned()
+        else {
+            // The song has no rows
+            self.frame = self.frames;
+            return Ok(SeekedTo {
+                track_id: 0,
+                required_ts: target,
+                actual_ts: self.frames,
+            });
+        };
+        loop {
+            let mut next = player.clone();
+            let mut end = frame;
+            loop {
+                end += next.tick() as u64;
+                if next.at_row_start() || next.ended() {
+                    break;
+                }
+            }
+            if end > target || end >= self.frames || next.ended() {
+                break;
+            }
+            (frame, player) = (end, next);
+        }
+        self.player = player;
+        self.frame = frame;
+        Ok(SeekedTo {
+            track_id: 0,
+            required_ts: target,
+            actual_ts: frame,
+        })
+    }
+
+    fn next_packet(&mut self) -> Result<Packet> {
+        if self.frame >= self.frames {
+            return Err(Error::IoError(std::io::ErrorKind::UnexpectedEof.into()));
+        }
+        let ts = self.frame;
+        let frames = PACKET_FRAMES.min(self.frames - self.frame);
+        self.buf.resize(frames as usize * 2, 0.0);
+        self.player.render(&mut self.buf);
+        self.frame += frames;
+        Ok(super::pcm_packet(ts, frames, &self.buf))
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.source
+    }
+}
@@ -0,0 +1,421 @@
+//! Impulse Tracker modules, with their compressed samples. New note
+//! actions aren't followed: a new note on a channel always stops the last.
+
+use symphonia::core::errors::{Result, decode_error};
+
+use super::module::{
+    Bytes, Cell, Envelope, Instrument, Kind, Loop, Module, NOTES, Note, Pattern, Sample, Volume,
+    pcm_16, signed_8, text, unsigned_8,
+};
+use super::s3m;
+
+pub const SIGNATURE: &[u8] = b"IMPM";
+
+const CHANNELS: usize = 64;
+
+/// Envelope nodes there is room for
+const NODES: usize = 25;
+
+/// Tone portamento speeds of the volume column
+const TONE_SPEEDS: [u8; 10] = [0, 1, 4, 8, 16, 32, 64, 96, 128, 255];
+
+/// A 0 to 64 pan as 0 to 255
+fn pan(value: u8) -> u8 {
+    (value.min(64) as u16 * 255 / 64) as u8
+}
+
+fn volume(value: u8) -> Volume {
+    match value {
+        0..=64 => Volume::Set(value),
+        65..=74 => Volume::FineUp(value - 65),
+        75..=84 => Volume::FineDown(value - 75),
+        85..=94 => Volume::SlideUp(value - 85),
+        95..=104 => Volume::SlideDown(value - 95),
+        128..=192 => Volume::Pan(pan(value - 128)),
+        193..=202 => Volume::TonePorta(TONE_SPEEDS[(value - 193) as usize]),
+        _ => Volume::None,
+    }
+}
+
+/// The cells of a pattern, by row and channel
+fn pattern(data: &[u8], rows: usize) -> Vec<(usize, usize, Cell)> {
+    let mut cells = Vec::new();
+    let mut bytes = data.iter().copied();
+    // Each channel's last mask and values, which later cells can repeat
+    let mut masks = [0u8; CHANNELS];
+    let mut last = [(255u8, 0u8, 255u8, 0u8, 0u8); CHANNELS];
+    let mut row = 0;
+    while row < rows {
+        let Some(what) = bytes.next() else {
+            break;
+        };
+        if what == 0 {
+            row += 1;
+            continue;
+        }
+        let channel = (what as usize - 1) & (CHANNELS - 1);
+        if what & 0x80 != 0 {
+            masks[channel] = bytes.next().unwrap_or(0);
+        }
+        let mask = masks[channel];
+        let (mut note, mut instrument, mut volume_column, mut command, mut param) =
+            (None, 0, 255, 0, 0);
+        let remembered = &mut last[channel];
+        if mask & 0x01 != 0 {
+            remembered.0 = bytes.next().unwrap_or(0);
+            note = Some(remembered.0);
+        }
+        if mask & 0x02 != 0 {
+            remembered.1 = bytes.next().unwrap_or(0);
+            instrument = remembered.1;
+        }
+        if mask & 0x04 != 0 {
+            remembered.2 = bytes.next().unwrap_or(255);
+            volume_column = remembered.2;
+        }
+        if mask & 0x08 != 0 {
+            remembered.3 = bytes.next().unwrap_or(0);
+            remembered.4 = bytes.next().unwrap_or(0);
+            (command, param) = (remembered.3, remembered.4);
+        }
+        if mask & 0x10 != 0 {
+            note = Some(remembered.0);
+        }
+        if mask & 0x20 != 0 {
+            instrument = remembered.1;
+        }
+        if mask & 0x40 != 0 {
+            volume_column = remembered.2;
+        }
+        if mask & 0x80 != 0 {
+            (command, param) = (remembered.3, remembered.4);
+        }
+        let cell = Cell {
+            note: match note {
+                None => Note::None,
+                Some(note @ 0..120) => Note::On(note),
+                Some(255) => Note::Off,
+                Some(254) => Note::Cut,
+                Some(_) => Note::Fade,
+            },
+            instrument,
+            volume: volume(volume_column),
+            effect: s3m::effect(command, param, Kind::It),
+        };
+        cells.push((row, channel, cell));
+    }
+    cells
+}
+
+/// Reads bits from the lowest of each byte up
+struct Bits<'a> {
+    data: &'a [u8],
+    bit: usize,
+}
+
+impl Bits<'_> {
+    fn read(&mut self, count: u32) -> Option<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            let byte = *self.data.get(self.bit / 8)?;
+            value |= ((byte >> (self.bit % 8)) as u32 & 1) << i;
+            self.bit += 1;
+        }
+        Some(value)
+    }
+}
+
+/// `value` as a signed number of `bits` bits
+fn signed(value: u32, bits: u32) -> i32 {
+    ((value << (32 - bits)) as i32) >> (32 - bits)
+}
+
+/// One block of an IT214 or IT215 compressed sample: differences (or for
+/// IT215, differences of differences) packed in as few bits as the block
+/// needs, with escape values that change the width
+fn decompress_block(data: &[u8], count: usize, wide: bool, it215: bool, out: &mut Vec<f32>) {
+    let bits = if wide { 16 } else { 8 };
+    let top = bits + 1;
+    let mut reader = Bits { data, bit: 0 };
+    let mut width = top;
+    let (mut first, mut second) = (0i32, 0i32);
+    let mut done = 0;
+    while done < count {
+        let Some(value) = reader.read(width) else {
+            break;
+        };
+        let changed = if width < 7 {
+            (value == 1 << (width - 1))
+                .then(|| reader.read(if wide { 4 } else { 3 }).map(|value| value + 1))
+                .flatten()
+        } else if width < top {
+            let border = (((1 << bits) - 1) >> (top - width)) - if wide { 8 } else { 4 };
+            let range = if wide { 16 } else { 8 };
+            (value > border && value <= border + range).then(|| value - border)
+        } else {
+            (value & (1 << bits) != 0).then_some((value + 1) & 0xff)
+        };
+        if let Some(new) = changed {
+            width = if new < width { new } else { new + 1 };
+            if width == 0 || width > top {
+                break;
+            }
+            continue;
+        }
+        first = signed((first + signed(value, width.min(bits))) as u32, bits);
+        second = signed((second + first) as u32, bits);
+        let value = if it215 { second } else { first };
+        out.push(value as f32 / (1 << (bits - 1)) as f32);
+        done += 1;
+    }
+}
+
+/// A compressed sample of `length` samples from blocks of at most 32 KiB
+/// samples of 8 bits or 16 KiB samples of 16
+fn decompress(data: &[u8], length: usize, wide: bool, it215: bool) -> Vec<f32> {
+    let block = if wide { 0x4000 } else { 0x8000 };
+    // Every sample takes at least a bit
+    let mut out = Vec::with_capacity(length.min(data.len() * 8));
+    let mut at = 0;
+    while out.len() < length && at + 2 <= data.len() {
+        let size = u16::from_le_bytes([data[at], data[at + 1]]) as usize;
+        let end = (at + 2 + size).min(data.len());
+        let count = (length - out.len()).min(block);
+        let before = out.len();
+        decompress_block(&data[at + 2..end], count, wide, it215, &mut out);
+        // A block that breaks off early is padded out with silence
+        out.resize(before + count, 0.0);
+        at = end;
+    }
+    out
+}
+
+fn sample(bytes: &Bytes, at: usize) -> Result<Sample> {
+    if bytes.slice(at, 4)? != b"IMPS" {
+        return decode_error("it: bad sample header");
+    }
+    let flags = bytes.u8(at + 18)?;
+    let convert = bytes.u8(at + 46)?;
+    let default_pan = bytes.u8(at + 47)?;
+    let length = bytes.u32(at + 48)? as usize;
+    let offset = bytes.u32(at + 72)? as usize;
+    let wide = flags & 0x02 != 0;
+    let data = match flags {
+        _ if flags & 0x01 == 0 => Vec::new(),
+        _ if flags & 0x08 != 0 => decompress(
+            bytes.up_to(offset, usize::MAX),
+            length,
+            wide,
+            convert & 0x04 != 0,
+        ),
+        _ if wide => pcm_16(bytes.up_to(offset, length * 2), convert & 0x01 != 0),
+        _ if convert & 0x01 != 0 => signed_8(bytes.up_to(offset, length)),
+        _ => unsigned_8(bytes.up_to(offset, length)),
+    };
+    // Only the sustain loop, if that's all there is, played as a loop
+    let (looping, loop_start, loop_end) = match flags {
+        _ if flags & 0x10 != 0 => (flags & 0x40, bytes.u32(at + 52)?, bytes.u32(at + 56)?),
+        _ if flags & 0x20 != 0 => (flags & 0x80, bytes.u32(at + 64)?, bytes.u32(at + 68)?),
+        _ => (0, 0, 0),
+    };
+    let mut sample = Sample {
+        name: bytes.text(at + 20, 26),
+        data,
+        looping: match (flags & 0x30, looping) {
+            (0, _) => Loop::None,
+            (_, 0) => Loop::Forward,
+            _ => Loop::PingPong,
+        },
+        loop_start: loop_start as usize,
+        loop_end: loop_end as usize,
+        volume: bytes.u8(at + 19)?.min(64),
+        global_volume: bytes.u8(at + 17)?.min(64),
+        pan: (default_pan & 0x80 != 0).then(|| pan(default_pan & 0x7f)),
+        c5_speed: bytes.u32(at + 60)? as f64,
+    };
+    sample.fix_loop();
+    Ok(sample)
+}
+
+fn instrument(bytes: &Bytes, at: usize, old: bool) -> Result<Instrument> {
+    if bytes.slice(at, 4)? != b"IMPI" {
+        return decode_error("it: bad instrument header");
+    }
+    let mut keymap = Vec::with_capacity(NOTES);
+    for (key, entry) in bytes.slice(at + 64, NOTES * 2)?.chunks_exact(2).enumerate() {
+        let note = if (entry[0] as usize) < NOTES {
+            entry[0]
+        } else {
+            key as u8
+        };
+        keymap.push((note, entry[1] as u16));
+    }
+    let name = bytes.text(at + 32, 26);
+
+    // Instruments from before Impulse Tracker 2 keep the envelope and
+    // fadeout elsewhere, and have no default pan
+    if old {
+        let flags = bytes.u8(at + 17)?;
+        let mut points = Vec::new();
+        for node in bytes.slice(at + 504, NODES * 2)?.chunks_exact(2) {
+            if node[0] == 0xff {
+                break;
+            }
+            points.push((node[0] as u16, node[1].min(64)));
+        }
+        let range = |start: usize, end: usize| -> Result<(usize, usize)> {
+            Ok((bytes.u8(at + start)? as usize, bytes.u8(at + end)? as usize))
+        };
+        return Ok(Instrument {
+            name,
+            keymap,
+            envelope: match flags & 1 {
+                0 => None,
+                _ => Envelope {
+                    points,
+                    sustain: (flags & 4 != 0).then(|| range(20, 21)).transpose()?,
+                    looped: (flags & 2 != 0).then(|| range(18, 19)).transpose()?,
+                }
+                .checked(),
+            },
+            fadeout: bytes.u16(at + 24)? as f32 / 512.0,
+            pan: None,
+        });
+    }
+
+    let flags = bytes.u8(at + 304)?;
+    let count = (bytes.u8(at + 305)? as usize).min(NODES);
+    let mut points = Vec::with_capacity(count);
+    for node in bytes.slice(at + 310, count * 3)?.chunks_exact(3) {
+        points.push((u16::from_le_bytes([node[1], node[2]]), node[0].min(64)));
+    }
+    let range = |start: usize| -> Result<(usize, usize)> {
+        Ok((
+            bytes.u8(at + start)? as usize,
+            bytes.u8(at + start + 1)? as usize,
+        ))
+    };
+    let default_pan = bytes.u8(at + 25)?;
+    Ok(Instrument {
+        name,
+        keymap,
+        envelope: match flags & 1 {
+            0 => None,
+            _ => Envelope {
+                points,
+                sustain: (flags & 4 != 0).then(|| range(308)).transpose()?,
+                looped: (flags & 2 != 0).then(|| range(306)).transpose()?,
+            }
+            .checked(),
+        },
+        fadeout: bytes.u16(at + 20)? as f32 / 1024.0,
+        pan: (default_pan & 0x80 == 0).then(|| pan(default_pan)),
+    })
+}
+
+/// The song message, its lines ended with carriage returns
+fn message(bytes: &Bytes) -> Result<String> {
+    if bytes.u16(46)? & 1 == 0 {
+        return Ok(String::new());
+    }
+    let data = bytes.up_to(bytes.u32(56)? as usize, bytes.u16(54)? as usize);
+    let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+    let lines: Vec<String> = data[..end]
+        .split(|&b| b == b'\r' || b == b'\n')
+        .map(text)
+        .collect();
+    Ok(lines.join("\n").trim().to_owned())
+}
+
+pub fn load(data: &[u8]) -> Result<Module> {
+    let bytes = Bytes(data);
+    if bytes.slice(0, 4)? != SIGNATURE {
+        return decode_error("it: not an Impulse Tracker module");
+    }
+    let order_count = bytes.u16(32)? as usize;
+    let instrument_count = bytes.u16(34)? as usize;
+    let sample_count = bytes.u16(36)? as usize;
+    let pattern_count = bytes.u16(38)? as usize;
+    let compatible = bytes.u16(42)?;
+    let flags = bytes.u16(44)?;
+    let pointers = 192 + order_count;
+    let pointer = |index: usize| bytes.u32(pointers + index * 4).map(|at| at as usize);
+
+    let mut samples = Vec::with_capacity(sample_count);
+    for index in 0..sample_count {
+        samples.push(sample(&bytes, pointer(instrument_count + index)?)?);
+    }
+    // Without instruments, the samples are played alone
+    let mut instruments = Vec::with_capacity(instrument_count);
+    if flags & 0x04 != 0 {
+        for index in 0..instrument_count {
+            instruments.push(instrument(&bytes, pointer(index)?, compatible < 0x200)?);
+        }
+    } else {
+        instruments.extend((1..=sample_count as u16).map(Instrument::of_sample));
+    }
+
+    // Channels marked disabled are dropped, and the rest up to the last
+    // one used kept
+    let channel_pans = bytes.slice(64, CHANNELS)?;
+    let mut cells = Vec::with_capacity(pattern_count);
+    let mut rows = Vec::with_capacity(pattern_count);
+    let mut channels = 0;
+    for index in 0..pattern_count {
+        let at = pointer(instrument_count + sample_count + index)?;
+        let (count, pattern) = match at {
+            0 => (64, Vec::new()),
+            _ => {
+                let count = bytes.u16(at + 2)? as usize;
+                let data = bytes.up_to(at + 8, bytes.u16(at)? as usize);
+                (count, pattern(data, count))
+            }
+        };
+        for &(_, channel, _) in &pattern {
+            if channel_pans[channel] & 0x80 == 0 {
+                channels = channels.max(channel + 1);
+            }
+        }
+        rows.push(count.max(1));
+        cells.push(pattern);
+    }
+    let channels = channels.max(1);
+    let patterns = cells
+        .into_iter()
+        .zip(rows)
+        .map(|(cells, rows)| {
+            let mut pattern = Pattern::empty(rows, channels);
+            for (row, channel, cell) in cells {
+                if channel < channels && channel_pans[channel] & 0x80 == 0 {
+                    pattern.cells[row * channels + channel] = cell;
+                }
+            }
+            pattern
+        })
+        .collect();
+    let stereo = flags & 0x01 != 0;
+    let pans = channel_pans[..channels]
+        .iter()
+        .map(|&value| match value & 0x7f {
+            // Surround is played in the middle
+            value if stereo && value <= 64 => pan(value),
+            _ => 128,
+        })
+        .collect();
+
+    Ok(Module {
+        kind: Kind::It,
+        title: bytes.text(4, 26),
+        message: message(&bytes)?,
+        channels,
+        orders: bytes.slice(192, order_count)?.to_vec(),
+        patterns,
+        samples,
+        instruments,
+        speed: bytes.u8(50)?,
+        tempo: bytes.u8(51)?,
+        global_volume: bytes.u8(48)?.min(128),
+        pans,
+        linear: flags & 0x08 != 0,
+    })
+}
@@ -0,0 +1,366 @@
+//! What the four module formats load into: patterns of cells whose notes
+//! and effects are put in common terms, and samples as floats.
+
+use symphonia::core::errors::{Result, decode_error};
+
+/// The order list entry that is skipped, `+++`
+pub const ORDER_SKIP: u8 = 254;
+/// The order list entry that ends the song, `---`
+pub const ORDER_END: u8 = 255;
+
+/// Notes are numbered from C-0, and the note a sample plays at its own
+/// rate (`Sample::c5_speed`) is C-5 whatever the format calls it
+pub const MIDDLE_C: u8 = 60;
+
+/// Notes there are, C-0 to B-9
+pub const NOTES: usize = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    /// ProTracker and its many-channel relatives
+    Mod,
+    /// Scream Tracker 3
+    S3m,
+    /// FastTracker 2
+    Xm,
+    /// Impulse Tracker
+    It,
+}
+
+impl Kind {
+    /// Whether volume slides and pitch slides code their fine variants in
+    /// the parameter (`DxF`, `EFx`), as Scream Tracker's and Impulse
+    /// Tracker's do, and a zero parameter repeats the last
+    pub fn st3_effects(self) -> bool {
+        matches!(self, Kind::S3m | Kind::It)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Note {
+    #[default]
+    None,
+    On(u8),
+    /// Key off: the envelope leaves its sustain and the note fades
+    Off,
+    /// Stop the note at once
+    Cut,
+    /// Fade the note out without leaving the sustain
+    Fade,
+}
+
+/// The volume column, where there is one
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Volume {
+    #[default]
+    None,
+    Set(u8),
+    SlideUp(u8),
+    SlideDown(u8),
+    FineUp(u8),
+    FineDown(u8),
+    /// 0 (left) to 255 (right)
+    Pan(u8),
+    TonePorta(u8),
+}
+
+/// Effects in common terms. Parameters are as the format stores them,
+/// apart from panning, which is always 0 to 255.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Effect {
+    #[default]
+    None,
+    Arpeggio(u8),
+    PortaUp(u8),
+    PortaDown(u8),
+    FinePortaUp(u8),
+    FinePortaDown(u8),
+    ExtraFinePortaUp(u8),
+    ExtraFinePortaDown(u8),
+    TonePorta(u8),
+    Vibrato(u8),
+    TonePortaVolSlide(u8),
+    VibratoVolSlide(u8),
+    Pan(u8),
+    SampleOffset(u8),
+    VolSlide(u8),
+    FineVolUp(u8),
+    FineVolDown(u8),
+    /// Go to this order position after the row
+    Jump(u8),
+    SetVolume(u8),
+    /// Go to this row of the next order position after the row
+    Break(u8),
+    /// 0 marks the start of the loop, more plays it that many more times
+    PatternLoop(u8),
+    NoteCut(u8),
+    NoteDelay(u8),
+    /// Play the row this many more times over, without new notes
+    PatternDelay(u8),
+    Speed(u8),
+    Tempo(u8),
+    /// 0 to 128
+    GlobalVolume(u8),
+    GlobalVolSlide(u8),
+    Retrig(u8),
+    /// Key off at this tick
+    KeyOff(u8),
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Cell {
+    pub note: Note,
+    /// From 1; 0 for none
+    pub instrument: u8,
+    pub volume: Volume,
+    pub effect: Effect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pattern {
+    pub rows: usize,
+    /// Row by row, a cell for each channel
+    pub cells: Vec<Cell>,
+}
+
+impl Pattern {
+    pub fn empty(rows: usize, channels: usize) -> Self {
+        Self {
+            rows,
+            cells: vec![Cell::default(); rows * channels],
+        }
+    }
+
+    pub fn row(&self, row: usize, channels: usize) -> &[Cell] {
+        &self.cells[row * channels..(row + 1) * channels]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Loop {
+    None,
+    Forward,
+    PingPong,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    /// Mono, -1.0 to 1.0
+    pub data: Vec<f32>,
+    pub looping: Loop,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    /// 0 to 64
+    pub volume: u8,
+    /// 0 to 64; Impulse Tracker's per-sample scaling
+    pub global_volume: u8,
+    /// 0 to 255, if the sample sets one
+    pub pan: Option<u8>,
+    /// Rate the sample plays at for C-5
+    pub c5_speed: f64,
+}
+
+impl Sample {
+    /// Check the loop against the data, dropping one that doesn't fit
+    pub fn fix_loop(&mut self) {
+        if self.loop_end > self.data.len() {
+            self.loop_end = self.data.len();
+        }
+        if self.loop_start + 1 >= self.loop_end {
+            self.looping = Loop::None;
+        }
+    }
+}
+
+/// Volume envelope: points of (tick, 0 to 64)
+#[derive(Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub points: Vec<(u16, u8)>,
+    /// First and last point of the loop held while the key is down
+    pub sustain: Option<(usize, usize)>,
+    pub looped: Option<(usize, usize)>,
+}
+
+impl Envelope {
+    /// Fix point indices that are out of range
+    pub fn checked(self) -> Option<Self> {
+        let count = self.points.len();
+        let valid = |range: Option<(usize, usize)>| {
+            range.filter(|&(start, end)| start <= end && end < count)
+        };
+        (count > 0).then(|| Self {
+            sustain: valid(self.sustain),
+            looped: valid(self.looped),
+            points: self.points,
+        })
+    }
+
+    /// Level at `tick`, 0.0 to 1.0, between the points either side
+    pub fn value(&self, tick: u16) -> f32 {
+        let after = self.points.partition_point(|&(at, _)| at <= tick);
+        let level = match (
+            self.points.get(after.wrapping_sub(1)),
+            self.points.get(after),
+        ) {
+            (Some(&(t0, v0)), Some(&(t1, v1))) if t1 > t0 => {
+                let through = (tick - t0) as f32 / (t1 - t0) as f32;
+                v0 as f32 + (v1 as f32 - v0 as f32) * through
+            }
+            (Some(&(_, v)), _) | (None, Some(&(_, v))) => v as f32,
+            (None, None) => 64.0,
+        };
+        level / 64.0
+    }
+
+    /// The tick after `tick`, going round the sustain loop while the key is
+    /// down and round the loop
+    pub fn next(&self, tick: u16, key_on: bool) -> u16 {
+        let next = tick.saturating_add(1);
+        let range = match self.sustain {
+            Some(range) if key_on => Some(range),
+            _ => self.looped,
+        };
+        if let Some((start, end)) = range
+            && next > self.points[end].0
+        {
+            return self.points[start].0;
+        }
+        next.min(self.end())
+    }
+
+    /// Tick of the last point
+    pub fn end(&self) -> u16 {
+        self.points.last().map_or(0, |&(tick, _)| tick)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instrument {
+    pub name: String,
+    /// For each note, the note to play and the sample (from 1; 0 for none)
+    pub keymap: Vec<(u8, u16)>,
+    pub envelope: Option<Envelope>,
+    /// How much of full volume is lost per tick after key off
+    pub fadeout: f32,
+    /// 0 to 255, if the instrument sets one
+    pub pan: Option<u8>,
+}
+
+impl Instrument {
+    /// The instrument of a format without them: one sample, every note
+    pub fn of_sample(sample: u16) -> Self {
+        Self {
+            name: String::new(),
+            keymap: (0..NOTES as u8).map(|note| (note, sample)).collect(),
+            envelope: None,
+            fadeout: 0.0,
+            pan: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Module {
+    pub kind: Kind,
+    pub title: String,
+    /// Song message, where the format has one
+    pub message: String,
+    pub channels: usize,
+    pub orders: Vec<u8>,
+    pub patterns: Vec<Pattern>,
+    pub samples: Vec<Sample>,
+    /// Instrument 1 first
+    pub instruments: Vec<Instrument>,
+    pub speed: u8,
+    pub tempo: u8,
+    /// 0 to 128
+    pub global_volume: u8,
+    /// Starting pan of each channel, 0 to 255
+    pub pans: Vec<u8>,
+    /// Pitch slides by fractions of a semitone rather than by Amiga period
+    pub linear: bool,
+}
+
+impl Module {
+    /// The pattern at an order position, if there is one
+    pub fn pattern(&self, order: usize) -> Option<&Pattern> {
+        self.patterns.get(*self.orders.get(order)? as usize)
+    }
+}
+
+/// Reads little-endian fields at offsets of a whole file
+pub struct Bytes<'a>(pub &'a [u8]);
+
+impl<'a> Bytes<'a> {
+    pub fn slice(&self, offset: usize, length: usize) -> Result<&'a [u8]> {
+        match self.0.get(offset..offset.saturating_add(length)) {
+            Some(slice) => Ok(slice),
+            None => decode_error("tracker: file is truncated"),
+        }
+    }
+
+    /// Up to `length` bytes, fewer if the file ends first
+    pub fn up_to(&self, offset: usize, length: usize) -> &'a [u8] {
+        let start = offset.min(self.0.len());
+        &self.0[start..offset.saturating_add(length).min(self.0.len())]
+    }
+
+    pub fn u8(&self, offset: usize) -> Result<u8> {
+        Ok(self.slice(offset, 1)?[0])
+    }
+
+    pub fn u16(&self, offset: usize) -> Result<u16> {
+        let bytes = self.slice(offset, 2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&self, offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(
+            self.slice(offset, 4)?.try_into().unwrap(),
+        ))
+    }
+
+    /// A fixed-length text field, up to its first NUL
+    pub fn text(&self, offset: usize, length: usize) -> String {
+        text(self.up_to(offset, length))
+    }
+}
+
+/// Tracker texts are DOS code page 437 or Latin-1; anything outside ASCII
+/// is taken as Latin-1
+pub fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    bytes[..end]
+        .iter()
+        .map(|&b| match b {
+            0x20..=0x7e | 0xa0..=0xff => b as char,
+            _ => ' ',
+        })
+        .collect::<String>()
+        .trim_end()
+        .to_owned()
+}
+
+/// 8-bit signed samples
+pub fn signed_8(data: &[u8]) -> Vec<f32> {
+    data.iter().map(|&s| s as i8 as f32 / 128.0).collect()
+}
+
+/// 8-bit unsigned samples
+pub fn unsigned_8(data: &[u8]) -> Vec<f32> {
+    data.iter().map(|&s| (s as f32 - 128.0) / 128.0).collect()
+}
+
+/// 16-bit little-endian samples, signed or unsigned
+pub fn pcm_16(data: &[u8], signed: bool) -> Vec<f32> {
+    data.chunks_exact(2)
+        .map(|s| {
+            let value = u16::from_le_bytes([s[0], s[1]]);
+            match signed {
+                true => value as i16 as f32 / 32768.0,
+                false => (value as f32 - 32768.0) / 32768.0,
+            }
+        })
+        .collect()
+}
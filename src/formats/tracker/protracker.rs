@@ -0,0 +1,173 @@
+//! ProTracker modules, and the 31-sample formats that only differ from
+//! them in the number of channels: `M.K.`, `FLT8`, `6CHN`, `16CH` and the
+//! like. The 15-sample Soundtracker modules before them have no signature
+//! to know them by and aren't read.
+
+use symphonia::core::errors::{Result, decode_error};
+
+use super::module::{
+    Bytes, Cell, Effect, Instrument, Kind, Loop, MIDDLE_C, Module, Note, Pattern, Sample, signed_8,
+};
+
+/// Where the signature is
+pub const SIGNATURE_OFFSET: u64 = 1080;
+
+pub const SIGNATURES: &[&[u8]] = &[
+    b"M.K.", b"M!K!", b"FLT4", b"FLT8", b"CD81", b"OKTA", b"1CHN", b"2CHN", b"3CHN", b"4CHN",
+    b"5CHN", b"6CHN", b"7CHN", b"8CHN", b"9CHN", b"10CH", b"11CH", b"12CH", b"13CH", b"14CH",
+    b"15CH", b"16CH", b"17CH", b"18CH", b"19CH", b"20CH", b"21CH", b"22CH", b"23CH", b"24CH",
+    b"25CH", b"26CH", b"27CH", b"28CH", b"29CH", b"30CH", b"31CH", b"32CH",
+];
+
+/// Period of C-5, the note samples play at their own rate
+const MIDDLE_PERIOD: f64 = 428.0;
+
+const SAMPLES: usize = 31;
+const ROWS: usize = 64;
+
+/// Amiga's hard left-right-right-left split, softened for headphones
+const LEFT: u8 = 64;
+const RIGHT: u8 = 191;
+
+fn channels(signature: &[u8]) -> Option<usize> {
+    let digit = |byte: u8| byte.is_ascii_digit().then(|| (byte - b'0') as usize);
+    match signature {
+        b"M.K." | b"M!K!" | b"FLT4" => Some(4),
+        b"FLT8" | b"CD81" | b"OKTA" => Some(8),
+        [count, b'C', b'H', b'N'] => digit(*count),
+        [tens, units, b'C', b'H'] => Some(digit(*tens)? * 10 + digit(*units)?),
+        _ => None,
+    }
+    .filter(|&count| count > 0)
+}
+
+fn u16_be(bytes: &Bytes, offset: usize) -> Result<u16> {
+    let bytes = bytes.slice(offset, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// ProTracker and FastTracker 2 effects, commands 0 to F
+pub fn effect(command: u8, param: u8) -> Effect {
+    let (x, y) = (param >> 4, param & 0xf);
+    match command {
+        0x0 if param != 0 => Effect::Arpeggio(param),
+        0x1 => Effect::PortaUp(param),
+        0x2 => Effect::PortaDown(param),
+        0x3 => Effect::TonePorta(param),
+        0x4 => Effect::Vibrato(param),
+        0x5 => Effect::TonePortaVolSlide(param),
+        0x6 => Effect::VibratoVolSlide(param),
+        0x8 => Effect::Pan(param),
+        0x9 => Effect::SampleOffset(param),
+        0xa => Effect::VolSlide(param),
+        0xb => Effect::Jump(param),
+        0xc => Effect::SetVolume(param.min(64)),
+        // The row is in decimal
+        0xd => Effect::Break(x * 10 + y),
+        0xe => match x {
+            0x1 => Effect::FinePortaUp(y),
+            0x2 => Effect::FinePortaDown(y),
+            0x6 => Effect::PatternLoop(y),
+            0x8 => Effect::Pan(y * 17),
+            0x9 => Effect::Retrig(y),
+            0xa => Effect::FineVolUp(y),
+            0xb => Effect::FineVolDown(y),
+            0xc => Effect::NoteCut(y),
+            0xd => Effect::NoteDelay(y),
+            0xe => Effect::PatternDelay(y),
+            _ => Effect::None,
+        },
+        0xf if param < 0x20 => Effect::Speed(param),
+        0xf => Effect::Tempo(param),
+        _ => Effect::None,
+    }
+}
+
+pub fn load(data: &[u8]) -> Result<Module> {
+    let bytes = Bytes(data);
+    let Some(channels) = channels(bytes.slice(SIGNATURE_OFFSET as usize, 4)?) else {
+        return decode_error("mod: unknown signature");
+    };
+
+    let mut samples = Vec::with_capacity(SAMPLES);
+    let mut lengths = Vec::with_capacity(SAMPLES);
+    for i in 0..SAMPLES {
+        let at = 20 + i * 30;
+        let finetune = ((bytes.u8(at + 24)? << 4) as i8 >> 4) as f64;
+        let loop_start = u16_be(&bytes, at + 26)? as usize * 2;
+        let loop_length = u16_be(&bytes, at + 28)? as usize * 2;
+        lengths.push(u16_be(&bytes, at + 22)? as usize * 2);
+        samples.push(Sample {
+            name: bytes.text(at, 22),
+            data: Vec::new(),
+            // A loop of one word is how no loop is written
+            looping: if loop_length > 2 {
+                Loop::Forward
+            } else {
+                Loop::None
+            },
+            loop_start,
+            loop_end: loop_start + loop_length,
+            volume: bytes.u8(at + 25)?.min(64),
+            global_volume: 64,
+            pan: None,
+            // Finetune is in eighths of a semitone
+            c5_speed: 8363.0 * 2f64.powf(finetune / 96.0),
+        });
+    }
+
+    let length = (bytes.u8(950)? as usize).clamp(1, 128);
+    let orders = bytes.slice(952, 128)?;
+    let patterns = orders.iter().max().map_or(0, |&most| most as usize + 1);
+    let mut at = SIGNATURE_OFFSET as usize + 4;
+    let mut loaded = Vec::with_capacity(patterns);
+    for _ in 0..patterns {
+        let mut pattern = Pattern::empty(ROWS, channels);
+        let data = bytes.slice(at, ROWS * channels * 4)?;
+        for (cell, b) in pattern.cells.iter_mut().zip(data.chunks_exact(4)) {
+            let period = ((b[0] & 0xf) as u16) << 8 | b[1] as u16;
+            *cell = Cell {
+                note: match period {
+                    0 => Note::None,
+                    period => {
+                        let steps = 12.0 * (MIDDLE_PERIOD / period as f64).log2();
+                        Note::On((MIDDLE_C as f64 + steps.round()).clamp(0.0, 119.0) as u8)
+                    }
+                },
+                instrument: (b[0] & 0xf0) | b[2] >> 4,
+                volume: Default::default(),
+                effect: effect(b[2] & 0xf, b[3]),
+            };
+        }
+        loaded.push(pattern);
+        at += data.len();
+    }
+
+    // A module cut short still plays what there is of its samples
+    for (sample, length) in samples.iter_mut().zip(lengths) {
+        sample.data = signed_8(bytes.up_to(at, length));
+        sample.fix_loop();
+        at += length;
+    }
+
+    Ok(Module {
+        kind: Kind::Mod,
+        title: bytes.text(0, 20),
+        message: String::new(),
+        channels,
+        orders: orders[..length].to_vec(),
+        patterns: loaded,
+        instruments: (1..=SAMPLES as u16).map(Instrument::of_sample).collect(),
+        samples,
+        speed: 6,
+        tempo: 125,
+        global_volume: 128,
+        pans: (0..channels)
+            .map(|channel| match channel % 4 {
+                0 | 3 => LEFT,
+                _ => RIGHT,
+            })
+            .collect(),
+        linear: false,
+    })
+}
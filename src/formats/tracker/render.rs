@@ -0,0 +1,785 @@
+//! Plays a module a tick at a time: the sequencer walking the order list
+//! and the rows of each pattern, the effects on each channel, and the
+//! mixer.
+
+use std::f64::consts::TAU;
+use std::sync::Arc;
+
+use super::module::{
+    Cell, Effect, Instrument, Kind, Loop, MIDDLE_C, Module, Note, ORDER_END, ORDER_SKIP, Sample,
+    Volume,
+};
+
+/// Amiga period of C-5 for a sample at 8363 Hz, in the quarter steps
+/// Scream Tracker counts periods in
+const AMIGA_PERIOD: f64 = 1712.0;
+const AMIGA_RATE: f64 = 8363.0;
+
+/// Linear periods count down from C-10 in 64 steps to a semitone
+const LINEAR_TOP: f64 = 120.0 * 64.0;
+const LINEAR_MIDDLE_C: f64 = LINEAR_TOP - MIDDLE_C as f64 * 64.0;
+
+/// Level of one channel at full volume, panned to the centre. Modules with
+/// more than four channels are turned down by the square root of how many
+/// more.
+const MIX_GAIN: f32 = 0.5;
+
+/// Rows in the stand-in for a pattern the order list names but the file
+/// doesn't have
+const EMPTY_ROWS: usize = 64;
+
+#[derive(Debug, Clone, Default)]
+struct Channel {
+    /// Sample playing, from 0, and where in it
+    sample: Option<usize>,
+    position: f64,
+    backwards: bool,
+    instrument: Option<usize>,
+    period: f64,
+    /// Where a tone portamento is sliding to
+    target: f64,
+    /// 0 to 64
+    volume: u8,
+    /// 0 to 255
+    pan: u8,
+    key_on: bool,
+    fading: bool,
+    /// 1.0 down to 0.0 once fading
+    fade: f32,
+    envelope_tick: u16,
+    envelope: f32,
+
+    /// Pitch changes for this tick alone, from vibrato and arpeggio
+    vibrato_offset: f64,
+    arpeggio: u8,
+
+    /// The last parameter of effects that repeat it when given 0
+    vol_slide: u8,
+    porta_up: u8,
+    porta_down: u8,
+    tone_speed: u8,
+    vibrato: u8,
+    vibrato_phase: u8,
+    offset: u8,
+    retrig: u8,
+    retrig_ticks: u8,
+    arpeggio_param: u8,
+    global_slide: u8,
+
+    /// Pattern loop start row and how many more times round
+    loop_row: usize,
+    loop_count: u8,
+}
+
+/// The last nonzero `param`, remembered in `memory`
+fn remember(memory: &mut u8, param: u8) -> u8 {
+    if param != 0 {
+        *memory = param;
+    }
+    *memory
+}
+
+/// A fine slide coded in a Scream Tracker volume slide parameter, `DxF` up
+/// or `DFx` down
+fn st3_fine_slide(param: u8) -> Option<i32> {
+    match (param >> 4, param & 0xf) {
+        (up, 0xf) if up > 0 => Some(up as i32),
+        (0xf, down) if down > 0 => Some(-(down as i32)),
+        _ => None,
+    }
+}
+
+fn slide_volume(volume: &mut u8, by: i32) {
+    *volume = (*volume as i32 + by).clamp(0, 64) as u8;
+}
+
+/// `Axy`: up by x, or if x is 0 down by y
+fn volume_slide(param: u8) -> i32 {
+    match param >> 4 {
+        0 => -((param & 0xf) as i32),
+        up => up as i32,
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Player {
+    module: Arc<Module>,
+    rate: u32,
+    order: usize,
+    row: usize,
+    /// Tick of the row, counting on through the repeats of a pattern delay
+    tick: u32,
+    /// Frames of the current tick still to mix
+    left: usize,
+    speed: u8,
+    tempo: u8,
+    /// 0 to 128
+    global_volume: u8,
+    /// Times the row is played again, from a pattern delay
+    repeats: u8,
+    /// Where jumps, breaks and pattern loops on this row go next
+    jump: Option<usize>,
+    break_row: Option<usize>,
+    loop_to: Option<usize>,
+    /// Row a pattern loop last went back to
+    looped_back: Option<usize>,
+    ended: bool,
+    gain: f32,
+    channels: Vec<Channel>,
+}
+
+impl Player {
+    pub fn new(module: Arc<Module>, rate: u32) -> Self {
+        let channels = module
+            .pans
+            .iter()
+            .map(|&pan| Channel {
+                pan,
+                ..Default::default()
+            })
+            .collect();
+        let gain = MIX_GAIN / (module.channels as f32 / 4.0).sqrt().max(1.0);
+        let mut player = Self {
+            rate,
+            order: 0,
+            row: 0,
+            tick: 0,
+            left: 0,
+            speed: module.speed.max(1),
+            tempo: module.tempo.max(32),
+            global_volume: module.global_volume.min(128),
+            repeats: 0,
+            jump: None,
+            break_row: None,
+            loop_to: None,
+            looped_back: None,
+            ended: false,
+            gain,
+            channels,
+            module,
+        };
+        player.go_to(0, 0);
+        player
+    }
+
+    /// Order position and row being played
+    pub fn position(&self) -> (usize, usize) {
+        (self.order, self.row)
+    }
+
+    /// Whether nothing of the current row has been played yet
+    pub fn at_row_start(&self) -> bool {
+        self.tick == 0 && self.left == 0
+    }
+
+    /// Whether the order list has run out
+    pub fn ended(&self) -> bool {
+        self.ended
+    }
+
+    /// The row a pattern loop went back to since this was last asked
+    pub fn take_looped_back(&mut self) -> Option<usize> {
+        self.looped_back.take()
+    }
+
+    /// Mix into `out`, interleaved stereo, with silence once the song ends
+    pub fn render(&mut self, out: &mut [f32]) {
+        out.fill(0.0);
+        let frames = out.len() / 2;
+        let mut done = 0;
+        while done < frames {
+            if self.left == 0 {
+                if self.ended {
+                    return;
+                }
+                self.start_tick();
+            }
+            let count = self.left.min(frames - done);
+            self.mix(Some(&mut out[done * 2..(done + count) * 2]), count);
+            done += count;
+            self.left -= count;
+            if self.left == 0 {
+                self.end_tick();
+            }
+        }
+    }
+
+    /// Play the rest of the tick without mixing it, returning its frames
+    pub fn tick(&mut self) -> usize {
+        if self.left == 0 {
+            if self.ended {
+                return 0;
+            }
+            self.start_tick();
+        }
+        let frames = self.left;
+        self.mix(None, frames);
+        self.left = 0;
+        self.end_tick();
+        frames
+    }
+
+    fn rows(&self, order: usize) -> usize {
+        self.module
+            .pattern(order)
+            .map_or(EMPTY_ROWS, |pattern| pattern.rows)
+            .max(1)
+    }
+
+    /// Move to `row` of the first playable order position from `order`
+    fn go_to(&mut self, mut order: usize, row: usize) {
+        let orders = &self.module.orders;
+        while orders.get(order) == Some(&ORDER_SKIP) {
+            order += 1;
+        }
+        if order != self.order {
+            for channel in &mut self.channels {
+                channel.loop_row = 0;
+                channel.loop_count = 0;
+            }
+        }
+        self.order = order;
+        match orders.get(order) {
+            None | Some(&ORDER_END) => self.ended = true,
+            Some(_) => self.row = if row < self.rows(order) { row } else { 0 },
+        }
+    }
+
+    fn start_tick(&mut self) {
+        let module = Arc::clone(&self.module);
+        let speed = self.speed as u32;
+        let first = self.tick < speed;
+        let tick = (self.tick % speed) as u8;
+        let cells = module
+            .pattern(self.order)
+            .map(|pattern| pattern.row(self.row, module.channels));
+        for c in 0..self.channels.len() {
+            let cell = cells.map_or(Cell::default(), |cells| cells[c]);
+            self.play_cell(c, cell, tick, first);
+            self.update_voice(c);
+        }
+        self.left = self.rate as usize * 5 / (self.tempo as usize * 2);
+    }
+
+    fn end_tick(&mut self) {
+        self.tick += 1;
+        if self.tick < self.speed as u32 * (self.repeats as u32 + 1) {
+            return;
+        }
+        self.tick = 0;
+        self.repeats = 0;
+        let (jump, break_row) = (self.jump.take(), self.break_row.take());
+        if let Some(row) = self.loop_to.take() {
+            self.looped_back = Some(row);
+            self.row = row;
+        } else if jump.is_some() || break_row.is_some() {
+            self.go_to(jump.unwrap_or(self.order + 1), break_row.unwrap_or(0));
+        } else if self.row + 1 < self.rows(self.order) {
+            self.row += 1;
+        } else {
+            self.go_to(self.order + 1, 0);
+        }
+    }
+
+    fn instrument(&self, c: usize) -> Option<&Instrument> {
+        self.module.instruments.get(self.channels[c].instrument?)
+    }
+
+    fn sample(&self, c: usize) -> Option<&Sample> {
+        self.module.samples.get(self.channels[c].sample?)
+    }
+
+    fn period(&self, note: u8, sample: &Sample) -> f64 {
+        let note = note as f64;
+        match self.module.linear {
+            true => LINEAR_TOP - note * 64.0,
+            false => {
+                AMIGA_PERIOD * AMIGA_RATE / sample.c5_speed.max(1.0)
+                    * 2f64.powf((MIDDLE_C as f64 - note) / 12.0)
+            }
+        }
+    }
+
+    fn frequency(&self, period: f64, sample: &Sample) -> f64 {
+        match self.module.linear {
+            true => sample.c5_speed * 2f64.powf((LINEAR_MIDDLE_C - period) / 768.0),
+            false => AMIGA_PERIOD * AMIGA_RATE / period.max(1.0),
+        }
+    }
+
+    /// Raise the pitch by `by` steps of the period: quarter Amiga steps, or
+    /// 64ths of a semitone
+    fn slide_pitch(&mut self, c: usize, by: f64) {
+        let channel = &mut self.channels[c];
+        channel.period = (channel.period - by).clamp(1.0, LINEAR_TOP * 2.0);
+    }
+
+    fn play_cell(&mut self, c: usize, cell: Cell, tick: u8, first: bool) {
+        let channel = &mut self.channels[c];
+        channel.vibrato_offset = 0.0;
+        channel.arpeggio = 0;
+        let delay = match cell.effect {
+            Effect::NoteDelay(delay) => delay,
+            _ => 0,
+        };
+        if first && tick == delay {
+            self.trigger(c, cell);
+        }
+        let kind = self.module.kind;
+        if tick == 0 {
+            self.volume_column_start(c, cell.volume);
+            self.effect_start(c, cell, first, kind);
+        } else {
+            self.volume_column_tick(c, cell);
+            self.effect_tick(c, cell, tick, kind);
+        }
+    }
+
+    /// Start a row's note, or take its instrument
+    fn trigger(&mut self, c: usize, cell: Cell) {
+        let module = Arc::clone(&self.module);
+        let porta = matches!(
+            cell.effect,
+            Effect::TonePorta(_) | Effect::TonePortaVolSlide(_)
+        ) || matches!(cell.volume, Volume::TonePorta(_));
+        if cell.instrument > 0 {
+            let index = cell.instrument as usize - 1;
+            self.channels[c].instrument = (index < module.instruments.len()).then_some(index);
+        }
+        match cell.note {
+            Note::On(note) => {
+                let Some(instrument) = self.instrument(c) else {
+                    return;
+                };
+                let (note, sample) = instrument.keymap[note as usize % instrument.keymap.len()];
+                let pan = instrument.pan;
+                let Some(index) = (sample as usize).checked_sub(1) else {
+                    self.channels[c].sample = None;
+                    return;
+                };
+                let Some(sample) = module
+                    .samples
+                    .get(index)
+                    .filter(|sample| !sample.data.is_empty())
+                else {
+                    self.channels[c].sample = None;
+                    return;
+                };
+                let period = self.period(note, sample);
+                let channel = &mut self.channels[c];
+                if porta && channel.sample.is_some() {
+                    channel.target = period;
+                } else {
+                    channel.sample = Some(index);
+                    channel.position = 0.0;
+                    channel.backwards = false;
+                    channel.period = period;
+                    channel.target = period;
+                    channel.key_on = true;
+                    channel.fading = false;
+                    channel.fade = 1.0;
+                    channel.envelope_tick = 0;
+                    channel.vibrato_phase = 0;
+                    if let Some(pan) = sample.pan.or(pan) {
+                        channel.pan = pan;
+                    }
+                }
+                if cell.instrument > 0 {
+                    channel.volume = sample.volume.min(64);
+                }
+            }
+            Note::Off => self.key_off(c),
+            Note::Cut => self.channels[c].sample = None,
+            Note::Fade => self.channels[c].fading = true,
+            Note::None => {
+                if cell.instrument > 0
+                    && let Some(volume) = self.sample(c).map(|sample| sample.volume)
+                {
+                    self.channels[c].volume = volume.min(64);
+                }
+            }
+        }
+        // A delayed note takes the volume column with it
+        match cell.volume {
+            Volume::Set(volume) => self.channels[c].volume = volume.min(64),
+            Volume::Pan(pan) => self.channels[c].pan = pan,
+            _ => {}
+        }
+    }
+
+    /// Leave the sustain and fade out, or stop if there's nothing to fade
+    fn key_off(&mut self, c: usize) {
+        let fades = self
+            .instrument(c)
+            .is_some_and(|instrument| instrument.envelope.is_some() || instrument.fadeout > 0.0);
+        let channel = &mut self.channels[c];
+        channel.key_on = false;
+        match fades {
+            true => channel.fading = true,
+            false => channel.sample = None,
+        }
+    }
+
+    fn volume_column_start(&mut self, c: usize, volume: Volume) {
+        let channel = &mut self.channels[c];
+        match volume {
+            Volume::Set(volume) => channel.volume = volume.min(64),
+            Volume::Pan(pan) => channel.pan = pan,
+            Volume::FineUp(by) => slide_volume(&mut channel.volume, by as i32),
+            Volume::FineDown(by) => slide_volume(&mut channel.volume, -(by as i32)),
+            Volume::TonePorta(speed) => {
+                remember(&mut channel.tone_speed, speed);
+            }
+            _ => {}
+        }
+    }
+
+    fn volume_column_tick(&mut self, c: usize, cell: Cell) {
+        let channel = &mut self.channels[c];
+        match cell.volume {
+            Volume::SlideUp(by) => slide_volume(&mut channel.volume, by as i32),
+            Volume::SlideDown(by) => slide_volume(&mut channel.volume, -(by as i32)),
+            Volume::TonePorta(_)
+                if !matches!(
+                    cell.effect,
+                    Effect::TonePorta(_) | Effect::TonePortaVolSlide(_)
+                ) =>
+            {
+                self.tone_porta(c)
+            }
+            _ => {}
+        }
+    }
+
+    /// Effects on the first tick of the row, and the parameters the later
+    /// ticks go by. Jumps and loops are only taken the first time a
+    /// delayed row plays.
+    fn effect_start(&mut self, c: usize, cell: Cell, first: bool, kind: Kind) {
+        let row = self.row;
+        let st3 = kind.st3_effects();
+        let channel = &mut self.channels[c];
+        match cell.effect {
+            Effect::Speed(speed) if speed > 0 => self.speed = speed,
+            Effect::Tempo(tempo) if tempo >= 32 => self.tempo = tempo,
+            Effect::Jump(order) if first => self.jump = Some(order as usize),
+            Effect::Break(row) if first => self.break_row = Some(row as usize),
+            Effect::PatternLoop(0) if first => channel.loop_row = row,
+            Effect::PatternLoop(count) if first => {
+                if channel.loop_count == 0 {
+                    channel.loop_count = count;
+                    self.loop_to = Some(channel.loop_row);
+                } else {
+                    channel.loop_count -= 1;
+                    if channel.loop_count > 0 {
+                        self.loop_to = Some(channel.loop_row);
+                    }
+                }
+            }
+            Effect::PatternDelay(count) if first && self.repeats == 0 => self.repeats = count,
+            Effect::SetVolume(volume) => channel.volume = volume.min(64),
+            Effect::Pan(pan) => channel.pan = pan,
+            Effect::GlobalVolume(volume) => self.global_volume = volume.min(128),
+            Effect::GlobalVolSlide(param) => {
+                remember(&mut channel.global_slide, param);
+            }
+            Effect::FineVolUp(by) => slide_volume(&mut channel.volume, by as i32),
+            Effect::FineVolDown(by) => slide_volume(&mut channel.volume, -(by as i32)),
+            Effect::VolSlide(param)
+            | Effect::TonePortaVolSlide(param)
+            | Effect::VibratoVolSlide(param) => {
+                if st3 {
+                    let param = remember(&mut channel.vol_slide, param);
+                    if let Some(by) = st3_fine_slide(param) {
+                        slide_volume(&mut channel.volume, by);
+                    }
+                } else if kind == Kind::Xm {
+                    remember(&mut channel.vol_slide, param);
+                }
+            }
+            Effect::PortaUp(param) | Effect::PortaDown(param) => {
+                let up = matches!(cell.effect, Effect::PortaUp(_));
+                if st3 {
+                    // One memory for both directions
+                    let param = remember(&mut channel.porta_up, param);
+                    let by = match param {
+                        0xf0.. => (param & 0xf) as f64 * 4.0,
+                        0xe0.. => (param & 0xf) as f64,
+                        _ => 0.0,
+                    };
+                    self.slide_pitch(c, if up { by } else { -by });
+                } else if kind == Kind::Xm {
+                    match up {
+                        true => remember(&mut channel.porta_up, param),
+                        false => remember(&mut channel.porta_down, param),
+                    };
+                }
+            }
+            Effect::FinePortaUp(by) => self.slide_pitch(c, by as f64 * 4.0),
+            Effect::FinePortaDown(by) => self.slide_pitch(c, -(by as f64) * 4.0),
+            Effect::ExtraFinePortaUp(by) => self.slide_pitch(c, by as f64),
+            Effect::ExtraFinePortaDown(by) => self.slide_pitch(c, -(by as f64)),
+            Effect::TonePorta(speed) => {
+                remember(&mut channel.tone_speed, speed);
+            }
+            Effect::Vibrato(param) => {
+                if param >> 4 > 0 {
+                    channel.vibrato = (param & 0xf0) | (channel.vibrato & 0xf);
+                }
+                if param & 0xf > 0 {
+                    channel.vibrato = (channel.vibrato & 0xf0) | (param & 0xf);
+                }
+            }
+            Effect::Arpeggio(param) => {
+                remember(&mut channel.arpeggio_param, param);
+            }
+            Effect::SampleOffset(param) => {
+                let offset = remember(&mut channel.offset, param) as f64 * 256.0;
+                let started = matches!(cell.note, Note::On(_))
+                    && !matches!(cell.volume, Volume::TonePorta(_));
+                if started && let Some(sample) = self.sample(c) {
+                    let fits = offset < sample.data.len() as f64;
+                    let channel = &mut self.channels[c];
+                    match fits {
+                        true => channel.position = offset,
+                        false => channel.sample = None,
+                    }
+                }
+            }
+            Effect::Retrig(param) => {
+                remember(&mut channel.retrig, param);
+                channel.retrig_ticks = 0;
+            }
+            Effect::NoteCut(0) => channel.volume = 0,
+            Effect::KeyOff(0) => self.key_off(c),
+            _ => {}
+        }
+    }
+
+    /// Effects on the ticks after the first
+    fn effect_tick(&mut self, c: usize, cell: Cell, tick: u8, kind: Kind) {
+        let st3 = kind.st3_effects();
+        let channel = &mut self.channels[c];
+        match cell.effect {
+            Effect::VolSlide(param)
+            | Effect::TonePortaVolSlide(param)
+            | Effect::VibratoVolSlide(param) => {
+                let param = match kind {
+                    Kind::Mod => param,
+                    _ => channel.vol_slide,
+                };
+                if !(st3 && st3_fine_slide(param).is_some()) {
+                    slide_volume(&mut channel.volume, volume_slide(param));
+                }
+                match cell.effect {
+                    Effect::TonePortaVolSlide(_) => self.tone_porta(c),
+                    Effect::VibratoVolSlide(_) => self.vibrato(c),
+                    _ => {}
+                }
+            }
+            Effect::PortaUp(param) | Effect::PortaDown(param) => {
+                let up = matches!(cell.effect, Effect::PortaUp(_));
+                let param = match (kind, up) {
+                    (Kind::Mod, _) => param,
+                    (Kind::Xm, false) => channel.porta_down,
+                    _ => channel.porta_up,
+                };
+                if !(st3 && param >= 0xe0) {
+                    let by = param as f64 * 4.0;
+                    self.slide_pitch(c, if up { by } else { -by });
+                }
+            }
+            Effect::TonePorta(_) => self.tone_porta(c),
+            Effect::Vibrato(_) => self.vibrato(c),
+            Effect::Arpeggio(_) => {
+                let param = channel.arpeggio_param;
+                channel.arpeggio = match tick % 3 {
+                    0 => 0,
+                    1 => param >> 4,
+                    _ => param & 0xf,
+                };
+            }
+            Effect::GlobalVolSlide(_) => {
+                // Impulse Tracker's global volume goes to 128, the others'
+                // to 64
+                let scale = if kind == Kind::It { 1 } else { 2 };
+                let by = volume_slide(channel.global_slide) * scale;
+                self.global_volume = (self.global_volume as i32 + by).clamp(0, 128) as u8;
+            }
+            Effect::Retrig(_) => {
+                let param = channel.retrig;
+                let every = param & 0xf;
+                channel.retrig_ticks += 1;
+                if every > 0 && channel.retrig_ticks >= every {
+                    channel.retrig_ticks = 0;
+                    channel.position = 0.0;
+                    channel.backwards = false;
+                    let volume = channel.volume as i32;
+                    channel.volume = match param >> 4 {
+                        change @ 1..=5 => volume - (1 << (change - 1)),
+                        6 => volume * 2 / 3,
+                        7 => volume / 2,
+                        change @ 9..=0xd => volume + (1 << (change - 9)),
+                        0xe => volume * 3 / 2,
+                        0xf => volume * 2,
+                        _ => volume,
+                    }
+                    .clamp(0, 64) as u8;
+                }
+            }
+            Effect::NoteCut(at) if at == tick => channel.volume = 0,
+            Effect::KeyOff(at) if at == tick => self.key_off(c),
+            _ => {}
+        }
+    }
+
+    fn tone_porta(&mut self, c: usize) {
+        let channel = &mut self.channels[c];
+        let speed = channel.tone_speed as f64 * 4.0;
+        channel.period = match channel.period < channel.target {
+            true => (channel.period + speed).min(channel.target),
+            false => (channel.period - speed).max(channel.target),
+        };
+    }
+
+    fn vibrato(&mut self, c: usize) {
+        let channel = &mut self.channels[c];
+        let (speed, depth) = (channel.vibrato >> 4, channel.vibrato & 0xf);
+        let phase = channel.vibrato_phase as f64 / 64.0 * TAU;
+        channel.vibrato_offset = phase.sin() * depth as f64 * 8.0;
+        channel.vibrato_phase = (channel.vibrato_phase + speed) % 64;
+    }
+
+    /// Move the envelope and fade on a tick
+    fn update_voice(&mut self, c: usize) {
+        if self.channels[c].sample.is_none() {
+            return;
+        }
+        let (envelope, fadeout) = match self.instrument(c) {
+            Some(instrument) => {
+                let channel = &self.channels[c];
+                let envelope = instrument.envelope.as_ref().map(|envelope| {
+                    let tick = channel.envelope_tick;
+                    let value = envelope.value(tick);
+                    let held =
+                        envelope.looped.is_some() || (channel.key_on && envelope.sustain.is_some());
+                    let over = !held && tick >= envelope.end() && value == 0.0;
+                    (value, envelope.next(tick, channel.key_on), over)
+                });
+                (envelope, instrument.fadeout)
+            }
+            None => (None, 0.0),
+        };
+        let channel = &mut self.channels[c];
+        channel.envelope = match envelope {
+            Some((value, next, over)) => {
+                channel.envelope_tick = next;
+                if over {
+                    channel.sample = None;
+                }
+                value
+            }
+            None => 1.0,
+        };
+        if channel.fading {
+            channel.fade -= fadeout;
+            if channel.fade <= 0.0 {
+                channel.sample = None;
+            }
+        }
+    }
+
+    /// Mix `frames` frames of every channel into `out`, or just move the
+    /// voices on as far if there's nothing to mix into
+    fn mix(&mut self, mut out: Option<&mut [f32]>, frames: usize) {
+        let module = Arc::clone(&self.module);
+        let global = self.global_volume as f32 / 128.0 * self.gain;
+        for c in 0..self.channels.len() {
+            let Some(sample) = self.sample(c) else {
+                continue;
+            };
+            let channel = &self.channels[c];
+            let arpeggio = 2f64.powf(channel.arpeggio as f64 / 12.0);
+            let frequency = self.frequency(channel.period + channel.vibrato_offset, sample);
+            let step = frequency * arpeggio / self.rate as f64;
+            let sample = &module.samples[channel.sample.unwrap()];
+            let channel = &mut self.channels[c];
+
+            let Some(out) = out.as_deref_mut() else {
+                channel.position += match channel.backwards {
+                    true => -step * frames as f64,
+                    false => step * frames as f64,
+                };
+                if !wrap(channel, sample) {
+                    channel.sample = None;
+                }
+                continue;
+            };
+
+            let volume = channel.volume as f32 / 64.0 * sample.global_volume as f32 / 64.0
+                * channel.envelope
+                * channel.fade.max(0.0)
+                * global;
+            let pan = channel.pan as f32;
+            let left = volume * ((255.0 - pan) / 128.0).min(1.0);
+            let right = volume * (pan / 128.0).min(1.0);
+            for frame in out.chunks_exact_mut(2).take(frames) {
+                let value = interpolate(channel, sample);
+                frame[0] += value * left;
+                frame[1] += value * right;
+                channel.position += if channel.backwards { -step } else { step };
+                if !wrap(channel, sample) {
+                    channel.sample = None;
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// The sample between the two either side of the voice's position
+fn interpolate(channel: &Channel, sample: &Sample) -> f32 {
+    let data = &sample.data;
+    let index = (channel.position as usize).min(data.len() - 1);
+    let next = match index + 1 {
+        next if sample.looping == Loop::Forward && next >= sample.loop_end => sample.loop_start,
+        next => next.min(data.len() - 1),
+    };
+    let through = (channel.position - index as f64) as f32;
+    data[index] + (data[next] - data[index]) * through
+}
+
+/// Bring the voice's position back into the sample's loop, returning
+/// whether it is still playing
+fn wrap(channel: &mut Channel, sample: &Sample) -> bool {
+    let (start, end) = (sample.loop_start as f64, sample.loop_end as f64);
+    match sample.looping {
+        Loop::None => channel.position < sample.data.len() as f64,
+        Loop::Forward => {
+            if channel.position >= end {
+                channel.position = start + (channel.position - start) % (end - start);
+            }
+            true
+        }
+        Loop::PingPong => {
+            let outside = match channel.backwards {
+                true => channel.position < start,
+                false => channel.position >= end,
+            };
+            if outside {
+                // How far round the loop, there and back, the voice is
+                let length = end - start;
+                let travelled = match channel.backwards {
+                    true => 2.0 * length - (channel.position - start),
+                    false => channel.position - start,
+                }
+                .rem_euclid(2.0 * length);
+                channel.backwards = travelled >= length;
+                channel.position = match channel.backwards {
+                    true => start + 2.0 * length - travelled,
+                    false => start + travelled,
+                };
+            }
+            true
+        }
+    }
+}
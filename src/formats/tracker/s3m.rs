@@ -0,0 +1,213 @@
+//! Scream Tracker 3 modules. AdLib instruments are read as silent samples.
+
+use symphonia::core::errors::{Result, decode_error};
+
+use super::module::{
+    Bytes, Cell, Effect, Instrument, Kind, Loop, Module, Note, Pattern, Sample, Volume, pcm_16,
+    signed_8, unsigned_8,
+};
+
+/// Where the signature is
+pub const SIGNATURE_OFFSET: u64 = 44;
+
+const ROWS: usize = 64;
+
+/// Channels of the pattern data
+const CHANNELS: usize = 32;
+
+/// Scream Tracker's default pans, for channels set left and right
+const LEFT: u8 = 0x3 * 17;
+const RIGHT: u8 = 0xc * 17;
+
+/// `dp` when the default pans follow the pointers
+const DEFAULT_PANS: u8 = 252;
+
+/// Scream Tracker 3 and Impulse Tracker effects, commands `A` (1) to `Z`
+pub fn effect(command: u8, param: u8, kind: Kind) -> Effect {
+    let (x, y) = (param >> 4, param & 0xf);
+    match command {
+        1 => Effect::Speed(param),
+        2 => Effect::Jump(param),
+        3 if kind == Kind::It => Effect::Break(param),
+        // Scream Tracker writes the row in decimal
+        3 => Effect::Break(x * 10 + y),
+        4 => Effect::VolSlide(param),
+        5 => Effect::PortaDown(param),
+        6 => Effect::PortaUp(param),
+        7 => Effect::TonePorta(param),
+        8 => Effect::Vibrato(param),
+        10 => Effect::Arpeggio(param),
+        11 => Effect::VibratoVolSlide(param),
+        12 => Effect::TonePortaVolSlide(param),
+        15 => Effect::SampleOffset(param),
+        17 => Effect::Retrig(param),
+        19 => match x {
+            0x8 => Effect::Pan(y * 17),
+            0xb => Effect::PatternLoop(y),
+            0xc => Effect::NoteCut(y),
+            0xd => Effect::NoteDelay(y),
+            0xe => Effect::PatternDelay(y),
+            _ => Effect::None,
+        },
+        // Below 0x20 are tempo slides
+        20 if param >= 0x20 => Effect::Tempo(param),
+        22 if kind == Kind::It => Effect::GlobalVolume(param.min(128)),
+        22 => Effect::GlobalVolume(param.min(64) * 2),
+        23 if kind == Kind::It => Effect::GlobalVolSlide(param),
+        24 if kind == Kind::It => Effect::Pan(param),
+        24 if param <= 0x80 => Effect::Pan((param as u16 * 255 / 0x80) as u8),
+        _ => Effect::None,
+    }
+}
+
+fn note(value: u8) -> Note {
+    match value {
+        254 => Note::Cut,
+        // Octave in the high nibble; C-4 plays at the sample's own rate
+        _ if value >> 4 < 10 && value & 0xf < 12 => {
+            Note::On((value >> 4) * 12 + (value & 0xf) + 12)
+        }
+        _ => Note::None,
+    }
+}
+
+fn pattern(data: &[u8], channels: &[Option<usize>], count: usize) -> Pattern {
+    let mut pattern = Pattern::empty(ROWS, count);
+    let mut bytes = data.iter().copied();
+    let mut row = 0;
+    while row < ROWS {
+        let Some(what) = bytes.next() else {
+            break;
+        };
+        if what == 0 {
+            row += 1;
+            continue;
+        }
+        let mut cell = Cell::default();
+        if what & 0x20 != 0 {
+            cell.note = note(bytes.next().unwrap_or(255));
+            cell.instrument = bytes.next().unwrap_or(0);
+        }
+        if what & 0x40 != 0 {
+            let volume = bytes.next().unwrap_or(255);
+            if volume <= 64 {
+                cell.volume = Volume::Set(volume);
+            }
+        }
+        if what & 0x80 != 0 {
+            let command = bytes.next().unwrap_or(0);
+            cell.effect = effect(command, bytes.next().unwrap_or(0), Kind::S3m);
+        }
+        if let Some(channel) = channels[(what & 0x1f) as usize] {
+            pattern.cells[row * count + channel] = cell;
+        }
+    }
+    pattern
+}
+
+fn sample(bytes: &Bytes, at: usize, signed: bool) -> Result<Sample> {
+    let mut sample = Sample {
+        name: bytes.text(at + 48, 28),
+        data: Vec::new(),
+        looping: Loop::None,
+        loop_start: bytes.u32(at + 20)? as usize,
+        loop_end: bytes.u32(at + 24)? as usize,
+        volume: bytes.u8(at + 28)?.min(64),
+        global_volume: 64,
+        pan: None,
+        c5_speed: match bytes.u32(at + 32)? {
+            0 => 8363.0,
+            rate => rate as f64,
+        },
+    };
+    // Anything but a sampled instrument is silent
+    if bytes.u8(at)? != 1 {
+        return Ok(sample);
+    }
+    let flags = bytes.u8(at + 31)?;
+    let offset = ((bytes.u8(at + 13)? as usize) << 16 | bytes.u16(at + 14)? as usize) * 16;
+    let length = bytes.u32(at + 16)? as usize;
+    // Stereo samples have the left channel first, which is all that's read
+    sample.data = match (flags & 4 != 0, signed) {
+        (true, _) => pcm_16(bytes.up_to(offset, length * 2), signed),
+        (false, true) => signed_8(bytes.up_to(offset, length)),
+        (false, false) => unsigned_8(bytes.up_to(offset, length)),
+    };
+    if flags & 1 != 0 {
+        sample.looping = Loop::Forward;
+    }
+    sample.fix_loop();
+    Ok(sample)
+}
+
+pub fn load(data: &[u8]) -> Result<Module> {
+    let bytes = Bytes(data);
+    if bytes.slice(SIGNATURE_OFFSET as usize, 4)? != b"SCRM" {
+        return decode_error("s3m: not a Scream Tracker 3 module");
+    }
+    let order_count = bytes.u16(32)? as usize;
+    let instrument_count = bytes.u16(34)? as usize;
+    let pattern_count = bytes.u16(36)? as usize;
+    // Sample format 1 is signed, 2 unsigned
+    let signed = bytes.u16(42)? == 1;
+    let stereo = bytes.u8(51)? & 0x80 != 0;
+    let settings = bytes.slice(64, CHANNELS)?;
+    let orders = bytes.slice(96, order_count)?.to_vec();
+    let pointers = 96 + order_count;
+    // Pointers are in paragraphs of 16 bytes
+    let pointer = |index: usize| bytes.u16(pointers + index * 2).map(|at| at as usize * 16);
+
+    // Disabled and unused channels are left out
+    let mut channels = [None; CHANNELS];
+    let mut pans = Vec::new();
+    let default_pans = pointers + (instrument_count + pattern_count) * 2;
+    for (channel, &setting) in settings.iter().enumerate() {
+        if setting >= 16 {
+            continue;
+        }
+        channels[channel] = Some(pans.len());
+        let default = bytes.u8(default_pans + channel).unwrap_or(0);
+        pans.push(match setting {
+            _ if !stereo => 128,
+            _ if bytes.u8(53)? == DEFAULT_PANS && default & 0x20 != 0 => (default & 0xf) * 17,
+            0..8 => LEFT,
+            _ => RIGHT,
+        });
+    }
+    let count = pans.len();
+    if count == 0 {
+        return decode_error("s3m: no channels");
+    }
+
+    let mut samples = Vec::with_capacity(instrument_count);
+    for index in 0..instrument_count {
+        samples.push(sample(&bytes, pointer(index)?, signed)?);
+    }
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for index in 0..pattern_count {
+        let at = pointer(instrument_count + index)?;
+        let data = match at {
+            0 => &[][..],
+            _ => bytes.up_to(at + 2, bytes.u16(at)? as usize),
+        };
+        patterns.push(pattern(data, &channels, count));
+    }
+
+    Ok(Module {
+        kind: Kind::S3m,
+        title: bytes.text(0, 28),
+        message: String::new(),
+        channels: count,
+        orders,
+        patterns,
+        instruments: (1..=samples.len() as u16)
+            .map(Instrument::of_sample)
+            .collect(),
+        samples,
+        speed: bytes.u8(49)?,
+        tempo: bytes.u8(50)?,
+        global_volume: bytes.u8(48)?.min(64) * 2,
+        pans,
+        linear: false,
+    })
+}
@@ -0,0 +1,241 @@
+//! FastTracker 2 extended modules
+
+use symphonia::core::errors::{Result, decode_error};
+
+use super::module::{
+    Bytes, Cell, Effect, Envelope, Instrument, Kind, Loop, Module, NOTES, Note, Pattern, Sample,
+    Volume,
+};
+use super::protracker;
+
+pub const SIGNATURE: &[u8] = b"Extended Module: ";
+
+/// Notes of the keymap, C-0 to B-7, which start an octave below the notes
+/// here
+const KEYS: usize = 96;
+const KEY_OFFSET: u8 = 12;
+
+const NOTE_OFF: u8 = 97;
+
+/// Envelope points there is room for
+const POINTS: usize = 12;
+
+/// Fadeout is taken off a volume of this much each tick
+const FADE_SCALE: f32 = 32768.0;
+
+fn effect(command: u8, param: u8) -> Effect {
+    let (x, y) = (param >> 4, param & 0xf);
+    match command {
+        0x0..=0xf => protracker::effect(command, param),
+        // G
+        16 => Effect::GlobalVolume(param.min(64) * 2),
+        // H
+        17 => Effect::GlobalVolSlide(param),
+        // K
+        20 => Effect::KeyOff(param),
+        // R
+        27 => Effect::Retrig(param),
+        // X1y and X2y
+        33 if x == 1 => Effect::ExtraFinePortaUp(y),
+        33 if x == 2 => Effect::ExtraFinePortaDown(y),
+        _ => Effect::None,
+    }
+}
+
+fn volume(value: u8) -> Volume {
+    let y = value & 0xf;
+    match value {
+        0x10..=0x50 => Volume::Set(value - 0x10),
+        0x60..=0x6f => Volume::SlideDown(y),
+        0x70..=0x7f => Volume::SlideUp(y),
+        0x80..=0x8f => Volume::FineDown(y),
+        0x90..=0x9f => Volume::FineUp(y),
+        0xc0..=0xcf => Volume::Pan(y * 17),
+        0xf0..=0xff => Volume::TonePorta(y * 16),
+        _ => Volume::None,
+    }
+}
+
+fn pattern(data: &[u8], rows: usize, channels: usize) -> Pattern {
+    let mut pattern = Pattern::empty(rows, channels);
+    let mut bytes = data.iter().copied();
+    for cell in &mut pattern.cells {
+        let Some(first) = bytes.next() else {
+            break;
+        };
+        // A set high bit says which fields follow; otherwise all five do,
+        // starting with this byte
+        let (fields, note) = match first & 0x80 {
+            0 => (0x1e, Some(first)),
+            _ => (first, None),
+        };
+        let mut field = |bit: u8| match fields & bit {
+            0 => 0,
+            _ => bytes.next().unwrap_or(0),
+        };
+        let note = note.unwrap_or_else(|| field(0x01));
+        let (instrument, volume_column) = (field(0x02), field(0x04));
+        let (command, param) = (field(0x08), field(0x10));
+        *cell = Cell {
+            note: match note {
+                1..=96 => Note::On(note - 1 + KEY_OFFSET),
+                NOTE_OFF => Note::Off,
+                _ => Note::None,
+            },
+            instrument,
+            volume: volume(volume_column),
+            effect: effect(command, param),
+        };
+    }
+    pattern
+}
+
+/// Sample data is stored as differences from one sample to the next
+fn undelta(data: &[u8], wide: bool) -> Vec<f32> {
+    match wide {
+        true => data
+            .chunks_exact(2)
+            .scan(0i16, |last, pair| {
+                *last = last.wrapping_add(i16::from_le_bytes([pair[0], pair[1]]));
+                Some(*last as f32 / 32768.0)
+            })
+            .collect(),
+        false => data
+            .iter()
+            .scan(0i8, |last, &byte| {
+                *last = last.wrapping_add(byte as i8);
+                Some(*last as f32 / 128.0)
+            })
+            .collect(),
+    }
+}
+
+fn envelope(bytes: &Bytes, at: usize) -> Result<Option<Envelope>> {
+    let flags = bytes.u8(at + 233)?;
+    if flags & 1 == 0 {
+        return Ok(None);
+    }
+    let count = (bytes.u8(at + 225)? as usize).min(POINTS);
+    let mut points = Vec::with_capacity(count);
+    for point in 0..count {
+        let tick = bytes.u16(at + 129 + point * 4)?;
+        let value = bytes.u16(at + 131 + point * 4)?.min(64) as u8;
+        points.push((tick, value));
+    }
+    let sustain = bytes.u8(at + 227)? as usize;
+    let looped = (bytes.u8(at + 228)? as usize, bytes.u8(at + 229)? as usize);
+    Ok(Envelope {
+        points,
+        sustain: (flags & 2 != 0).then_some((sustain, sustain)),
+        looped: (flags & 4 != 0).then_some(looped),
+    }
+    .checked())
+}
+
+pub fn load(data: &[u8]) -> Result<Module> {
+    let bytes = Bytes(data);
+    if bytes.slice(0, SIGNATURE.len())? != SIGNATURE {
+        return decode_error("xm: not a FastTracker 2 module");
+    }
+    let length = (bytes.u16(64)? as usize).min(256);
+    let channels = bytes.u16(68)? as usize;
+    if channels == 0 || channels > 64 {
+        return decode_error("xm: bad channel count");
+    }
+    let pattern_count = bytes.u16(70)? as usize;
+    let instrument_count = bytes.u16(72)? as usize;
+
+    let mut at = 60 + bytes.u32(60)? as usize;
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for _ in 0..pattern_count {
+        let header = bytes.u32(at)? as usize;
+        let rows = bytes.u16(at + 5)? as usize;
+        let packed = bytes.u16(at + 7)? as usize;
+        patterns.push(pattern(
+            bytes.up_to(at + header, packed),
+            rows.max(1),
+            channels,
+        ));
+        at += header + packed;
+    }
+
+    let mut instruments = Vec::with_capacity(instrument_count);
+    let mut samples = Vec::new();
+    for _ in 0..instrument_count {
+        let size = bytes.u32(at)? as usize;
+        let count = bytes.u16(at + 27)? as usize;
+        let mut instrument = Instrument {
+            name: bytes.text(at + 4, 22),
+            keymap: (0..NOTES as u8).map(|note| (note, 0)).collect(),
+            envelope: None,
+            fadeout: 0.0,
+            pan: None,
+        };
+        if count == 0 {
+            instruments.push(instrument);
+            at += size;
+            continue;
+        }
+        let header = bytes.u32(at + 29)? as usize;
+        let first = samples.len() as u16 + 1;
+        for (key, &sample) in bytes.slice(at + 33, KEYS)?.iter().enumerate() {
+            if (sample as usize) < count {
+                instrument.keymap[key + KEY_OFFSET as usize].1 = first + sample as u16;
+            }
+        }
+        instrument.envelope = envelope(&bytes, at)?;
+        instrument.fadeout = bytes.u16(at + 239)? as f32 / FADE_SCALE;
+        instruments.push(instrument);
+        at += size;
+
+        let mut lengths = Vec::with_capacity(count);
+        for index in 0..count {
+            let h = at + index * header;
+            let kind = bytes.u8(h + 14)?;
+            // 16-bit samples count their lengths in bytes all the same
+            let unit = if kind & 0x10 != 0 { 2 } else { 1 };
+            let loop_start = bytes.u32(h + 4)? as usize / unit;
+            let finetune = bytes.u8(h + 13)? as i8 as f64 / 128.0;
+            let relative = bytes.u8(h + 16)? as i8 as f64;
+            lengths.push((bytes.u32(h)? as usize, unit == 2));
+            samples.push(Sample {
+                name: bytes.text(h + 18, 22),
+                data: Vec::new(),
+                looping: match kind & 3 {
+                    1 => Loop::Forward,
+                    2 => Loop::PingPong,
+                    _ => Loop::None,
+                },
+                loop_start,
+                loop_end: loop_start + bytes.u32(h + 8)? as usize / unit,
+                volume: bytes.u8(h + 12)?.min(64),
+                global_volume: 64,
+                pan: Some(bytes.u8(h + 15)?),
+                c5_speed: 8363.0 * 2f64.powf((relative + finetune) / 12.0),
+            });
+        }
+        at += count * header;
+        let loaded = samples.len() - count;
+        for (sample, (length, wide)) in samples[loaded..].iter_mut().zip(lengths) {
+            sample.data = undelta(bytes.up_to(at, length), wide);
+            sample.fix_loop();
+            at += length;
+        }
+    }
+
+    Ok(Module {
+        kind: Kind::Xm,
+        title: bytes.text(17, 20),
+        message: String::new(),
+        channels,
+        orders: bytes.slice(80, length)?.to_vec(),
+        patterns,
+        samples,
+        instruments,
+        speed: bytes.u16(76)?.min(255) as u8,
+        tempo: bytes.u16(78)?.min(255) as u8,
+        global_volume: 128,
+        pans: vec![128; channels],
+        linear: bytes.u16(74)? & 1 != 0,
+    })
+}
@@ -1,5 +1,21 @@
 pub mod player;
+pub mod chapters;
+pub mod codecs;
+pub mod config;
+pub mod cue;
 pub mod db;
+pub mod formats;
+pub mod library;
+pub mod playlist;
+pub mod probe;
+pub mod resume;
+pub mod scanner;
+pub mod scrobble;
+pub mod streams;
+pub mod tags;
+pub mod watcher;
 pub mod cli;
+#[cfg(feature = "server")]
+pub mod server;
 
 // Library crate root for rustyplayer; main.rs will call into `cli::run()`.
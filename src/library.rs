@@ -0,0 +1,316 @@
+//! Typed API for using the library from other programs.
+//!
+//! `Library` wraps the database behind plain lookups, paged listings, upserts
+//! and deletions, so that tools built on this crate need no SQL and get a
+//! matchable `LibraryError` rather than an `anyhow::Error`.
+//!
+//! ```no_run
+//! use rustyplayer::library::{Library, Page};
+//!
+//! let library = Library::open("library.db".as_ref())?;
+//! let loved = library.tracks("loved:yes", &[], Page::default())?;
+//! println!("{} of {} loved tracks", loved.items.len(), loved.total);
+//! # Ok::<(), rustyplayer::library::LibraryError>(())
+//! ```
+
+use std::path::Path;
+use thiserror::Error;
+
+pub use crate::chapters::Chapter;
+use crate::db::DB;
+pub use crate::db::{
+    Album, Artist, Bookmark, DbOptions, Expr, Love, NewTrack, Page, ParseError, Playlist, SortKey,
+    Track, TrackId,
+};
+
+#[derive(Debug, Error)]
+pub enum LibraryError {
+    #[error("No track with id {0}")]
+    TrackNotFound(TrackId),
+    #[error("Invalid query: {0}")]
+    Query(#[from] ParseError),
+    #[error("The library database is open read-only")]
+    ReadOnly,
+    #[error("Library database error: {0}")]
+    Database(#[source] Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl From<anyhow::Error> for LibraryError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<ParseError>() {
+            Ok(error) => LibraryError::Query(error),
+            Err(error) => LibraryError::Database(error.into()),
+        }
+    }
+}
+
+pub type Result<T, E = LibraryError> = std::result::Result<T, E>;
+
+/// One page of a listing and the number of items in the whole listing
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paged<T> {
+    pub items: Vec<T>,
+    pub total: usize,
+    pub page: Page,
+}
+
+/// A music library
+pub struct Library {
+    db: DB,
+}
+
+impl From<DB> for Library {
+    fn from(db: DB) -> Self {
+        Self { db }
+    }
+}
+
+impl Library {
+    /// Open or create the library at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            db: DB::open(path)?,
+        })
+    }
+
+    /// Open the library with a busy timeout, or read-only (see `DB::open_with`)
+    pub fn open_with(path: &Path, options: &DbOptions) -> Result<Self> {
+        Ok(Self {
+            db: DB::open_with(path, options)?,
+        })
+    }
+
+    /// The database underneath, for anything this API doesn't cover
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.db.is_read_only()
+    }
+
+    fn writable(&self) -> Result<()> {
+        match self.db.is_read_only() {
+            true => Err(LibraryError::ReadOnly),
+            false => Ok(()),
+        }
+    }
+
+    pub fn track_count(&self) -> Result<usize> {
+        Ok(self.db.track_count()?)
+    }
+
+    pub fn track(&self, id: TrackId) -> Result<Option<Track>> {
+        Ok(self.db.track(id)?)
+    }
+
+    pub fn track_by_path(&self, path: &Path) -> Result<Option<Track>> {
+        Ok(self.db.track_by_path(&path.to_string_lossy())?)
+    }
+
+    /// Tracks matching a query in the `library` command's language (an empty
+    /// query matches everything), in `sort` order
+    pub fn tracks(&self, query: &str, sort: &[SortKey], page: Page) -> Result<Paged<Track>> {
+        let expr = Expr::parse(query)?;
+        Ok(Paged {
+            items: self.db.query_tracks(&expr, sort, page)?,
+            total: self.db.count_query_tracks(&expr)?,
+            page,
+        })
+    }
+
+    /// Chapters of an audiobook or other long track, in order
+    pub fn chapters(&self, id: TrackId) -> Result<Vec<Chapter>> {
+        Ok(self.db.chapters(id)?)
+    }
+
+    /// Bookmarks of a track, in the order they come in it
+    pub fn bookmarks(&self, id: TrackId) -> Result<Vec<Bookmark>> {
+        Ok(self.db.bookmarks(id)?)
+    }
+
+    /// Where playback of a track last stopped, in milliseconds
+    pub fn resume_position(&self, id: TrackId) -> Result<Option<i64>> {
+        Ok(self.db.resume_position(id)?)
+    }
+
+    /// Best full-text matches for `text` across titles, artists, albums and more
+    pub fn search(&self, text: &str, limit: usize) -> Result<Vec<Track>> {
+        Ok(self.db.search(text, limit)?)
+    }
+
+    /// Albums by album artist, year and title
+    pub fn albums(&self, page: Page) -> Result<Paged<Album>> {
+        Ok(Paged {
+            items: self.db.album_list(page)?,
+            total: self.db.count_album_list()?,
+            page,
+        })
+    }
+
+    pub fn album(&self, id: i64) -> Result<Option<Album>> {
+        Ok(self.db.album(id)?)
+    }
+
+    /// Tracks of an album in disc and track order
+    pub fn album_tracks(&self, id: i64) -> Result<Vec<Track>> {
+        Ok(self.db.album_tracks(id)?)
+    }
+
+    /// Artists by name
+    pub fn artists(&self, page: Page) -> Result<Paged<Artist>> {
+        Ok(Paged {
+            items: self.db.artist_list(page)?,
+            total: self.db.count_artist_list()?,
+            page,
+        })
+    }
+
+    pub fn artist(&self, id: i64) -> Result<Option<Artist>> {
+        Ok(self.db.artist(id)?)
+    }
+
+    /// Albums with this album artist, oldest first
+    pub fn artist_albums(&self, id: i64) -> Result<Vec<Album>> {
+        Ok(self.db.artist_albums(id)?)
+    }
+
+    pub fn playlists(&self, page: Page) -> Result<Paged<Playlist>> {
+        Ok(Paged {
+            items: self.db.playlists(page)?,
+            total: self.db.count_playlists()?,
+            page,
+        })
+    }
+
+    pub fn playlist(&self, id: i64) -> Result<Option<Playlist>> {
+        Ok(self.db.playlist(id)?)
+    }
+
+    /// Tracks of a playlist in order; smart playlists are evaluated now
+    pub fn playlist_tracks(&self, id: i64) -> Result<Vec<Track>> {
+        Ok(self.db.playlist_tracks(id)?)
+    }
+
+    /// Add a track, or update the one at the same path. Ids, ratings and play
+    /// history of existing tracks are kept.
+    pub fn upsert_track(&self, track: &NewTrack) -> Result<TrackId> {
+        self.writable()?;
+        Ok(self.db.insert_track(track)?)
+    }
+
+    /// Delete a track with its play history and playlist entries
+    pub fn delete_track(&self, id: TrackId) -> Result<()> {
+        self.writable()?;
+        match self.db.delete_track(id)? {
+            true => Ok(()),
+            false => Err(LibraryError::TrackNotFound(id)),
+        }
+    }
+
+    /// Bookmark a position in a track. Returns the bookmark's id.
+    pub fn add_bookmark(&self, id: TrackId, name: &str, position_ms: i64) -> Result<i64> {
+        self.writable()?;
+        if self.db.track(id)?.is_none() {
+            return Err(LibraryError::TrackNotFound(id));
+        }
+        Ok(self.db.add_bookmark(id, name, position_ms)?)
+    }
+
+    /// Returns whether there was such a bookmark
+    pub fn delete_bookmark(&self, id: i64) -> Result<bool> {
+        self.writable()?;
+        Ok(self.db.delete_bookmark(id)?)
+    }
+
+    /// Delete the tracks of a file, or of every file under a directory.
+    /// Returns how many were deleted.
+    pub fn delete_path(&self, path: &Path) -> Result<usize> {
+        self.writable()?;
+        let removed = self.db.remove_tracks(&path.to_string_lossy())?;
+        self.db.prune_catalog()?;
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::NamedTempFile;
+
+    #[test]
+    fn test_library_api() {
+        let file = NamedTempFile::new().unwrap();
+        let library = Library::open(file.path()).unwrap();
+        let track = |path: &str, title: &str| NewTrack {
+            path: path.into(),
+            title: Some(title.into()),
+            artist: Some("Boards of Canada".into()),
+            album: Some("Geogaddi".into()),
+            ..Default::default()
+        };
+        let id = library
+            .upsert_track(&track("/music/geogaddi/01.flac", "Ready Lets Go"))
+            .unwrap();
+        let chapter = Chapter {
+            title: Some("Intro".into()),
+            start_ms: 0,
+            end_ms: Some(60_000),
+        };
+        let other = library
+            .upsert_track(&NewTrack {
+                chapters: vec![chapter.clone()],
+                ..track("/music/geogaddi/02.flac", "Music Is Math")
+            })
+            .unwrap();
+        assert_eq!(library.chapters(other).unwrap(), vec![chapter]);
+        let bookmark = library.add_bookmark(other, "Drop", 90_000).unwrap();
+        assert_eq!(library.bookmarks(other).unwrap()[0].id, bookmark);
+        assert!(library.delete_bookmark(bookmark).unwrap());
+        assert_eq!(
+            library
+                .upsert_track(&track("/music/geogaddi/01.flac", "Ready Lets Go"))
+                .unwrap(),
+            id
+        );
+
+        let page = Page {
+            limit: 1,
+            offset: 0,
+        };
+        let listing = library.tracks("album:geogaddi", &[], page).unwrap();
+        assert_eq!((listing.items.len(), listing.total), (1, 2));
+        let albums = library.albums(Page::default()).unwrap();
+        assert_eq!((albums.total, albums.items[0].track_count), (1, 2));
+        assert_eq!(library.artists(Page::default()).unwrap().total, 1);
+        let found = library
+            .track_by_path(Path::new("/music/geogaddi/02.flac"))
+            .unwrap();
+        assert_eq!(found.map(|t| t.id), Some(other));
+
+        library.delete_track(id).unwrap();
+        assert!(matches!(
+            library.delete_track(id),
+            Err(LibraryError::TrackNotFound(missing)) if missing == id
+        ));
+        assert!(matches!(
+            library.tracks("year:>", &[], Page::default()),
+            Err(LibraryError::Query(_))
+        ));
+        assert_eq!(
+            library.delete_path(Path::new("/music/geogaddi")).unwrap(),
+            1
+        );
+        assert_eq!(library.albums(Page::default()).unwrap().total, 0);
+
+        let options = DbOptions {
+            read_only: true,
+            ..Default::default()
+        };
+        let reader = Library::open_with(file.path(), &options).unwrap();
+        assert!(matches!(
+            reader.upsert_track(&track("/x.flac", "X")),
+            Err(LibraryError::ReadOnly)
+        ));
+    }
+}
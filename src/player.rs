@@ -1,8 +1,15 @@
+use std::collections::VecDeque;
 use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use symphonia::core::meta::Tag;
 use thiserror::Error;
 
+use crate::chapters::Chapter;
+use crate::streams::{AudioTrack, StreamChoice};
+use crate::tags;
+
 #[derive(Debug, Error)]
 pub enum PlayerError {
     #[error("Audio feature not enabled")]
@@ -24,7 +31,8 @@ pub enum PlayerError {
 }
 
 /// Current state of the player
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum PlayerState {
     Stopped,
     Playing,
@@ -32,24 +40,101 @@ pub enum PlayerState {
 }
 
 /// Status information about the current playback
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PlayerStatus {
     pub state: PlayerState,
     pub position: Option<Duration>,
     pub duration: Option<Duration>,
     pub current_file: Option<PathBuf>,
     pub volume: f32,
+    /// Chapters of the file playing, if it has any
+    pub chapters: Vec<Chapter>,
+    /// Index into `chapters` of the one playing
+    pub chapter: Option<usize>,
+}
+
+impl PlayerStatus {
+    /// Position within the chapter playing, and the chapter's length
+    pub fn chapter_progress(&self) -> Option<(Duration, Option<Duration>)> {
+        let chapter = self.chapters.get(self.chapter?)?;
+        let start = Duration::from_millis(chapter.start_ms.max(0) as u64);
+        let length = chapter
+            .end_ms
+            .map(|end| Duration::from_millis((end - chapter.start_ms).max(0) as u64));
+        Some((self.position?.saturating_sub(start), length))
+    }
+}
+
+/// How far into a chapter `Player::prev_chapter` restarts it rather than
+/// going back to the one before
+const RESTART_CHAPTER: Duration = Duration::from_secs(3);
+
+/// Which ReplayGain values set the loudness of each file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplayGain {
+    #[default]
+    Off,
+    Track,
+    Album,
+}
+
+impl ReplayGain {
+    /// Volume multiplier for a file with these tags, kept low enough that the
+    /// loudest sample doesn't clip. Files without ReplayGain tags play as they are.
+    pub fn factor(self, tags: &[Tag]) -> f32 {
+        let gain = match self {
+            ReplayGain::Off => None,
+            ReplayGain::Track => tags::replaygain_from_tags(tags, false),
+            ReplayGain::Album => tags::replaygain_from_tags(tags, true),
+        };
+        let Some(gain) = gain else {
+            return 1.0;
+        };
+        let factor = 10f64.powf(gain.db / 20.0);
+        match gain.peak.filter(|peak| *peak > 0.0) {
+            Some(peak) => factor.min(1.0 / peak) as f32,
+            None => factor as f32,
+        }
+    }
+}
+
+/// How the player opens its output and sets loudness
+#[derive(Debug, Clone, PartialEq)]
+pub struct PlayerOptions {
+    /// Output device name; the system default if `None`
+    pub device: Option<String>,
+    /// Starting volume, 0.0 to 1.0
+    pub volume: f32,
+    pub replaygain: ReplayGain,
+    /// Audio stream to play from files with several
+    pub stream: StreamChoice,
+}
+
+impl Default for PlayerOptions {
+    fn default() -> Self {
+        Self {
+            device: None,
+            volume: 1.0,
+            replaygain: ReplayGain::Off,
+            stream: StreamChoice::default(),
+        }
+    }
 }
 
 /// Player configuration and state
 pub struct Player {
     #[cfg(feature = "audio")]
-    inner: Arc<Mutex<PlayerInner>>,
+    inner: Arc<Mutex<audio::PlayerInner>>,
     #[cfg(not(feature = "audio"))]
     state: PlayerState,
+    /// Shared with the decoder, which follows a cue sheet track into the next
+    /// one when that is queued
+    queue: Arc<Mutex<VecDeque<PathBuf>>>,
 }
 
 #[cfg(test)]
+#[allow(clippy::items_after_test_module)]
 mod tests {
     use super::*;
     use tempfile::NamedTempFile;
@@ -60,6 +145,8 @@ mod tests {
         assert_eq!(player.state(), PlayerState::Stopped);
         
         let result = player.play(Path::new("nonexistent.mp3"));
+        #[cfg(feature = "audio")]
+        assert!(matches!(result.unwrap_err(), PlayerError::FileNotFound(_)));
         #[cfg(not(feature = "audio"))]
         assert!(matches!(result.unwrap_err(), PlayerError::AudioDisabled));
     }
@@ -89,6 +176,70 @@ mod tests {
         assert!(matches!(result.unwrap_err(), PlayerError::AudioDisabled));
     }
 
+    #[test]
+    fn test_queue_operations() {
+        let player = Player::new().expect("Failed to create player");
+        player.enqueue(Path::new("a.mp3"));
+        player.enqueue(Path::new("b.mp3"));
+        player.enqueue(Path::new("c.mp3"));
+        assert_eq!(player.queue().len(), 3);
+
+        assert_eq!(player.remove_from_queue(1), Some(PathBuf::from("b.mp3")));
+        assert_eq!(player.remove_from_queue(5), None);
+        assert_eq!(player.queue(), vec![PathBuf::from("a.mp3"), PathBuf::from("c.mp3")]);
+
+        player.clear_queue();
+        assert!(player.queue().is_empty());
+        assert!(matches!(player.play_next(), Ok(None)));
+    }
+
+    #[test]
+    fn test_replaygain_factor() {
+        use symphonia::core::meta::{StandardTagKey, Value};
+        let tag = |key, value: &str| Tag::new(Some(key), "", Value::from(value));
+        let tags = [
+            tag(StandardTagKey::ReplayGainTrackGain, "-6.02 dB"),
+            tag(StandardTagKey::ReplayGainAlbumGain, "+6.02 dB"),
+            tag(StandardTagKey::ReplayGainAlbumPeak, "0.8"),
+        ];
+        assert_eq!(ReplayGain::Off.factor(&tags), 1.0);
+        assert!((ReplayGain::Track.factor(&tags) - 0.5).abs() < 0.001);
+        // +6 dB would clip a peak of 0.8, so it stops at 1.25
+        assert_eq!(ReplayGain::Album.factor(&tags), 1.25);
+        assert_eq!(ReplayGain::Album.factor(&[]), 1.0);
+    }
+
+    #[test]
+    fn test_chapter_progress() {
+        let chapter = |start_ms, end_ms| Chapter {
+            title: None,
+            start_ms,
+            end_ms,
+        };
+        let mut status = PlayerStatus {
+            state: PlayerState::Playing,
+            position: Some(Duration::from_secs(75)),
+            duration: None,
+            current_file: None,
+            volume: 1.0,
+            chapters: vec![chapter(0, Some(60_000)), chapter(60_000, None)],
+            chapter: Some(1),
+        };
+        assert_eq!(status.chapter_progress(), Some((Duration::from_secs(15), None)));
+        status.chapter = Some(0);
+        status.position = Some(Duration::from_secs(20));
+        assert_eq!(
+            status.chapter_progress(),
+            Some((Duration::from_secs(20), Some(Duration::from_secs(60))))
+        );
+
+        let player = Player::new().expect("Failed to create player");
+        #[cfg(not(feature = "audio"))]
+        assert!(matches!(player.next_chapter(), Err(PlayerError::AudioDisabled)));
+        #[cfg(feature = "audio")]
+        assert!(matches!(player.next_chapter(), Err(PlayerError::InvalidState(_))));
+    }
+
     #[test]
     fn test_status_tracking() {
         let player = Player::new().expect("Failed to create player");
@@ -111,88 +262,376 @@ mod tests {
         #[cfg(not(feature = "audio"))]
         assert!(status.current_file.is_none());
     }
+
+    /// One second of 8 kHz mono PCM whose samples count its frames, with a
+    /// cue sheet splitting it into tracks at frames 0, 2666 and 5333
+    #[cfg(feature = "audio")]
+    fn ramp_album(dir: &Path) -> PathBuf {
+        let samples: Vec<u8> = (0..8000u16).flat_map(|frame| frame.to_le_bytes()).collect();
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&(36 + samples.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16_000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&samples);
+
+        let file = dir.join("Album.wav");
+        std::fs::write(&file, wav).unwrap();
+        std::fs::write(
+            dir.join("Album.cue"),
+            "FILE \"Album.wav\" WAVE\n\
+             \x20 TRACK 01 AUDIO\n    INDEX 01 00:00:00\n\
+             \x20 TRACK 02 AUDIO\n    INDEX 01 00:00:25\n\
+             \x20 TRACK 03 AUDIO\n    INDEX 01 00:00:50\n",
+        )
+        .unwrap();
+        file
+    }
+
+    /// The frames of the ramp a decoder plays
+    #[cfg(feature = "audio")]
+    fn ramp_frames(source: audio::SymphoniaDecoder) -> Vec<u64> {
+        source
+            .map(|sample| (sample * 32768.0).round() as u64)
+            .collect()
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn test_cue_track_bounds() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = ramp_album(dir.path());
+        let track = PathBuf::from(crate::cue::track_path(&file, 2));
+        let queue = Arc::default();
+
+        let opened = audio::open(&track, &queue, &StreamChoice::default(), ReplayGain::Off)
+            .expect("Failed to open the cue track");
+        assert_eq!(opened.source.current_path(), Some(track));
+        let frames = ramp_frames(opened.source);
+        assert_eq!(frames.first(), Some(&2666));
+        assert_eq!(frames.last(), Some(&5332));
+        assert_eq!(frames.len(), 5333 - 2666);
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn test_cue_track_hands_off_to_the_next() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = ramp_album(dir.path());
+        let second = PathBuf::from(crate::cue::track_path(&file, 2));
+        let third = PathBuf::from(crate::cue::track_path(&file, 3));
+        let queue = Arc::new(Mutex::new(VecDeque::from([third.clone()])));
+
+        let opened = audio::open(&second, &queue, &StreamChoice::default(), ReplayGain::Off)
+            .expect("Failed to open the cue track");
+        let source = opened.source.clone();
+        let frames = ramp_frames(opened.source);
+        // The third track follows on without a gap and plays to the end
+        assert_eq!(frames, (2666..8000).collect::<Vec<_>>());
+        assert!(queue.lock().unwrap().is_empty());
+        assert_eq!(source.current_path(), Some(third));
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn test_select_track_keeps_position() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("ramp.mka");
+        std::fs::write(&file, crate::codecs::fixtures::flac_streams_mka()).unwrap();
+
+        let player = Player::new().expect("Failed to create player");
+        player.play(&file).expect("Failed to play");
+        player.pause().unwrap();
+        player.seek_to(Duration::from_millis(500)).unwrap();
+        let ids: Vec<_> = player.audio_tracks().iter().map(|track| track.id).collect();
+        assert_eq!(ids, [1, 2]);
+
+        player.select_track(2).unwrap();
+        let status = player.status();
+        assert_eq!(status.state, PlayerState::Paused);
+        assert_eq!(status.position, Some(Duration::from_millis(500)));
+        let selected: Vec<_> = player
+            .audio_tracks()
+            .into_iter()
+            .filter(|track| track.selected)
+            .map(|track| track.id)
+            .collect();
+        assert_eq!(selected, [2]);
+
+        // The second stream counts its frames from 16000, half a second in
+        let decoder = player.inner.lock().unwrap().decoder().unwrap();
+        assert_eq!(ramp_frames(decoder).first(), Some(&(16_000 + 4000)));
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn test_chapter_navigation() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("ramp.flac");
+        std::fs::write(&file, crate::codecs::fixtures::flac_chapters()).unwrap();
+
+        let player = Player::new().expect("Failed to create player");
+        player.play(&file).expect("Failed to play");
+        player.pause().unwrap();
+        assert_eq!(player.status().chapters.len(), 3);
+        assert_eq!(player.status().chapter, Some(0));
+
+        player.next_chapter().unwrap();
+        let status = player.status();
+        assert_eq!(status.chapter, Some(1));
+        assert_eq!(status.position, Some(Duration::from_millis(250)));
+        let decoder = player.inner.lock().unwrap().decoder().unwrap();
+        assert_eq!(ramp_frames(decoder).first(), Some(&2000));
+
+        // Only just into the chapter, so back to the one before
+        player.prev_chapter().unwrap();
+        assert_eq!(player.status().chapter, Some(0));
+        player.play_chapter(2).unwrap();
+        assert_eq!(player.status().position, Some(Duration::from_millis(500)));
+        assert!(matches!(player.play_chapter(3), Err(PlayerError::InvalidState(_))));
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn test_seek_within_cue_track() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = ramp_album(dir.path());
+        let track = PathBuf::from(crate::cue::track_path(&file, 2));
+        let queue = Arc::default();
+
+        let mut opened = audio::open(&track, &queue, &StreamChoice::default(), ReplayGain::Off)
+            .expect("Failed to open the cue track");
+        // Seconds count from the start of the track, not of the file
+        opened.source.seek(Duration::from_millis(100)).unwrap();
+        let frames = ramp_frames(opened.source);
+        assert_eq!(frames.first(), Some(&3466));
+        assert_eq!(frames.last(), Some(&5332));
+
+        let mut opened = audio::open(&file, &queue, &StreamChoice::default(), ReplayGain::Off)
+            .expect("Failed to open the file");
+        opened.source.seek(Duration::from_millis(500)).unwrap();
+        assert_eq!(ramp_frames(opened.source).first(), Some(&4000));
+    }
 }
 
 #[cfg(feature = "audio")]
 mod audio {
     use super::*;
-    use rodio::{OutputStream, OutputStreamHandle, Sample, Sink, Source};
-    use symphonia::core::audio::{AudioBufferRef, Signal};
-    use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
-    use symphonia::core::formats::{FormatOptions, SeekMode, SeekTo};
+    use crate::chapters;
+    use crate::cue::{self, Segment};
+    use crate::streams;
+    use rodio::cpal::traits::{DeviceTrait, HostTrait};
+    use rodio::{OutputStream, OutputStreamHandle, Sink, Source};
+    use symphonia::core::audio::SampleBuffer;
+    use symphonia::core::codecs::DecoderOptions;
+    use symphonia::core::formats::{FormatOptions, SeekMode, SeekTo, Track};
     use symphonia::core::io::MediaSourceStream;
     use symphonia::core::meta::MetadataOptions;
     use symphonia::core::probe::Hint;
-    use symphonia::core::units::Time;
+    use symphonia::core::units::{Time, TimeBase};
     use std::fs::File;
-    use std::io::BufReader;
+    use std::sync::mpsc;
+    use std::thread;
     use std::time::Duration;
 
+    /// The cue sheet track being played and the file it is cut from
+    #[derive(Clone)]
+    struct Span {
+        file: PathBuf,
+        segment: Segment,
+    }
+
+    /// Where a frame lies relative to the current `Span`
+    enum Bound {
+        Before,
+        Within,
+        Past,
+    }
+
+    /// The time of frame `frame` of a stream at `rate`
+    fn frame_time(frame: u64, rate: u32) -> Time {
+        let rate = rate.max(1) as u64;
+        Time::new(frame / rate, (frame % rate) as f64 / rate as f64)
+    }
+
     /// Custom Source implementation that bridges Symphonia's decoder with Rodio
     #[derive(Clone)]
-    struct SymphoniaDecoder {
+    pub(super) struct SymphoniaDecoder {
         decoder: Arc<Mutex<Box<dyn symphonia::core::codecs::Decoder>>>,
         format: Arc<Mutex<Box<dyn symphonia::core::formats::FormatReader>>>,
-        current_frame: Arc<Mutex<Option<AudioBufferRef<'static>>>>,
+        /// Interleaved samples of the packet last decoded
+        current_frame: Arc<Mutex<Vec<f32>>>,
         frame_offset: Arc<Mutex<usize>>,
+        /// Frame of the file the first sample of `current_frame` belongs to
+        frame_ts: Arc<Mutex<u64>>,
+        /// Frames before this one are decoded but not played, so that a seek
+        /// lands where it was asked to
+        skip_to: Arc<Mutex<u64>>,
+        /// The frame last handed to the output
+        played: Arc<Mutex<u64>>,
         sample_rate: u32,
         channels: u16,
         track_id: u32,
+        /// Units of packet timestamps, if the track gives them
+        time_base: Option<TimeBase>,
         duration: Option<Duration>,
+        /// Set when playing a track of a file split by a cue sheet
+        span: Arc<Mutex<Option<Span>>>,
+        /// Every track of that file, to carry on into the next
+        segments: Arc<Vec<Segment>>,
+        queue: Arc<Mutex<VecDeque<PathBuf>>>,
     }
 
     impl SymphoniaDecoder {
         fn new(
-            mut format: Box<dyn symphonia::core::formats::FormatReader>,
+            format: Box<dyn symphonia::core::formats::FormatReader>,
             decoder: Box<dyn symphonia::core::codecs::Decoder>,
-            track_id: u32,
-            sample_rate: u32,
-            channels: u16,
+            track: &Track,
+            span: Option<Span>,
+            segments: Vec<Segment>,
+            queue: Arc<Mutex<VecDeque<PathBuf>>>,
         ) -> Self {
-            // Try to get track duration if available
-            let duration = format
-                .metadata()
-                .current()
-                .and_then(|m| m.duration())
-                .map(|time| Duration::from_secs_f64(time.seconds as f64));
+            let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
+            let channels = track.codec_params.channels.map_or(2, |c| c.count() as u16);
+            let duration = track
+                .codec_params
+                .n_frames
+                .map(|frames| Duration::from_secs_f64(frames as f64 / sample_rate as f64));
 
             Self {
                 decoder: Arc::new(Mutex::new(decoder)),
                 format: Arc::new(Mutex::new(format)),
-                current_frame: Arc::new(Mutex::new(None)),
+                current_frame: Arc::new(Mutex::new(Vec::new())),
                 frame_offset: Arc::new(Mutex::new(0)),
+                frame_ts: Arc::new(Mutex::new(0)),
+                skip_to: Arc::new(Mutex::new(0)),
+                played: Arc::new(Mutex::new(0)),
                 sample_rate,
                 channels,
-                track_id,
+                track_id: track.id,
+                time_base: track.codec_params.time_base,
                 duration,
+                span: Arc::new(Mutex::new(span)),
+                segments: Arc::new(segments),
+                queue,
             }
         }
 
-        fn seek(&mut self, time: u64) -> Result<(), PlayerError> {
-            // Convert seconds to timestamp
-            let ts = Time::new(time as u64, 1);
-            
+        /// Decode another stream of the same file from here on
+        pub(super) fn switch_track(&mut self, track: &Track) -> Result<(), PlayerError> {
+            let decoder = crate::codecs::get()
+                .make(&track.codec_params, &DecoderOptions::default())
+                .map_err(|e| PlayerError::UnsupportedFormat(e.to_string()))?;
+            self.decoder = Arc::new(Mutex::new(decoder));
+            self.track_id = track.id;
+            self.time_base = track.codec_params.time_base;
+            self.sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
+            self.channels = track.codec_params.channels.map_or(2, |c| c.count() as u16);
+            self.current_frame.lock().unwrap().clear();
+            Ok(())
+        }
+
+        fn frames_to_duration(&self, frames: u64) -> Duration {
+            Duration::from_secs_f64(frames as f64 / self.sample_rate as f64)
+        }
+
+        /// A packet timestamp in seconds
+        fn ts_seconds(&self, ts: u64) -> f64 {
+            match self.time_base {
+                Some(time_base) => {
+                    let time = time_base.calc_time(ts);
+                    time.seconds as f64 + time.frac
+                }
+                None => ts as f64 / self.sample_rate as f64,
+            }
+        }
+
+        /// A packet timestamp in frames, which some containers count in
+        /// other units
+        fn ts_frames(&self, ts: u64) -> u64 {
+            (self.ts_seconds(ts) * self.sample_rate as f64).round() as u64
+        }
+
+        /// The library path being played: the cue sheet track, if any
+        pub(super) fn current_path(&self) -> Option<PathBuf> {
+            let span = self.span.lock().unwrap();
+            span.as_ref()
+                .map(|span| cue::track_path(&span.file, span.segment.number).into())
+        }
+
+        /// Position and length of the cue sheet track being played
+        fn span_times(&self) -> Option<(Duration, Option<Duration>)> {
+            let span = self.span.lock().unwrap();
+            let segment = &span.as_ref()?.segment;
+            let played = self.played.lock().unwrap().saturating_sub(segment.start);
+            Some((
+                self.frames_to_duration(played),
+                segment.end.map(|end| self.frames_to_duration(end - segment.start)),
+            ))
+        }
+
+        /// Check `frame` against the cue sheet track being played. At its end,
+        /// playback carries on into the next track without a gap if that is the
+        /// next one queued.
+        fn bound(&self, frame: u64) -> Bound {
+            let mut span = self.span.lock().unwrap();
+            let Some(current) = span.as_mut() else {
+                return Bound::Within;
+            };
+            if frame < current.segment.start {
+                return Bound::Before;
+            }
+            let Some(end) = current.segment.end.filter(|&end| frame >= end) else {
+                return Bound::Within;
+            };
+            let Some(next) = self.segments.iter().find(|segment| segment.start == end) else {
+                return Bound::Past;
+            };
+            let mut queue = self.queue.lock().unwrap();
+            let next_path = PathBuf::from(cue::track_path(&current.file, next.number));
+            if queue.front() != Some(&next_path) {
+                return Bound::Past;
+            }
+            queue.pop_front();
+            current.segment = next.clone();
+            Bound::Within
+        }
+
+        pub(super) fn seek(&mut self, position: Duration) -> Result<(), PlayerError> {
+            // Within a cue sheet track, seconds count from its start
+            let start = self.span.lock().unwrap().as_ref().map_or(0, |span| span.segment.start);
+            let target = start + (position.as_secs_f64() * self.sample_rate as f64) as u64;
+            let to = SeekTo::Time {
+                time: frame_time(target, self.sample_rate),
+                track_id: Some(self.track_id),
+            };
+
             // Attempt to seek in the format reader
-            match self.format.lock().unwrap().seek(
-                SeekMode::Accurate,
-                SeekTo::Time {
-                    time: ts,
-                    track_id: self.track_id,
-                },
-            ) {
+            match self.format.lock().unwrap().seek(SeekMode::Accurate, to) {
                 Ok(seeked_to) => {
                     // Clear current frame as it's no longer valid
-                    *self.current_frame.lock().unwrap() = None;
+                    self.current_frame.lock().unwrap().clear();
                     *self.frame_offset.lock().unwrap() = 0;
-                    
+                    self.decoder.lock().unwrap().reset();
+                    *self.skip_to.lock().unwrap() = target;
+
                     // Verify we seeked to approximately where we wanted
-                    if (seeked_to.actual_ts.seconds as i64 - time as i64).abs() > 2 {
-                        return Err(PlayerError::AudioError(
-                            format!("Seek was not accurate: requested {}s, got {}s",
-                                time, seeked_to.actual_ts.seconds)
-                        ));
+                    let requested = self.ts_seconds(seeked_to.required_ts);
+                    let actual = self.ts_seconds(seeked_to.actual_ts);
+                    if (actual - requested).abs() > 2.0 {
+                        return Err(PlayerError::AudioError(format!(
+                            "Seek was not accurate: requested {:.3}s, got {:.3}s",
+                            requested, actual
+                        )));
                     }
-                    
+
                     Ok(())
                 }
                 Err(err) => Err(PlayerError::AudioError(
@@ -207,13 +646,24 @@ mod audio {
                     Ok(packet) => packet,
                     Err(_) => return Ok(false),
                 };
+                // Video and other audio streams of the container
+                if packet.track_id() != self.track_id {
+                    continue;
+                }
 
-                let decoded = self.decoder.lock().unwrap().decode(&packet).map_err(|e| {
+                let mut decoder = self.decoder.lock().unwrap();
+                let decoded = decoder.decode(&packet).map_err(|e| {
                     PlayerError::DecodeError(format!("Failed to decode audio frame: {}", e))
                 })?;
+                let mut samples =
+                    SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+                samples.copy_interleaved_ref(decoded);
 
-                *self.current_frame.lock().unwrap() = Some(decoded);
+                let mut frame = self.current_frame.lock().unwrap();
+                frame.clear();
+                frame.extend_from_slice(samples.samples());
                 *self.frame_offset.lock().unwrap() = 0;
+                *self.frame_ts.lock().unwrap() = self.ts_frames(packet.ts());
                 return Ok(true);
             }
         }
@@ -225,15 +675,24 @@ mod audio {
         fn next(&mut self) -> Option<f32> {
             loop {
                 // If we have a frame, try to get the next sample
-                if let Some(frame) = self.current_frame.lock().unwrap().as_ref() {
-                    let offset = *self.frame_offset.lock().unwrap();
-                    if offset < frame.frames() * frame.spec().channels.count() {
-                        let sample = match frame.planes().planes()[0].as_slice::<f32>() {
-                            Ok(plane) => plane[offset],
-                            Err(_) => return None,
-                        };
-                        *self.frame_offset.lock().unwrap() += 1;
-                        return Some(sample);
+                let offset = *self.frame_offset.lock().unwrap();
+                let sample = self.current_frame.lock().unwrap().get(offset).copied();
+                if let Some(sample) = sample {
+                    *self.frame_offset.lock().unwrap() += 1;
+
+                    // Cue sheet tracks play only their part of the file
+                    let frame = *self.frame_ts.lock().unwrap()
+                        + (offset / self.channels.max(1) as usize) as u64;
+                    if frame < *self.skip_to.lock().unwrap() {
+                        continue;
+                    }
+                    match self.bound(frame) {
+                        Bound::Before => continue,
+                        Bound::Within => {
+                            *self.played.lock().unwrap() = frame;
+                            return Some(sample);
+                        }
+                        Bound::Past => return None,
                     }
                 }
 
@@ -248,7 +707,13 @@ mod audio {
 
     impl Source for SymphoniaDecoder {
         fn current_frame_len(&self) -> Option<usize> {
-            self.current_frame.as_ref().map(|f| f.frames())
+            let remaining = self
+                .current_frame
+                .lock()
+                .unwrap()
+                .len()
+                .saturating_sub(*self.frame_offset.lock().unwrap());
+            Some(remaining).filter(|&remaining| remaining > 0)
         }
 
         fn channels(&self) -> u16 {
@@ -264,23 +729,202 @@ mod audio {
         }
     }
 
+    /// A file opened and ready to hand to the output
+    pub(super) struct Opened {
+        pub(super) source: SymphoniaDecoder,
+        /// ReplayGain multiplier
+        gain: f32,
+        chapters: Vec<Chapter>,
+        /// Every stream of the file
+        tracks: Vec<Track>,
+    }
+
+    /// Open `path`, which may name a track of a cue sheet, and set up the
+    /// decoder for the stream `choice` picks
+    pub(super) fn open(
+        path: &Path,
+        queue: &Arc<Mutex<VecDeque<PathBuf>>>,
+        choice: &StreamChoice,
+        replaygain: ReplayGain,
+    ) -> Result<Opened, PlayerError> {
+        // A track of a file split by a cue sheet plays part of that file
+        let (file_path, cue_track) = match cue::split_path(path) {
+            Some((file, number)) => (file, Some(number)),
+            None => (path.to_owned(), None),
+        };
+
+        // Open the media file
+        let file = File::open(&file_path)
+            .map_err(|_| PlayerError::FileNotFound(path.display().to_string()))?;
+        
+        let mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+        // Create a hint to help the format registry guess what format reader is appropriate
+        let mut hint = Hint::new();
+        if let Some(ext_str) = file_path.extension().and_then(|e| e.to_str()) {
+            hint.with_extension(ext_str);
+        }
+
+        // Use the default options for metadata and format reading
+        let format_opts: FormatOptions = Default::default();
+        let metadata_opts: MetadataOptions = Default::default();
+
+        // Probe the media format
+        let mut probed = crate::formats::get()
+            .format(&hint, mss, &format_opts, &metadata_opts)
+            .map_err(|_| PlayerError::UnsupportedFormat(path.display().to_string()))?;
+
+        // ReplayGain tags may sit in a container header or in the stream
+        let mut tags = Vec::new();
+        if let Some(metadata) = probed.metadata.get()
+            && let Some(revision) = metadata.current()
+        {
+            tags.extend_from_slice(revision.tags());
+        }
+        if let Some(revision) = probed.format.metadata().current() {
+            tags.extend_from_slice(revision.tags());
+        }
+        let gain = replaygain.factor(&tags);
+
+        // Get the format reader
+        let mut format = probed.format;
+
+        // Pick the audio stream, passing over any video or subtitles
+        let track = streams::choose(format.tracks(), format.default_track(), choice)
+            .cloned()
+            .ok_or_else(|| PlayerError::UnsupportedFormat("No audio track found".into()))?;
+        let tracks = format.tracks().to_vec();
+
+        let track_id = track.id;
+        
+        // Get audio parameters
+        let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
+
+        // Find the cue sheet track and start at its first frame
+        let mut segments = Vec::new();
+        let mut span = None;
+        if let Some(number) = cue_track {
+            segments = cue::find_split(&file_path, &tags, format.cues(), sample_rate)
+                .map(|split| split.segments)
+                .unwrap_or_default();
+            let segment = segments
+                .iter()
+                .find(|segment| segment.number == number)
+                .cloned()
+                .ok_or_else(|| PlayerError::FileNotFound(path.display().to_string()))?;
+            if segment.start > 0 {
+                format
+                    .seek(
+                        SeekMode::Accurate,
+                        SeekTo::Time {
+                            time: frame_time(segment.start, sample_rate),
+                            track_id: Some(track_id),
+                        },
+                    )
+                    .map_err(|e| PlayerError::AudioError(format!("Failed to seek: {}", e)))?;
+            }
+            span = Some(Span {
+                file: file_path.clone(),
+                segment,
+            });
+        }
+
+        // Chapters belong to whole files, not to cue sheet tracks
+        let chapters = match span {
+            Some(_) => Vec::new(),
+            None => {
+                let duration_ms = track.codec_params.n_frames.map(|frames| {
+                    (frames as u128 * 1000 / sample_rate.max(1) as u128) as i64
+                });
+                chapters::read(&file_path, &tags, duration_ms)
+            }
+        };
+
+        // Create a decoder for the track
+        let decoder_opts: DecoderOptions = Default::default();
+        let decoder = crate::codecs::get()
+            .make(&track.codec_params, &decoder_opts)
+            .map_err(|_| PlayerError::UnsupportedFormat("Failed to create decoder".into()))?;
+
+        // Create our custom decoder that implements rodio::Source
+        let source =
+            SymphoniaDecoder::new(format, decoder, &track, span, segments, queue.clone());
+        Ok(Opened {
+            source,
+            gain,
+            chapters,
+            tracks,
+        })
+    }
+
+    /// Open the output on a thread of its own, since the stream can't move
+    /// between threads, and keep it open there until the sender returned is
+    /// dropped
+    fn open_output(
+        device: Option<String>,
+    ) -> Result<(OutputStreamHandle, mpsc::Sender<()>), PlayerError> {
+        let (opened_tx, opened_rx) = mpsc::channel();
+        let (close_tx, close_rx) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let opened = match &device {
+                Some(name) => rodio::cpal::default_host()
+                    .output_devices()
+                    .map_err(|e| PlayerError::AudioError(e.to_string()))
+                    .and_then(|mut devices| {
+                        devices
+                            .find(|device| device.name().is_ok_and(|n| &n == name))
+                            .ok_or(PlayerError::NoAudioDevice)
+                    })
+                    .and_then(|device| {
+                        OutputStream::try_from_device(&device)
+                            .map_err(|_| PlayerError::NoAudioDevice)
+                    }),
+                None => OutputStream::try_default().map_err(|_| PlayerError::NoAudioDevice),
+            };
+            match opened {
+                Ok((stream, handle)) => {
+                    let _ = opened_tx.send(Ok(handle));
+                    let _ = close_rx.recv();
+                    drop(stream);
+                }
+                Err(e) => {
+                    let _ = opened_tx.send(Err(e));
+                }
+            }
+        });
+        let handle = opened_rx
+            .recv()
+            .map_err(|_| PlayerError::AudioError("The output thread stopped".into()))??;
+        Ok((handle, close_tx))
+    }
+
     pub(crate) struct PlayerInner {
-        _stream: OutputStream,
+        /// Holds the output stream open; dropping it closes the stream
+        _stream: mpsc::Sender<()>,
         stream_handle: OutputStreamHandle,
         sink: Option<Sink>,
         decoder: Arc<Mutex<Option<SymphoniaDecoder>>>,
         state: PlayerState,
         current_file: Option<PathBuf>,
+        chapters: Vec<Chapter>,
+        /// Every stream of the file playing
+        tracks: Vec<Track>,
         start_time: Option<std::time::Instant>,
         paused_position: Option<Duration>,
         volume: f32,
+        replaygain: ReplayGain,
+        stream: StreamChoice,
+        /// ReplayGain multiplier for the current file
+        gain: f32,
     }
 
     impl PlayerInner {
-        pub fn new() -> Result<Self, PlayerError> {
-            let (_stream, stream_handle) = OutputStream::try_default()
-                .map_err(|e| PlayerError::NoAudioDevice)?;
-            
+        pub fn new(options: &PlayerOptions) -> Result<Self, PlayerError> {
+            if !(0.0..=1.0).contains(&options.volume) {
+                return Err(PlayerError::InvalidVolume(options.volume));
+            }
+            let (stream_handle, _stream) = open_output(options.device.clone())?;
+
             Ok(Self {
                 _stream,
                 stream_handle,
@@ -288,72 +932,33 @@ mod audio {
                 decoder: Arc::new(Mutex::new(None)),
                 state: PlayerState::Stopped,
                 current_file: None,
+                chapters: Vec::new(),
+                tracks: Vec::new(),
                 start_time: None,
                 paused_position: None,
-                volume: 1.0,
+                volume: options.volume,
+                replaygain: options.replaygain,
+                stream: options.stream.clone(),
+                gain: 1.0,
             })
         }
 
-        pub fn play(&mut self, path: &Path) -> Result<(), PlayerError> {
+        pub fn play(
+            &mut self,
+            path: &Path,
+            queue: &Arc<Mutex<VecDeque<PathBuf>>>,
+            choice: Option<&StreamChoice>,
+        ) -> Result<(), PlayerError> {
             // Stop any existing playback
             self.stop()?;
 
-            // Open the media file
-            let file = File::open(path)
-                .map_err(|_| PlayerError::FileNotFound(path.display().to_string()))?;
-            
-            let mss = MediaSourceStream::new(
-                Box::new(BufReader::new(file)),
-                Default::default(),
-            );
-
-            // Create a hint to help the format registry guess what format reader is appropriate
-            let mut hint = Hint::new();
-            if let Some(extension) = path.extension() {
-                if let Some(ext_str) = extension.to_str() {
-                    hint.with_extension(ext_str);
-                }
-            }
-
-            // Use the default options for metadata and format reading
-            let format_opts: FormatOptions = Default::default();
-            let metadata_opts: MetadataOptions = Default::default();
-
-            // Probe the media format
-            let probed = symphonia::default::get_probe()
-                .format(&hint, mss, &format_opts, &metadata_opts)
-                .map_err(|_| PlayerError::UnsupportedFormat(path.display().to_string()))?;
-
-            // Get the format reader
-            let format = probed.format;
-
-            // Find the first audio track
-            let track = format
-                .tracks()
-                .iter()
-                .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-                .ok_or_else(|| PlayerError::UnsupportedFormat("No audio track found".into()))?;
-
-            let track_id = track.id;
-            
-            // Get audio parameters
-            let sample_rate = track.codec_params.sample_rate.unwrap_or(44100);
-            let channels = track.codec_params.channels.unwrap_or(2) as u16;
-
-            // Create a decoder for the track
-            let decoder_opts: DecoderOptions = Default::default();
-            let decoder = symphonia::default::get_codecs()
-                .make(&track.codec_params, &decoder_opts)
-                .map_err(|_| PlayerError::UnsupportedFormat("Failed to create decoder".into()))?;
-
-            // Create our custom decoder that implements rodio::Source
-            let source = SymphoniaDecoder::new(
-                format,
-                decoder,
-                track_id,
-                sample_rate,
-                channels,
-            );
+            let choice = choice.unwrap_or(&self.stream);
+            let Opened {
+                source,
+                gain,
+                chapters,
+                tracks,
+            } = open(path, queue, choice, self.replaygain)?;
 
             // Store the decoder for seeking
             *self.decoder.lock().unwrap() = Some(source.clone());
@@ -362,12 +967,16 @@ mod audio {
             let sink = Sink::try_new(&self.stream_handle)
                 .map_err(|e| PlayerError::AudioError(format!("Failed to create audio sink: {}", e)))?;
 
+            sink.set_volume(self.volume * gain);
             sink.append(source);
             sink.play();
+            self.gain = gain;
 
             self.sink = Some(sink);
             self.state = PlayerState::Playing;
             self.current_file = Some(path.to_owned());
+            self.chapters = chapters;
+            self.tracks = tracks;
             self.start_time = Some(std::time::Instant::now());
             self.paused_position = None;
             
@@ -416,26 +1025,42 @@ mod audio {
             }
             self.sink = None;
             self.current_file = None;
+            self.chapters.clear();
+            self.tracks.clear();
             self.start_time = None;
             self.paused_position = None;
             Ok(())
         }
 
         pub fn seek(&mut self, seconds: u64) -> Result<(), PlayerError> {
+            self.seek_to(Duration::from_secs(seconds))
+        }
+
+        pub fn seek_to(&mut self, position: Duration) -> Result<(), PlayerError> {
             if let Some(decoder) = &mut self.decoder.lock().unwrap().as_mut() {
-                // First seek in the decoder
-                decoder.seek(seconds)?;
-                
+                // Stop the old sink first, as it reads from the same decoder
+                if let Some(old_sink) = self.sink.take() {
+                    old_sink.pause();
+                    old_sink.stop();
+                }
+
+                decoder.seek(position)?;
+
                 // Create a new sink with the current decoder
                 let new_sink = Sink::try_new(&self.stream_handle)
                     .map_err(|e| PlayerError::AudioError(format!("Failed to create audio sink: {}", e)))?;
-                
-                // Stop and replace the old sink
-                if let Some(old_sink) = self.sink.take() {
-                    old_sink.stop();
+
+                new_sink.set_volume(self.volume * self.gain);
+                match self.state {
+                    PlayerState::Paused => {
+                        new_sink.pause();
+                        self.paused_position = Some(position);
+                    }
+                    _ => {
+                        self.start_time = std::time::Instant::now().checked_sub(position);
+                    }
                 }
-                
-                new_sink.play();
+                new_sink.append(decoder.clone());
                 self.sink = Some(new_sink);
                 
                 Ok(())
@@ -444,6 +1069,32 @@ mod audio {
             }
         }
 
+        pub fn audio_tracks(&self) -> Vec<AudioTrack> {
+            let selected = self.decoder.lock().unwrap().as_ref().map(|d| d.track_id);
+            streams::describe(&self.tracks, selected)
+        }
+
+        /// Carry on from the same position in another stream of the file
+        pub fn select_track(&mut self, id: u32) -> Result<(), PlayerError> {
+            let track = streams::audio_tracks(&self.tracks)
+                .into_iter()
+                .find(|track| track.id == id)
+                .cloned()
+                .ok_or_else(|| PlayerError::InvalidState(format!("No audio track {}", id)))?;
+            let position = self.status().position.unwrap_or_default();
+            // The sink must not read on while the decoder changes under it
+            if let Some(sink) = self.sink.take() {
+                sink.pause();
+                sink.stop();
+            }
+            match self.decoder.lock().unwrap().as_mut() {
+                Some(decoder) => decoder.switch_track(&track)?,
+                None => return Err(PlayerError::InvalidState("No active playback".into())),
+            }
+            // Hands the sink a copy of the decoder reading the new stream
+            self.seek_to(position)
+        }
+
         pub fn state(&self) -> PlayerState {
             self.state
         }
@@ -455,16 +1106,37 @@ mod audio {
                 _ => None,
             };
 
-            let duration = self.decoder.lock().unwrap()
-                .as_ref()
-                .and_then(|decoder| decoder.duration);
+            let decoder = self.decoder.lock().unwrap();
+            let duration = decoder.as_ref().and_then(|decoder| decoder.duration);
+
+            // A cue sheet track reports its own path, position and length,
+            // which change as playback moves on to the next one
+            let playing = decoder.as_ref().filter(|_| self.state != PlayerState::Stopped);
+            if let Some(decoder) = playing
+                && let Some((played, length)) = decoder.span_times()
+            {
+                return PlayerStatus {
+                    state: self.state,
+                    position: Some(played),
+                    duration: length.or(duration),
+                    current_file: decoder.current_path(),
+                    volume: self.volume,
+                    chapters: Vec::new(),
+                    chapter: None,
+                };
+            }
 
+            let chapter = position.and_then(|position| {
+                chapters::at(&self.chapters, position.as_millis() as i64)
+            });
             PlayerStatus {
                 state: self.state,
                 position,
                 duration,
                 current_file: self.current_file.clone(),
                 volume: self.volume,
+                chapters: self.chapters.clone(),
+                chapter,
             }
         }
 
@@ -476,7 +1148,7 @@ mod audio {
 
             self.volume = volume;
             if let Some(sink) = &self.sink {
-                sink.set_volume(volume);
+                sink.set_volume(volume * self.gain);
             }
             Ok(())
         }
@@ -485,31 +1157,35 @@ mod audio {
             self.volume
         }
 
-        pub fn increase_volume(&mut self) -> Result<(), PlayerError> {
-            let new_volume = (self.volume + 0.1).min(1.0);
-            self.set_volume(new_volume)
-        }
-
-        pub fn decrease_volume(&mut self) -> Result<(), PlayerError> {
-            let new_volume = (self.volume - 0.1).max(0.0);
-            self.set_volume(new_volume)
+        /// A copy of the decoder, sharing its position with the one playing
+        #[cfg(test)]
+        pub(super) fn decoder(&self) -> Option<SymphoniaDecoder> {
+            self.decoder.lock().unwrap().clone()
         }
     }
 }
 
 impl Player {
     pub fn new() -> Result<Self, PlayerError> {
+        Self::with_options(&PlayerOptions::default())
+    }
+
+    /// Open the output device named in `options` at the given volume
+    pub fn with_options(options: &PlayerOptions) -> Result<Self, PlayerError> {
         #[cfg(feature = "audio")]
         {
-            let inner = audio::PlayerInner::new()?;
+            let inner = audio::PlayerInner::new(options)?;
             Ok(Self {
                 inner: Arc::new(Mutex::new(inner)),
+                queue: Arc::new(Mutex::new(VecDeque::new())),
             })
         }
         #[cfg(not(feature = "audio"))]
         {
+            let _ = options;
             Ok(Self {
                 state: PlayerState::Stopped,
+                queue: Arc::new(Mutex::new(VecDeque::new())),
             })
         }
     }
@@ -518,14 +1194,54 @@ impl Player {
         #[cfg(feature = "audio")]
         {
             // Forward the path parameter to inner implementation
-            self.inner.lock().unwrap().play(path)
+            self.inner.lock().unwrap().play(path, &self.queue, None)
         }
         #[cfg(not(feature = "audio"))]
         {
-            // Verify path exists but still return AudioDisabled
-            if !path.exists() {
-                return Err(PlayerError::FileNotFound(path.display().to_string()));
-            }
+            let _ = path;
+            Err(PlayerError::AudioDisabled)
+        }
+    }
+
+    /// Play a file, picking its audio stream by `choice` rather than by the
+    /// player's options
+    pub fn play_stream(&self, path: &Path, choice: &StreamChoice) -> Result<(), PlayerError> {
+        #[cfg(feature = "audio")]
+        {
+            self.inner
+                .lock()
+                .unwrap()
+                .play(path, &self.queue, Some(choice))
+        }
+        #[cfg(not(feature = "audio"))]
+        {
+            let _ = (path, choice);
+            Err(PlayerError::AudioDisabled)
+        }
+    }
+
+    /// Audio streams of the file playing, with codec, language and channels
+    pub fn audio_tracks(&self) -> Vec<AudioTrack> {
+        #[cfg(feature = "audio")]
+        {
+            self.inner.lock().unwrap().audio_tracks()
+        }
+        #[cfg(not(feature = "audio"))]
+        {
+            Vec::new()
+        }
+    }
+
+    /// Switch to another audio stream of the file playing, by the id
+    /// `audio_tracks` gives it, carrying on from the same position
+    pub fn select_track(&self, id: u32) -> Result<(), PlayerError> {
+        #[cfg(feature = "audio")]
+        {
+            self.inner.lock().unwrap().select_track(id)
+        }
+        #[cfg(not(feature = "audio"))]
+        {
+            let _ = id;
             Err(PlayerError::AudioDisabled)
         }
     }
@@ -579,6 +1295,61 @@ impl Player {
         }
     }
 
+    /// Jump to `position` in the file playing
+    pub fn seek_to(&self, position: Duration) -> Result<(), PlayerError> {
+        #[cfg(feature = "audio")]
+        {
+            self.inner.lock().unwrap().seek_to(position)
+        }
+        #[cfg(not(feature = "audio"))]
+        {
+            let _ = position;
+            Err(PlayerError::AudioDisabled)
+        }
+    }
+
+    /// Jump to chapter `index` (counting from 0) of the file playing
+    pub fn play_chapter(&self, index: usize) -> Result<(), PlayerError> {
+        self.jump_to_chapter(|_| Some(index))
+    }
+
+    pub fn next_chapter(&self) -> Result<(), PlayerError> {
+        self.jump_to_chapter(|status| Some(status.chapter.map_or(0, |index| index + 1)))
+    }
+
+    /// Go back to the start of the chapter playing, or to the chapter before
+    /// if this one has only just begun
+    pub fn prev_chapter(&self) -> Result<(), PlayerError> {
+        self.jump_to_chapter(|status| {
+            let index = status.chapter?;
+            let (into, _) = status.chapter_progress()?;
+            Some(match into < RESTART_CHAPTER {
+                true => index.saturating_sub(1),
+                false => index,
+            })
+        })
+    }
+
+    fn jump_to_chapter(
+        &self,
+        pick: impl FnOnce(&PlayerStatus) -> Option<usize>,
+    ) -> Result<(), PlayerError> {
+        #[cfg(feature = "audio")]
+        {
+            let mut inner = self.inner.lock().unwrap();
+            let status = inner.status();
+            let chapter = pick(&status)
+                .and_then(|index| status.chapters.get(index))
+                .ok_or_else(|| PlayerError::InvalidState("No such chapter".into()))?;
+            inner.seek_to(Duration::from_millis(chapter.start_ms.max(0) as u64))
+        }
+        #[cfg(not(feature = "audio"))]
+        {
+            let _ = pick;
+            Err(PlayerError::AudioDisabled)
+        }
+    }
+
     pub fn state(&self) -> PlayerState {
         #[cfg(feature = "audio")]
         {
@@ -603,7 +1374,67 @@ impl Player {
                 duration: None,
                 current_file: None,
                 volume: 0.0,
+                chapters: Vec::new(),
+                chapter: None,
+            }
+        }
+    }
+
+    pub fn set_volume(&self, volume: f32) -> Result<(), PlayerError> {
+        #[cfg(feature = "audio")]
+        {
+            self.inner.lock().unwrap().set_volume(volume)
+        }
+        #[cfg(not(feature = "audio"))]
+        {
+            if !(0.0..=1.0).contains(&volume) {
+                return Err(PlayerError::InvalidVolume(volume));
+            }
+            Err(PlayerError::AudioDisabled)
+        }
+    }
+
+    pub fn volume(&self) -> f32 {
+        #[cfg(feature = "audio")]
+        {
+            self.inner.lock().unwrap().get_volume()
+        }
+        #[cfg(not(feature = "audio"))]
+        {
+            0.0
+        }
+    }
+
+    /// Append a file to the end of the play queue.
+    pub fn enqueue(&self, path: &Path) {
+        self.queue.lock().unwrap().push_back(path.to_owned());
+    }
+
+    /// Snapshot of the files waiting in the play queue, next one first.
+    pub fn queue(&self) -> Vec<PathBuf> {
+        self.queue.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Remove the queue entry at `index`, returning it if it existed.
+    pub fn remove_from_queue(&self, index: usize) -> Option<PathBuf> {
+        self.queue.lock().unwrap().remove(index)
+    }
+
+    pub fn clear_queue(&self) {
+        self.queue.lock().unwrap().clear();
+    }
+
+    /// Pop the next queued file and start playing it.
+    ///
+    /// Returns `Ok(None)` when the queue is empty.
+    pub fn play_next(&self) -> Result<Option<PathBuf>, PlayerError> {
+        let next = self.queue.lock().unwrap().pop_front();
+        match next {
+            Some(path) => {
+                self.play(&path)?;
+                Ok(Some(path))
             }
+            None => Ok(None),
         }
     }
 }
@@ -0,0 +1,478 @@
+//! Playlist files: extended M3U, M3U8, PLS and XSPF.
+//!
+//! Files are read into `Entry` values and matched against the library with
+//! `resolve`: first by path, then by artist and title when the paths were
+//! written on another machine.
+
+use anyhow::Result;
+use percent_encoding::{AsciiSet, CONTROLS, percent_decode_str, utf8_percent_encode};
+use std::fmt;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use thiserror::Error;
+
+use crate::db::{DB, Track};
+
+mod m3u;
+mod pls;
+mod xspf;
+
+#[derive(Debug, Error)]
+pub enum PlaylistError {
+    #[error("Unsupported playlist format: {0}")]
+    UnsupportedFormat(String),
+    #[error("Invalid {format} playlist: {message}")]
+    Invalid { format: Format, message: String },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+/// A playlist file format
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// Extended M3U; read as UTF-8, falling back to Latin-1
+    M3u,
+    /// Extended M3U, always UTF-8
+    M3u8,
+    Pls,
+    Xspf,
+}
+
+impl Format {
+    /// Guess the format from a file extension
+    pub fn from_path(path: &Path) -> Option<Format> {
+        path.extension()?.to_str()?.parse().ok()
+    }
+}
+
+impl FromStr for Format {
+    type Err = PlaylistError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "m3u" => Ok(Format::M3u),
+            "m3u8" => Ok(Format::M3u8),
+            "pls" => Ok(Format::Pls),
+            "xspf" => Ok(Format::Xspf),
+            _ => Err(PlaylistError::UnsupportedFormat(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::M3u => "M3U",
+            Format::M3u8 => "M3U8",
+            Format::Pls => "PLS",
+            Format::Xspf => "XSPF",
+        })
+    }
+}
+
+/// One item of a playlist file
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Entry {
+    /// Path or URL exactly as written in the file
+    pub location: String,
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub duration_seconds: Option<i64>,
+}
+
+impl From<&Track> for Entry {
+    fn from(track: &Track) -> Self {
+        Self {
+            location: track.path.clone(),
+            title: track.title.clone(),
+            artist: track.artist.clone(),
+            album: track.album.clone(),
+            duration_seconds: track.duration_seconds,
+        }
+    }
+}
+
+impl Entry {
+    /// `Artist - Title` as shown by M3U and PLS players
+    fn display_title(&self) -> Option<String> {
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => Some(format!("{} - {}", artist, title)),
+            (None, Some(title)) => Some(title.clone()),
+            _ => None,
+        }
+    }
+
+    /// Fill `artist` and `title` from an `Artist - Title` display title
+    fn set_display_title(&mut self, display: &str) {
+        let display = display.trim();
+        if display.is_empty() {
+            return;
+        }
+        match display.split_once(" - ") {
+            Some((artist, title)) => {
+                self.artist = Some(artist.trim().to_owned());
+                self.title = Some(title.trim().to_owned());
+            }
+            None => self.title = Some(display.to_owned()),
+        }
+    }
+}
+
+/// Parse playlist text in the given format
+pub fn parse(format: Format, text: &str) -> Result<Vec<Entry>, PlaylistError> {
+    let text = text.trim_start_matches('\u{feff}');
+    match format {
+        Format::M3u | Format::M3u8 => Ok(m3u::parse(text)),
+        Format::Pls => pls::parse(text),
+        Format::Xspf => xspf::parse(text),
+    }
+}
+
+/// Read a playlist file, choosing the format from its extension
+pub fn read(path: &Path) -> Result<Vec<Entry>, PlaylistError> {
+    let format = Format::from_path(path)
+        .ok_or_else(|| PlaylistError::UnsupportedFormat(path.display().to_string()))?;
+    let bytes = std::fs::read(path)?;
+    let text = match String::from_utf8(bytes) {
+        Ok(text) => text,
+        // Plain .m3u files from older players are usually Latin-1
+        Err(err) if format == Format::M3u => err.into_bytes().iter().map(|&b| b as char).collect(),
+        Err(err) => {
+            return Err(PlaylistError::Invalid {
+                format,
+                message: err.to_string(),
+            });
+        }
+    };
+    parse(format, &text)
+}
+
+/// Write entries in the given format (always UTF-8)
+pub fn write<W: Write>(format: Format, entries: &[Entry], mut out: W) -> io::Result<()> {
+    match format {
+        Format::M3u | Format::M3u8 => m3u::write(entries, &mut out)?,
+        Format::Pls => pls::write(entries, &mut out)?,
+        Format::Xspf => xspf::write(entries, &mut out)?,
+    }
+    out.flush()
+}
+
+/// Characters escaped when a path is written as a `file://` URL
+const URL_PATH: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'[')
+    .add(b']')
+    .add(b'\\')
+    .add(b'^')
+    .add(b'`')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
+
+fn file_url(path: &str) -> String {
+    format!("file://{}", utf8_percent_encode(path, URL_PATH))
+}
+
+/// The local path an entry location refers to, relative paths being taken
+/// from `base` (the playlist's directory). `None` for remote URLs.
+fn local_path(location: &str, base: &Path) -> Option<PathBuf> {
+    let location = location.trim();
+    let path = if let Some(rest) = location.strip_prefix("file://") {
+        // file:///music/a.mp3, or file://localhost/music/a.mp3
+        let rest = rest.strip_prefix("localhost").unwrap_or(rest);
+        PathBuf::from(percent_decode_str(rest).decode_utf8().ok()?.into_owned())
+    } else if location.contains("://") {
+        return None;
+    } else if location.contains('\\') && !location.contains('/') {
+        // Written by a Windows player
+        PathBuf::from(location.replace('\\', "/"))
+    } else {
+        PathBuf::from(location)
+    };
+    Some(normalize(&base.join(path)))
+}
+
+/// Resolve `.` and `..` without touching the filesystem
+fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                out.pop();
+            }
+            other => out.push(other),
+        }
+    }
+    out
+}
+
+/// A playlist entry matched against the library and filesystem
+#[derive(Debug, Clone)]
+pub struct Resolved {
+    pub entry: Entry,
+    /// The library track the entry refers to
+    pub track: Option<Track>,
+    /// A playable file: the track's path, or a file that exists but isn't in the library
+    pub path: Option<PathBuf>,
+}
+
+/// Below this similarity a tag match is not trusted
+const MATCH_THRESHOLD: f64 = 0.9;
+
+/// Tracks whose durations differ by more than this are different recordings
+const DURATION_TOLERANCE: i64 = 10;
+
+/// Match entries to library tracks: by path first (relative to `base`), then by
+/// artist and title from the entry's tags or file name.
+pub fn resolve(db: &DB, entries: Vec<Entry>, base: &Path) -> Result<Vec<Resolved>> {
+    entries
+        .into_iter()
+        .map(|entry| {
+            let local = local_path(&entry.location, base);
+            if let Some(path) = &local {
+                let canonical = path.canonicalize().ok();
+                for candidate in [Some(path), canonical.as_ref()].into_iter().flatten() {
+                    if let Some(track) = db.track_by_path(&candidate.to_string_lossy())? {
+                        return Ok(found(entry, track));
+                    }
+                }
+                if path.is_file() {
+                    return Ok(Resolved {
+                        path: local.clone(),
+                        entry,
+                        track: None,
+                    });
+                }
+            }
+            Ok(match fuzzy_match(db, &entry)? {
+                Some(track) => found(entry, track),
+                None => Resolved {
+                    entry,
+                    track: None,
+                    path: None,
+                },
+            })
+        })
+        .collect()
+}
+
+fn found(entry: Entry, track: Track) -> Resolved {
+    Resolved {
+        path: Some(PathBuf::from(&track.path)),
+        entry,
+        track: Some(track),
+    }
+}
+
+/// The best library match for an entry's artist and title, taken from its tags
+/// or else from a `01 - Artist - Title.mp3` style file name
+fn fuzzy_match(db: &DB, entry: &Entry) -> Result<Option<Track>> {
+    let mut tags = entry.clone();
+    if tags.title.is_none() {
+        let stem = Path::new(&entry.location.replace('\\', "/"))
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let stem = stem.trim_start_matches(|c: char| c.is_ascii_digit() || " -._".contains(c));
+        tags.set_display_title(stem);
+    }
+    let Some(title) = tags.title.as_deref() else {
+        return Ok(None);
+    };
+
+    let query = format!("{} {}", tags.artist.as_deref().unwrap_or(""), title);
+    let best = db
+        .search_any(&query, 25)?
+        .into_iter()
+        .map(|track| (similarity(&tags, &track), track))
+        .filter(|(score, _)| *score >= MATCH_THRESHOLD)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b));
+    Ok(best.map(|(_, track)| track))
+}
+
+/// How alike an entry's tags and a track are, from 0 to 1
+fn similarity(entry: &Entry, track: &Track) -> f64 {
+    if let (Some(a), Some(b)) = (entry.duration_seconds, track.duration_seconds)
+        && (a - b).abs() > DURATION_TOLERANCE
+    {
+        return 0.0;
+    }
+    let alike = |a: &str, b: &str| strsim::jaro_winkler(&simplify(a), &simplify(b));
+    let title = match (&entry.title, &track.title) {
+        (Some(a), Some(b)) => alike(a, b),
+        _ => return 0.0,
+    };
+    match (&entry.artist, &track.artist) {
+        (Some(a), Some(b)) => 0.6 * title + 0.4 * alike(a, b),
+        // A title alone is weak evidence; only an exact match passes
+        _ => 0.9 * title,
+    }
+}
+
+/// Lowercase, without bracketed suffixes like "(Remastered)" or punctuation
+fn simplify(text: &str) -> String {
+    let mut out = String::new();
+    let mut depth = 0usize;
+    for c in text.chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = depth.saturating_sub(1),
+            c if depth == 0 && c.is_alphanumeric() => out.extend(c.to_lowercase()),
+            c if depth == 0 && c.is_whitespace() && !out.ends_with(' ') => out.push(' '),
+            _ => {}
+        }
+    }
+    out.trim().to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::NewTrack;
+    use tempfile::{NamedTempFile, tempdir};
+
+    fn entry(location: &str, artist: &str, title: &str, duration: i64) -> Entry {
+        Entry {
+            location: location.into(),
+            artist: Some(artist.into()),
+            title: Some(title.into()),
+            album: None,
+            duration_seconds: Some(duration),
+        }
+    }
+
+    #[test]
+    fn test_formats_round_trip() {
+        let entries = vec![
+            entry(
+                "/music/BoC/01 Roygbiv.flac",
+                "Boards of Canada",
+                "Roygbiv",
+                151,
+            ),
+            entry(
+                "/music/Sigur Rós/Hoppípolla & co.mp3",
+                "Sigur Rós",
+                "Hoppípolla",
+                268,
+            ),
+            Entry {
+                location: "/music/unknown.ogg".into(),
+                ..Default::default()
+            },
+        ];
+        for format in [Format::M3u, Format::M3u8, Format::Pls, Format::Xspf] {
+            let mut out = Vec::new();
+            write(format, &entries, &mut out).unwrap();
+            let text = String::from_utf8(out).unwrap();
+            let mut parsed = parse(format, &text).unwrap();
+            if format == Format::Xspf {
+                // XSPF writes locations as file:// URLs
+                assert!(
+                    text.contains(
+                        "file:///music/Sigur%20R%C3%B3s/Hopp%C3%ADpolla%20&amp;%20co.mp3"
+                    )
+                );
+                for (parsed, original) in parsed.iter_mut().zip(&entries) {
+                    let base = Path::new("/");
+                    assert_eq!(
+                        local_path(&parsed.location, base),
+                        Some(PathBuf::from(&original.location))
+                    );
+                    parsed.location = original.location.clone();
+                }
+            }
+            assert_eq!(parsed, entries, "{}", format);
+        }
+    }
+
+    #[test]
+    fn test_local_paths() {
+        let base = Path::new("/music/lists");
+        let local = |location| local_path(location, base);
+        assert_eq!(local("../a.mp3"), Some("/music/a.mp3".into()));
+        assert_eq!(local("./b/./c.mp3"), Some("/music/lists/b/c.mp3".into()));
+        assert_eq!(local("/abs/d.mp3"), Some("/abs/d.mp3".into()));
+        assert_eq!(local("..\\Win\\e.mp3"), Some("/music/Win/e.mp3".into()));
+        assert_eq!(local("file:///x/f%20g.mp3"), Some("/x/f g.mp3".into()));
+        assert_eq!(local("file://localhost/x/h.mp3"), Some("/x/h.mp3".into()));
+        assert_eq!(local("http://radio.example/stream"), None);
+    }
+
+    #[test]
+    fn test_resolve() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let add = |path: &str, artist: &str, title: &str| {
+            db.insert_track(&NewTrack {
+                path: path.into(),
+                artist: Some(artist.into()),
+                title: Some(title.into()),
+                duration_seconds: Some(200),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        let roygbiv = add("/music/boc/roygbiv.flac", "Boards of Canada", "Roygbiv");
+        let julie = add(
+            "/music/boc/julie.flac",
+            "Boards of Canada",
+            "Julie and Candy",
+        );
+        let hopp = add("/music/sr/hoppipolla.flac", "Sigur Rós", "Hoppípolla");
+        add("/music/other/roygbiv.flac", "Somebody Else", "Roygbiv");
+
+        let dir = tempdir().unwrap();
+        let loose = dir.path().join("not-in-library.mp3");
+        std::fs::write(&loose, b"").unwrap();
+
+        let entries = vec![
+            // Relative path into the library
+            entry("../music/boc/roygbiv.flac", "", "", 0),
+            // Another machine's path, tags slightly different
+            entry(
+                "D:\\Music\\julie.mp3",
+                "Boards Of Canada",
+                "Julie & Candy (2002 Remaster)",
+                201,
+            ),
+            // No tags; artist and title from the file name
+            Entry {
+                location: "/old/03 - Sigur Rós - Hoppípolla.mp3".into(),
+                ..Default::default()
+            },
+            // A file that exists but was never scanned
+            Entry {
+                location: loose.display().to_string(),
+                ..Default::default()
+            },
+            // Same title, wrong artist and length
+            entry("/gone/x.mp3", "Aphex Twin", "Roygbiv", 400),
+            entry("/gone/y.mp3", "Nobody", "Nothing Like It", 100),
+        ];
+        let resolved = resolve(&db, entries, Path::new("/lists")).unwrap();
+        let ids: Vec<_> = resolved
+            .iter()
+            .map(|r| r.track.as_ref().map(|t| t.id))
+            .collect();
+        assert_eq!(
+            ids,
+            vec![Some(roygbiv), Some(julie), Some(hopp), None, None, None]
+        );
+        assert_eq!(resolved[3].path.as_deref(), Some(loose.as_path()));
+        assert!(resolved[4].path.is_none());
+        assert_eq!(
+            resolved[1].path,
+            Some(PathBuf::from("/music/boc/julie.flac"))
+        );
+    }
+}
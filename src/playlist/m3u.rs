@@ -0,0 +1,84 @@
+use std::io::{self, Write};
+
+use super::Entry;
+
+/// Parse extended or plain M3U; comments other than `#EXTINF` are skipped
+pub(super) fn parse(text: &str) -> Vec<Entry> {
+    let mut entries = Vec::new();
+    let mut info = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(extinf) = line.strip_prefix("#EXTINF:") {
+            info = Some(parse_extinf(extinf));
+        } else if !line.is_empty() && !line.starts_with('#') {
+            let mut entry: Entry = info.take().unwrap_or_default();
+            entry.location = line.to_owned();
+            entries.push(entry);
+        }
+    }
+    entries
+}
+
+/// `#EXTINF:<seconds>[ key="value"...],<Artist - Title>`
+fn parse_extinf(extinf: &str) -> Entry {
+    let (head, display) = extinf.split_once(',').unwrap_or((extinf, ""));
+    let mut entry = Entry {
+        duration_seconds: head
+            .split_whitespace()
+            .next()
+            .and_then(|d| d.parse::<f64>().ok())
+            .filter(|d| *d >= 0.0)
+            .map(|d| d.round() as i64),
+        ..Default::default()
+    };
+    entry.set_display_title(display);
+    entry
+}
+
+pub(super) fn write(entries: &[Entry], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "#EXTM3U")?;
+    for entry in entries {
+        let display = entry.display_title();
+        if display.is_some() || entry.duration_seconds.is_some() {
+            writeln!(
+                out,
+                "#EXTINF:{},{}",
+                entry.duration_seconds.unwrap_or(-1),
+                display.unwrap_or_default()
+            )?;
+        }
+        writeln!(out, "{}", entry.location)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_variants() {
+        let text = "#EXTM3U\r\n\
+            #EXTINF:123 tvg-id=\"x\",Autechre - Gantz Graf\r\n\
+            /music/gantz.flac\r\n\
+            # a comment\r\n\
+            \r\n\
+            plain.mp3\r\n\
+            #EXTINF:-1,Just A Title\r\n\
+            http://radio.example/stream\r\n";
+        let entries = parse(text);
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].artist.as_deref(), Some("Autechre"));
+        assert_eq!(entries[0].title.as_deref(), Some("Gantz Graf"));
+        assert_eq!(entries[0].duration_seconds, Some(123));
+        assert_eq!(
+            entries[1],
+            Entry {
+                location: "plain.mp3".into(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(entries[2].artist, None);
+        assert_eq!(entries[2].title.as_deref(), Some("Just A Title"));
+        assert_eq!(entries[2].duration_seconds, None);
+    }
+}
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use super::{Entry, Format, PlaylistError};
+
+/// Parse a PLS file: `FileN=`, `TitleN=` and `LengthN=` keys under `[playlist]`
+pub(super) fn parse(text: &str) -> Result<Vec<Entry>, PlaylistError> {
+    let mut entries: BTreeMap<u32, Entry> = BTreeMap::new();
+    let mut in_playlist = false;
+    let mut seen_header = false;
+    for line in text.lines().map(str::trim) {
+        if line.starts_with('[') {
+            in_playlist = line.eq_ignore_ascii_case("[playlist]");
+            seen_header |= in_playlist;
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        if !in_playlist {
+            continue;
+        }
+        let key = key.trim().to_ascii_lowercase();
+        let digits = key.find(|c: char| c.is_ascii_digit()).unwrap_or(key.len());
+        let Ok(index) = key[digits..].parse() else {
+            continue;
+        };
+        let entry = entries.entry(index).or_default();
+        let value = value.trim();
+        match &key[..digits] {
+            "file" => entry.location = value.to_owned(),
+            "title" => entry.set_display_title(value),
+            "length" => entry.duration_seconds = value.parse().ok().filter(|d: &i64| *d >= 0),
+            _ => {}
+        }
+    }
+    if !seen_header {
+        return Err(PlaylistError::Invalid {
+            format: Format::Pls,
+            message: "missing [playlist] section".into(),
+        });
+    }
+    Ok(entries
+        .into_values()
+        .filter(|entry| !entry.location.is_empty())
+        .collect())
+}
+
+pub(super) fn write(entries: &[Entry], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "[playlist]")?;
+    for (i, entry) in entries.iter().enumerate() {
+        let n = i + 1;
+        writeln!(out, "File{}={}", n, entry.location)?;
+        if let Some(display) = entry.display_title() {
+            writeln!(out, "Title{}={}", n, display)?;
+        }
+        writeln!(out, "Length{}={}", n, entry.duration_seconds.unwrap_or(-1))?;
+    }
+    writeln!(out, "NumberOfEntries={}", entries.len())?;
+    writeln!(out, "Version=2")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_out_of_order_keys() {
+        let text = "[playlist]\nnumberofentries=2\nTitle2=Stream\nFile2=http://radio.example/\n\
+            File1=a.mp3\nLength1=61\nLength2=-1\nVersion=2\n";
+        let entries = parse(text).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].location, "a.mp3");
+        assert_eq!(entries[0].duration_seconds, Some(61));
+        assert_eq!(entries[1].title.as_deref(), Some("Stream"));
+        assert_eq!(entries[1].duration_seconds, None);
+        assert!(parse("File1=a.mp3\n").is_err());
+    }
+}
@@ -0,0 +1,171 @@
+//! XSPF ("spiff"), the XML Shareable Playlist Format. Only the `<track>` fields
+//! used here are read, with a scanner rather than a full XML parser.
+
+use std::io::{self, Write};
+
+use super::{Entry, Format, PlaylistError, file_url};
+
+pub(super) fn parse(text: &str) -> Result<Vec<Entry>, PlaylistError> {
+    let invalid = |message: &str| PlaylistError::Invalid {
+        format: Format::Xspf,
+        message: message.to_owned(),
+    };
+    let Some(list) = element(text, "trackList") else {
+        return Err(invalid("missing <trackList>"));
+    };
+
+    let mut entries = Vec::new();
+    let mut rest = list;
+    while let Some(start) = find_tag(rest, "track") {
+        let body = &rest[start..];
+        let end = body
+            .find("</track>")
+            .ok_or_else(|| invalid("unclosed <track>"))?;
+        let track = &body[..end];
+        rest = &body[end + "</track>".len()..];
+
+        let field = |name| element(track, name).map(|v| unescape(v.trim()));
+        let Some(location) = field("location") else {
+            continue;
+        };
+        entries.push(Entry {
+            location,
+            title: field("title"),
+            artist: field("creator"),
+            album: field("album"),
+            duration_seconds: field("duration")
+                .and_then(|ms| ms.parse::<i64>().ok())
+                .map(|ms| (ms + 500) / 1000),
+        });
+    }
+    Ok(entries)
+}
+
+/// Byte offset just past the opening `<name>` or `<name ...>` tag
+fn find_tag(text: &str, name: &str) -> Option<usize> {
+    let open = format!("<{}", name);
+    let mut from = 0;
+    while let Some(i) = text[from..].find(&open) {
+        let after = from + i + open.len();
+        match text[after..].chars().next() {
+            Some('>') => return Some(after + 1),
+            Some(c) if c.is_whitespace() => return Some(after + text[after..].find('>')? + 1),
+            _ => from = after,
+        }
+    }
+    None
+}
+
+/// Contents of the first `<name>` element in `text`
+fn element<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let start = find_tag(text, name)?;
+    let end = text[start..].find(&format!("</{}>", name))?;
+    Some(&text[start..start + end])
+}
+
+fn unescape(text: &str) -> String {
+    if let Some(cdata) = text
+        .strip_prefix("<![CDATA[")
+        .and_then(|t| t.strip_suffix("]]>"))
+    {
+        return cdata.to_owned();
+    }
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        out.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let Some(semi) = rest.find(';') else { break };
+        let decoded = match &rest[1..semi] {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            entity => entity
+                .strip_prefix("#x")
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(str::parse))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match decoded {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[semi + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+pub(super) fn write(entries: &[Entry], out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+    writeln!(
+        out,
+        r#"<playlist version="1" xmlns="http://xspf.org/ns/0/">"#
+    )?;
+    writeln!(out, "  <trackList>")?;
+    for entry in entries {
+        writeln!(out, "    <track>")?;
+        let location = if entry.location.starts_with('/') {
+            file_url(&entry.location)
+        } else {
+            entry.location.clone()
+        };
+        writeln!(out, "      <location>{}</location>", escape(&location))?;
+        for (name, value) in [
+            ("creator", &entry.artist),
+            ("album", &entry.album),
+            ("title", &entry.title),
+        ] {
+            if let Some(value) = value {
+                writeln!(out, "      <{0}>{1}</{0}>", name, escape(value))?;
+            }
+        }
+        if let Some(seconds) = entry.duration_seconds {
+            writeln!(out, "      <duration>{}</duration>", seconds * 1000)?;
+        }
+        writeln!(out, "    </track>")?;
+    }
+    writeln!(out, "  </trackList>")?;
+    writeln!(out, "</playlist>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let text = r#"<?xml version="1.0"?>
+            <playlist version="1" xmlns="http://xspf.org/ns/0/">
+              <title>Not a track title</title>
+              <trackList>
+                <track><location>file:///m/a.flac</location><title>A &amp; B&#33;</title>
+                  <creator><![CDATA[<Artist>]]></creator><duration>61499</duration></track>
+                <track><title>No location</title></track>
+                <track xml:id="x"><location>b.mp3</location></track>
+              </trackList>
+            </playlist>"#;
+        let entries = parse(text).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].title.as_deref(), Some("A & B!"));
+        assert_eq!(entries[0].artist.as_deref(), Some("<Artist>"));
+        assert_eq!(entries[0].duration_seconds, Some(61));
+        assert_eq!(entries[1].location, "b.mp3");
+        assert!(parse("<playlist/>").is_err());
+    }
+}
@@ -0,0 +1,372 @@
+//! `info`: everything symphonia makes of a file, for finding out why one
+//! won't scan or play. Probes the file the same way the player does, then
+//! decodes every audio track to the end to surface decode errors.
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, Decoder, DecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::{MediaSourceStream, ReadBytes, SeekBuffered};
+use symphonia::core::meta::{MetadataOptions, MetadataRevision};
+use symphonia::core::probe::{Descriptor, Instantiate, QueryDescriptor};
+use symphonia::default::formats::{
+    AdtsReader, CafReader, FlacReader, IsoMp4Reader, MkvReader, OggReader, WavReader,
+};
+use symphonia_metadata::id3v2::Id3v2Reader;
+
+use crate::formats::dsd::DsdReader;
+use crate::formats::midi::MidiReader;
+use crate::formats::tracker::TrackerReader;
+
+/// Decode errors kept per track; the rest are only counted
+const MAX_ERRORS: usize = 20;
+
+#[derive(Debug, Serialize)]
+pub struct FileInfo {
+    pub path: String,
+    pub format: Option<Name>,
+    pub tracks: Vec<TrackInfo>,
+    /// Tag blocks ahead of the stream, then the container's own, oldest first
+    pub metadata: Vec<RevisionInfo>,
+    pub decode: Vec<DecodeReport>,
+    /// What stopped the trial decode before the end of the file
+    pub demux_error: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Name {
+    pub short: String,
+    pub long: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TrackInfo {
+    pub id: u32,
+    pub codec: Name,
+    pub language: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub sample_format: Option<String>,
+    pub bits_per_sample: Option<u32>,
+    pub bits_per_coded_sample: Option<u32>,
+    pub channels: Option<usize>,
+    pub channel_map: Option<String>,
+    pub channel_layout: Option<String>,
+    pub frames: Option<u64>,
+    pub start_ts: u64,
+    /// As a fraction of a second, such as `1/44100`
+    pub time_base: Option<String>,
+    pub duration_seconds: Option<f64>,
+    /// Encoder delay and padding, in frames
+    pub delay: Option<u32>,
+    pub padding: Option<u32>,
+    pub max_frames_per_packet: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevisionInfo {
+    /// The tag format, such as `id3v2`, or `container` for the stream's own
+    pub source: String,
+    pub tags: Vec<TagInfo>,
+    pub visuals: Vec<VisualInfo>,
+    /// Application-specific blocks, by identifier
+    pub vendor_data: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TagInfo {
+    pub key: String,
+    pub standard_key: Option<String>,
+    pub value: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct VisualInfo {
+    pub media_type: String,
+    pub usage: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct DecodeReport {
+    pub track_id: u32,
+    pub packets: u64,
+    pub frames: u64,
+    /// The first few errors, with the timestamp of the packet
+    pub errors: Vec<String>,
+    pub error_count: usize,
+    /// Why the track couldn't be decoded at all
+    pub unsupported: Option<String>,
+}
+
+/// Readers registered by `crate::formats::get`, in its order, so that
+/// whatever it found can be named
+fn descriptors() -> impl Iterator<Item = &'static Descriptor> {
+    [
+        AdtsReader::query(),
+        CafReader::query(),
+        FlacReader::query(),
+        IsoMp4Reader::query(),
+        WavReader::query(),
+        OggReader::query(),
+        MkvReader::query(),
+        Id3v2Reader::query(),
+        MidiReader::query(),
+        DsdReader::query(),
+        TrackerReader::query(),
+    ]
+    .into_iter()
+    .flatten()
+}
+
+/// Name the reader whose start marker the probe just stopped at, matching
+/// markers the way the probe does
+fn marker_name(mss: &mut MediaSourceStream) -> Result<Option<Name>> {
+    let mut context = [0u8; 16];
+    mss.read_buf_exact(&mut context)?;
+    mss.seek_buffered_rev(context.len());
+    Ok(descriptors()
+        .find(|descriptor| {
+            descriptor
+                .markers
+                .iter()
+                .any(|marker| context.starts_with(marker))
+        })
+        .map(|descriptor| Name {
+            short: descriptor.short_name.into(),
+            long: descriptor.long_name.into(),
+        }))
+}
+
+/// Probe `path`, read all of its metadata and trial-decode its audio
+pub fn probe(path: &Path) -> Result<FileInfo> {
+    let file = File::open(path).with_context(|| format!("opening {}", path.display()))?;
+    let mut mss = MediaSourceStream::new(Box::new(file), Default::default());
+
+    // What `Probe::format` does, keeping track of what was found
+    let probe = crate::formats::get();
+    let mut metadata = Vec::new();
+    let (format, mut reader) = loop {
+        let instantiate = probe.next(&mut mss).context("unrecognised format")?;
+        let name = marker_name(&mut mss)?;
+        match instantiate {
+            Instantiate::Format(open) => {
+                let reader = open(mss, &FormatOptions::default())
+                    .context("the container could not be read")?;
+                break (name, reader);
+            }
+            Instantiate::Metadata(open) => {
+                let revision = open(&MetadataOptions::default())
+                    .read_all(&mut mss)
+                    .context("a tag block could not be read")?;
+                let source = name.map_or_else(|| "unknown".into(), |name| name.short);
+                metadata.push(RevisionInfo::new(source, &revision));
+            }
+        }
+    };
+
+    let tracks = reader.tracks().iter().map(TrackInfo::new).collect();
+    let (decode, demux_error) = trial_decode(reader.as_mut());
+
+    // Revisions met while decoding are queued behind the first
+    let mut log = reader.metadata();
+    while let Some(revision) = log.pop() {
+        metadata.push(RevisionInfo::new("container".into(), &revision));
+    }
+    if let Some(revision) = log.current() {
+        metadata.push(RevisionInfo::new("container".into(), revision));
+    }
+
+    Ok(FileInfo {
+        path: path.display().to_string(),
+        format,
+        tracks,
+        metadata,
+        decode,
+        demux_error,
+    })
+}
+
+fn codec_name(codec: symphonia::core::codecs::CodecType) -> Name {
+    if codec == CODEC_TYPE_NULL {
+        return Name {
+            short: "none".into(),
+            long: "No codec".into(),
+        };
+    }
+    match crate::codecs::get().get_codec(codec) {
+        Some(descriptor) => Name {
+            short: descriptor.short_name.into(),
+            long: descriptor.long_name.into(),
+        },
+        None => Name {
+            short: codec.to_string(),
+            long: "Unsupported codec".into(),
+        },
+    }
+}
+
+impl TrackInfo {
+    fn new(track: &Track) -> Self {
+        let params = &track.codec_params;
+        let duration_seconds = params.time_base.zip(params.n_frames).map(|(base, frames)| {
+            let time = base.calc_time(frames);
+            time.seconds as f64 + time.frac
+        });
+        Self {
+            id: track.id,
+            codec: codec_name(params.codec),
+            language: track.language.clone(),
+            sample_rate: params.sample_rate,
+            sample_format: params.sample_format.map(|format| format!("{:?}", format)),
+            bits_per_sample: params.bits_per_sample,
+            bits_per_coded_sample: params.bits_per_coded_sample,
+            channels: params.channels.map(|channels| channels.count()),
+            channel_map: params.channels.map(|channels| format!("{:?}", channels)),
+            channel_layout: params.channel_layout.map(|layout| format!("{:?}", layout)),
+            frames: params.n_frames,
+            start_ts: params.start_ts,
+            time_base: params
+                .time_base
+                .map(|base| format!("{}/{}", base.numer, base.denom)),
+            duration_seconds,
+            delay: params.delay,
+            padding: params.padding,
+            max_frames_per_packet: params.max_frames_per_packet,
+        }
+    }
+}
+
+impl RevisionInfo {
+    fn new(source: String, revision: &MetadataRevision) -> Self {
+        Self {
+            source,
+            tags: revision
+                .tags()
+                .iter()
+                .map(|tag| TagInfo {
+                    key: tag.key.clone(),
+                    standard_key: tag.std_key.map(|key| format!("{:?}", key)),
+                    value: tag.value.to_string(),
+                })
+                .collect(),
+            visuals: revision
+                .visuals()
+                .iter()
+                .map(|visual| VisualInfo {
+                    media_type: visual.media_type.clone(),
+                    usage: visual.usage.map(|usage| format!("{:?}", usage)),
+                    width: visual.dimensions.map(|size| size.width),
+                    height: visual.dimensions.map(|size| size.height),
+                    bytes: visual.data.len(),
+                })
+                .collect(),
+            vendor_data: revision
+                .vendor_data()
+                .iter()
+                .map(|data| data.ident.clone())
+                .collect(),
+        }
+    }
+}
+
+/// Decode every packet of every audio track, counting what comes out and
+/// collecting what goes wrong. Also returns the error that ended demuxing,
+/// unless it was the end of the file.
+fn trial_decode(reader: &mut dyn FormatReader) -> (Vec<DecodeReport>, Option<String>) {
+    let mut tracks: Vec<(DecodeReport, Option<Box<dyn Decoder>>)> = reader
+        .tracks()
+        .iter()
+        .filter(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+        .map(|track| {
+            let mut report = DecodeReport {
+                track_id: track.id,
+                ..Default::default()
+            };
+            let decoder = crate::codecs::get()
+                .make(&track.codec_params, &DecoderOptions::default())
+                .map_err(|e| report.unsupported = Some(e.to_string()))
+                .ok();
+            (report, decoder)
+        })
+        .collect();
+
+    let demux_error = loop {
+        let packet = match reader.next_packet() {
+            Ok(packet) => packet,
+            Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => break None,
+            Err(Error::ResetRequired) => {
+                for decoder in tracks
+                    .iter_mut()
+                    .filter_map(|(_, decoder)| decoder.as_mut())
+                {
+                    decoder.reset();
+                }
+                continue;
+            }
+            Err(e) => break Some(e.to_string()),
+        };
+        let Some((report, Some(decoder))) = tracks
+            .iter_mut()
+            .find(|(report, _)| report.track_id == packet.track_id())
+        else {
+            continue;
+        };
+        report.packets += 1;
+        match decoder.decode(&packet) {
+            Ok(buffer) => report.frames += buffer.frames() as u64,
+            Err(e) => {
+                report.error_count += 1;
+                if report.errors.len() < MAX_ERRORS {
+                    report.errors.push(format!("at {}: {}", packet.ts(), e));
+                }
+            }
+        }
+    };
+    (
+        tracks.into_iter().map(|(report, _)| report).collect(),
+        demux_error,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::tests::wav;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_probe_wav() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("tone.wav");
+        std::fs::write(&path, wav(&[(b"INAM", "Tone"), (b"IART", "Nobody")])).unwrap();
+
+        let info = probe(&path).unwrap();
+        assert_eq!(info.format.unwrap().short, "wave");
+        let track = &info.tracks[0];
+        assert_eq!(track.codec.short, "pcm_s16le");
+        assert_eq!(
+            (track.sample_rate, track.channels, track.frames),
+            (Some(8000), Some(1), Some(8000))
+        );
+        assert_eq!(track.duration_seconds, Some(1.0));
+        let tags = &info.metadata[0].tags;
+        assert_eq!(info.metadata[0].source, "container");
+        // Values are shown as stored, terminator and all
+        assert!(
+            tags.iter()
+                .any(|tag| tag.standard_key.as_deref() == Some("TrackTitle")
+                    && tag.value == "Tone\0")
+        );
+        let decode = &info.decode[0];
+        assert_eq!((decode.frames, decode.error_count), (8000, 0));
+        assert_eq!(info.demux_error, None);
+
+        std::fs::write(&path, b"not audio at all").unwrap();
+        assert!(probe(&path).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Resuming long files, such as audiobooks and podcasts, where they were
+//! left off.
+//!
+//! Positions are saved per track when playback stops, pauses or moves on,
+//! and now and then while it plays so an unclean exit loses little.
+
+use anyhow::Result;
+use std::time::{Duration, Instant};
+
+use crate::db::{DB, Track};
+use crate::player::{PlayerState, PlayerStatus};
+
+/// Within this much of the end a file counts as finished, and starts over
+/// next time
+const FINISHED_WITHIN: Duration = Duration::from_secs(30);
+
+/// How often the position of a file playing is saved
+const SAVE_EVERY: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResumeOptions {
+    /// Shorter files always start from the beginning
+    pub min_length: Duration,
+    /// How far before the saved position to pick up again
+    pub rewind: Duration,
+}
+
+impl Default for ResumeOptions {
+    fn default() -> Self {
+        Self {
+            min_length: Duration::from_secs(600),
+            rewind: Duration::from_secs(5),
+        }
+    }
+}
+
+impl ResumeOptions {
+    fn applies(&self, length: Option<Duration>) -> bool {
+        length.is_some_and(|length| length >= self.min_length)
+    }
+}
+
+/// Where to start playing `track`: a little before where it was left off,
+/// or `None` for the beginning
+pub fn start_position(db: &DB, track: &Track, options: &ResumeOptions) -> Result<Option<Duration>> {
+    let length = track
+        .duration_seconds
+        .map(|secs| Duration::from_secs(secs.max(0) as u64));
+    if !options.applies(length) {
+        return Ok(None);
+    }
+    Ok(db
+        .resume_position(track.id)?
+        .map(|ms| Duration::from_millis(ms.max(0) as u64).saturating_sub(options.rewind))
+        .filter(|position| !position.is_zero()))
+}
+
+/// Remember where the library track in `status` is, or forget it if it was
+/// played to the end
+pub fn save(db: &DB, status: &PlayerStatus, options: &ResumeOptions) -> Result<()> {
+    let (Some(path), Some(position)) = (&status.current_file, status.position) else {
+        return Ok(());
+    };
+    let Some(track) = db.track_by_path(&path.to_string_lossy())? else {
+        return Ok(());
+    };
+    let length = status.duration.or(track
+        .duration_seconds
+        .map(|secs| Duration::from_secs(secs.max(0) as u64)));
+    if !options.applies(length) {
+        return Ok(());
+    }
+    match length {
+        Some(length) if position + FINISHED_WITHIN >= length => db.clear_resume_position(track.id),
+        _ => db.save_resume_position(track.id, position.as_millis() as i64),
+    }
+}
+
+/// Watches player status snapshots and saves resume positions as playback
+/// pauses, stops or moves to another file
+#[derive(Debug, Default)]
+pub struct ResumeTracker {
+    last: Option<PlayerStatus>,
+    saved_at: Option<Instant>,
+}
+
+impl ResumeTracker {
+    /// Take a status snapshot taken at `at`
+    pub fn observe(
+        &mut self,
+        db: &DB,
+        status: &PlayerStatus,
+        at: Instant,
+        options: &ResumeOptions,
+    ) -> Result<()> {
+        let last = self.last.replace(status.clone());
+        let due = self
+            .saved_at
+            .is_none_or(|saved| at.saturating_duration_since(saved) >= SAVE_EVERY);
+        match last {
+            // Stopped or moved on: the last snapshot is the closest to where it ended
+            Some(last) if last.current_file != status.current_file => {
+                self.saved_at = Some(at);
+                save(db, &last, options)
+            }
+            Some(last)
+                if last.state == PlayerState::Playing && status.state == PlayerState::Paused =>
+            {
+                self.saved_at = Some(at);
+                save(db, status, options)
+            }
+            _ if status.state == PlayerState::Playing && due => {
+                self.saved_at = Some(at);
+                save(db, status, options)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Save the position of whatever was playing, as on exit
+    pub fn flush(&mut self, db: &DB, options: &ResumeOptions) -> Result<()> {
+        match self.last.take() {
+            Some(last) => save(db, &last, options),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::NewTrack;
+    use tempfile::NamedTempFile;
+
+    fn status(file: Option<&str>, state: PlayerState, secs: u64) -> PlayerStatus {
+        PlayerStatus {
+            state,
+            position: file.map(|_| Duration::from_secs(secs)),
+            duration: file.map(|_| Duration::from_secs(3600)),
+            current_file: file.map(Into::into),
+            volume: 1.0,
+            chapters: Vec::new(),
+            chapter: None,
+        }
+    }
+
+    #[test]
+    fn test_tracker_saves_and_resumes() {
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let id = db
+            .insert_track(&NewTrack {
+                path: "/books/dune.m4b".into(),
+                duration_seconds: Some(3600),
+                ..Default::default()
+            })
+            .unwrap();
+        let track = db.track(id).unwrap().unwrap();
+        let options = ResumeOptions::default();
+        let mut tracker = ResumeTracker::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let book = Some("/books/dune.m4b");
+
+        tracker
+            .observe(
+                &db,
+                &status(book, PlayerState::Playing, 100),
+                at(0),
+                &options,
+            )
+            .unwrap();
+        tracker
+            .observe(
+                &db,
+                &status(book, PlayerState::Playing, 105),
+                at(5),
+                &options,
+            )
+            .unwrap();
+        assert_eq!(db.resume_position(id).unwrap(), Some(100_000));
+        tracker
+            .observe(
+                &db,
+                &status(book, PlayerState::Paused, 107),
+                at(7),
+                &options,
+            )
+            .unwrap();
+        assert_eq!(db.resume_position(id).unwrap(), Some(107_000));
+        tracker
+            .observe(
+                &db,
+                &status(book, PlayerState::Playing, 200),
+                at(9),
+                &options,
+            )
+            .unwrap();
+        tracker
+            .observe(
+                &db,
+                &status(None, PlayerState::Stopped, 0),
+                at(10),
+                &options,
+            )
+            .unwrap();
+        assert_eq!(
+            start_position(&db, &track, &options).unwrap(),
+            Some(Duration::from_secs(195))
+        );
+
+        // Played to the end: start over next time
+        save(&db, &status(book, PlayerState::Playing, 3590), &options).unwrap();
+        assert_eq!(start_position(&db, &track, &options).unwrap(), None);
+
+        // Too short to bother with
+        let short = ResumeOptions {
+            min_length: Duration::from_secs(7200),
+            ..options
+        };
+        save(&db, &status(book, PlayerState::Playing, 300), &short).unwrap();
+        assert_eq!(db.resume_position(id).unwrap(), None);
+    }
+}
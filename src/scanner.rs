@@ -0,0 +1,839 @@
+//! Library scanner: walks a directory, reads tags with symphonia and imports
+//! every audio file into the library. A file with a cue sheet is imported as
+//! the tracks the sheet splits it into.
+
+use anyhow::{Context, Result, bail};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::{MetadataOptions, StandardTagKey, Tag};
+use symphonia::core::probe::Hint;
+use walkdir::WalkDir;
+
+use crate::chapters;
+use crate::cue::{self, Split};
+use crate::db::{DB, NewTrack};
+use crate::streams;
+use crate::tags;
+
+/// File extensions the scanner picks up
+pub const AUDIO_EXTENSIONS: &[&str] = &[
+    "aac", "aif", "aiff", "caf", "dff", "dsf", "flac", "it", "kar", "m4a", "m4b", "m4v", "mid",
+    "midi", "mka", "mkv", "mod", "mp3", "mp4", "oga", "ogg", "opus", "s3m", "wav", "webm", "xm",
+];
+
+/// Outcome of a scan
+#[derive(Debug, Default)]
+pub struct ScanReport {
+    pub imported: usize,
+    /// Files skipped because they haven't changed since they were read
+    pub unchanged: usize,
+    /// Tracks that followed their file to a new path
+    pub moved: usize,
+    /// Tracks whose files are gone
+    pub removed: usize,
+    /// Files that looked like audio but could not be read, with the reason
+    pub failed: Vec<(PathBuf, String)>,
+    /// Directories skipped because an interrupted scan had already finished them
+    pub resumed: usize,
+    /// The scan was stopped before it finished
+    pub cancelled: bool,
+}
+
+/// Whether a path has one of the `AUDIO_EXTENSIONS`
+pub fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// Tracks written per transaction while scanning
+const BATCH_SIZE: usize = 256;
+
+/// Longest a probed track waits for its batch to be written
+const BATCH_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How a scan runs
+#[derive(Debug, Clone)]
+pub struct ScanOptions {
+    /// Files probed at once
+    pub threads: usize,
+    /// Read every file again, even ones unchanged since they were imported
+    pub full: bool,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        Self {
+            threads: thread::available_parallelism().map_or(4, |n| n.get()),
+            full: false,
+        }
+    }
+}
+
+/// How far a scan has got
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    /// Files probed so far
+    pub done: usize,
+    /// Files to probe in all
+    pub total: usize,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Files probed per second
+    pub fn rate(&self) -> f64 {
+        self.done as f64 / self.elapsed.as_secs_f64().max(0.001)
+    }
+
+    /// Time left at the current rate
+    pub fn eta(&self) -> Option<Duration> {
+        let rate = self.rate();
+        (self.done > 0 && rate > 0.0)
+            .then(|| Duration::from_secs_f64((self.total - self.done) as f64 / rate))
+    }
+}
+
+/// Import every audio file under `root`, then drop albums and artists that no
+/// longer have tracks
+pub fn scan(db: &DB, root: &Path) -> Result<ScanReport> {
+    scan_with(
+        db,
+        root,
+        &ScanOptions::default(),
+        &AtomicBool::new(false),
+        |_| {},
+    )
+}
+
+/// Import every new or modified audio file under `root`, probing files on
+/// `options.threads` threads while this thread writes them in batches.
+///
+/// Setting `cancel` stops the scan after the files already being probed; the
+/// report then has `cancelled` set. Finished directories are recorded as the
+/// scan goes, so the next scan of the same root picks up where it stopped.
+pub fn scan_with(
+    db: &DB,
+    root: &Path,
+    options: &ScanOptions,
+    cancel: &AtomicBool,
+    mut progress: impl FnMut(&Progress),
+) -> Result<ScanReport> {
+    let started = Instant::now();
+    let root = std::path::absolute(root)?;
+    let root_key = root.to_string_lossy().into_owned();
+    let mut report = ScanReport::default();
+    let finished = db.scanned_directories(&root_key)?;
+    report.resumed = finished.len();
+    let known = if options.full {
+        HashMap::new()
+    } else {
+        db.track_files(&root_key)?
+    };
+
+    // Walk everything first so progress has a total to go by
+    let mut pending: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+    let mut sheets = SheetTimes::default();
+    for entry in WalkDir::new(&root).follow_links(true).sort_by_file_name() {
+        if cancel.load(Ordering::Relaxed) {
+            report.cancelled = true;
+            return Ok(report);
+        }
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().unwrap_or(&root).to_owned();
+                // Don't count a directory as done if it couldn't be listed
+                pending.remove(&path);
+                report.failed.push((path, e.to_string()));
+                continue;
+            }
+        };
+        let path = entry.path();
+        if entry.file_type().is_dir() {
+            if !finished.contains(path.to_string_lossy().as_ref()) {
+                pending.insert(path.to_owned(), Vec::new());
+            }
+            continue;
+        }
+        if !entry.file_type().is_file() || !is_audio_file(path) {
+            continue;
+        }
+        let Some(files) = path.parent().and_then(|dir| pending.get_mut(dir)) else {
+            continue;
+        };
+        let recorded = known
+            .get(path.to_string_lossy().as_ref())
+            .copied()
+            .flatten();
+        let modified = sheets.modified(path, entry.metadata().ok().as_ref());
+        if recorded.is_some() && recorded == modified {
+            report.unchanged += 1;
+        } else {
+            files.push(path.to_owned());
+        }
+    }
+
+    let directories: Vec<String> = pending
+        .keys()
+        .map(|dir| dir.to_string_lossy().into_owned())
+        .collect();
+    let mut remaining: Vec<usize> = pending.values().map(Vec::len).collect();
+    let mut status = Progress {
+        done: 0,
+        total: remaining.iter().sum(),
+        elapsed: started.elapsed(),
+    };
+    progress(&status);
+
+    let (job_sender, jobs) = mpsc::channel();
+    for (index, files) in pending.into_values().enumerate() {
+        for file in files {
+            job_sender.send((index, file))?;
+        }
+    }
+    drop(job_sender);
+    let jobs = Mutex::new(jobs);
+
+    // Directories with nothing left to read are done straight away
+    let mut done_dirs: Vec<String> = (0..directories.len())
+        .filter(|&i| remaining[i] == 0)
+        .map(|i| directories[i].clone())
+        .collect();
+    let mut batch = Vec::new();
+    let mut last_write = Instant::now();
+    thread::scope(|scope| -> Result<()> {
+        let (result_sender, results) = mpsc::channel();
+        for _ in 0..options.threads.max(1) {
+            let result_sender = result_sender.clone();
+            let jobs = &jobs;
+            scope.spawn(move || {
+                while !cancel.load(Ordering::Relaxed) {
+                    let Ok((index, file)) = jobs.lock().unwrap().recv() else {
+                        break;
+                    };
+                    let tracks = read_tracks(&file);
+                    if result_sender.send((index, file, tracks)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(result_sender);
+
+        for (index, file, tracks) in results {
+            match tracks {
+                Ok(tracks) => batch.push((file.to_string_lossy().into_owned(), tracks)),
+                Err(e) => report.failed.push((file, format!("{:#}", e))),
+            }
+            remaining[index] -= 1;
+            if remaining[index] == 0 {
+                done_dirs.push(directories[index].clone());
+            }
+            status.done += 1;
+            status.elapsed = started.elapsed();
+            progress(&status);
+            if batch.len() >= BATCH_SIZE || last_write.elapsed() >= BATCH_INTERVAL {
+                db.import_batch(&root_key, &batch, &done_dirs)?;
+                report.imported += batch.iter().map(|(_, tracks)| tracks.len()).sum::<usize>();
+                batch.clear();
+                done_dirs.clear();
+                last_write = Instant::now();
+            }
+        }
+        Ok(())
+    })?;
+    db.import_batch(&root_key, &batch, &done_dirs)?;
+    report.imported += batch.iter().map(|(_, tracks)| tracks.len()).sum::<usize>();
+
+    if cancel.load(Ordering::Relaxed) && status.done < status.total {
+        report.cancelled = true;
+    } else {
+        db.finish_scan(&root_key)?;
+    }
+    db.prune_catalog()?;
+    Ok(report)
+}
+
+/// Bring the library in line with `root`: import new and modified files, skip
+/// files unchanged since they were read and drop tracks whose files are gone
+pub fn reconcile(db: &DB, root: &Path) -> Result<ScanReport> {
+    let mut report = ScanReport::default();
+    reconcile_into(db, root, &mut report)?;
+    db.prune_catalog()?;
+    Ok(report)
+}
+
+fn reconcile_into(db: &DB, root: &Path, report: &mut ScanReport) -> Result<()> {
+    let root = std::path::absolute(root)?;
+    // An unmounted share looks just like a deleted collection
+    if !root.is_dir() {
+        bail!("{} is not a directory", root.display());
+    }
+    let mut known = db.track_files(&root.to_string_lossy())?;
+    let mut unreadable = Vec::new();
+    let mut sheets = SheetTimes::default();
+    for entry in WalkDir::new(&root).follow_links(true).sort_by_file_name() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                let path = e.path().unwrap_or(&root).to_owned();
+                report.failed.push((path.clone(), e.to_string()));
+                unreadable.push(path);
+                continue;
+            }
+        };
+        if !entry.file_type().is_file() || !is_audio_file(entry.path()) {
+            continue;
+        }
+        let recorded = known.remove(entry.path().to_string_lossy().as_ref());
+        let modified = sheets.modified(entry.path(), entry.metadata().ok().as_ref());
+        if recorded
+            .flatten()
+            .is_some_and(|recorded| Some(recorded) == modified)
+        {
+            report.unchanged += 1;
+            continue;
+        }
+        import(db, entry.path(), report)?;
+    }
+    // Keep tracks in directories that couldn't be read rather than losing them
+    for path in known.keys() {
+        if !unreadable
+            .iter()
+            .any(|dir| Path::new(path).starts_with(dir))
+        {
+            report.removed += db.remove_tracks(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Bring one changed path up to date: an audio file is read again if it was
+/// modified, a directory is reconciled and a path that no longer exists is
+/// dropped. A cue sheet that changed or went has the audio files beside it
+/// read again. The catalog is left for the caller to prune.
+pub fn sync_path(db: &DB, path: &Path, report: &mut ScanReport) -> Result<()> {
+    if cue::is_cue_file(path) {
+        return resplit(db, path, report);
+    }
+    match fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => reconcile_into(db, path, report),
+        Ok(meta) if is_audio_file(path) => {
+            let recorded = db.track_files(&path.to_string_lossy())?;
+            let modified = SheetTimes::default().modified(path, Some(&meta));
+            if recorded
+                .values()
+                .any(|&recorded| recorded.is_some() && recorded == modified)
+            {
+                report.unchanged += 1;
+                return Ok(());
+            }
+            import(db, path, report)
+        }
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            report.removed += db.remove_tracks(&path.to_string_lossy())?;
+            Ok(())
+        }
+        Err(e) => {
+            report.failed.push((path.to_owned(), e.to_string()));
+            Ok(())
+        }
+    }
+}
+
+/// Read again the audio files beside a cue sheet, which may now be split
+/// differently
+fn resplit(db: &DB, sheet: &Path, report: &mut ScanReport) -> Result<()> {
+    let Some(dir) = sheet.parent() else {
+        return Ok(());
+    };
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Ok(());
+    };
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_audio_file(path))
+        .collect();
+    files.sort();
+    for file in files {
+        import(db, &file, report)?;
+    }
+    Ok(())
+}
+
+fn import(db: &DB, path: &Path, report: &mut ScanReport) -> Result<()> {
+    match read_tracks(path) {
+        Ok(tracks) => {
+            let file = std::path::absolute(path)?;
+            db.import_file(&file.to_string_lossy(), &tracks)?;
+            report.imported += tracks.len();
+        }
+        Err(e) => report.failed.push((path.to_owned(), format!("{:#}", e))),
+    }
+    Ok(())
+}
+
+/// Modification time in unix seconds
+fn mtime(meta: &Metadata) -> Option<i64> {
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(modified.as_secs() as i64)
+}
+
+/// Modification times of the cue sheets in each directory, looked up once
+/// per directory
+#[derive(Default)]
+struct SheetTimes(HashMap<PathBuf, Option<i64>>);
+
+impl SheetTimes {
+    /// When an audio file last changed, counting edits to the cue sheets
+    /// beside it, which change how it is split
+    fn modified(&mut self, file: &Path, meta: Option<&Metadata>) -> Option<i64> {
+        let dir = file.parent().unwrap_or(Path::new("")).to_owned();
+        let sheets = *self
+            .0
+            .entry(dir)
+            .or_insert_with(|| cue::sheets_modified(file));
+        meta.and_then(mtime).max(sheets)
+    }
+}
+
+/// Read the tags and duration of an audio file: one track, or one per cue
+/// sheet track if a sheet splits it
+pub fn read_tracks(path: &Path) -> Result<Vec<NewTrack>> {
+    let path = std::path::absolute(path)?;
+    let file = File::open(&path).with_context(|| format!("opening {}", path.display()))?;
+    let file_mtime = SheetTimes::default().modified(&path, file.metadata().ok().as_ref());
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
+        hint.with_extension(ext);
+    }
+    let mut probed = crate::formats::get()
+        .format(
+            &hint,
+            mss,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )
+        .context("unrecognised format")?;
+
+    // Tags may sit in a container header (ID3v2) or in the stream itself
+    let mut tags: Vec<Tag> = Vec::new();
+    if let Some(metadata) = probed.metadata.get()
+        && let Some(revision) = metadata.current()
+    {
+        tags.extend_from_slice(revision.tags());
+    }
+    if let Some(revision) = probed.format.metadata().current() {
+        tags.extend_from_slice(revision.tags());
+    }
+
+    // The stream the player picks by default, not a video stream ahead of it
+    let format = &probed.format;
+    let params = streams::choose(format.tracks(), format.default_track(), &Default::default())
+        .map(|track| track.codec_params.clone())
+        .unwrap_or_default();
+    let duration = params
+        .time_base
+        .zip(params.n_frames)
+        .map(|(base, frames)| base.calc_time(frames));
+
+    let mut track = from_tags(&tags);
+    track.path = path.to_string_lossy().into_owned();
+    track.duration_seconds = duration.map(|time| time.seconds as i64 + (time.frac >= 0.5) as i64);
+    track.file_mtime = file_mtime;
+    track.chapters = chapters::read(
+        &path,
+        &tags,
+        duration.map(|time| time.seconds as i64 * 1000 + (time.frac * 1000.0) as i64),
+    );
+    let split = params.sample_rate.and_then(|rate| {
+        Some((
+            rate,
+            cue::find_split(&path, &tags, probed.format.cues(), rate)?,
+        ))
+    });
+    Ok(match split {
+        Some((rate, split)) => split_tracks(&path, &track, &split, rate, params.n_frames),
+        None => vec![track],
+    })
+}
+
+/// The tracks of a file split by a cue sheet. What the sheet doesn't say is
+/// taken from the file's own tags (`file`), except what only fits the whole.
+fn split_tracks(
+    path: &Path,
+    file: &NewTrack,
+    split: &Split,
+    sample_rate: u32,
+    frames: Option<u64>,
+) -> Vec<NewTrack> {
+    let ms = |frame: u64| (frame as u128 * 1000 / sample_rate as u128) as i64;
+    let date = split.date.clone().or_else(|| file.date.clone());
+    split
+        .segments
+        .iter()
+        .map(|segment| {
+            let performer = segment
+                .performer
+                .clone()
+                .or_else(|| split.performer.clone());
+            let end = segment.end.or(frames);
+            NewTrack {
+                path: cue::track_path(path, segment.number),
+                title: Some(
+                    segment
+                        .title
+                        .clone()
+                        .unwrap_or_else(|| format!("Track {:02}", segment.number)),
+                ),
+                artists: match performer {
+                    Some(_) => Vec::new(),
+                    None => file.artists.clone(),
+                },
+                artist_mbid: match performer {
+                    Some(_) => None,
+                    None => file.artist_mbid.clone(),
+                },
+                artist: performer.or_else(|| file.artist.clone()),
+                album: split.title.clone().or_else(|| file.album.clone()),
+                album_artist: split
+                    .performer
+                    .clone()
+                    .or_else(|| file.album_artist.clone()),
+                genre: split.genre.clone().or_else(|| file.genre.clone()),
+                composer: segment.songwriter.clone(),
+                track_number: Some(segment.number.into()),
+                track_total: Some(split.segments.len() as i64),
+                year: date.as_deref().and_then(parse_year),
+                date: date.clone(),
+                duration_seconds: end
+                    .map(|end| {
+                        (end.saturating_sub(segment.start) + sample_rate as u64 / 2)
+                            / sample_rate as u64
+                    })
+                    .map(|seconds| seconds as i64),
+                start_ms: Some(ms(segment.start)),
+                end_ms: segment.end.map(ms),
+                mbid: None,
+                rating: None,
+                // Chapters are for the whole file
+                chapters: Vec::new(),
+                ..file.clone()
+            }
+        })
+        .collect()
+}
+
+/// Build track metadata from symphonia tags. The first value of a tag wins,
+/// except for artists, which may be tagged once per artist.
+fn from_tags(tags: &[Tag]) -> NewTrack {
+    let values = |key: StandardTagKey| -> Vec<String> {
+        tags.iter()
+            .filter(|tag| tag.std_key == Some(key))
+            .map(|tag| tag.value.to_string().trim_matches(['\0', ' ']).to_owned())
+            .filter(|value| !value.is_empty())
+            .collect()
+    };
+    let first = |key: StandardTagKey| values(key).into_iter().next();
+    let number = |key: StandardTagKey| first(key).as_deref().and_then(parse_number);
+
+    let artists = values(StandardTagKey::Artist);
+    let (track_number, track_total) = first(StandardTagKey::TrackNumber)
+        .as_deref()
+        .map(parse_position)
+        .unwrap_or_default();
+    let (disc_number, disc_total) = first(StandardTagKey::DiscNumber)
+        .as_deref()
+        .map(parse_position)
+        .unwrap_or_default();
+    let date = first(StandardTagKey::Date)
+        .or_else(|| first(StandardTagKey::ReleaseDate))
+        .or_else(|| first(StandardTagKey::OriginalDate));
+
+    NewTrack {
+        title: first(StandardTagKey::TrackTitle),
+        artist: (!artists.is_empty()).then(|| artists.join(", ")),
+        // A single credit is split into main and featured artists on insert
+        artists: if artists.len() > 1 {
+            artists
+        } else {
+            Vec::new()
+        },
+        album: first(StandardTagKey::Album),
+        album_artist: first(StandardTagKey::AlbumArtist),
+        genre: first(StandardTagKey::Genre),
+        comment: first(StandardTagKey::Comment),
+        composer: first(StandardTagKey::Composer),
+        compilation: first(StandardTagKey::Compilation)
+            .is_some_and(|v| matches!(v.to_ascii_lowercase().as_str(), "1" | "true" | "yes")),
+        track_number,
+        track_total: track_total.or_else(|| number(StandardTagKey::TrackTotal)),
+        disc_number,
+        disc_total: disc_total.or_else(|| number(StandardTagKey::DiscTotal)),
+        year: date.as_deref().and_then(parse_year),
+        date,
+        rating: tags::rating_from_tags(tags),
+        mbid: first(StandardTagKey::MusicBrainzRecordingId),
+        album_mbid: first(StandardTagKey::MusicBrainzAlbumId),
+        artist_mbid: first(StandardTagKey::MusicBrainzArtistId),
+        album_artist_mbid: first(StandardTagKey::MusicBrainzAlbumArtistId),
+        ..Default::default()
+    }
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    text.trim().parse().ok().filter(|n| *n > 0)
+}
+
+/// Parse a track or disc position such as `3` or `3/12`
+fn parse_position(text: &str) -> (Option<i64>, Option<i64>) {
+    match text.split_once('/') {
+        Some((number, total)) => (parse_number(number), parse_number(total)),
+        None => (parse_number(text), None),
+    }
+}
+
+/// The year of a date such as `2001`, `2001-03-12` or `2001-03-12T00:00:00`
+fn parse_year(date: &str) -> Option<i64> {
+    let digits: String = date.trim().chars().take(4).collect();
+    (digits.len() == 4 && digits.chars().all(|c| c.is_ascii_digit()))
+        .then(|| digits.parse().ok())
+        .flatten()
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::db::Page;
+    use tempfile::{NamedTempFile, tempdir};
+
+    /// A second of silent 8 kHz mono PCM with a RIFF INFO tag list
+    pub(crate) fn wav(tags: &[(&[u8; 4], &str)]) -> Vec<u8> {
+        let mut info = b"INFO".to_vec();
+        for (id, value) in tags {
+            let mut value = value.as_bytes().to_vec();
+            value.push(0);
+            info.extend_from_slice(*id);
+            info.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            if value.len() % 2 == 1 {
+                value.push(0);
+            }
+            info.extend_from_slice(&value);
+        }
+        let samples = vec![0u8; 16_000];
+
+        let mut body = b"WAVE".to_vec();
+        body.extend_from_slice(b"fmt ");
+        body.extend_from_slice(&16u32.to_le_bytes());
+        body.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        body.extend_from_slice(&1u16.to_le_bytes()); // mono
+        body.extend_from_slice(&8000u32.to_le_bytes());
+        body.extend_from_slice(&16_000u32.to_le_bytes());
+        body.extend_from_slice(&2u16.to_le_bytes());
+        body.extend_from_slice(&16u16.to_le_bytes());
+        body.extend_from_slice(b"LIST");
+        body.extend_from_slice(&(info.len() as u32).to_le_bytes());
+        body.extend_from_slice(&info);
+        body.extend_from_slice(b"data");
+        body.extend_from_slice(&(samples.len() as u32).to_le_bytes());
+        body.extend_from_slice(&samples);
+
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(&body);
+        out
+    }
+
+    #[test]
+    fn test_positions_and_years() {
+        assert_eq!(parse_position("3/12"), (Some(3), Some(12)));
+        assert_eq!(parse_position(" 7 "), (Some(7), None));
+        assert_eq!(parse_position("0/x"), (None, None));
+        assert_eq!(parse_year("2001-03-12"), Some(2001));
+        assert_eq!(parse_year("'01"), None);
+    }
+
+    #[test]
+    fn test_scan_reads_tags_into_the_catalog() {
+        let dir = tempdir().unwrap();
+        let album = dir.path().join("Discovery");
+        std::fs::create_dir(&album).unwrap();
+        for (file, title, artist, number) in [
+            ("02.wav", "Aerodynamic", "Daft Punk", "2/14"),
+            (
+                "01.wav",
+                "One More Time",
+                "Daft Punk feat. Romanthony",
+                "1/14",
+            ),
+        ] {
+            let data = wav(&[
+                (b"INAM", title),
+                (b"IART", artist),
+                (b"IPRD", "Discovery"),
+                (b"IPRT", number),
+                (b"ICRD", "2001-03-12"),
+                (b"IGNR", "House"),
+            ]);
+            std::fs::write(album.join(file), data).unwrap();
+        }
+        std::fs::write(album.join("cover.jpg"), b"not audio").unwrap();
+        std::fs::write(album.join("broken.wav"), b"RIFF").unwrap();
+
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let report = scan(&db, dir.path()).unwrap();
+        assert_eq!(report.imported, 2);
+        assert_eq!(report.failed.len(), 1);
+        assert!(report.failed[0].0.ends_with("broken.wav"));
+
+        let albums = db.album_list(Page::default()).unwrap();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(
+            (albums[0].title.as_str(), albums[0].artist.as_str()),
+            ("Discovery", "Daft Punk")
+        );
+        assert_eq!(albums[0].year, Some(2001));
+
+        let tracks = db.album_tracks(albums[0].id).unwrap();
+        let titles: Vec<_> = tracks.iter().filter_map(|t| t.title.as_deref()).collect();
+        assert_eq!(titles, vec!["One More Time", "Aerodynamic"]);
+        assert_eq!(
+            (tracks[0].track_number, tracks[0].track_total),
+            (Some(1), Some(14))
+        );
+        assert_eq!(tracks[0].genre.as_deref(), Some("House"));
+        assert_eq!(tracks[0].date.as_deref(), Some("2001-03-12"));
+        assert_eq!(tracks[0].duration_seconds, Some(1));
+        let credits: Vec<_> = db
+            .track_credits(tracks[0].id)
+            .unwrap()
+            .into_iter()
+            .map(|c| c.name)
+            .collect();
+        assert_eq!(credits, vec!["Daft Punk", "Romanthony"]);
+    }
+
+    #[test]
+    fn test_reconcile_skips_unchanged_files() {
+        let dir = tempdir().unwrap();
+        let keep = dir.path().join("keep.wav");
+        let gone = dir.path().join("gone.wav");
+        std::fs::write(&keep, wav(&[(b"INAM", "Keep")])).unwrap();
+        std::fs::write(&gone, wav(&[(b"INAM", "Gone")])).unwrap();
+
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        assert_eq!(reconcile(&db, dir.path()).unwrap().imported, 2);
+
+        std::fs::remove_file(&gone).unwrap();
+        std::fs::write(dir.path().join("new.wav"), wav(&[(b"INAM", "New")])).unwrap();
+        let report = reconcile(&db, dir.path()).unwrap();
+        assert_eq!(
+            (report.imported, report.unchanged, report.removed),
+            (1, 1, 1)
+        );
+        assert!(reconcile(&db, &dir.path().join("missing")).is_err());
+        assert_eq!(db.track_count().unwrap(), 2);
+    }
+
+    #[test]
+    fn test_cue_sheet_splits_a_file() {
+        let dir = tempdir().unwrap();
+        let image = dir.path().join("Album.wav");
+        std::fs::write(&image, wav(&[(b"IART", "Someone")])).unwrap();
+        let sheet = dir.path().join("Album.cue");
+        std::fs::write(
+            &sheet,
+            "PERFORMER \"Band\"\nTITLE \"Album\"\nFILE \"Album.wav\" WAVE\n\
+             TRACK 01 AUDIO\n TITLE \"Intro\"\n INDEX 01 00:00:00\n\
+             TRACK 02 AUDIO\n TITLE \"Outro\"\n INDEX 01 00:00:30\n",
+        )
+        .unwrap();
+
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        assert_eq!(scan(&db, dir.path()).unwrap().imported, 2);
+        let second = db
+            .track_by_path(&cue::track_path(&image, 2))
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.title.as_deref(), Some("Outro"));
+        assert_eq!(second.album.as_deref(), Some("Album"));
+        assert_eq!(second.artist.as_deref(), Some("Band"));
+        assert_eq!((second.start_ms, second.end_ms), (Some(400), None));
+        assert_eq!(second.duration_seconds, Some(1));
+        assert_eq!(
+            db.track_files(&dir.path().to_string_lossy()).unwrap().len(),
+            1
+        );
+
+        // Without the sheet the file is a single track again
+        std::fs::remove_file(&sheet).unwrap();
+        let mut report = ScanReport::default();
+        sync_path(&db, &sheet, &mut report).unwrap();
+        assert_eq!(report.imported, 1);
+        assert_eq!(db.track_count().unwrap(), 1);
+        let whole = db.track_by_path(&image.to_string_lossy()).unwrap().unwrap();
+        assert_eq!(
+            (whole.artist.as_deref(), whole.start_ms),
+            (Some("Someone"), None)
+        );
+    }
+
+    #[test]
+    fn test_scan_resumes_after_interrupt() {
+        let dir = tempdir().unwrap();
+        for (album, files) in [("A", &["1.wav"][..]), ("B", &["1.wav", "2.wav"][..])] {
+            let album = dir.path().join(album);
+            std::fs::create_dir(&album).unwrap();
+            for file in files {
+                std::fs::write(album.join(file), wav(&[(b"INAM", file)])).unwrap();
+            }
+        }
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let options = ScanOptions {
+            threads: 2,
+            full: false,
+        };
+
+        let cancelled = scan_with(&db, dir.path(), &options, &AtomicBool::new(true), |_| {});
+        assert!(cancelled.unwrap().cancelled);
+        assert_eq!(db.track_count().unwrap(), 0);
+
+        // As if an earlier scan had got through A before being stopped
+        let root = dir.path().to_string_lossy();
+        let a = dir.path().join("A").to_string_lossy().into_owned();
+        db.import_batch(&root, &[], &[a]).unwrap();
+        let mut last = None;
+        let report = scan_with(&db, dir.path(), &options, &AtomicBool::new(false), |p| {
+            last = Some(*p)
+        })
+        .unwrap();
+        assert_eq!((report.resumed, report.imported), (1, 2));
+        assert!(!report.cancelled);
+        let last = last.unwrap();
+        assert_eq!((last.done, last.total), (2, 2));
+        assert_eq!(last.eta(), Some(Duration::ZERO));
+
+        // A finished scan starts from scratch next time, skipping unchanged files
+        let report = scan(&db, dir.path()).unwrap();
+        assert_eq!(
+            (report.resumed, report.imported, report.unchanged),
+            (0, 1, 2)
+        );
+    }
+}
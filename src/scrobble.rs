@@ -0,0 +1,495 @@
+//! Scrobbling: reporting listens to Last.fm and ListenBrainz.
+//!
+//! Listens go through a queue in the library database (see `DB::queue_listen`)
+//! and are submitted in batches by a background worker, so nothing is lost while
+//! offline or between runs. A service that fails is retried with exponential
+//! backoff; "now playing" updates are sent straight away and never retried.
+
+mod http;
+mod lastfm;
+mod listenbrainz;
+
+use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+
+use crate::db::{DB, Listen, now};
+use crate::player::{PlayerState, PlayerStatus};
+
+pub use lastfm::{LASTFM_URL, LastFm};
+pub use listenbrainz::{LISTENBRAINZ_URL, ListenBrainz};
+
+#[derive(Debug, Error)]
+pub enum ScrobbleError {
+    /// Network trouble, rate limiting or a server error; worth retrying
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+    /// The credentials were refused; listens wait until they are fixed
+    #[error("Not authorised: {0}")]
+    Unauthorized(String),
+    /// The submission itself was refused, so retrying won't help
+    #[error("Submission rejected: {0}")]
+    Rejected(String),
+}
+
+/// A scrobbling service
+pub trait Service: Send {
+    /// Key of the service's listens in the queue
+    fn name(&self) -> &'static str;
+    /// Most listens sent in one request
+    fn batch_size(&self) -> usize;
+    fn now_playing(&self, listen: &Listen) -> Result<(), ScrobbleError>;
+    fn submit(&self, listens: &[Listen]) -> Result<(), ScrobbleError>;
+}
+
+/// Whether a play counts as a listen: the track is longer than 30 seconds and
+/// was played for half its length or four minutes, whichever comes first.
+/// Without a known length only the four minutes count.
+pub fn counts_as_listen(duration: Option<u64>, listened: u64) -> bool {
+    match duration {
+        Some(duration) => duration > 30 && listened >= (duration / 2).min(240),
+        None => listened >= 240,
+    }
+}
+
+/// Seconds to wait after `attempts` failed rounds: a minute, doubling up to six hours
+pub fn backoff(attempts: u32) -> i64 {
+    (60i64 << attempts.min(10)).min(6 * 3600)
+}
+
+/// Outcome of a flush
+#[derive(Debug, Default)]
+pub struct FlushReport {
+    pub submitted: usize,
+    /// Listens a service refused outright, which are dropped
+    pub rejected: usize,
+    /// Errors by service name
+    pub errors: Vec<(&'static str, ScrobbleError)>,
+}
+
+/// Submits listens to a set of services
+pub struct Scrobbler {
+    services: Vec<Box<dyn Service>>,
+}
+
+impl Scrobbler {
+    pub fn new(services: Vec<Box<dyn Service>>) -> Self {
+        Self { services }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.services.is_empty()
+    }
+
+    pub fn service_names(&self) -> Vec<&'static str> {
+        self.services.iter().map(|s| s.name()).collect()
+    }
+
+    /// Queue a listen for every service
+    pub fn queue(&self, db: &DB, listen: &Listen) -> Result<()> {
+        for service in &self.services {
+            db.queue_listen(service.name(), listen)?;
+        }
+        Ok(())
+    }
+
+    /// Tell every service what is playing. Failures are ignored: by the time a
+    /// retry could happen the update would be stale.
+    pub fn now_playing(&self, listen: &Listen) {
+        for service in &self.services {
+            let _ = service.now_playing(listen);
+        }
+    }
+
+    /// Submit every listen that is due at `now`, oldest first, in batches. A
+    /// service that fails keeps its listens and is left alone until its backoff
+    /// expires.
+    pub fn flush(&self, db: &DB, now: i64) -> Result<FlushReport> {
+        let mut report = FlushReport::default();
+        for service in &self.services {
+            loop {
+                let due = db.due_listens(service.name(), now, service.batch_size())?;
+                if due.is_empty() {
+                    break;
+                }
+                let ids: Vec<i64> = due.iter().map(|q| q.id).collect();
+                let listens: Vec<Listen> = due.iter().map(|q| q.listen.clone()).collect();
+                match service.submit(&listens) {
+                    Ok(()) => {
+                        db.remove_listens(&ids)?;
+                        report.submitted += ids.len();
+                    }
+                    Err(e @ ScrobbleError::Rejected(_)) => {
+                        db.remove_listens(&ids)?;
+                        report.rejected += ids.len();
+                        report.errors.push((service.name(), e));
+                    }
+                    Err(e) => {
+                        let attempts = due.iter().map(|q| q.attempts).max().unwrap_or(0);
+                        db.defer_listens(service.name(), now + backoff(attempts), &e.to_string())?;
+                        report.errors.push((service.name(), e));
+                        break;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// How often the worker retries the queue when nothing new arrives
+const FLUSH_INTERVAL: Duration = Duration::from_secs(60);
+
+enum Message {
+    NowPlaying(Listen),
+    Played(Listen),
+}
+
+/// Handle to a background thread that queues and submits listens. Dropping it
+/// stops the thread after a last flush.
+pub struct ScrobbleWorker {
+    sender: mpsc::Sender<Message>,
+}
+
+impl ScrobbleWorker {
+    /// Start the worker on its own connection to the library. Listens left in
+    /// the queue by earlier runs are submitted first.
+    pub fn spawn(db: DB, scrobbler: Scrobbler) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            loop {
+                if let Ok(report) = scrobbler.flush(&db, now()) {
+                    for (service, error) in report.errors {
+                        eprintln!("Scrobbling to {} failed: {}", service, error);
+                    }
+                }
+                // Drain everything waiting before the next flush
+                let mut message = receiver.recv_timeout(FLUSH_INTERVAL);
+                loop {
+                    match message {
+                        Ok(Message::NowPlaying(listen)) => scrobbler.now_playing(&listen),
+                        Ok(Message::Played(listen)) => {
+                            if let Err(e) = scrobbler.queue(&db, &listen) {
+                                eprintln!("Failed to queue scrobble: {:#}", e);
+                            }
+                        }
+                        Err(RecvTimeoutError::Timeout) => break,
+                        Err(RecvTimeoutError::Disconnected) => {
+                            let _ = scrobbler.flush(&db, now());
+                            return;
+                        }
+                    }
+                    message = receiver.try_recv().map_err(|e| match e {
+                        mpsc::TryRecvError::Empty => RecvTimeoutError::Timeout,
+                        mpsc::TryRecvError::Disconnected => RecvTimeoutError::Disconnected,
+                    });
+                }
+            }
+        });
+        Self { sender }
+    }
+
+    pub fn now_playing(&self, listen: Listen) {
+        let _ = self.sender.send(Message::NowPlaying(listen));
+    }
+
+    /// Queue a completed listen and submit it as soon as possible
+    pub fn played(&self, listen: Listen) {
+        let _ = self.sender.send(Message::Played(listen));
+    }
+}
+
+/// What happened to playback between two status observations
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlaybackEvent {
+    Started {
+        path: PathBuf,
+        started_at: i64,
+    },
+    Finished {
+        path: PathBuf,
+        started_at: i64,
+        /// Seconds actually spent playing, not counting pauses
+        listened: u64,
+        duration: Option<u64>,
+    },
+}
+
+struct Playing {
+    path: PathBuf,
+    started_at: i64,
+    duration: Option<u64>,
+    listened: Duration,
+    state: PlayerState,
+    last_seen: Instant,
+}
+
+/// Follows player status snapshots to work out when tracks start and how long
+/// they were listened to
+#[derive(Default)]
+pub struct PlaybackTracker {
+    current: Option<Playing>,
+}
+
+impl PlaybackTracker {
+    /// Take a status snapshot taken at `at` (unix time `unix_now`)
+    pub fn observe(
+        &mut self,
+        status: &PlayerStatus,
+        at: Instant,
+        unix_now: i64,
+    ) -> Vec<PlaybackEvent> {
+        let mut events = Vec::new();
+        if let Some(playing) = &mut self.current {
+            if playing.state == PlayerState::Playing {
+                playing.listened += at.saturating_duration_since(playing.last_seen);
+            }
+            playing.last_seen = at;
+            playing.state = status.state;
+            if status.duration.is_some() {
+                playing.duration = status.duration.map(|d| d.as_secs());
+            }
+        }
+
+        let same = self.current.as_ref().map(|p| &p.path) == status.current_file.as_ref();
+        if !same {
+            if let Some(done) = self.current.take() {
+                events.push(PlaybackEvent::Finished {
+                    path: done.path,
+                    started_at: done.started_at,
+                    listened: done.listened.as_secs(),
+                    duration: done.duration,
+                });
+            }
+            if let Some(path) = &status.current_file {
+                events.push(PlaybackEvent::Started {
+                    path: path.clone(),
+                    started_at: unix_now,
+                });
+                self.current = Some(Playing {
+                    path: path.clone(),
+                    started_at: unix_now,
+                    duration: status.duration.map(|d| d.as_secs()),
+                    listened: Duration::ZERO,
+                    state: status.state,
+                    last_seen: at,
+                });
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use tempfile::NamedTempFile;
+
+    /// A stand-in HTTP server answering with the given statuses and bodies in
+    /// turn, recording each request's headers and body
+    fn stand_in(replies: Vec<(u16, &'static str)>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let seen = requests.clone();
+        thread::spawn(move || {
+            for (status, body) in replies {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.strip_prefix("Content-Length: ") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut content = vec![0; length];
+                reader.read_exact(&mut content).unwrap();
+                request.push_str(&String::from_utf8(content).unwrap());
+                seen.lock().unwrap().push(request);
+                let mut stream = reader.into_inner();
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn listen(title: &str, played_at: i64) -> Listen {
+        Listen {
+            artist: "Burial".into(),
+            title: title.into(),
+            album: Some("Untrue".into()),
+            duration_seconds: Some(238),
+            played_at,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_listen_rules() {
+        assert!(!counts_as_listen(Some(30), 30));
+        assert!(counts_as_listen(Some(31), 15));
+        assert!(!counts_as_listen(Some(300), 149));
+        assert!(counts_as_listen(Some(300), 150));
+        assert!(counts_as_listen(Some(3600), 240));
+        assert!(!counts_as_listen(None, 239));
+        assert_eq!((backoff(0), backoff(3), backoff(30)), (60, 480, 6 * 3600));
+    }
+
+    #[test]
+    fn test_tracker_counts_playing_time() {
+        let start = Instant::now();
+        let status = |file: Option<&str>, state| PlayerStatus {
+            state,
+            position: None,
+            duration: Some(Duration::from_secs(200)),
+            current_file: file.map(PathBuf::from),
+            volume: 1.0,
+            chapters: Vec::new(),
+            chapter: None,
+        };
+        let mut tracker = PlaybackTracker::default();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(
+            tracker.observe(&status(Some("/a"), PlayerState::Playing), at(0), 1000),
+            vec![PlaybackEvent::Started {
+                path: "/a".into(),
+                started_at: 1000
+            }]
+        );
+        tracker.observe(&status(Some("/a"), PlayerState::Paused), at(60), 1060);
+        tracker.observe(&status(Some("/a"), PlayerState::Playing), at(500), 1500);
+        let events = tracker.observe(&status(Some("/b"), PlayerState::Playing), at(540), 1540);
+        assert_eq!(
+            events[0],
+            PlaybackEvent::Finished {
+                path: "/a".into(),
+                started_at: 1000,
+                listened: 100,
+                duration: Some(200)
+            }
+        );
+        assert!(matches!(events[1], PlaybackEvent::Started { .. }));
+        assert!(
+            tracker
+                .observe(&status(Some("/b"), PlayerState::Playing), at(541), 1541)
+                .is_empty()
+        );
+        assert_eq!(
+            tracker
+                .observe(&status(None, PlayerState::Stopped), at(542), 1542)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_listenbrainz_batches_and_retries() {
+        let (url, requests) = stand_in(vec![
+            (503, r#"{"error": "down"}"#),
+            (200, r#"{"status": "ok"}"#),
+            (200, r#"{"status": "ok"}"#),
+        ]);
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let scrobbler = Scrobbler::new(vec![Box::new(ListenBrainz {
+            url,
+            token: "tok".into(),
+        })]);
+        for (i, title) in ["Archangel", "Near Dark", "Ghost Hardware"]
+            .iter()
+            .enumerate()
+        {
+            scrobbler
+                .queue(&db, &listen(title, 1000 + i as i64))
+                .unwrap();
+        }
+
+        // Offline: everything stays queued and backs off
+        let report = scrobbler.flush(&db, 2000).unwrap();
+        assert_eq!(report.submitted, 0);
+        assert!(matches!(report.errors[0].1, ScrobbleError::Unavailable(_)));
+        assert_eq!(
+            db.listen_queue("listenbrainz").unwrap().retry_at,
+            Some(2060)
+        );
+        assert_eq!(scrobbler.flush(&db, 2030).unwrap().submitted, 0);
+
+        let report = scrobbler.flush(&db, 2060).unwrap();
+        assert_eq!(report.submitted, 3);
+        assert_eq!(db.listen_queue("listenbrainz").unwrap().pending, 0);
+
+        scrobbler.now_playing(&listen("Untrue", 3000));
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert!(requests[1].starts_with("POST /1/submit-listens HTTP/1.1\r\n"));
+        assert!(requests[1].contains("Authorization: Token tok\r\n"));
+        let body: serde_json::Value =
+            serde_json::from_str(requests[1].split("\r\n\r\n").nth(1).unwrap()).unwrap();
+        assert_eq!(body["listen_type"], "import");
+        assert_eq!(body["payload"].as_array().unwrap().len(), 3);
+        assert_eq!(body["payload"][0]["listened_at"], 1000);
+        assert_eq!(
+            body["payload"][0]["track_metadata"]["release_name"],
+            "Untrue"
+        );
+        assert!(requests[2].contains(r#""listen_type":"playing_now""#));
+    }
+
+    #[test]
+    fn test_lastfm_submission() {
+        let (url, requests) = stand_in(vec![
+            (200, r#"{"scrobbles": {"@attr": {"accepted": 1}}}"#),
+            (200, r#"{"error": 9, "message": "Invalid session key"}"#),
+        ]);
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let scrobbler = Scrobbler::new(vec![Box::new(LastFm {
+            url: format!("{}/2.0/", url),
+            api_key: "key".into(),
+            api_secret: "secret".into(),
+            session_key: "session".into(),
+        })]);
+        scrobbler.queue(&db, &listen("Archangel", 1000)).unwrap();
+        assert_eq!(scrobbler.flush(&db, 2000).unwrap().submitted, 1);
+
+        // A bad session keeps the listen for later
+        scrobbler.queue(&db, &listen("Near Dark", 1500)).unwrap();
+        let report = scrobbler.flush(&db, 2000).unwrap();
+        assert!(matches!(report.errors[0].1, ScrobbleError::Unauthorized(_)));
+        assert_eq!(db.listen_queue("lastfm").unwrap().pending, 1);
+
+        let requests = requests.lock().unwrap();
+        let body = requests[0].split("\r\n\r\n").nth(1).unwrap();
+        assert!(requests[0].starts_with("POST /2.0/ HTTP/1.1\r\n"));
+        for param in [
+            "artist%5B0%5D=Burial",
+            "track%5B0%5D=Archangel",
+            "timestamp%5B0%5D=1000",
+            "method=track.scrobble",
+            "sk=session",
+            "api_sig=",
+        ] {
+            assert!(body.contains(param), "{} missing from {}", param, body);
+        }
+    }
+}
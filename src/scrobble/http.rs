@@ -0,0 +1,46 @@
+//! Just enough HTTP to POST a submission and read the reply, over `http://`
+//! for tests and local stand-ins or `https://` for the services themselves.
+
+use std::io;
+use std::time::Duration;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Status code and body of a response
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub body: String,
+}
+
+/// POST `body` to `url` with extra `headers` as `(name, value)` pairs
+pub fn post(
+    url: &str,
+    content_type: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> io::Result<Response> {
+    if !(url.starts_with("http://") || url.starts_with("https://")) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("unsupported URL {}", url),
+        ));
+    }
+    let agent = ureq::AgentBuilder::new()
+        .timeout(TIMEOUT)
+        .user_agent(concat!("rustyplayer/", env!("CARGO_PKG_VERSION")))
+        .build();
+    let mut request = agent.post(url).set("Content-Type", content_type);
+    for (name, value) in headers {
+        request = request.set(name, value);
+    }
+    // Error statuses still carry the service's explanation
+    let response = match request.send_bytes(body) {
+        Ok(response) | Err(ureq::Error::Status(_, response)) => response,
+        Err(ureq::Error::Transport(e)) => return Err(io::Error::other(e.to_string())),
+    };
+    Ok(Response {
+        status: response.status(),
+        body: response.into_string()?,
+    })
+}
@@ -0,0 +1,175 @@
+//! Last.fm scrobbling through the audioscrobbler 2.0 web service
+
+use md5::{Digest, Md5};
+use percent_encoding::{AsciiSet, NON_ALPHANUMERIC, utf8_percent_encode};
+
+use super::{ScrobbleError, Service, http};
+use crate::db::Listen;
+
+/// Default API root
+pub const LASTFM_URL: &str = "https://ws.audioscrobbler.com/2.0/";
+
+/// Characters escaped in `application/x-www-form-urlencoded` bodies
+const FORM: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'*');
+
+/// Last.fm accepts at most this many scrobbles per request
+const BATCH_SIZE: usize = 50;
+
+/// A Last.fm account, authorised for this application with a session key
+#[derive(Debug, Clone)]
+pub struct LastFm {
+    pub url: String,
+    pub api_key: String,
+    pub api_secret: String,
+    pub session_key: String,
+}
+
+/// `api_sig` of a call: the MD5 of every parameter name and value in name
+/// order, followed by the shared secret
+fn signature(params: &[(String, String)], secret: &str) -> String {
+    let mut sorted: Vec<_> = params.iter().collect();
+    sorted.sort();
+    let mut text = String::new();
+    for (name, value) in sorted {
+        text.push_str(name);
+        text.push_str(value);
+    }
+    text.push_str(secret);
+    format!("{:x}", Md5::digest(text))
+}
+
+/// Track parameters of a listen, suffixed with `[index]` in batch submissions
+fn listen_params(listen: &Listen, index: Option<usize>) -> Vec<(String, String)> {
+    let key = |name: &str| match index {
+        Some(i) => format!("{}[{}]", name, i),
+        None => name.to_owned(),
+    };
+    let mut params = vec![
+        (key("artist"), listen.artist.clone()),
+        (key("track"), listen.title.clone()),
+    ];
+    let optional = [
+        ("album", listen.album.clone()),
+        ("albumArtist", listen.album_artist.clone()),
+        ("trackNumber", listen.track_number.map(|n| n.to_string())),
+        ("duration", listen.duration_seconds.map(|d| d.to_string())),
+        ("mbid", listen.mbid.clone()),
+    ];
+    for (name, value) in optional {
+        if let Some(value) = value {
+            params.push((key(name), value));
+        }
+    }
+    params
+}
+
+impl LastFm {
+    fn call(&self, method: &str, mut params: Vec<(String, String)>) -> Result<(), ScrobbleError> {
+        params.push(("method".into(), method.into()));
+        params.push(("api_key".into(), self.api_key.clone()));
+        params.push(("sk".into(), self.session_key.clone()));
+        let sig = signature(&params, &self.api_secret);
+        params.push(("api_sig".into(), sig));
+        params.push(("format".into(), "json".into()));
+
+        let body = params
+            .iter()
+            .map(|(name, value)| {
+                format!(
+                    "{}={}",
+                    utf8_percent_encode(name, FORM),
+                    utf8_percent_encode(value, FORM)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("&");
+        let response = http::post(
+            &self.url,
+            "application/x-www-form-urlencoded",
+            &[],
+            body.as_bytes(),
+        )
+        .map_err(|e| ScrobbleError::Unavailable(e.to_string()))?;
+
+        let json: serde_json::Value = serde_json::from_str(&response.body).unwrap_or_default();
+        if let Some(code) = json["error"].as_i64() {
+            let message = format!(
+                "error {}: {}",
+                code,
+                json["message"].as_str().unwrap_or("unknown")
+            );
+            return Err(match code {
+                // Authentication failed, invalid API key or session, suspended key
+                4 | 9 | 10 | 14 | 26 => ScrobbleError::Unauthorized(message),
+                // Service offline, temporarily unavailable, rate limited
+                11 | 16 | 29 => ScrobbleError::Unavailable(message),
+                _ => ScrobbleError::Rejected(message),
+            });
+        }
+        match response.status {
+            200..=299 => Ok(()),
+            500.. => Err(ScrobbleError::Unavailable(format!(
+                "HTTP {}",
+                response.status
+            ))),
+            status => Err(ScrobbleError::Rejected(format!("HTTP {}", status))),
+        }
+    }
+}
+
+impl Service for LastFm {
+    fn name(&self) -> &'static str {
+        "lastfm"
+    }
+
+    fn batch_size(&self) -> usize {
+        BATCH_SIZE
+    }
+
+    fn now_playing(&self, listen: &Listen) -> Result<(), ScrobbleError> {
+        self.call("track.updateNowPlaying", listen_params(listen, None))
+    }
+
+    fn submit(&self, listens: &[Listen]) -> Result<(), ScrobbleError> {
+        let mut params = Vec::new();
+        for (i, listen) in listens.iter().enumerate() {
+            params.extend(listen_params(listen, Some(i)));
+            params.push((format!("timestamp[{}]", i), listen.played_at.to_string()));
+        }
+        self.call("track.scrobble", params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_signature() {
+        let params = vec![
+            ("track".to_owned(), "Archangel".to_owned()),
+            ("artist".to_owned(), "Burial".to_owned()),
+        ];
+        let expected = format!("{:x}", Md5::digest("artistBurialtrackArchangelsecret"));
+        assert_eq!(signature(&params, "secret"), expected);
+
+        let listen = Listen {
+            artist: "Burial".into(),
+            title: "Archangel".into(),
+            duration_seconds: Some(238),
+            ..Default::default()
+        };
+        assert_eq!(
+            listen_params(&listen, Some(3)),
+            vec![
+                ("artist[3]".to_owned(), "Burial".to_owned()),
+                ("track[3]".to_owned(), "Archangel".to_owned()),
+                ("duration[3]".to_owned(), "238".to_owned()),
+            ]
+        );
+    }
+}
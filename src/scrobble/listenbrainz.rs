@@ -0,0 +1,108 @@
+//! ListenBrainz submission through its JSON API
+
+use serde_json::{Map, Value, json};
+
+use super::{ScrobbleError, Service, http};
+use crate::db::Listen;
+
+/// Default API root
+pub const LISTENBRAINZ_URL: &str = "https://api.listenbrainz.org";
+
+/// Listens per `import` request; the API allows more, but smaller requests fail
+/// less painfully
+const BATCH_SIZE: usize = 100;
+
+/// A ListenBrainz account, identified by its user token
+#[derive(Debug, Clone)]
+pub struct ListenBrainz {
+    pub url: String,
+    pub token: String,
+}
+
+fn track_metadata(listen: &Listen) -> Value {
+    let mut info = Map::new();
+    info.insert("submission_client".into(), "rustyplayer".into());
+    info.insert(
+        "submission_client_version".into(),
+        env!("CARGO_PKG_VERSION").into(),
+    );
+    if let Some(duration) = listen.duration_seconds {
+        info.insert("duration_ms".into(), (duration * 1000).into());
+    }
+    if let Some(number) = listen.track_number {
+        info.insert("tracknumber".into(), number.into());
+    }
+    if let Some(mbid) = &listen.mbid {
+        info.insert("recording_mbid".into(), mbid.clone().into());
+    }
+    let mut metadata = json!({
+        "artist_name": listen.artist,
+        "track_name": listen.title,
+        "additional_info": info,
+    });
+    if let Some(album) = &listen.album {
+        metadata["release_name"] = album.clone().into();
+    }
+    metadata
+}
+
+impl ListenBrainz {
+    fn submit_listens(&self, listen_type: &str, payload: Vec<Value>) -> Result<(), ScrobbleError> {
+        let body = json!({ "listen_type": listen_type, "payload": payload }).to_string();
+        let url = format!("{}/1/submit-listens", self.url.trim_end_matches('/'));
+        let response = http::post(
+            &url,
+            "application/json",
+            &[("Authorization", format!("Token {}", self.token))],
+            body.as_bytes(),
+        )
+        .map_err(|e| ScrobbleError::Unavailable(e.to_string()))?;
+
+        let message = || {
+            let json: Value = serde_json::from_str(&response.body).unwrap_or_default();
+            match json["error"].as_str() {
+                Some(error) => format!("HTTP {}: {}", response.status, error),
+                None => format!("HTTP {}", response.status),
+            }
+        };
+        match response.status {
+            200..=299 => Ok(()),
+            401 | 403 => Err(ScrobbleError::Unauthorized(message())),
+            429 | 500.. => Err(ScrobbleError::Unavailable(message())),
+            _ => Err(ScrobbleError::Rejected(message())),
+        }
+    }
+}
+
+impl Service for ListenBrainz {
+    fn name(&self) -> &'static str {
+        "listenbrainz"
+    }
+
+    fn batch_size(&self) -> usize {
+        BATCH_SIZE
+    }
+
+    fn now_playing(&self, listen: &Listen) -> Result<(), ScrobbleError> {
+        let payload = json!({ "track_metadata": track_metadata(listen) });
+        self.submit_listens("playing_now", vec![payload])
+    }
+
+    fn submit(&self, listens: &[Listen]) -> Result<(), ScrobbleError> {
+        let listen_type = if listens.len() == 1 {
+            "single"
+        } else {
+            "import"
+        };
+        let payload = listens
+            .iter()
+            .map(|listen| {
+                json!({
+                    "listened_at": listen.played_at,
+                    "track_metadata": track_metadata(listen),
+                })
+            })
+            .collect();
+        self.submit_listens(listen_type, payload)
+    }
+}
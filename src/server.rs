@@ -0,0 +1,894 @@
+//! Embedded HTTP/JSON API and WebSocket event feed for remote control.
+//!
+//! Only built with the `server` feature. Requests are served on the thread
+//! that owns the `Player`, except that streamed file bodies are written on a
+//! thread each; each WebSocket subscriber gets its own writer thread fed with
+//! status updates over a channel.
+
+mod api;
+mod subsonic;
+
+use anyhow::{Result, anyhow};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Request, Response};
+use tungstenite::handshake::derive_accept_key;
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use crate::db::{DB, Listen, now};
+use crate::player::Player;
+use crate::resume::{ResumeOptions, ResumeTracker};
+use crate::scrobble::{PlaybackEvent, PlaybackTracker, ScrobbleWorker, counts_as_listen};
+use api::{Api, ApiRequest, Body};
+
+pub use subsonic::SubsonicConfig;
+
+/// How often the server wakes up to check for player status changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Path of the WebSocket feed that pushes `PlayerStatus` changes
+const EVENTS_PATH: &str = "/api/v1/events";
+
+pub struct Server {
+    http: tiny_http::Server,
+    api: Api,
+    subscribers: Vec<Sender<String>>,
+    last_status: String,
+    tracker: PlaybackTracker,
+    resume: ResumeTracker,
+    scrobbler: Option<ScrobbleWorker>,
+}
+
+impl Server {
+    /// Bind the API to `addr` (e.g. `127.0.0.1:8080`). Every request except the
+    /// OpenAPI document must carry `token` as a bearer token.
+    pub fn bind(addr: &str, token: String, db: DB, player: Player) -> Result<Self> {
+        let http =
+            tiny_http::Server::http(addr).map_err(|e| anyhow!("Failed to bind {}: {}", addr, e))?;
+        let api = Api::new(db, player, token);
+        let last_status = api.status_json();
+        Ok(Self {
+            http,
+            api,
+            subscribers: Vec::new(),
+            last_status,
+            tracker: PlaybackTracker::default(),
+            resume: ResumeTracker::default(),
+            scrobbler: None,
+        })
+    }
+
+    /// Also serve the Subsonic API under `/rest/` for mobile and desktop clients.
+    pub fn enable_subsonic(&mut self, config: SubsonicConfig) {
+        self.api.set_subsonic(subsonic::Subsonic::new(config));
+    }
+
+    /// Report what the player plays to scrobbling services
+    pub fn enable_scrobbling(&mut self, worker: ScrobbleWorker) {
+        self.scrobbler = Some(worker);
+    }
+
+    /// Which files remember where they were stopped, and how far to rewind
+    pub fn set_resume_options(&mut self, options: ResumeOptions) {
+        self.api.set_resume_options(options);
+    }
+
+    /// The address actually bound, useful when binding to port 0
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.http.server_addr().to_ip()
+    }
+
+    /// Serve requests until the process is interrupted, then save where
+    /// playback got to.
+    pub fn run(&mut self) -> Result<()> {
+        let exit = Arc::new(AtomicBool::new(false));
+        signal_hook::flag::register(signal_hook::consts::SIGINT, exit.clone())?;
+        signal_hook::flag::register(signal_hook::consts::SIGTERM, exit.clone())?;
+        while !exit.load(Ordering::Relaxed) {
+            self.poll()?;
+        }
+        let options = *self.api.resume_options();
+        self.resume.flush(self.api.db(), &options)
+    }
+
+    /// Handle at most one request, then push a status event if anything changed.
+    pub fn poll(&mut self) -> Result<()> {
+        if let Some(request) = self.http.recv_timeout(POLL_INTERVAL)? {
+            self.dispatch(request);
+        }
+        self.broadcast_status();
+        self.track_playback();
+        self.save_resume_position();
+        Ok(())
+    }
+
+    fn dispatch(&mut self, mut request: Request) {
+        let (path, query) = match request.url().split_once('?') {
+            Some((path, query)) => (path.to_owned(), parse_query(query)),
+            None => (request.url().to_owned(), Vec::new()),
+        };
+        let mut api_request = ApiRequest {
+            method: request.method().as_str().to_uppercase(),
+            path,
+            query,
+            authorization: header(&request, "Authorization").map(String::from),
+            body: Vec::new(),
+        };
+
+        // An upgrade request's reader is the raw socket, so don't try to drain a body
+        if api_request.method == "GET" && api_request.path == EVENTS_PATH {
+            self.subscribe(request, &api_request);
+            return;
+        }
+        if request
+            .as_reader()
+            .read_to_end(&mut api_request.body)
+            .is_err()
+        {
+            let _ = request.respond(Response::empty(400));
+            return;
+        }
+
+        let response = self.api.handle(&api_request);
+        let content_type = Header::from_bytes("Content-Type", response.content_type.as_bytes())
+            .expect("content type is a valid header value");
+        let _ = match response.body {
+            Body::Bytes(data) => request.respond(
+                Response::from_data(data)
+                    .with_status_code(response.status)
+                    .with_header(content_type),
+            ),
+            // Streams can take as long as the file plays, so they are written
+            // on a thread of their own rather than holding up other requests
+            Body::Stream(reader, length) => {
+                let response = Response::new(
+                    response.status.into(),
+                    vec![content_type],
+                    reader,
+                    length,
+                    None,
+                );
+                thread::spawn(move || request.respond(response));
+                Ok(())
+            }
+        };
+    }
+
+    /// Upgrade an events request to a WebSocket and start feeding it status updates.
+    fn subscribe(&mut self, request: Request, api_request: &ApiRequest) {
+        if self.api.authorize(api_request).is_err() {
+            let _ = request.respond(Response::empty(401));
+            return;
+        }
+        let Some(key) =
+            header(&request, "Sec-WebSocket-Key").map(|k| derive_accept_key(k.as_bytes()))
+        else {
+            let _ = request.respond(Response::empty(400));
+            return;
+        };
+        let accept = Header::from_bytes("Sec-WebSocket-Accept", key).expect("accept key is valid");
+        let stream = request.upgrade("websocket", Response::empty(101).with_header(accept));
+
+        let (tx, rx) = mpsc::channel::<String>();
+        let _ = tx.send(self.last_status.clone());
+        self.subscribers.push(tx);
+        thread::spawn(move || {
+            let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+            for event in rx {
+                if socket.send(Message::text(event)).is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    fn broadcast_status(&mut self) {
+        let status = self.api.status_json();
+        if status == self.last_status {
+            return;
+        }
+        // Writer threads exit when their client goes away, which closes the channel
+        self.subscribers
+            .retain(|tx| tx.send(status.clone()).is_ok());
+        self.last_status = status;
+    }
+
+    /// Record library tracks the player finished as plays, and pass them on to
+    /// the scrobbler when there is one
+    fn track_playback(&mut self) {
+        let status = self.api.player_status();
+        for event in self.tracker.observe(&status, Instant::now(), now()) {
+            let (path, started_at, counted) = match event {
+                PlaybackEvent::Started { path, started_at } => (path, started_at, false),
+                PlaybackEvent::Finished {
+                    path,
+                    started_at,
+                    listened,
+                    duration,
+                } if counts_as_listen(duration, listened) => (path, started_at, true),
+                PlaybackEvent::Finished { .. } => continue,
+            };
+            let db = self.api.db();
+            let Ok(Some(track)) = db.track_by_path(&path.to_string_lossy()) else {
+                continue;
+            };
+            if counted && let Err(e) = db.record_play(track.id, started_at) {
+                eprintln!("Failed to record play of {}: {:#}", track.path, e);
+            }
+            let (Some(worker), Some(listen)) =
+                (&self.scrobbler, Listen::from_track(&track, started_at))
+            else {
+                continue;
+            };
+            if counted {
+                worker.played(listen);
+            } else {
+                worker.now_playing(listen);
+            }
+        }
+    }
+
+    /// Remember where long library tracks were paused or stopped
+    fn save_resume_position(&mut self) {
+        let status = self.api.player_status();
+        let options = *self.api.resume_options();
+        if let Err(e) = self
+            .resume
+            .observe(self.api.db(), &status, Instant::now(), &options)
+        {
+            eprintln!("Failed to save the playback position: {:#}", e);
+        }
+    }
+}
+
+fn header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .headers()
+        .iter()
+        .find(|h| h.field.as_str().as_str().eq_ignore_ascii_case(name))
+        .map(|h| h.value.as_str())
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    form_urlencoded::parse(query.as_bytes())
+        .map(|(k, v)| (k.into_owned(), v.into_owned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{NewTrack, TrackId};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use tempfile::NamedTempFile;
+
+    const TOKEN: &str = "secret";
+
+    /// Start a server on an ephemeral port in a background thread.
+    fn spawn_server(db_file: &NamedTempFile) -> SocketAddr {
+        spawn_server_with(db_file, None)
+    }
+
+    fn spawn_server_with(db_file: &NamedTempFile, subsonic: Option<SubsonicConfig>) -> SocketAddr {
+        let db_path = db_file.path().to_owned();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let db = DB::open(&db_path).unwrap();
+            let player = Player::new().unwrap();
+            let mut server = Server::bind("127.0.0.1:0", TOKEN.into(), db, player).unwrap();
+            if let Some(config) = subsonic {
+                server.enable_subsonic(config);
+            }
+            tx.send(server.local_addr().unwrap()).unwrap();
+            server.run().unwrap();
+        });
+        rx.recv().unwrap()
+    }
+
+    fn request(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> (u16, String) {
+        let (status, body) = request_bytes(addr, method, path, token, body);
+        (status, String::from_utf8(body).unwrap())
+    }
+
+    fn request_bytes(
+        addr: SocketAddr,
+        method: &str,
+        path: &str,
+        token: Option<&str>,
+        body: &str,
+    ) -> (u16, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let auth = token
+            .map(|t| format!("Authorization: Bearer {}\r\n", t))
+            .unwrap_or_default();
+        write!(
+            stream,
+            "{} {} HTTP/1.0\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            auth,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        let status = std::str::from_utf8(&response[9..12])
+            .unwrap()
+            .parse()
+            .unwrap();
+        let body = response
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .map(|at| response[at + 4..].to_vec())
+            .unwrap_or_default();
+        (status, body)
+    }
+
+    #[test]
+    fn test_library_endpoints() {
+        let db_file = NamedTempFile::new().unwrap();
+        {
+            let db = DB::open(db_file.path()).unwrap();
+            for (path, artist, album) in [
+                ("/m/1.flac", "A", "X"),
+                ("/m/2.flac", "A", "Y"),
+                ("/m/3.flac", "B", "Z"),
+            ] {
+                db.insert_track(&NewTrack {
+                    path: path.into(),
+                    artist: Some(artist.into()),
+                    album: Some(album.into()),
+                    ..Default::default()
+                })
+                .unwrap();
+            }
+            // A compilation is one album whoever plays each track
+            for (path, artist) in [("/m/4.flac", "C"), ("/m/5.flac", "D")] {
+                db.insert_track(&NewTrack {
+                    path: path.into(),
+                    artist: Some(artist.into()),
+                    album: Some("Hits".into()),
+                    album_artist: Some("Various Artists".into()),
+                    ..Default::default()
+                })
+                .unwrap();
+            }
+            let playlist = db.create_playlist("Mix").unwrap();
+            for track_id in [3, 1, 2] {
+                db.add_to_playlist(playlist, TrackId(track_id)).unwrap();
+            }
+        }
+        let addr = spawn_server(&db_file);
+
+        let (status, _) = request(addr, "GET", "/api/v1/tracks", None, "");
+        assert_eq!(status, 401);
+        let (status, _) = request(addr, "GET", "/api/v1/tracks", Some("wrong"), "");
+        assert_eq!(status, 401);
+        let (status, _) = request(addr, "GET", "/api/v1/tracks", Some(""), "");
+        assert_eq!(status, 401);
+        // Only the event feed takes the token in the query string
+        let path = format!("/api/v1/tracks?token={}", TOKEN);
+        let (status, _) = request(addr, "GET", &path, None, "");
+        assert_eq!(status, 401);
+
+        let (status, body) = request(
+            addr,
+            "GET",
+            "/api/v1/tracks?artist=A&limit=1",
+            Some(TOKEN),
+            "",
+        );
+        assert_eq!(status, 200);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["total"], 2);
+        assert_eq!(json["items"].as_array().unwrap().len(), 1);
+
+        let (_, body) = request(addr, "GET", "/api/v1/artists", Some(TOKEN), "");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["total"], 5);
+        let (_, body) = request(addr, "GET", "/api/v1/albums", Some(TOKEN), "");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["total"], 4);
+        let hits = json["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|album| album["title"] == "Hits")
+            .unwrap();
+        assert_eq!(hits["track_count"], 2);
+        let path = format!("/api/v1/albums/{}/tracks", hits["id"]);
+        let (_, body) = request(addr, "GET", &path, Some(TOKEN), "");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json.as_array().unwrap().len(), 2);
+        let path = format!("/api/v1/artists/{}/albums", hits["artist_id"]);
+        let (_, body) = request(addr, "GET", &path, Some(TOKEN), "");
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json[0]["title"], "Hits");
+        let (status, _) = request(addr, "GET", "/api/v1/albums/999/tracks", Some(TOKEN), "");
+        assert_eq!(status, 404);
+
+        let (_, body) = request(
+            addr,
+            "GET",
+            "/api/v1/playlists/1/tracks?limit=2&offset=1",
+            Some(TOKEN),
+            "",
+        );
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["total"], 3);
+        let paths: Vec<_> = json["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|track| track["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, ["/m/1.flac", "/m/2.flac"]);
+        let (status, _) = request(addr, "GET", "/api/v1/playlists/999/tracks", Some(TOKEN), "");
+        assert_eq!(status, 404);
+
+        let (status, _) = request(addr, "GET", "/api/v1/tracks/999", Some(TOKEN), "");
+        assert_eq!(status, 404);
+
+        let (status, body) = request(
+            addr,
+            "PUT",
+            "/api/v1/tracks/1/rating",
+            Some(TOKEN),
+            r#"{"rating": 3.5, "loved": "loved"}"#,
+        );
+        assert_eq!(status, 200);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            (json["rating"].as_f64(), json["loved"].as_str()),
+            (Some(3.5), Some("loved"))
+        );
+        let (status, _) = request(
+            addr,
+            "PUT",
+            "/api/v1/tracks/1/rating",
+            Some(TOKEN),
+            r#"{"rating": 3.2}"#,
+        );
+        assert_eq!(status, 400);
+        let (_, body) = request(
+            addr,
+            "PUT",
+            "/api/v1/tracks/1/rating",
+            Some(TOKEN),
+            r#"{"rating": null}"#,
+        );
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            (json["rating"].is_null(), json["loved"].as_str()),
+            (true, Some("loved"))
+        );
+        let (status, _) = request(addr, "POST", "/api/v1/player/rating", Some(TOKEN), "{}");
+        assert_eq!(status, 409);
+
+        let (status, body) = request(
+            addr,
+            "POST",
+            "/api/v1/tracks/2/bookmarks",
+            Some(TOKEN),
+            r#"{"name": "Bridge", "position_ms": 95000}"#,
+        );
+        assert_eq!(status, 200);
+        let bookmark: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(bookmark["track_id"], 2);
+        // Without a position, the track has to be the one playing
+        let (status, _) = request(
+            addr,
+            "POST",
+            "/api/v1/tracks/2/bookmarks",
+            Some(TOKEN),
+            r#"{"name": "Here"}"#,
+        );
+        assert_eq!(status, 400);
+        let (_, body) = request(addr, "GET", "/api/v1/tracks/2/bookmarks", Some(TOKEN), "");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()[0]["name"],
+            "Bridge"
+        );
+        let path = format!("/api/v1/bookmarks/{}", bookmark["id"]);
+        let (status, body) = request(addr, "DELETE", &path, Some(TOKEN), "");
+        assert_eq!((status, body.as_str()), (200, "[]"));
+        let (status, _) = request(addr, "DELETE", &path, Some(TOKEN), "");
+        assert_eq!(status, 404);
+
+        let (status, body) = request(addr, "GET", "/api/v1/stats?since=30d", Some(TOKEN), "");
+        assert_eq!(status, 200);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["totals"]["plays"], 0);
+        assert_eq!(json["by_hour"].as_array().unwrap().len(), 24);
+        let (status, _) = request(addr, "GET", "/api/v1/stats?since=soon", Some(TOKEN), "");
+        assert_eq!(status, 400);
+
+        let (status, body) = request(addr, "GET", "/api/v1/openapi.json", None, "");
+        assert_eq!(status, 200);
+        assert!(body.contains("\"openapi\""));
+    }
+
+    #[test]
+    fn test_queue_and_player_endpoints() {
+        let db_file = NamedTempFile::new().unwrap();
+        let addr = spawn_server(&db_file);
+
+        let (status, body) = request(
+            addr,
+            "POST",
+            "/api/v1/queue",
+            Some(TOKEN),
+            r#"{"path": "/m/a.mp3"}"#,
+        );
+        assert_eq!(status, 200);
+        assert_eq!(body, r#"["/m/a.mp3"]"#);
+
+        let (status, _) = request(
+            addr,
+            "POST",
+            "/api/v1/queue",
+            Some(TOKEN),
+            r#"{"track_id": 42}"#,
+        );
+        assert_eq!(status, 404);
+        let (status, _) = request(addr, "POST", "/api/v1/queue", Some(TOKEN), "{}");
+        assert_eq!(status, 400);
+
+        let (status, body) = request(addr, "GET", "/api/v1/player", Some(TOKEN), "");
+        assert_eq!(status, 200);
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["state"], "stopped");
+        assert_eq!(json["queue_length"], 1);
+
+        let (status, body) = request(addr, "DELETE", "/api/v1/queue/0", Some(TOKEN), "");
+        assert_eq!(status, 200);
+        assert_eq!(body, "[]");
+
+        #[cfg(not(feature = "audio"))]
+        {
+            let (status, _) = request(addr, "POST", "/api/v1/player/pause", Some(TOKEN), "");
+            assert_eq!(status, 503);
+            let (status, _) = request(
+                addr,
+                "POST",
+                "/api/v1/player/audio-track",
+                Some(TOKEN),
+                r#"{"id": 2}"#,
+            );
+            assert_eq!(status, 503);
+            let (_, body) = request(addr, "GET", "/api/v1/player/audio-tracks", Some(TOKEN), "");
+            assert_eq!(body, "[]");
+        }
+    }
+
+    /// A minute of silent 8 kHz mono PCM
+    #[cfg(feature = "audio")]
+    fn minute_wav() -> Vec<u8> {
+        let data = vec![0u8; 60 * 16_000];
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+        wav.extend_from_slice(&8000u32.to_le_bytes());
+        wav.extend_from_slice(&16_000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        wav.extend_from_slice(&data);
+        wav
+    }
+
+    #[cfg(feature = "audio")]
+    #[test]
+    fn test_player_resumes_where_it_was_paused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("lecture.wav");
+        std::fs::write(&path, minute_wav()).unwrap();
+        let db_file = NamedTempFile::new().unwrap();
+        let id = DB::open(db_file.path())
+            .unwrap()
+            .insert_track(&NewTrack {
+                path: path.to_string_lossy().into_owned(),
+                duration_seconds: Some(60),
+                ..Default::default()
+            })
+            .unwrap();
+
+        let db_path = db_file.path().to_owned();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let db = DB::open(&db_path).unwrap();
+            let player = Player::new().unwrap();
+            let mut server = Server::bind("127.0.0.1:0", TOKEN.into(), db, player).unwrap();
+            server.set_resume_options(ResumeOptions {
+                min_length: Duration::from_secs(30),
+                rewind: Duration::from_secs(5),
+            });
+            tx.send(server.local_addr().unwrap()).unwrap();
+            server.run().unwrap();
+        });
+        let addr = rx.recv().unwrap();
+        let post = |path: &str, body: &str| {
+            let (status, body) = request(addr, "POST", path, Some(TOKEN), body);
+            assert_eq!(status, 200, "{}", body);
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+        let play = format!(r#"{{"track_id": {}}}"#, id);
+
+        let status = post("/api/v1/player/play", &play);
+        assert_eq!(status["position_secs"], 0);
+        post("/api/v1/player/seek", r#"{"seconds": 20}"#);
+        let status = post("/api/v1/player/pause", "");
+        assert_eq!(status["position_secs"], 20);
+        // Saved as the server notices the pause, before it takes the next request
+        post("/api/v1/player/stop", "");
+        let db = DB::open(db_file.path()).unwrap();
+        let saved = db.resume_position(id).unwrap().unwrap();
+        assert!((20_000..21_000).contains(&saved), "{}", saved);
+
+        let status = post("/api/v1/player/play", &play);
+        assert_eq!(status["state"], "playing");
+        assert_eq!(status["position_secs"], 15);
+    }
+
+    #[test]
+    fn test_event_feed_sends_status() {
+        let db_file = NamedTempFile::new().unwrap();
+        let addr = spawn_server(&db_file);
+
+        let stream = TcpStream::connect(addr).unwrap();
+        let url = format!("ws://{}{}?token={}", addr, EVENTS_PATH, TOKEN);
+        let (mut socket, _) = tungstenite::client(url.as_str(), stream).unwrap();
+        let event = socket.read().unwrap();
+        let json: serde_json::Value = serde_json::from_str(event.to_text().unwrap()).unwrap();
+        assert_eq!(json["state"], "stopped");
+
+        // Queue changes are part of the status, so they trigger a new event
+        request(
+            addr,
+            "POST",
+            "/api/v1/queue",
+            Some(TOKEN),
+            r#"{"path": "/m/a.mp3"}"#,
+        );
+        let event = socket.read().unwrap();
+        let json: serde_json::Value = serde_json::from_str(event.to_text().unwrap()).unwrap();
+        assert_eq!(json["queue_length"], 1);
+    }
+
+    #[test]
+    fn test_subsonic_endpoints() {
+        use md5::{Digest, Md5};
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let song_path = dir.path().join("01 Julie.mp3");
+        std::fs::write(&song_path, b"FAKEAUDIO").unwrap();
+        std::fs::write(dir.path().join("Cover.JPG"), b"FAKEJPEG").unwrap();
+        let transcoder = dir.path().join("fake-ffmpeg");
+        std::fs::write(
+            &transcoder,
+            "#!/bin/sh\necho \"$@\" > \"$0.args\"\necho transcoded\n",
+        )
+        .unwrap();
+        let album_path = dir.path().join("Album.wav");
+        std::fs::write(&album_path, crate::scanner::tests::wav(&[])).unwrap();
+        std::fs::set_permissions(&transcoder, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let db_file = NamedTempFile::new().unwrap();
+        let (id, cue_id) = {
+            let db = DB::open(db_file.path()).unwrap();
+            db.insert_track(&NewTrack {
+                path: "/m/other.flac".into(),
+                title: Some("Roygbiv".into()),
+                artist: Some("Boards of Canada".into()),
+                album: Some("Music Has the Right to Children".into()),
+                duration_seconds: Some(150),
+                ..Default::default()
+            })
+            .unwrap();
+            let id = db
+                .insert_track(&NewTrack {
+                    path: song_path.display().to_string(),
+                    title: Some("Julie and Candy".into()),
+                    artist: Some("Boards of Canada".into()),
+                    album: Some("Geogaddi".into()),
+                    duration_seconds: Some(330),
+                    ..Default::default()
+                })
+                .unwrap();
+            let cue_id = db
+                .insert_track(&NewTrack {
+                    path: format!("{}#02", album_path.display()),
+                    title: Some("Second".into()),
+                    start_ms: Some(250),
+                    end_ms: Some(750),
+                    ..Default::default()
+                })
+                .unwrap();
+            (id, cue_id)
+        };
+        let addr = spawn_server_with(
+            &db_file,
+            Some(SubsonicConfig {
+                username: "alice".into(),
+                password: "pw".into(),
+                transcoder: Some(transcoder),
+            }),
+        );
+        let call = |method: &str, params: &str| {
+            let path = format!("/rest/{}.view?u=alice&p=pw&f=json{}", method, params);
+            let (_, body) = request(addr, "GET", &path, None, "");
+            body
+        };
+        let json = |method: &str, params: &str| -> serde_json::Value {
+            let body = call(method, params);
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()["subsonic-response"].clone()
+        };
+
+        // Authentication: plain, hex-encoded and salted token
+        let (_, body) = request(addr, "GET", "/rest/ping.view?u=alice&p=pw", None, "");
+        assert!(body.contains(r#"status="ok""#));
+        let (_, body) = request(
+            addr,
+            "GET",
+            "/rest/ping?u=alice&p=enc:7077&f=json",
+            None,
+            "",
+        );
+        assert!(body.contains(r#""status":"ok""#));
+        let token = Md5::digest(b"pwNaCl")
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        let (_, body) = request(
+            addr,
+            "GET",
+            &format!("/rest/ping?u=alice&t={}&s=NaCl", token),
+            None,
+            "",
+        );
+        assert!(body.contains(r#"status="ok""#));
+        let (_, body) = request(addr, "GET", "/rest/ping?u=alice&p=nope", None, "");
+        assert!(body.contains(r#"code="40""#));
+
+        // Browsing
+        let artists = json("getArtists", "");
+        let index = &artists["artists"]["index"][0];
+        assert_eq!(index["name"], "B");
+        let artist_id = index["artist"][0]["id"].as_str().unwrap().to_owned();
+        let artist = json("getArtist", &format!("&id={}", artist_id));
+        assert_eq!(artist["artist"]["albumCount"], 2);
+        let album_id = artist["artist"]["album"][0]["id"]
+            .as_str()
+            .unwrap()
+            .to_owned();
+        let album = json("getAlbum", &format!("&id={}", album_id));
+        assert_eq!(album["album"]["name"], "Geogaddi");
+        assert_eq!(album["album"]["song"][0]["title"], "Julie and Candy");
+        assert_eq!(json("getAlbum", "&id=al-00")["status"], "failed");
+
+        let found = json("search3", "&query=julie");
+        assert_eq!(found["searchResult3"]["song"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            found["searchResult3"]["artist"].as_array().unwrap().len(),
+            0
+        );
+        let found = json("search3", "&query=%22%22&albumCount=1&albumOffset=1");
+        let albums = found["searchResult3"]["album"].as_array().unwrap();
+        assert_eq!(albums.len(), 1);
+        assert_eq!(albums[0]["name"], "Music Has the Right to Children");
+        let found = json("search3", "&query=canada");
+        assert_eq!(
+            found["searchResult3"]["artist"][0]["name"],
+            "Boards of Canada"
+        );
+
+        // JSONP wraps the response in a call to the callback
+        let (_, body) = request(
+            addr,
+            "GET",
+            "/rest/ping?u=alice&p=pw&f=jsonp&callback=handle",
+            None,
+            "",
+        );
+        assert!(body.starts_with("handle({\"subsonic-response\":"));
+        assert!(body.ends_with("});"));
+        let (_, body) = request(
+            addr,
+            "GET",
+            "/rest/ping?u=alice&p=pw&f=jsonp&callback=alert(1)",
+            None,
+            "",
+        );
+        assert!(body.contains(r#""code":10"#));
+
+        // Streaming, transcoding and cover art
+        let song_id = format!("tr-{}", id);
+        assert_eq!(call("stream", &format!("&id={}", song_id)), "FAKEAUDIO");
+        assert_eq!(
+            call("download", &format!("&id={}&format=mp3", song_id)),
+            "FAKEAUDIO"
+        );
+        assert_eq!(
+            call("stream", &format!("&id={}&format=mp3", song_id)),
+            "transcoded\n"
+        );
+        assert_eq!(
+            call("getCoverArt", &format!("&id={}", album_id)),
+            "FAKEJPEG"
+        );
+
+        // A cue sheet track is cut from its file: half a second of 8 kHz mono
+        let cue_id = format!("tr-{}", cue_id);
+        let song = &json("getSong", &format!("&id={}", cue_id))["song"];
+        assert_eq!(
+            (&song["suffix"], &song["transcodedSuffix"]),
+            (&"wav".into(), &"wav".into())
+        );
+        let path = format!("/rest/download?u=alice&p=pw&id={}", cue_id);
+        let (_, wav) = request_bytes(addr, "GET", &path, None, "");
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(wav.len(), 44 + 4000 * 2);
+        assert_eq!(
+            call("stream", &format!("&id={}&format=mp3", cue_id)),
+            "transcoded\n"
+        );
+        let args = std::fs::read_to_string(dir.path().join("fake-ffmpeg.args")).unwrap();
+        assert!(args.contains("-ss 0.250 -to 0.750 -i"), "{}", args);
+
+        // Scrobbles, stars and ratings
+        assert_eq!(
+            json("scrobble", &format!("&id={}&submission=false", song_id))["status"],
+            "ok"
+        );
+        json("scrobble", &format!("&id={}&time=1700000000000", song_id));
+        json("star", &format!("&id={}", song_id));
+        json("setRating", &format!("&id={}&rating=4", song_id));
+        let song = &json("getSong", &format!("&id={}", song_id))["song"];
+        assert_eq!(song["playCount"], 1);
+        assert_eq!(song["userRating"], 4);
+        assert!(song["starred"].is_string());
+        let starred = json("getStarred2", "");
+        assert_eq!(starred["starred2"]["song"].as_array().unwrap().len(), 1);
+
+        // Playlists
+        let created = json(
+            "createPlaylist",
+            &format!("&name=Mix&songId={}&songId={}", song_id, song_id),
+        );
+        assert_eq!(created["playlist"]["songCount"], 2);
+        let playlist_id = created["playlist"]["id"].as_str().unwrap().to_owned();
+        let lists = json("getPlaylists", "");
+        assert_eq!(lists["playlists"]["playlist"][0]["duration"], 660);
+        let updated = json(
+            "createPlaylist",
+            &format!("&playlistId={}&songId={}", playlist_id, song_id),
+        );
+        assert_eq!(updated["playlist"]["entry"].as_array().unwrap().len(), 1);
+        assert_eq!(
+            json("deletePlaylist", &format!("&id={}", playlist_id))["status"],
+            "ok"
+        );
+        assert_eq!(
+            json("getPlaylist", &format!("&id={}", playlist_id))["error"]["code"],
+            70
+        );
+    }
+}
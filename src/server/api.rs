@@ -0,0 +1,658 @@
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use thiserror::Error;
+
+use super::subsonic::Subsonic;
+use crate::chapters::Chapter;
+use crate::db::{DB, Love, Page, TrackFilter, TrackId, Window};
+use crate::player::{Player, PlayerError, PlayerState, PlayerStatus};
+use crate::resume::{self, ResumeOptions};
+
+/// OpenAPI 3 description of every route handled here
+const OPENAPI_SPEC: &str = include_str!("openapi.json");
+
+/// Largest page a client may request in one call
+const MAX_PAGE_SIZE: usize = 500;
+
+#[derive(Debug, Error)]
+pub(crate) enum ApiError {
+    #[error("Not found")]
+    NotFound,
+    #[error("Missing or invalid API token")]
+    Unauthorized,
+    #[error("Bad request: {0}")]
+    BadRequest(String),
+    #[error(transparent)]
+    Player(#[from] PlayerError),
+    #[error("Database error: {0}")]
+    Database(#[from] anyhow::Error),
+}
+
+impl ApiError {
+    fn status(&self) -> u16 {
+        match self {
+            ApiError::NotFound => 404,
+            ApiError::Unauthorized => 401,
+            ApiError::BadRequest(_) => 400,
+            ApiError::Player(PlayerError::AudioDisabled | PlayerError::NoAudioDevice) => 503,
+            ApiError::Player(PlayerError::FileNotFound(_)) => 404,
+            ApiError::Player(PlayerError::InvalidVolume(_) | PlayerError::InvalidState(_)) => 409,
+            ApiError::Player(PlayerError::UnsupportedFormat(_)) => 415,
+            ApiError::Player(_) | ApiError::Database(_) => 500,
+        }
+    }
+}
+
+/// A transport-independent HTTP request
+pub(crate) struct ApiRequest {
+    pub method: String,
+    pub path: String,
+    pub query: Vec<(String, String)>,
+    pub authorization: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl ApiRequest {
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.query
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value of a repeated query parameter
+    pub fn params(&self, name: &str) -> Vec<&str> {
+        self.query
+            .iter()
+            .filter(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+            .collect()
+    }
+
+    fn page(&self) -> Result<Page, ApiError> {
+        let mut page = Page::default();
+        if let Some(limit) = self.param("limit") {
+            page.limit = parse_number(limit, "limit")?;
+        }
+        if let Some(offset) = self.param("offset") {
+            page.offset = parse_number(offset, "offset")?;
+        }
+        page.limit = page.limit.min(MAX_PAGE_SIZE);
+        Ok(page)
+    }
+
+    fn json<'a, T: Deserialize<'a>>(&'a self) -> Result<T, ApiError> {
+        serde_json::from_slice(&self.body)
+            .map_err(|e| ApiError::BadRequest(format!("invalid JSON body: {}", e)))
+    }
+}
+
+/// Response payload: either fully buffered or streamed from a file or process
+pub(crate) enum Body {
+    Bytes(Vec<u8>),
+    Stream(Box<dyn Read + Send>, Option<usize>),
+}
+
+/// A transport-independent HTTP response
+pub(crate) struct ApiResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub body: Body,
+}
+
+impl ApiResponse {
+    pub fn bytes(content_type: &str, body: Vec<u8>) -> Self {
+        Self {
+            status: 200,
+            content_type: content_type.to_owned(),
+            body: Body::Bytes(body),
+        }
+    }
+
+    fn json<T: Serialize>(value: &T) -> Self {
+        Self::bytes(
+            "application/json",
+            serde_json::to_vec(value).unwrap_or_default(),
+        )
+    }
+
+    fn error(err: &ApiError) -> Self {
+        let mut response = Self::json(&serde_json::json!({ "error": err.to_string() }));
+        response.status = err.status();
+        response
+    }
+}
+
+/// One page of a listing together with the total number of matches
+#[derive(Serialize)]
+struct Paged<T> {
+    items: Vec<T>,
+    total: usize,
+    limit: usize,
+    offset: usize,
+}
+
+impl<T> Paged<T> {
+    fn new(items: Vec<T>, total: usize, page: Page) -> Self {
+        Self {
+            items,
+            total,
+            limit: page.limit,
+            offset: page.offset,
+        }
+    }
+}
+
+/// JSON view of `PlayerStatus`, with times in whole seconds
+#[derive(Serialize)]
+struct StatusView {
+    state: PlayerState,
+    position_secs: Option<u64>,
+    duration_secs: Option<u64>,
+    current_file: Option<PathBuf>,
+    volume: f32,
+    queue_length: usize,
+    chapters: Vec<Chapter>,
+    chapter: Option<ChapterView>,
+}
+
+/// The chapter playing, with the position and length relative to it
+#[derive(Serialize)]
+struct ChapterView {
+    index: usize,
+    title: Option<String>,
+    position_secs: Option<u64>,
+    duration_secs: Option<u64>,
+}
+
+/// Body of requests that name something to play: a file path or a library track id
+#[derive(Deserialize)]
+struct PlayTarget {
+    path: Option<PathBuf>,
+    track_id: Option<TrackId>,
+}
+
+#[derive(Deserialize)]
+struct SeekBody {
+    seconds: u64,
+}
+
+#[derive(Deserialize)]
+struct ChapterBody {
+    index: usize,
+}
+
+/// A bookmark to add; without a position it marks where the track is playing
+#[derive(Deserialize)]
+struct BookmarkBody {
+    name: String,
+    position_ms: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct BookmarkId {
+    id: i64,
+}
+
+#[derive(Deserialize)]
+struct AudioTrackId {
+    id: u32,
+}
+
+#[derive(Deserialize)]
+struct VolumeBody {
+    volume: f32,
+}
+
+/// Body of rating requests; fields left out are unchanged, a `null` rating clears it
+#[derive(Deserialize)]
+struct RatingBody {
+    #[serde(default, deserialize_with = "present")]
+    rating: Option<Option<f64>>,
+    loved: Option<Love>,
+}
+
+/// Distinguish `"rating": null` from a missing field
+fn present<'de, D: serde::Deserializer<'de>>(d: D) -> Result<Option<Option<f64>>, D::Error> {
+    Option::deserialize(d).map(Some)
+}
+
+/// Serialise the player status the way the REST API and event feed report it.
+pub(crate) fn status_json(status: &PlayerStatus, queue_length: usize) -> String {
+    let view = StatusView {
+        state: status.state,
+        position_secs: status.position.map(|p| p.as_secs()),
+        duration_secs: status.duration.map(|d| d.as_secs()),
+        current_file: status.current_file.clone(),
+        volume: status.volume,
+        queue_length,
+        chapters: status.chapters.clone(),
+        chapter: status.chapter.map(|index| {
+            let progress = status.chapter_progress();
+            ChapterView {
+                index,
+                title: status.chapters[index].title.clone(),
+                position_secs: progress.map(|(into, _)| into.as_secs()),
+                duration_secs: progress.and_then(|(_, length)| length).map(|d| d.as_secs()),
+            }
+        }),
+    };
+    serde_json::to_string(&view).unwrap_or_default()
+}
+
+/// Compare tokens in time that depends only on their lengths, so that
+/// timing doesn't give away how much of a guess was right
+fn tokens_match(supplied: &str, token: &str) -> bool {
+    let (supplied, token) = (supplied.as_bytes(), token.as_bytes());
+    let differences = supplied
+        .iter()
+        .zip(token)
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    supplied.len() == token.len() && differences == 0
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str, name: &str) -> Result<T, ApiError> {
+    value
+        .parse()
+        .map_err(|_| ApiError::BadRequest(format!("invalid {}: {}", name, value)))
+}
+
+/// Routes API requests to the library and player
+pub(crate) struct Api {
+    db: DB,
+    player: Player,
+    token: String,
+    subsonic: Option<Subsonic>,
+    resume: ResumeOptions,
+}
+
+impl Api {
+    pub fn new(db: DB, player: Player, token: String) -> Self {
+        Self {
+            db,
+            player,
+            token,
+            subsonic: None,
+            resume: ResumeOptions::default(),
+        }
+    }
+
+    /// Which files play from where they were left off
+    pub fn set_resume_options(&mut self, options: ResumeOptions) {
+        self.resume = options;
+    }
+
+    pub fn resume_options(&self) -> &ResumeOptions {
+        &self.resume
+    }
+
+    /// Serve the Subsonic API under `/rest/`
+    pub fn set_subsonic(&mut self, subsonic: Subsonic) {
+        self.subsonic = Some(subsonic);
+    }
+
+    /// Check the bearer token from the `Authorization` header, or from the
+    /// `token` query parameter on the event feed, whose browser clients
+    /// can't set headers. Query strings end up in logs, so nothing else
+    /// takes the token there.
+    pub fn authorize(&self, request: &ApiRequest) -> Result<(), ApiError> {
+        let header = request
+            .authorization
+            .as_deref()
+            .and_then(|value| value.strip_prefix("Bearer "));
+        let supplied = match request.path == super::EVENTS_PATH {
+            true => header.or_else(|| request.param("token")),
+            false => header,
+        };
+        match supplied {
+            Some(token) if tokens_match(token, &self.token) => Ok(()),
+            _ => Err(ApiError::Unauthorized),
+        }
+    }
+
+    pub fn handle(&self, request: &ApiRequest) -> ApiResponse {
+        if request.method == "GET" && request.path == "/api/v1/openapi.json" {
+            return ApiResponse::bytes("application/json", OPENAPI_SPEC.as_bytes().to_vec());
+        }
+        // Subsonic clients authenticate with their own credentials
+        if let (Some(subsonic), Some(method)) =
+            (&self.subsonic, request.path.strip_prefix("/rest/"))
+        {
+            return subsonic.handle(&self.db, method, request);
+        }
+        match self.authorize(request).and_then(|()| self.route(request)) {
+            Ok(response) => response,
+            Err(err) => ApiResponse::error(&err),
+        }
+    }
+
+    pub fn player_status(&self) -> PlayerStatus {
+        self.player.status()
+    }
+
+    pub fn db(&self) -> &DB {
+        &self.db
+    }
+
+    pub fn status_json(&self) -> String {
+        status_json(&self.player.status(), self.player.queue().len())
+    }
+
+    fn status_response(&self) -> ApiResponse {
+        ApiResponse::bytes("application/json", self.status_json().into_bytes())
+    }
+
+    fn route(&self, request: &ApiRequest) -> Result<ApiResponse, ApiError> {
+        let segments: Vec<&str> = request
+            .path
+            .trim_matches('/')
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .collect();
+        let ["api", "v1", rest @ ..] = segments.as_slice() else {
+            return Err(ApiError::NotFound);
+        };
+
+        match (request.method.as_str(), rest) {
+            ("GET", ["tracks"]) => {
+                let page = request.page()?;
+                let filter = TrackFilter {
+                    artist: request.param("artist").map(String::from),
+                    album: request.param("album").map(String::from),
+                    text: request.param("q").map(String::from),
+                    starred: request.param("starred") == Some("true"),
+                };
+                let items = self.db.tracks(&filter, page)?;
+                let total = self.db.count_tracks(&filter)?;
+                Ok(ApiResponse::json(&Paged::new(items, total, page)))
+            }
+            ("GET", ["tracks", id]) => {
+                let track = self.db.track(parse_number(id, "track id")?)?;
+                track
+                    .map(|t| ApiResponse::json(&t))
+                    .ok_or(ApiError::NotFound)
+            }
+            ("GET", ["tracks", id, "chapters"]) => {
+                let id = parse_number(id, "track id")?;
+                if self.db.track(id)?.is_none() {
+                    return Err(ApiError::NotFound);
+                }
+                Ok(ApiResponse::json(&self.db.chapters(id)?))
+            }
+            ("GET", ["tracks", id, "bookmarks"]) => {
+                let id = parse_number(id, "track id")?;
+                if self.db.track(id)?.is_none() {
+                    return Err(ApiError::NotFound);
+                }
+                Ok(ApiResponse::json(&self.db.bookmarks(id)?))
+            }
+            ("POST", ["tracks", id, "bookmarks"]) => {
+                let track = self
+                    .db
+                    .track(parse_number(id, "track id")?)?
+                    .ok_or(ApiError::NotFound)?;
+                let body: BookmarkBody = request.json()?;
+                let position_ms = match body.position_ms {
+                    Some(ms) if ms >= 0 => ms,
+                    Some(ms) => {
+                        return Err(ApiError::BadRequest(format!("invalid position_ms: {}", ms)));
+                    }
+                    None => {
+                        let status = self.player.status();
+                        let playing = status.current_file.as_deref().map(|p| p.to_string_lossy());
+                        match status.position {
+                            Some(position) if playing.as_deref() == Some(track.path.as_str()) => {
+                                position.as_millis() as i64
+                            }
+                            _ => {
+                                return Err(ApiError::BadRequest(
+                                    "position_ms is required when the track isn't playing".into(),
+                                ));
+                            }
+                        }
+                    }
+                };
+                let id = self.db.add_bookmark(track.id, &body.name, position_ms)?;
+                let bookmark = self.db.bookmark(id)?.ok_or(ApiError::NotFound)?;
+                Ok(ApiResponse::json(&bookmark))
+            }
+            ("DELETE", ["bookmarks", id]) => {
+                let bookmark = self
+                    .db
+                    .bookmark(parse_number(id, "bookmark id")?)?
+                    .ok_or(ApiError::NotFound)?;
+                self.db.delete_bookmark(bookmark.id)?;
+                Ok(ApiResponse::json(&self.db.bookmarks(bookmark.track_id)?))
+            }
+            ("GET", ["albums"]) => {
+                let page = request.page()?;
+                let items = self.db.album_list(page)?;
+                let total = self.db.count_album_list()?;
+                Ok(ApiResponse::json(&Paged::new(items, total, page)))
+            }
+            ("GET", ["albums", id, "tracks"]) => {
+                let id = parse_number(id, "album id")?;
+                self.db.album(id)?.ok_or(ApiError::NotFound)?;
+                Ok(ApiResponse::json(&self.db.album_tracks(id)?))
+            }
+            ("GET", ["artists"]) => {
+                let page = request.page()?;
+                let items = self.db.artist_list(page)?;
+                let total = self.db.count_artist_list()?;
+                Ok(ApiResponse::json(&Paged::new(items, total, page)))
+            }
+            ("GET", ["artists", id, "albums"]) => {
+                let id = parse_number(id, "artist id")?;
+                self.db.artist(id)?.ok_or(ApiError::NotFound)?;
+                Ok(ApiResponse::json(&self.db.artist_albums(id)?))
+            }
+            ("GET", ["playlists"]) => {
+                let page = request.page()?;
+                let items = self.db.playlists(page)?;
+                let total = self.db.count_playlists()?;
+                Ok(ApiResponse::json(&Paged::new(items, total, page)))
+            }
+            ("GET", ["playlists", id, "tracks"]) => {
+                let id = parse_number(id, "playlist id")?;
+                self.db.playlist(id)?.ok_or(ApiError::NotFound)?;
+                let page = request.page()?;
+                // Smart playlists are only known in full, so page them here
+                let tracks = self.db.playlist_tracks(id)?;
+                let total = tracks.len();
+                let items = tracks
+                    .into_iter()
+                    .skip(page.offset)
+                    .take(page.limit)
+                    .collect();
+                Ok(ApiResponse::json(&Paged::new(items, total, page)))
+            }
+            ("GET", ["stats"]) => {
+                let utc_offset = self.db.utc_offset()?;
+                let bound = |name: &str| {
+                    request
+                        .param(name)
+                        .map(|text| {
+                            Window::parse_bound(text, utc_offset).ok_or_else(|| {
+                                ApiError::BadRequest(format!("invalid {}: {}", name, text))
+                            })
+                        })
+                        .transpose()
+                };
+                let window = Window {
+                    since: bound("since")?,
+                    until: bound("until")?,
+                    utc_offset,
+                };
+                let limit = match request.param("limit") {
+                    Some(limit) => parse_number::<usize>(limit, "limit")?.min(MAX_PAGE_SIZE),
+                    None => 10,
+                };
+                Ok(ApiResponse::json(&self.db.stats(window, limit)?))
+            }
+            ("GET", ["player"]) => Ok(self.status_response()),
+            ("POST", ["player", "play"]) => {
+                let path = self.resolve_target(request.json()?)?;
+                let track = self.db.track_by_path(&path.to_string_lossy())?;
+                match track
+                    .map(|track| self.db.stream_choice(track.id))
+                    .transpose()?
+                {
+                    Some(Some(choice)) => self.player.play_stream(&path, &choice)?,
+                    _ => self.player.play(&path)?,
+                }
+                self.resume_playing()?;
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "pause"]) => {
+                self.player.pause()?;
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "resume"]) => {
+                self.player.resume()?;
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "stop"]) => {
+                self.player.stop()?;
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "next"]) => {
+                self.player.play_next()?;
+                self.resume_playing()?;
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "seek"]) => {
+                let body: SeekBody = request.json()?;
+                self.player.seek(body.seconds)?;
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "bookmark"]) => {
+                let body: BookmarkId = request.json()?;
+                let bookmark = self.db.bookmark(body.id)?.ok_or(ApiError::NotFound)?;
+                let track = self
+                    .db
+                    .track(bookmark.track_id)?
+                    .ok_or(ApiError::NotFound)?;
+                let playing = self.player.status().current_file;
+                if playing.as_deref() != Some(Path::new(&track.path)) {
+                    self.player.play(Path::new(&track.path))?;
+                }
+                self.player
+                    .seek_to(Duration::from_millis(bookmark.position_ms as u64))?;
+                Ok(self.status_response())
+            }
+            ("GET", ["player", "audio-tracks"]) => {
+                Ok(ApiResponse::json(&self.player.audio_tracks()))
+            }
+            ("POST", ["player", "audio-track"]) => {
+                let body: AudioTrackId = request.json()?;
+                self.player.select_track(body.id)?;
+                // Library tracks play the same stream next time
+                let selected = self.player.audio_tracks().into_iter().find(|t| t.selected);
+                let playing = self.player.status().current_file;
+                if let (Some(selected), Some(path)) = (selected, playing)
+                    && let Some(track) = self.db.track_by_path(&path.to_string_lossy())?
+                {
+                    self.db.set_stream_choice(track.id, &selected.choice())?;
+                }
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "chapter"]) => {
+                let body: ChapterBody = request.json()?;
+                self.player.play_chapter(body.index)?;
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "chapter", "next"]) => {
+                self.player.next_chapter()?;
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "chapter", "prev"]) => {
+                self.player.prev_chapter()?;
+                Ok(self.status_response())
+            }
+            ("POST", ["player", "rating"]) => {
+                let path = self
+                    .player
+                    .status()
+                    .current_file
+                    .ok_or_else(|| PlayerError::InvalidState("nothing is playing".into()))?;
+                let track = self
+                    .db
+                    .track_by_path(&path.to_string_lossy())?
+                    .ok_or(ApiError::NotFound)?;
+                self.rate(track.id, request.json()?)
+            }
+            ("PUT", ["tracks", id, "rating"]) => {
+                self.rate(parse_number(id, "track id")?, request.json()?)
+            }
+            ("POST", ["player", "volume"]) => {
+                let body: VolumeBody = request.json()?;
+                self.player.set_volume(body.volume)?;
+                Ok(self.status_response())
+            }
+            ("GET", ["queue"]) => Ok(ApiResponse::json(&self.player.queue())),
+            ("POST", ["queue"]) => {
+                let path = self.resolve_target(request.json()?)?;
+                self.player.enqueue(&path);
+                Ok(ApiResponse::json(&self.player.queue()))
+            }
+            ("DELETE", ["queue"]) => {
+                self.player.clear_queue();
+                Ok(ApiResponse::json(&self.player.queue()))
+            }
+            ("DELETE", ["queue", index]) => {
+                self.player
+                    .remove_from_queue(parse_number(index, "queue index")?)
+                    .ok_or(ApiError::NotFound)?;
+                Ok(ApiResponse::json(&self.player.queue()))
+            }
+            _ => Err(ApiError::NotFound),
+        }
+    }
+
+    fn rate(&self, track_id: TrackId, body: RatingBody) -> Result<ApiResponse, ApiError> {
+        if self.db.track(track_id)?.is_none() {
+            return Err(ApiError::NotFound);
+        }
+        if let Some(rating) = body.rating {
+            if let Some(r) = rating {
+                DB::check_rating(r).map_err(|e| ApiError::BadRequest(e.to_string()))?;
+            }
+            self.db.set_rating(track_id, rating)?;
+        }
+        if let Some(loved) = body.loved {
+            self.db.set_loved(track_id, loved)?;
+        }
+        let track = self.db.track(track_id)?.ok_or(ApiError::NotFound)?;
+        Ok(ApiResponse::json(&track))
+    }
+
+    /// Pick up a long library track where it was left off
+    fn resume_playing(&self) -> Result<(), ApiError> {
+        let Some(path) = self.player.status().current_file else {
+            return Ok(());
+        };
+        let Some(track) = self.db.track_by_path(&path.to_string_lossy())? else {
+            return Ok(());
+        };
+        if let Some(position) = resume::start_position(&self.db, &track, &self.resume)? {
+            self.player.seek_to(position)?;
+        }
+        Ok(())
+    }
+
+    fn resolve_target(&self, target: PlayTarget) -> Result<PathBuf, ApiError> {
+        match (target.path, target.track_id) {
+            (Some(path), None) => Ok(path),
+            (None, Some(id)) => self
+                .db
+                .track(id)?
+                .map(|t| PathBuf::from(t.path))
+                .ok_or(ApiError::NotFound),
+            _ => Err(ApiError::BadRequest(
+                "expected exactly one of `path` or `track_id`".into(),
+            )),
+        }
+    }
+}
@@ -0,0 +1,1081 @@
+//! Subsonic/OpenSubsonic-compatible API over the library.
+//!
+//! Implements the subset of <http://www.subsonic.org/pages/api.jsp> that common
+//! mobile clients need: browsing by artist and album, search, streaming with
+//! optional transcoding, cover art, scrobbling, stars, ratings and playlists.
+//! Responses are XML unless the client asks for `f=json` or `f=jsonp`. Cue
+//! sheet tracks are cut from their file, as WAV or through the transcoder.
+
+use md5::{Digest, Md5};
+use serde_json::{Map, Value};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::probe::Hint;
+
+use super::api::{ApiRequest, ApiResponse, Body};
+use crate::cue;
+use crate::db::{Album, DB, Page, Track, TrackFilter, TrackId, civil_from_days};
+
+/// Subsonic REST API version we claim compatibility with
+const API_VERSION: &str = "1.16.1";
+
+/// Bitrate (kbps) used when a client asks for a format without a bitrate
+const DEFAULT_TRANSCODE_BITRATE: u32 = 192;
+
+/// Image names looked up next to a track before falling back to embedded art
+const COVER_NAMES: &[&str] = &["cover", "folder", "front", "album"];
+
+// Error codes from the Subsonic specification
+const ERR_GENERIC: u32 = 0;
+const ERR_MISSING_PARAMETER: u32 = 10;
+const ERR_WRONG_CREDENTIALS: u32 = 40;
+const ERR_NOT_FOUND: u32 = 70;
+
+/// Credentials and options for the Subsonic endpoint
+#[derive(Debug, Clone)]
+pub struct SubsonicConfig {
+    pub username: String,
+    pub password: String,
+    /// `ffmpeg`-compatible binary used when clients request another format or bitrate
+    pub transcoder: Option<PathBuf>,
+}
+
+/// A Subsonic error, reported with HTTP 200 and `status="failed"` as the spec requires
+struct Fault {
+    code: u32,
+    message: String,
+}
+
+impl Fault {
+    fn missing(name: &str) -> Self {
+        Self {
+            code: ERR_MISSING_PARAMETER,
+            message: format!("Required parameter is missing: {}", name),
+        }
+    }
+
+    fn not_found(what: &str) -> Self {
+        Self {
+            code: ERR_NOT_FOUND,
+            message: format!("{} not found", what),
+        }
+    }
+
+    fn generic(message: impl Into<String>) -> Self {
+        Self {
+            code: ERR_GENERIC,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<anyhow::Error> for Fault {
+    fn from(err: anyhow::Error) -> Self {
+        Self::generic(err.to_string())
+    }
+}
+
+/// A response element, rendered either as XML or in Subsonic's JSON convention
+/// where attributes become keys and repeated children become arrays.
+struct Element {
+    name: &'static str,
+    attrs: Vec<(&'static str, Value)>,
+    children: Vec<Content>,
+}
+
+enum Content {
+    Single(Element),
+    List(&'static str, Vec<Element>),
+}
+
+impl Element {
+    fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            attrs: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    fn attr(mut self, key: &'static str, value: impl Into<Value>) -> Self {
+        self.attrs.push((key, value.into()));
+        self
+    }
+
+    fn opt_attr<T: Into<Value>>(self, key: &'static str, value: Option<T>) -> Self {
+        match value {
+            Some(value) => self.attr(key, value),
+            None => self,
+        }
+    }
+
+    fn list(mut self, name: &'static str, elements: Vec<Element>) -> Self {
+        self.children.push(Content::List(name, elements));
+        self
+    }
+
+    fn write_xml(&self, out: &mut String) {
+        out.push('<');
+        out.push_str(self.name);
+        for (key, value) in &self.attrs {
+            let text = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            out.push_str(&format!(" {}=\"{}\"", key, xml_escape(&text)));
+        }
+        if self.children.is_empty() {
+            out.push_str("/>");
+            return;
+        }
+        out.push('>');
+        for content in &self.children {
+            match content {
+                Content::Single(element) => element.write_xml(out),
+                Content::List(_, elements) => elements.iter().for_each(|e| e.write_xml(out)),
+            }
+        }
+        out.push_str(&format!("</{}>", self.name));
+    }
+
+    fn to_json(&self) -> Value {
+        let mut object = Map::new();
+        for (key, value) in &self.attrs {
+            object.insert((*key).to_owned(), value.clone());
+        }
+        for content in &self.children {
+            match content {
+                Content::Single(element) => {
+                    object.insert(element.name.to_owned(), element.to_json());
+                }
+                Content::List(name, elements) => {
+                    let items = elements.iter().map(Element::to_json).collect();
+                    object.insert((*name).to_owned(), Value::Array(items));
+                }
+            }
+        }
+        Value::Object(object)
+    }
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// Identifiers handed out to clients: the row id, prefixed with what kind of
+/// thing it is, as clients treat ids of every kind as one namespace.
+#[derive(Debug, PartialEq)]
+enum Id {
+    Track(TrackId),
+    Album(i64),
+    Artist(i64),
+    Playlist(i64),
+}
+
+impl Id {
+    fn parse(text: &str) -> Option<Self> {
+        let (kind, rest) = text.split_once('-')?;
+        match kind {
+            "tr" => rest.parse().ok().map(Id::Track),
+            "pl" => rest.parse().ok().map(Id::Playlist),
+            "ar" => rest.parse().ok().map(Id::Artist),
+            "al" => rest.parse().ok().map(Id::Album),
+            _ => None,
+        }
+    }
+
+    fn encode(&self) -> String {
+        match self {
+            Id::Track(id) => format!("tr-{}", id),
+            Id::Playlist(id) => format!("pl-{}", id),
+            Id::Artist(id) => format!("ar-{}", id),
+            Id::Album(id) => format!("al-{}", id),
+        }
+    }
+}
+
+/// Format a unix timestamp as an ISO 8601 UTC date-time
+fn iso8601(secs: i64) -> String {
+    let (year, month, day) = civil_from_days(secs.div_euclid(86_400));
+    let rem = secs.rem_euclid(86_400);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn suffix(path: &Path) -> String {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_lowercase)
+        .unwrap_or_default()
+}
+
+fn content_type(suffix: &str) -> &'static str {
+    match suffix {
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "m4a" | "m4b" | "mp4" | "m4v" => "audio/mp4",
+        "aac" => "audio/aac",
+        "caf" => "audio/x-caf",
+        "mka" | "mkv" => "audio/x-matroska",
+        "webm" => "audio/webm",
+        "wav" => "audio/wav",
+        "mid" | "midi" | "kar" => "audio/midi",
+        "dsf" => "audio/x-dsf",
+        "dff" => "audio/x-dff",
+        "mod" => "audio/x-mod",
+        "s3m" => "audio/x-s3m",
+        "xm" => "audio/x-xm",
+        "it" => "audio/x-it",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        _ => "application/octet-stream",
+    }
+}
+
+fn song(name: &'static str, track: &Track) -> Element {
+    let path = Path::new(cue::file_of(&track.path));
+    let suffix = suffix(path);
+    // Cue sheet tracks are cut out of their file and sent as WAV
+    let cut = track.start_ms.is_some().then_some("wav");
+    let title = track.title.clone().unwrap_or_else(|| {
+        path.file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+    let album_id = track.album_id.map(|id| Id::Album(id).encode());
+    Element::new(name)
+        .attr("id", Id::Track(track.id).encode())
+        .opt_attr("parent", album_id.clone())
+        .attr("isDir", false)
+        .attr("title", title)
+        .opt_attr("album", track.album.clone())
+        .opt_attr("artist", track.artist.clone())
+        .attr("coverArt", Id::Track(track.id).encode())
+        .attr("contentType", content_type(&suffix))
+        .attr("suffix", suffix)
+        .opt_attr("transcodedSuffix", cut)
+        .opt_attr("transcodedContentType", cut.map(content_type))
+        .opt_attr("track", track.track_number)
+        .opt_attr("discNumber", track.disc_number)
+        .opt_attr("year", track.year)
+        .opt_attr("genre", track.genre.clone())
+        .opt_attr("duration", track.duration_seconds)
+        .attr("path", track.path.clone())
+        .attr("playCount", track.play_count)
+        .opt_attr("userRating", track.rating.map(|r| r.round() as i64))
+        .opt_attr("starred", track.starred_at.map(iso8601))
+        .opt_attr("albumId", album_id)
+        .attr("type", "music")
+}
+
+fn album(album: &Album) -> Element {
+    let id = Id::Album(album.id).encode();
+    Element::new("album")
+        .attr("id", id.clone())
+        .attr("name", album.title.clone())
+        .attr("artist", album.artist.clone())
+        .attr("artistId", Id::Artist(album.artist_id).encode())
+        .attr("coverArt", id)
+        .attr("songCount", album.track_count)
+        .attr("duration", album.duration_seconds)
+        .opt_attr("year", album.year)
+}
+
+fn playlist(playlist: &crate::db::Playlist, duration: i64) -> Element {
+    Element::new("playlist")
+        .attr("id", Id::Playlist(playlist.id).encode())
+        .attr("name", playlist.name.clone())
+        .attr("songCount", playlist.track_count)
+        .attr("duration", duration)
+        .opt_attr("created", playlist.created_at.map(iso8601))
+        .attr("public", false)
+}
+
+/// Child process output that reaps the transcoder when the response is done
+struct Transcode {
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for Transcode {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.stdout.read(buf)
+    }
+}
+
+impl Drop for Transcode {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A cue sheet track cut from its file and sent as 16-bit WAV, decoding as
+/// the client reads. The promised length is made up with silence if the file
+/// ends early.
+struct Segment {
+    reader: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    channels: usize,
+    /// First and end frames of the track in the file
+    start: u64,
+    end: u64,
+    /// Frames written so far
+    written: u64,
+    out: Vec<u8>,
+    read: usize,
+}
+
+impl Segment {
+    /// Open the part of `file` from `start_ms` to `end_ms`, or to the end
+    fn open(file: &Path, start_ms: i64, end_ms: Option<i64>) -> Option<(Self, usize)> {
+        let source = MediaSourceStream::new(Box::new(File::open(file).ok()?), Default::default());
+        let mut hint = Hint::new();
+        hint.with_extension(&suffix(file));
+        let mut reader = crate::formats::get()
+            .format(&hint, source, &Default::default(), &Default::default())
+            .ok()?
+            .format;
+        let track =
+            crate::streams::choose(reader.tracks(), reader.default_track(), &Default::default())?
+                .clone();
+        let rate = track.codec_params.sample_rate? as u64;
+        let channels = track.codec_params.channels?.count();
+        let frame = |ms: i64| ms.max(0) as u64 * rate / 1000;
+        let start = frame(start_ms);
+        let end = end_ms
+            .map(frame)
+            .or(track.codec_params.n_frames)?
+            .max(start);
+        if start > 0 {
+            reader
+                .seek(
+                    SeekMode::Accurate,
+                    SeekTo::TimeStamp {
+                        ts: start,
+                        track_id: track.id,
+                    },
+                )
+                .ok()?;
+        }
+        let decoder = crate::codecs::get()
+            .make(&track.codec_params, &DecoderOptions::default())
+            .ok()?;
+
+        let data = (end - start) as usize * channels * 2;
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(36 + data as u32).to_le_bytes());
+        out.extend_from_slice(b"WAVEfmt ");
+        out.extend_from_slice(&16u32.to_le_bytes());
+        out.extend_from_slice(&1u16.to_le_bytes());
+        out.extend_from_slice(&(channels as u16).to_le_bytes());
+        out.extend_from_slice(&(rate as u32).to_le_bytes());
+        out.extend_from_slice(&(rate as u32 * channels as u32 * 2).to_le_bytes());
+        out.extend_from_slice(&(channels as u16 * 2).to_le_bytes());
+        out.extend_from_slice(&16u16.to_le_bytes());
+        out.extend_from_slice(b"data");
+        out.extend_from_slice(&(data as u32).to_le_bytes());
+        let length = out.len() + data;
+        let segment = Self {
+            reader,
+            decoder,
+            track_id: track.id,
+            channels,
+            start,
+            end,
+            written: 0,
+            out,
+            read: 0,
+        };
+        Some((segment, length))
+    }
+
+    /// Decode the next packet into `out`, or pad to the end with silence
+    /// when there are none left
+    fn fill(&mut self) -> std::io::Result<()> {
+        let left = self.end - self.start - self.written;
+        let packet = loop {
+            match self.reader.next_packet() {
+                Ok(packet) if packet.track_id() == self.track_id => break Some(packet),
+                Ok(_) => continue,
+                Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    break None;
+                }
+                Err(Error::ResetRequired) => self.decoder.reset(),
+                Err(e) => return Err(std::io::Error::other(e)),
+            }
+        };
+        let Some(packet) = packet else {
+            self.out.resize(left as usize * self.channels * 2, 0);
+            self.written += left;
+            return Ok(());
+        };
+        let decoded = match self.decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(Error::DecodeError(_)) => return Ok(()),
+            Err(e) => return Err(std::io::Error::other(e)),
+        };
+        let mut samples = SampleBuffer::<i16>::new(decoded.capacity() as u64, *decoded.spec());
+        samples.copy_interleaved_ref(decoded);
+        let frames = samples.samples().len() / self.channels;
+        // Frames before the track are dropped, and so is anything after it
+        let skip = self.start.saturating_sub(packet.ts()).min(frames as u64) as usize;
+        let take = (frames - skip).min(left as usize);
+        let kept = &samples.samples()[skip * self.channels..(skip + take) * self.channels];
+        self.out
+            .extend(kept.iter().flat_map(|sample| sample.to_le_bytes()));
+        self.written += take as u64;
+        Ok(())
+    }
+}
+
+impl Read for Segment {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.read == self.out.len() {
+            if self.written == self.end - self.start {
+                return Ok(0);
+            }
+            self.out.clear();
+            self.read = 0;
+            self.fill()?;
+        }
+        let count = buf.len().min(self.out.len() - self.read);
+        buf[..count].copy_from_slice(&self.out[self.read..self.read + count]);
+        self.read += count;
+        Ok(count)
+    }
+}
+
+/// Handles `/rest/<method>` requests
+pub(crate) struct Subsonic {
+    config: SubsonicConfig,
+}
+
+impl Subsonic {
+    pub fn new(config: SubsonicConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn handle(&self, db: &DB, method: &str, request: &ApiRequest) -> ApiResponse {
+        let (format, callback) = Format::of(request);
+        let method = method.strip_suffix(".view").unwrap_or(method);
+        let result = callback
+            .and_then(|()| self.authenticate(request))
+            .and_then(|()| self.dispatch(db, method, request));
+        match result {
+            Ok(Reply::Raw(response)) => response,
+            Ok(Reply::Payload(payload)) => envelope("ok", payload, &format),
+            Err(fault) => {
+                let error = Element::new("error")
+                    .attr("code", fault.code)
+                    .attr("message", fault.message);
+                envelope("failed", Some(Content::Single(error)), &format)
+            }
+        }
+    }
+
+    /// Accept either a plain (`p`, optionally `enc:`-hex) password or the
+    /// salted token `t = md5(password + s)`.
+    fn authenticate(&self, request: &ApiRequest) -> Result<(), Fault> {
+        let user = request.param("u").ok_or_else(|| Fault::missing("u"))?;
+        let password_ok = match (request.param("t"), request.param("s"), request.param("p")) {
+            (Some(token), Some(salt), _) => {
+                let digest = Md5::digest(format!("{}{}", self.config.password, salt));
+                hex(&digest).eq_ignore_ascii_case(token)
+            }
+            (_, _, Some(password)) => {
+                let password = match password.strip_prefix("enc:") {
+                    Some(encoded) => unhex(encoded)
+                        .and_then(|bytes| String::from_utf8(bytes).ok())
+                        .unwrap_or_default(),
+                    None => password.to_owned(),
+                };
+                password == self.config.password
+            }
+            _ => return Err(Fault::missing("p")),
+        };
+        if user == self.config.username && password_ok {
+            Ok(())
+        } else {
+            Err(Fault {
+                code: ERR_WRONG_CREDENTIALS,
+                message: "Wrong username or password".into(),
+            })
+        }
+    }
+
+    fn dispatch(&self, db: &DB, method: &str, request: &ApiRequest) -> Result<Reply, Fault> {
+        let payload = match method {
+            "ping" => None,
+            "getLicense" => Some(Element::new("license").attr("valid", true)),
+            "getOpenSubsonicExtensions" => {
+                // The extension list sits directly in the response, not in a wrapper element
+                let extensions = Content::List("openSubsonicExtensions", Vec::new());
+                return Ok(Reply::Payload(Some(extensions)));
+            }
+            "getMusicFolders" => Some(Element::new("musicFolders").list(
+                "musicFolder",
+                vec![Element::new("musicFolder").attr("id", 1).attr("name", "Library")],
+            )),
+            "getArtists" => Some(self.artists(db)?),
+            "getArtist" => Some(self.artist(db, request)?),
+            "getAlbum" => Some(self.album(db, request)?),
+            "getSong" => {
+                let track = track_param(db, request, "id")?;
+                Some(song("song", &track))
+            }
+            "search3" => Some(self.search(db, request)?),
+            "getStarred2" => {
+                let filter = TrackFilter {
+                    starred: true,
+                    ..Default::default()
+                };
+                let songs = db.tracks(&filter, Page::all())?;
+                Some(
+                    Element::new("starred2")
+                        .list("song", songs.iter().map(|t| song("song", t)).collect()),
+                )
+            }
+            "stream" | "download" => {
+                let track = track_param(db, request, "id")?;
+                let transcode = method == "stream";
+                return self.stream(&track, request, transcode).map(Reply::Raw);
+            }
+            "getCoverArt" => return self.cover_art(db, request).map(Reply::Raw),
+            "scrobble" => {
+                self.scrobble(db, request)?;
+                None
+            }
+            "star" | "unstar" => {
+                if request.param("albumId").is_some() || request.param("artistId").is_some() {
+                    return Err(Fault::generic("Only songs can be starred"));
+                }
+                for id in request.params("id") {
+                    db.set_starred(track_id(id)?, method == "star")?;
+                }
+                None
+            }
+            "setRating" => {
+                let track = track_param(db, request, "id")?;
+                let rating: u8 = request
+                    .param("rating")
+                    .ok_or_else(|| Fault::missing("rating"))?
+                    .parse()
+                    .map_err(|_| Fault::generic("Rating must be a number from 0 to 5"))?;
+                if rating > 5 {
+                    return Err(Fault::generic("Rating must be a number from 0 to 5"));
+                }
+                db.set_rating(track.id, (rating > 0).then_some(f64::from(rating)))?;
+                None
+            }
+            "getPlaylists" => {
+                let items = db
+                    .playlists(Page::all())?
+                    .iter()
+                    .map(|p| Ok(playlist(p, playlist_duration(db, p.id)?)))
+                    .collect::<Result<Vec<_>, Fault>>()?;
+                Some(Element::new("playlists").list("playlist", items))
+            }
+            "getPlaylist" => Some(self.playlist(db, playlist_param(request, "id")?)?),
+            "createPlaylist" => {
+                let id = match (request.param("playlistId"), request.param("name")) {
+                    (Some(id), _) => {
+                        let id = playlist_id(id)?;
+                        if !db.clear_playlist(id)? {
+                            return Err(Fault::not_found("Playlist"));
+                        }
+                        id
+                    }
+                    (None, Some(name)) => db.create_playlist(name)?,
+                    (None, None) => return Err(Fault::missing("name")),
+                };
+                for song_id in request.params("songId") {
+                    db.add_to_playlist(id, track_id(song_id)?)?;
+                }
+                Some(self.playlist(db, id)?)
+            }
+            "deletePlaylist" => {
+                if !db.delete_playlist(playlist_param(request, "id")?)? {
+                    return Err(Fault::not_found("Playlist"));
+                }
+                None
+            }
+            _ => return Err(Fault::not_found(&format!("Method {}", method))),
+        };
+        Ok(Reply::Payload(payload.map(Content::Single)))
+    }
+
+    fn artists(&self, db: &DB) -> Result<Element, Fault> {
+        let mut indexes: Vec<(String, Vec<Element>)> = Vec::new();
+        // Artists only credited on tracks have no albums to browse to
+        for artist in db.artist_list(Page::all())? {
+            if artist.album_count == 0 {
+                continue;
+            }
+            let letter = artist
+                .name
+                .chars()
+                .next()
+                .filter(|c| c.is_alphabetic())
+                .map(|c| c.to_uppercase().to_string())
+                .unwrap_or_else(|| "#".into());
+            let element = Element::new("artist")
+                .attr("id", Id::Artist(artist.id).encode())
+                .attr("name", artist.name)
+                .attr("albumCount", artist.album_count);
+            match indexes.iter_mut().find(|(name, _)| *name == letter) {
+                Some((_, artists)) => artists.push(element),
+                None => indexes.push((letter, vec![element])),
+            }
+        }
+        indexes.sort_by(|a, b| a.0.cmp(&b.0));
+        let indexes = indexes
+            .into_iter()
+            .map(|(name, artists)| {
+                Element::new("index")
+                    .attr("name", name)
+                    .list("artist", artists)
+            })
+            .collect();
+        Ok(Element::new("artists")
+            .attr("ignoredArticles", "")
+            .list("index", indexes))
+    }
+
+    fn artist(&self, db: &DB, request: &ApiRequest) -> Result<Element, Fault> {
+        let id = request.param("id").ok_or_else(|| Fault::missing("id"))?;
+        let artist = match Id::parse(id) {
+            Some(Id::Artist(id)) => db.artist(id)?,
+            _ => None,
+        }
+        .ok_or_else(|| Fault::not_found("Artist"))?;
+        let albums = db.artist_albums(artist.id)?;
+        Ok(Element::new("artist")
+            .attr("id", id)
+            .attr("name", artist.name)
+            .attr("albumCount", albums.len())
+            .list("album", albums.iter().map(album).collect()))
+    }
+
+    fn album(&self, db: &DB, request: &ApiRequest) -> Result<Element, Fault> {
+        let id = request.param("id").ok_or_else(|| Fault::missing("id"))?;
+        let info = match Id::parse(id) {
+            Some(Id::Album(id)) => db.album(id)?,
+            _ => None,
+        }
+        .ok_or_else(|| Fault::not_found("Album"))?;
+        let songs = db.album_tracks(info.id)?;
+        Ok(album(&info).list("song", songs.iter().map(|t| song("song", t)).collect()))
+    }
+
+    fn search(&self, db: &DB, request: &ApiRequest) -> Result<Element, Fault> {
+        let query = request
+            .param("query")
+            .ok_or_else(|| Fault::missing("query"))?;
+        // Some clients send `""` to mean "everything"
+        let query = query.trim_matches('"');
+        let count = |name: &str| -> Page {
+            Page {
+                limit: request
+                    .param(&format!("{}Count", name))
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(20),
+                offset: request
+                    .param(&format!("{}Offset", name))
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(0),
+            }
+        };
+        let artists = db
+            .find_artists(query, count("artist"))?
+            .into_iter()
+            .map(|a| {
+                Element::new("artist")
+                    .attr("id", Id::Artist(a.id).encode())
+                    .attr("name", a.name)
+                    .attr("albumCount", a.album_count)
+            })
+            .collect();
+        let albums = db
+            .find_albums(query, count("album"))?
+            .iter()
+            .map(album)
+            .collect();
+
+        let filter = TrackFilter {
+            text: (!query.is_empty()).then(|| query.to_owned()),
+            ..Default::default()
+        };
+        let songs = db
+            .tracks(&filter, count("song"))?
+            .iter()
+            .map(|t| song("song", t))
+            .collect();
+
+        Ok(Element::new("searchResult3")
+            .list("artist", artists)
+            .list("album", albums)
+            .list("song", songs))
+    }
+
+    fn playlist(&self, db: &DB, id: i64) -> Result<Element, Fault> {
+        let info = db
+            .playlist(id)?
+            .ok_or_else(|| Fault::not_found("Playlist"))?;
+        let tracks = db.playlist_tracks(id)?;
+        let duration = tracks.iter().filter_map(|t| t.duration_seconds).sum();
+        Ok(playlist(&info, duration)
+            .list("entry", tracks.iter().map(|t| song("entry", t)).collect()))
+    }
+
+    fn scrobble(&self, db: &DB, request: &ApiRequest) -> Result<(), Fault> {
+        // Only completed plays are recorded; "now playing" notifications are accepted and ignored
+        if request.param("submission") == Some("false") {
+            return Ok(());
+        }
+        let ids = request.params("id");
+        if ids.is_empty() {
+            return Err(Fault::missing("id"));
+        }
+        let times = request.params("time");
+        for (index, id) in ids.iter().enumerate() {
+            let played_at = times
+                .get(index)
+                .and_then(|t| t.parse::<i64>().ok())
+                .map(|ms| ms / 1000)
+                .unwrap_or_else(crate::db::now);
+            db.record_play(track_id(id)?, played_at)?;
+        }
+        Ok(())
+    }
+
+    fn stream(
+        &self,
+        track: &Track,
+        request: &ApiRequest,
+        allow_transcode: bool,
+    ) -> Result<ApiResponse, Fault> {
+        let path = Path::new(&track.path);
+        // A cue sheet track is part of a file, from `start_ms` to `end_ms`
+        let cut = match (cue::split_path(path), track.start_ms) {
+            (Some((file, _)), Some(start)) => Some((file, start, track.end_ms)),
+            (None, _) if path.is_file() => None,
+            _ => return Err(Fault::not_found("File")),
+        };
+        let format = request.param("format").filter(|f| *f != "raw");
+        let bitrate = request
+            .param("maxBitRate")
+            .and_then(|b| b.parse::<u32>().ok())
+            .filter(|b| *b > 0);
+        if let Some(transcoder) = self.config.transcoder.as_ref().filter(|_| allow_transcode)
+            && (format.is_some() || bitrate.is_some())
+        {
+            let (file, span) = match &cut {
+                Some((file, start, end)) => (file.as_path(), Some((*start, *end))),
+                None => (path, None),
+            };
+            return transcode(
+                transcoder,
+                file,
+                span,
+                format.unwrap_or("mp3"),
+                bitrate.unwrap_or(DEFAULT_TRANSCODE_BITRATE),
+            );
+        }
+        if let Some((file, start, end)) = cut {
+            let (segment, length) = Segment::open(&file, start, end)
+                .ok_or_else(|| Fault::generic("The cue sheet track could not be decoded"))?;
+            return Ok(ApiResponse {
+                status: 200,
+                content_type: content_type("wav").to_owned(),
+                body: Body::Stream(Box::new(segment), Some(length)),
+            });
+        }
+        let file = File::open(path).map_err(|_| Fault::not_found("File"))?;
+        let length = file.metadata().ok().map(|m| m.len() as usize);
+        Ok(ApiResponse {
+            status: 200,
+            content_type: content_type(&suffix(path)).to_owned(),
+            body: Body::Stream(Box::new(file), length),
+        })
+    }
+
+    fn cover_art(&self, db: &DB, request: &ApiRequest) -> Result<ApiResponse, Fault> {
+        let id = request.param("id").ok_or_else(|| Fault::missing("id"))?;
+        let track = match Id::parse(id) {
+            Some(Id::Track(id)) => db.track(id)?,
+            Some(Id::Album(id)) => db.album_tracks(id)?.into_iter().next(),
+            _ => None,
+        };
+        let track = track.ok_or_else(|| Fault::not_found("Cover art"))?;
+        let (media_type, data) = find_cover(Path::new(cue::file_of(&track.path)))
+            .ok_or_else(|| Fault::not_found("Cover art"))?;
+        Ok(ApiResponse::bytes(&media_type, data))
+    }
+}
+
+enum Reply {
+    Payload(Option<Content>),
+    Raw(ApiResponse),
+}
+
+/// How the client asked for responses to be written, with `f`
+enum Format {
+    Xml,
+    Json,
+    /// JSON wrapped in a call to the named function
+    Jsonp(String),
+}
+
+impl Format {
+    /// The format asked for, and a fault if `f=jsonp` comes without a usable
+    /// `callback`; the name goes into a script, so it must be an identifier.
+    fn of(request: &ApiRequest) -> (Self, Result<(), Fault>) {
+        match request.param("f") {
+            Some("json") => (Format::Json, Ok(())),
+            Some("jsonp") => match request.param("callback") {
+                Some(name)
+                    if !name.is_empty()
+                        && name
+                            .chars()
+                            .all(|c| c.is_ascii_alphanumeric() || "_$.".contains(c)) =>
+                {
+                    (Format::Jsonp(name.to_owned()), Ok(()))
+                }
+                _ => (Format::Json, Err(Fault::missing("callback"))),
+            },
+            _ => (Format::Xml, Ok(())),
+        }
+    }
+}
+
+fn envelope(status: &str, payload: Option<Content>, format: &Format) -> ApiResponse {
+    let mut root = Element::new("subsonic-response")
+        .attr("status", status)
+        .attr("version", API_VERSION)
+        .attr("type", "rustyplayer")
+        .attr("serverVersion", env!("CARGO_PKG_VERSION"))
+        .attr("openSubsonic", true);
+    root.children.extend(payload);
+    let json = || serde_json::json!({ "subsonic-response": root.to_json() }).to_string();
+    match format {
+        Format::Json => ApiResponse::bytes("application/json", json().into_bytes()),
+        Format::Jsonp(callback) => {
+            let body = format!("{}({});", callback, json());
+            ApiResponse::bytes("application/javascript", body.into_bytes())
+        }
+        Format::Xml => {
+            let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+            root.attrs
+                .insert(0, ("xmlns", "http://subsonic.org/restapi".into()));
+            root.write_xml(&mut xml);
+            ApiResponse::bytes("text/xml; charset=utf-8", xml.into_bytes())
+        }
+    }
+}
+
+fn track_id(id: &str) -> Result<TrackId, Fault> {
+    match Id::parse(id) {
+        Some(Id::Track(id)) => Ok(id),
+        _ => Err(Fault::not_found("Song")),
+    }
+}
+
+fn playlist_id(id: &str) -> Result<i64, Fault> {
+    match Id::parse(id) {
+        Some(Id::Playlist(id)) => Ok(id),
+        _ => Err(Fault::not_found("Playlist")),
+    }
+}
+
+fn track_param(db: &DB, request: &ApiRequest, name: &str) -> Result<Track, Fault> {
+    let id = request.param(name).ok_or_else(|| Fault::missing(name))?;
+    db.track(track_id(id)?)?
+        .ok_or_else(|| Fault::not_found("Song"))
+}
+
+fn playlist_param(request: &ApiRequest, name: &str) -> Result<i64, Fault> {
+    playlist_id(request.param(name).ok_or_else(|| Fault::missing(name))?)
+}
+
+fn playlist_duration(db: &DB, id: i64) -> Result<i64, Fault> {
+    Ok(db
+        .playlist_tracks(id)?
+        .iter()
+        .filter_map(|t| t.duration_seconds)
+        .sum())
+}
+
+/// Transcode `path`, or the part of it between two times in milliseconds
+fn transcode(
+    transcoder: &Path,
+    path: &Path,
+    span: Option<(i64, Option<i64>)>,
+    format: &str,
+    bitrate: u32,
+) -> Result<ApiResponse, Fault> {
+    let (muxer, mime) = match format {
+        "mp3" => ("mp3", "audio/mpeg"),
+        "opus" => ("opus", "audio/ogg"),
+        "ogg" | "vorbis" => ("ogg", "audio/ogg"),
+        "aac" | "m4a" => ("adts", "audio/aac"),
+        "flac" => ("flac", "audio/flac"),
+        other => {
+            return Err(Fault::generic(format!(
+                "Unsupported transcode format: {}",
+                other
+            )));
+        }
+    };
+    let seconds = |ms: i64| format!("{}.{:03}", ms / 1000, ms % 1000);
+    let mut command = Command::new(transcoder);
+    command.args(["-v", "quiet"]);
+    if let Some((start, end)) = span {
+        command.arg("-ss").arg(seconds(start));
+        if let Some(end) = end {
+            command.arg("-to").arg(seconds(end));
+        }
+    }
+    let mut child = command
+        .arg("-i")
+        .arg(path)
+        .args(["-map", "0:a:0", "-vn", "-b:a"])
+        .arg(format!("{}k", bitrate))
+        .args(["-f", muxer, "-"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| Fault::generic(format!("Failed to start transcoder: {}", e)))?;
+    let stdout = child.stdout.take().expect("stdout is piped");
+    Ok(ApiResponse {
+        status: 200,
+        content_type: mime.to_owned(),
+        body: Body::Stream(Box::new(Transcode { child, stdout }), None),
+    })
+}
+
+/// Cover image for a track: a `cover.jpg`-style file in its directory, or the
+/// first picture embedded in its tags.
+fn find_cover(path: &Path) -> Option<(String, Vec<u8>)> {
+    if let Some(entries) = path.parent().and_then(|dir| fs::read_dir(dir).ok()) {
+        for entry in entries.flatten() {
+            let candidate = entry.path();
+            let stem = candidate
+                .file_stem()
+                .map(|s| s.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            let suffix = suffix(&candidate);
+            if COVER_NAMES.contains(&stem.as_str())
+                && matches!(suffix.as_str(), "jpg" | "jpeg" | "png")
+                && let Ok(data) = fs::read(&candidate)
+            {
+                return Some((content_type(&suffix).to_owned(), data));
+            }
+        }
+    }
+    embedded_cover(path)
+}
+
+fn embedded_cover(path: &Path) -> Option<(String, Vec<u8>)> {
+    use symphonia::core::io::MediaSourceStream;
+    use symphonia::core::probe::Hint;
+
+    let file = File::open(path).ok()?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    hint.with_extension(&suffix(path));
+    let mut probed = crate::formats::get()
+        .format(&hint, mss, &Default::default(), &Default::default())
+        .ok()?;
+
+    let from_probe = probed
+        .metadata
+        .get()
+        .and_then(|m| m.current().and_then(|rev| rev.visuals().first().cloned()));
+    let visual = from_probe.or_else(|| {
+        probed
+            .format
+            .metadata()
+            .current()
+            .and_then(|rev| rev.visuals().first().cloned())
+    })?;
+    Some((visual.media_type, visual.data.into_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_round_trip() {
+        for id in [
+            Id::Track(TrackId(7)),
+            Id::Playlist(3),
+            Id::Artist(12),
+            Id::Album(5),
+        ] {
+            assert_eq!(Id::parse(&id.encode()), Some(id));
+        }
+        assert_eq!(Id::parse("tr-x"), None);
+        assert_eq!(Id::parse("al-zz"), None);
+    }
+
+    #[test]
+    fn test_iso8601() {
+        assert_eq!(iso8601(0), "1970-01-01T00:00:00Z");
+        assert_eq!(iso8601(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(iso8601(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_element_rendering() {
+        let element = Element::new("artists")
+            .attr("ignoredArticles", "")
+            .list("index", vec![Element::new("index").attr("name", "A & B")]);
+        let mut xml = String::new();
+        element.write_xml(&mut xml);
+        assert_eq!(
+            xml,
+            r#"<artists ignoredArticles=""><index name="A &amp; B"/></artists>"#
+        );
+        assert_eq!(
+            element.to_json(),
+            serde_json::json!({ "ignoredArticles": "", "index": [{ "name": "A & B" }] })
+        );
+    }
+}
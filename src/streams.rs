@@ -0,0 +1,180 @@
+//! Picking the audio stream to play from files that hold several, or that
+//! hold video and subtitles too, such as MP4, Matroska and WebM.
+
+use serde::{Deserialize, Serialize};
+use symphonia::core::codecs::CODEC_TYPE_NULL;
+use symphonia::core::formats::Track;
+
+/// Which audio stream of a file to play. With neither set, or when nothing
+/// matches the language, it is the one the container marks as the default.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StreamChoice {
+    /// Position among the file's audio streams, counting from 0
+    pub index: Option<usize>,
+    /// Language tag such as `en`, `eng` or `en-US`
+    pub language: Option<String>,
+}
+
+/// An audio stream of the file playing
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AudioTrack {
+    /// The container's id for the stream, which `Player::select_track` takes
+    pub id: u32,
+    /// Position among the file's audio streams, counting from 0
+    pub index: usize,
+    /// Short codec name, such as `aac` or `vorbis`
+    pub codec: String,
+    pub language: Option<String>,
+    pub channels: Option<usize>,
+    pub sample_rate: Option<u32>,
+    pub bits_per_sample: Option<u32>,
+    /// Whether this is the stream playing
+    pub selected: bool,
+}
+
+impl AudioTrack {
+    /// A choice that finds this stream again: by language where the file
+    /// names one, else by position
+    pub fn choice(&self) -> StreamChoice {
+        match &self.language {
+            Some(language) => StreamChoice {
+                index: None,
+                language: Some(language.clone()),
+            },
+            None => StreamChoice {
+                index: Some(self.index),
+                language: None,
+            },
+        }
+    }
+}
+
+/// The audio streams among `tracks`, marking the one with id `selected`
+pub fn describe(tracks: &[Track], selected: Option<u32>) -> Vec<AudioTrack> {
+    let codecs = crate::codecs::get();
+    audio_tracks(tracks)
+        .into_iter()
+        .enumerate()
+        .map(|(index, track)| {
+            let params = &track.codec_params;
+            AudioTrack {
+                id: track.id,
+                index,
+                codec: codecs
+                    .get_codec(params.codec)
+                    .map_or_else(|| params.codec.to_string(), |codec| codec.short_name.into()),
+                language: track.language.clone(),
+                channels: params.channels.map(|channels| channels.count()),
+                sample_rate: params.sample_rate,
+                bits_per_sample: params.bits_per_sample,
+                selected: Some(track.id) == selected,
+            }
+        })
+        .collect()
+}
+
+/// Streams holding audio symphonia can decode, in container order. Video
+/// and subtitle streams have no codec symphonia knows, and no sample rate.
+pub fn audio_tracks(tracks: &[Track]) -> Vec<&Track> {
+    let codecs = crate::codecs::get();
+    tracks
+        .iter()
+        .filter(|track| {
+            let params = &track.codec_params;
+            params.codec != CODEC_TYPE_NULL
+                && params.sample_rate.is_some()
+                && codecs.get_codec(params.codec).is_some()
+        })
+        .collect()
+}
+
+/// The stream to play from `tracks`, given the one the container marks as
+/// its default. `None` if there is no audio, or no stream at `choice.index`.
+pub fn choose<'a>(
+    tracks: &'a [Track],
+    default: Option<&Track>,
+    choice: &StreamChoice,
+) -> Option<&'a Track> {
+    let audio = audio_tracks(tracks);
+    if let Some(index) = choice.index {
+        return audio.get(index).copied();
+    }
+    let by_language = choice.language.as_deref().and_then(|wanted| {
+        audio.iter().copied().find(|track| {
+            track
+                .language
+                .as_deref()
+                .is_some_and(|language| same_language(language, wanted))
+        })
+    });
+    let by_default = || {
+        let id = default?.id;
+        audio.iter().copied().find(|track| track.id == id)
+    };
+    by_language
+        .or_else(by_default)
+        .or_else(|| audio.first().copied())
+}
+
+/// Compare the primary language of two tags, so `en-GB` matches `en` and the
+/// ISO 639-2 `eng` that Matroska uses
+fn same_language(a: &str, b: &str) -> bool {
+    let primary = |tag: &str| {
+        tag.split(['-', '_'])
+            .next()
+            .unwrap_or("")
+            .to_ascii_lowercase()
+    };
+    let (a, b) = (primary(a), primary(b));
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    !short.is_empty() && long.starts_with(&short)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::codecs::{CODEC_TYPE_FLAC, CodecParameters};
+
+    fn track(id: u32, audio: bool, language: Option<&str>) -> Track {
+        let mut params = CodecParameters::new();
+        if audio {
+            params.for_codec(CODEC_TYPE_FLAC).with_sample_rate(48_000);
+        }
+        Track {
+            id,
+            codec_params: params,
+            language: language.map(String::from),
+        }
+    }
+
+    #[test]
+    fn test_choose_stream() {
+        let tracks = [
+            track(1, false, Some("eng")),
+            track(2, true, Some("eng")),
+            track(3, true, Some("fre")),
+            track(4, true, Some("ger")),
+        ];
+        let pick = |index, language: Option<&str>, default| {
+            let choice = StreamChoice {
+                index,
+                language: language.map(String::from),
+            };
+            choose(&tracks, default, &choice).map(|track| track.id)
+        };
+        assert_eq!(audio_tracks(&tracks).len(), 3);
+        // The video stream the container may list first is passed over
+        assert_eq!(pick(None, None, Some(&tracks[0])), Some(2));
+        assert_eq!(pick(None, None, Some(&tracks[3])), Some(4));
+        assert_eq!(pick(None, Some("fr-CA"), None), Some(3));
+        assert_eq!(pick(None, Some("ja"), Some(&tracks[3])), Some(4));
+        assert_eq!(pick(Some(1), Some("ger"), None), Some(3));
+        assert_eq!(pick(Some(3), None, None), None);
+
+        let listed = describe(&tracks, Some(3));
+        assert_eq!(listed.len(), 3);
+        assert_eq!((listed[1].index, listed[1].codec.as_str()), (1, "flac"));
+        assert!(listed[1].selected && !listed[0].selected);
+        assert_eq!(listed[1].choice().language.as_deref(), Some("fre"));
+    }
+}
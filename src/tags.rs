@@ -0,0 +1,580 @@
+//! Star ratings and ReplayGain stored in audio file tags.
+//!
+//! Ratings are read from ID3v2 `POPM` frames, `FMPS_Rating` (a 0.0–1.0 value in
+//! an ID3v2 `TXXX` frame or a Vorbis comment) and plain `RATING` comments, and
+//! written back to MP3 (ID3v2) and FLAC files. Other formats are read-only.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use symphonia::core::meta::{StandardTagKey, Tag};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TagError {
+    #[error("Writing tags to {0} files is not supported")]
+    UnsupportedFormat(String),
+    #[error("Invalid {format} tag: {message}")]
+    Invalid {
+        format: &'static str,
+        message: String,
+    },
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// `POPM` rating bytes for half stars 0.5 to 5, as written by Windows Media Player
+/// and MusicBee
+const POPM_STEPS: [u8; 10] = [13, 1, 54, 64, 118, 128, 186, 196, 242, 255];
+
+/// Email of `POPM` frames this player adds
+const POPM_EMAIL: &str = "rustyplayer";
+
+/// Stars (0.5–5) for a `POPM` rating byte; 0 means unrated
+pub fn popm_to_stars(byte: u8) -> Option<f64> {
+    if byte == 0 {
+        return None;
+    }
+    let (step, _) = POPM_STEPS
+        .iter()
+        .enumerate()
+        .min_by_key(|(_, value)| (i16::from(**value) - i16::from(byte)).abs())?;
+    Some((step + 1) as f64 / 2.0)
+}
+
+/// `POPM` rating byte for a rating, rounded to the nearest half star
+pub fn stars_to_popm(stars: f64) -> u8 {
+    match (stars * 2.0).round() as usize {
+        0 => 0,
+        steps => POPM_STEPS[steps.min(10) - 1],
+    }
+}
+
+fn half_stars(stars: f64) -> f64 {
+    ((stars * 2.0).round() / 2.0).clamp(0.0, 5.0)
+}
+
+/// The rating in a set of tags, if any. `FMPS_Rating` wins over `POPM`, which wins
+/// over `RATING`, since the former is least ambiguous.
+pub fn rating_from_tags(tags: &[Tag]) -> Option<f64> {
+    let text = |tag: &Tag| tag.value.to_string().trim_matches(['\0', ' ']).to_owned();
+    let fmps = tags
+        .iter()
+        .filter(|tag| {
+            let key = tag.key.strip_prefix("TXXX:").unwrap_or(&tag.key);
+            key.eq_ignore_ascii_case("FMPS_Rating")
+        })
+        .find_map(|tag| text(tag).parse::<f64>().ok())
+        .filter(|value| (0.0..=1.0).contains(value));
+    if let Some(value) = fmps {
+        return Some(half_stars(value * 5.0));
+    }
+    let rated = |popm: bool| {
+        tags.iter()
+            .filter(|tag| tag.std_key == Some(StandardTagKey::Rating))
+            .filter(move |tag| tag.key.starts_with("POPM") == popm)
+            .find_map(|tag| text(tag).parse::<f64>().ok())
+    };
+    if let Some(byte) = rated(true) {
+        return popm_to_stars(byte.clamp(0.0, 255.0) as u8);
+    }
+    // Plain RATING comments are either stars or a percentage
+    rated(false)
+        .filter(|value| *value > 0.0 && *value <= 100.0)
+        .map(|value| half_stars(if value <= 5.0 { value } else { value / 20.0 }))
+}
+
+/// A ReplayGain adjustment: the gain in dB and the peak sample it was measured with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Gain {
+    pub db: f64,
+    pub peak: Option<f64>,
+}
+
+/// The track or album ReplayGain in a set of tags. Files without album gain use
+/// their track gain.
+pub fn replaygain_from_tags(tags: &[Tag], album: bool) -> Option<Gain> {
+    let find = |key: StandardTagKey, name: &str| {
+        tags.iter()
+            .filter(|tag| {
+                let tag_name = tag.key.strip_prefix("TXXX:").unwrap_or(&tag.key);
+                tag.std_key == Some(key) || tag_name.eq_ignore_ascii_case(name)
+            })
+            .find_map(|tag| {
+                let text = tag.value.to_string();
+                let number = text.trim_matches(['\0', ' ']);
+                let number = number
+                    .strip_suffix("dB")
+                    .or_else(|| number.strip_suffix("db"))
+                    .unwrap_or(number);
+                number.trim().parse::<f64>().ok()
+            })
+    };
+    let track = || {
+        Some(Gain {
+            db: find(StandardTagKey::ReplayGainTrackGain, "REPLAYGAIN_TRACK_GAIN")?,
+            peak: find(StandardTagKey::ReplayGainTrackPeak, "REPLAYGAIN_TRACK_PEAK"),
+        })
+    };
+    if !album {
+        return track();
+    }
+    match find(StandardTagKey::ReplayGainAlbumGain, "REPLAYGAIN_ALBUM_GAIN") {
+        Some(db) => Some(Gain {
+            db,
+            peak: find(StandardTagKey::ReplayGainAlbumPeak, "REPLAYGAIN_ALBUM_PEAK"),
+        }),
+        None => track(),
+    }
+}
+
+/// Write a rating (or clear it, with `None`) to the tags of an MP3 or FLAC file.
+/// Existing `POPM` frames keep their email and play counter.
+pub fn write_rating(path: &Path, stars: Option<f64>) -> Result<(), TagError> {
+    let extension = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .unwrap_or("")
+        .to_ascii_lowercase();
+    let data = fs::read(path)?;
+    let updated = match extension.as_str() {
+        "mp3" => id3::set_rating(&data, stars)?,
+        "flac" => flac::set_rating(&data, stars)?,
+        _ => return Err(TagError::UnsupportedFormat(extension)),
+    };
+    replace_file(path, &updated)
+}
+
+/// Replace a file's contents without leaving it half written
+fn replace_file(path: &Path, data: &[u8]) -> Result<(), TagError> {
+    let dir = path.parent().filter(|p| !p.as_os_str().is_empty());
+    let mut temp = tempfile::NamedTempFile::new_in(dir.unwrap_or(Path::new(".")))?;
+    temp.write_all(data)?;
+    if let Ok(metadata) = fs::metadata(path) {
+        fs::set_permissions(temp.path(), metadata.permissions())?;
+    }
+    temp.persist(path).map_err(|e| TagError::Io(e.error))?;
+    Ok(())
+}
+
+fn fmps_value(stars: f64) -> String {
+    let value = half_stars(stars) / 5.0;
+    format!("{}", (value * 10.0).round() / 10.0)
+}
+
+mod id3 {
+    use super::{POPM_EMAIL, TagError, fmps_value, stars_to_popm};
+
+    fn invalid(message: &str) -> TagError {
+        TagError::Invalid {
+            format: "ID3v2",
+            message: message.into(),
+        }
+    }
+
+    fn syncsafe(bytes: &[u8]) -> usize {
+        bytes
+            .iter()
+            .fold(0, |n, b| (n << 7) | usize::from(b & 0x7f))
+    }
+
+    fn to_syncsafe(n: usize) -> [u8; 4] {
+        [
+            (n >> 21) as u8 & 0x7f,
+            (n >> 14) as u8 & 0x7f,
+            (n >> 7) as u8 & 0x7f,
+            n as u8 & 0x7f,
+        ]
+    }
+
+    pub(super) struct Frame {
+        pub id: [u8; 4],
+        pub flags: [u8; 2],
+        pub body: Vec<u8>,
+    }
+
+    /// Split a tag into its version, frames and the audio that follows it
+    pub(super) fn frames(data: &[u8]) -> Result<(u8, Vec<Frame>, &[u8]), TagError> {
+        if !data.starts_with(b"ID3") {
+            return Ok((4, Vec::new(), data));
+        }
+        if data.len() < 10 {
+            return Err(invalid("truncated header"));
+        }
+        let version = data[3];
+        if !(3..=4).contains(&version) {
+            return Err(invalid(&format!("version 2.{} is not supported", version)));
+        }
+        if data[5] & 0xc0 != 0 {
+            return Err(invalid(
+                "unsynchronised tags and extended headers are not supported",
+            ));
+        }
+        let end = 10 + syncsafe(&data[6..10]);
+        let footer = if version == 4 && data[5] & 0x10 != 0 {
+            10
+        } else {
+            0
+        };
+        let body = data.get(10..end).ok_or_else(|| invalid("truncated tag"))?;
+        let audio = data.get(end + footer..).unwrap_or_default();
+
+        let mut frames = Vec::new();
+        let mut at = 0;
+        while at + 10 <= body.len() && body[at] != 0 {
+            let header = &body[at..at + 10];
+            let size = if version == 4 {
+                syncsafe(&header[4..8])
+            } else {
+                u32::from_be_bytes(header[4..8].try_into().unwrap()) as usize
+            };
+            let frame = body
+                .get(at + 10..at + 10 + size)
+                .ok_or_else(|| invalid("frame overruns tag"))?;
+            frames.push(Frame {
+                id: header[..4].try_into().unwrap(),
+                flags: [header[8], header[9]],
+                body: frame.to_vec(),
+            });
+            at += 10 + size;
+        }
+        Ok((version, frames, audio))
+    }
+
+    /// Description of a `TXXX` frame
+    pub(super) fn txxx_description(body: &[u8]) -> String {
+        let Some((&encoding, text)) = body.split_first() else {
+            return String::new();
+        };
+        if encoding == 1 || encoding == 2 {
+            let big_endian = encoding == 2 || text.starts_with(&[0xfe, 0xff]);
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|c| {
+                    if big_endian {
+                        u16::from_be_bytes([c[0], c[1]])
+                    } else {
+                        u16::from_le_bytes([c[0], c[1]])
+                    }
+                })
+                .skip_while(|u| *u == 0xfeff)
+                .take_while(|u| *u != 0)
+                .collect();
+            String::from_utf16_lossy(&units)
+        } else {
+            let end = text.iter().position(|b| *b == 0).unwrap_or(text.len());
+            text[..end].iter().map(|b| char::from(*b)).collect()
+        }
+    }
+
+    pub(super) fn set_rating(data: &[u8], stars: Option<f64>) -> Result<Vec<u8>, TagError> {
+        let (version, mut frames, audio) = frames(data)?;
+        let byte = stars.map_or(0, stars_to_popm);
+
+        frames.retain(|f| {
+            !(&f.id == b"TXXX" && txxx_description(&f.body).eq_ignore_ascii_case("FMPS_Rating"))
+        });
+        let mut has_popm = false;
+        for frame in frames.iter_mut().filter(|f| &f.id == b"POPM") {
+            let email_end = frame.body.iter().position(|b| *b == 0);
+            if let Some(at) = email_end
+                && at + 1 < frame.body.len()
+            {
+                frame.body[at + 1] = byte;
+                has_popm = true;
+            }
+        }
+        if let Some(stars) = stars {
+            if !has_popm {
+                let mut body = POPM_EMAIL.as_bytes().to_vec();
+                body.extend([0, byte]);
+                frames.push(Frame {
+                    id: *b"POPM",
+                    flags: [0, 0],
+                    body,
+                });
+            }
+            // Latin-1 is valid in both 2.3 and 2.4 and covers the ASCII we write
+            let mut body = vec![0];
+            body.extend_from_slice(b"FMPS_Rating\0");
+            body.extend_from_slice(fmps_value(stars).as_bytes());
+            frames.push(Frame {
+                id: *b"TXXX",
+                flags: [0, 0],
+                body,
+            });
+        }
+
+        let mut tag = Vec::new();
+        for frame in &frames {
+            tag.extend_from_slice(&frame.id);
+            if version == 4 {
+                tag.extend_from_slice(&to_syncsafe(frame.body.len()));
+            } else {
+                tag.extend_from_slice(&(frame.body.len() as u32).to_be_bytes());
+            }
+            tag.extend_from_slice(&frame.flags);
+            tag.extend_from_slice(&frame.body);
+        }
+        // Leave room so the next edit doesn't have to move the audio
+        tag.resize(tag.len() + 1024, 0);
+
+        let mut out = b"ID3".to_vec();
+        out.extend([version, 0, 0]);
+        out.extend_from_slice(&to_syncsafe(tag.len()));
+        out.extend_from_slice(&tag);
+        out.extend_from_slice(audio);
+        Ok(out)
+    }
+}
+
+mod flac {
+    use super::{TagError, fmps_value};
+
+    const VORBIS_COMMENT: u8 = 4;
+    const PADDING: u8 = 1;
+
+    fn invalid(message: &str) -> TagError {
+        TagError::Invalid {
+            format: "FLAC",
+            message: message.into(),
+        }
+    }
+
+    /// A metadata block type and body
+    type Block = (u8, Vec<u8>);
+
+    /// Metadata blocks and the audio frames after them
+    fn blocks(data: &[u8]) -> Result<(Vec<Block>, &[u8]), TagError> {
+        if !data.starts_with(b"fLaC") {
+            return Err(invalid("missing fLaC marker"));
+        }
+        let mut blocks = Vec::new();
+        let mut at = 4;
+        loop {
+            let header = data
+                .get(at..at + 4)
+                .ok_or_else(|| invalid("truncated metadata"))?;
+            let last = header[0] & 0x80 != 0;
+            let size = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+            let body = data
+                .get(at + 4..at + 4 + size)
+                .ok_or_else(|| invalid("truncated metadata block"))?;
+            blocks.push((header[0] & 0x7f, body.to_vec()));
+            at += 4 + size;
+            if last {
+                return Ok((blocks, &data[at..]));
+            }
+        }
+    }
+
+    fn take<'a>(block: &'a [u8], at: &mut usize, len: usize) -> Result<&'a [u8], TagError> {
+        let bytes = block
+            .get(*at..*at + len)
+            .ok_or_else(|| invalid("truncated comment block"))?;
+        *at += len;
+        Ok(bytes)
+    }
+
+    fn take_u32(block: &[u8], at: &mut usize) -> Result<usize, TagError> {
+        Ok(u32::from_le_bytes(take(block, at, 4)?.try_into().unwrap()) as usize)
+    }
+
+    /// Vendor string and comments of a `VORBIS_COMMENT` block
+    pub(super) fn comments(block: &[u8]) -> Result<(Vec<u8>, Vec<String>), TagError> {
+        let mut at = 0;
+        let vendor_len = take_u32(block, &mut at)?;
+        let vendor = take(block, &mut at, vendor_len)?.to_vec();
+        let count = take_u32(block, &mut at)?;
+        let mut comments = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let len = take_u32(block, &mut at)?;
+            comments.push(String::from_utf8_lossy(take(block, &mut at, len)?).into_owned());
+        }
+        Ok((vendor, comments))
+    }
+
+    pub(super) fn set_rating(data: &[u8], stars: Option<f64>) -> Result<Vec<u8>, TagError> {
+        let (mut blocks, audio) = blocks(data)?;
+        let size = |blocks: &[Block]| blocks.iter().map(|(_, b)| 4 + b.len()).sum::<usize>();
+        let before = size(&blocks);
+        let index = match blocks.iter().position(|(kind, _)| *kind == VORBIS_COMMENT) {
+            Some(index) => index,
+            None => {
+                let vendor = b"rustyplayer";
+                let mut body = (vendor.len() as u32).to_le_bytes().to_vec();
+                body.extend_from_slice(vendor);
+                body.extend_from_slice(&0u32.to_le_bytes());
+                // STREAMINFO must stay first
+                blocks.insert(1, (VORBIS_COMMENT, body));
+                1
+            }
+        };
+
+        let (vendor, mut comments) = comments(&blocks[index].1)?;
+        comments.retain(|c| {
+            c.split_once('=')
+                .is_none_or(|(key, _)| !key.eq_ignore_ascii_case("FMPS_RATING"))
+        });
+        if let Some(stars) = stars {
+            comments.push(format!("FMPS_RATING={}", fmps_value(stars)));
+        }
+        let mut body = (vendor.len() as u32).to_le_bytes().to_vec();
+        body.extend_from_slice(&vendor);
+        body.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in &comments {
+            body.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            body.extend_from_slice(comment.as_bytes());
+        }
+        blocks[index].1 = body;
+
+        // Take the difference out of padding when there is enough of it
+        let grown = size(&blocks) as isize - before as isize;
+        if let Some(padding) = blocks.iter_mut().find(|(kind, _)| *kind == PADDING) {
+            let len = padding.1.len() as isize - grown;
+            if len >= 0 {
+                padding.1.resize(len as usize, 0);
+            }
+        }
+
+        let mut out = b"fLaC".to_vec();
+        let count = blocks.len();
+        for (i, (kind, body)) in blocks.iter().enumerate() {
+            if body.len() >= 1 << 24 {
+                return Err(invalid("metadata block too large"));
+            }
+            let last = if i + 1 == count { 0x80 } else { 0 };
+            out.push(kind | last);
+            out.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+            out.extend_from_slice(body);
+        }
+        out.extend_from_slice(audio);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::meta::Value;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_popm_conversions() {
+        for half in 1..=10 {
+            let stars = f64::from(half) / 2.0;
+            assert_eq!(popm_to_stars(stars_to_popm(stars)), Some(stars));
+        }
+        assert_eq!(stars_to_popm(0.0), 0);
+        assert_eq!(popm_to_stars(0), None);
+        assert_eq!(popm_to_stars(200), Some(4.0));
+
+        let tag = |std_key, key: &str, value: Value| Tag::new(std_key, key, value);
+        let popm = tag(
+            Some(StandardTagKey::Rating),
+            "POPM:x",
+            Value::UnsignedInt(242),
+        );
+        let fmps = tag(None, "TXXX:FMPS_Rating", Value::from("0.6"));
+        let percent = tag(Some(StandardTagKey::Rating), "RATING", Value::from("80"));
+        assert_eq!(rating_from_tags(&[popm.clone(), fmps]), Some(3.0));
+        assert_eq!(rating_from_tags(&[popm, percent.clone()]), Some(4.5));
+        assert_eq!(rating_from_tags(&[percent]), Some(4.0));
+        assert_eq!(rating_from_tags(&[]), None);
+    }
+
+    #[test]
+    fn test_replaygain() {
+        let tags = [
+            Tag::new(
+                Some(StandardTagKey::ReplayGainTrackGain),
+                "REPLAYGAIN_TRACK_GAIN",
+                Value::from("-6.50 dB"),
+            ),
+            Tag::new(None, "TXXX:REPLAYGAIN_TRACK_PEAK", Value::from("0.988")),
+        ];
+        let track = Some(Gain {
+            db: -6.5,
+            peak: Some(0.988),
+        });
+        assert_eq!(replaygain_from_tags(&tags, false), track);
+        assert_eq!(replaygain_from_tags(&tags, true), track);
+
+        let album = Tag::new(None, "replaygain_album_gain", Value::from("+1.2 dB"));
+        let gain = replaygain_from_tags(&[tags[0].clone(), album], true).unwrap();
+        assert_eq!((gain.db, gain.peak), (1.2, None));
+        assert_eq!(replaygain_from_tags(&[], false), None);
+    }
+
+    #[test]
+    fn test_write_id3_rating() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("song.mp3");
+        // A 2.3 tag with a title and a POPM frame carrying a play counter
+        let mut frames = Vec::new();
+        for (id, body) in [
+            (b"TIT2", b"\0Title".to_vec()),
+            (b"POPM", b"a@b\0\x40\0\0\0\x07".to_vec()),
+        ] {
+            frames.extend_from_slice(id);
+            frames.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            frames.extend_from_slice(&[0, 0]);
+            frames.extend_from_slice(&body);
+        }
+        let mut data = b"ID3\x03\0\0\0\0\0".to_vec();
+        data.push(frames.len() as u8);
+        data.extend_from_slice(&frames);
+        data.extend_from_slice(b"AUDIO");
+        fs::write(&path, &data).unwrap();
+
+        write_rating(&path, Some(4.5)).unwrap();
+        let written = fs::read(&path).unwrap();
+        let (version, frames, audio) = id3::frames(&written).unwrap();
+        assert_eq!((version, audio), (3, &b"AUDIO"[..]));
+        let ids: Vec<_> = frames.iter().map(|f| &f.id).collect();
+        assert_eq!(ids, vec![b"TIT2", b"POPM", b"TXXX"]);
+        assert_eq!(frames[1].body, b"a@b\0\xf2\0\0\0\x07");
+        assert_eq!(id3::txxx_description(&frames[2].body), "FMPS_Rating");
+        assert!(frames[2].body.ends_with(b"\x000.9"));
+
+        // Clearing keeps the counter and drops FMPS_Rating
+        write_rating(&path, None).unwrap();
+        let written = fs::read(&path).unwrap();
+        let (_, frames, _) = id3::frames(&written).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1].body, b"a@b\0\0\0\0\0\x07");
+
+        let other = dir.path().join("song.ogg");
+        fs::write(&other, b"OggS").unwrap();
+        assert!(matches!(
+            write_rating(&other, Some(1.0)),
+            Err(TagError::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_write_flac_rating() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("song.flac");
+        let mut data = b"fLaC".to_vec();
+        data.push(0); // STREAMINFO
+        data.extend_from_slice(&[0, 0, 34]);
+        data.extend_from_slice(&[0; 34]);
+        data.push(0x80 | 1); // PADDING, last
+        data.extend_from_slice(&[0, 0, 64]);
+        data.extend_from_slice(&[0; 64]);
+        data.extend_from_slice(b"FRAMES");
+        fs::write(&path, &data).unwrap();
+
+        write_rating(&path, Some(2.5)).unwrap();
+        write_rating(&path, Some(3.0)).unwrap();
+        let written = fs::read(&path).unwrap();
+        assert_eq!(written.len(), data.len());
+        assert!(written.ends_with(b"FRAMES"));
+        assert_eq!(written[42], 4);
+        let size = u32::from_be_bytes([0, written[43], written[44], written[45]]) as usize;
+        let (vendor, comments) = flac::comments(&written[46..46 + size]).unwrap();
+        assert_eq!(vendor, b"rustyplayer");
+        assert_eq!(comments, vec!["FMPS_RATING=0.6"]);
+    }
+}
@@ -0,0 +1,307 @@
+//! Live library sync: watches library roots (inotify on Linux) and applies
+//! file changes to the library as they happen.
+//!
+//! Events are collected until the roots have been quiet for a moment, so a
+//! copy or a batch retag becomes one update. Renames move tracks in place,
+//! keeping their ids and history, and a renamed directory is followed without
+//! reading any of its files again.
+
+use anyhow::{Context, Result, bail};
+use notify::event::{AccessKind, AccessMode, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::db::DB;
+use crate::scanner::{self, ScanReport};
+
+/// How long the roots must be quiet before a burst of changes is applied
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// Longest a change waits while events keep arriving
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Changes gathered from one burst of events
+#[derive(Debug, Default, PartialEq)]
+struct Changes {
+    /// Renames, in the order they happened
+    moves: Vec<(PathBuf, PathBuf)>,
+    /// Paths to check against the filesystem once the burst is over
+    touched: BTreeSet<PathBuf>,
+    /// Events were lost, so every root has to be reconciled
+    rescan: bool,
+}
+
+impl Changes {
+    fn add(&mut self, event: notify::Result<Event>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(_) => {
+                self.rescan = true;
+                return;
+            }
+        };
+        if event.need_rescan() {
+            self.rescan = true;
+        }
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                let (from, to) = (event.paths[0].clone(), event.paths[1].clone());
+                // The halves of the rename were reported on their own first
+                self.touched.remove(&from);
+                self.touched.remove(&to);
+                // Earlier changes inside a moved directory now live at its new path
+                self.touched = std::mem::take(&mut self.touched)
+                    .into_iter()
+                    .map(|path| match path.strip_prefix(&from) {
+                        Ok(rest) => to.join(rest),
+                        Err(_) => path,
+                    })
+                    .collect();
+                self.moves.push((from, to));
+            }
+            EventKind::Create(_)
+            | EventKind::Remove(_)
+            | EventKind::Modify(_)
+            | EventKind::Access(AccessKind::Close(AccessMode::Write)) => {
+                for path in event.paths {
+                    // Changes made just after a directory moved can still be
+                    // reported under its old path
+                    for (from, to) in &self.moves {
+                        if let Ok(rest) = path.strip_prefix(from) {
+                            self.touched.insert(to.join(rest));
+                        }
+                    }
+                    self.touched.insert(path);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.moves.is_empty() && self.touched.is_empty() && !self.rescan
+    }
+}
+
+/// Watches library roots and keeps the library in step with them
+pub struct LibraryWatcher {
+    // Dropping the watcher stops the events
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    roots: Vec<PathBuf>,
+}
+
+impl LibraryWatcher {
+    /// Start watching each of `roots` and everything below it
+    pub fn new(roots: &[PathBuf]) -> Result<Self> {
+        if roots.is_empty() {
+            bail!("No library directories to watch");
+        }
+        let (sender, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        let mut absolute = Vec::new();
+        for root in roots {
+            let root = std::path::absolute(root)?;
+            watcher
+                .watch(&root, RecursiveMode::Recursive)
+                .with_context(|| format!("watching {}", root.display()))?;
+            absolute.push(root);
+        }
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            roots: absolute,
+        })
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Catch up on changes made while nothing was watching. Call this after
+    /// `new`, so that nothing changed in between goes unnoticed.
+    pub fn reconcile(&self, db: &DB) -> Result<ScanReport> {
+        let mut report = ScanReport::default();
+        for root in &self.roots {
+            let root_report = scanner::reconcile(db, root)?;
+            report.imported += root_report.imported;
+            report.unchanged += root_report.unchanged;
+            report.removed += root_report.removed;
+            report.failed.extend(root_report.failed);
+        }
+        Ok(report)
+    }
+
+    /// Wait up to `timeout` for changes, then apply the whole burst once the
+    /// roots settle. Returns `None` if nothing relevant happened.
+    pub fn poll(&self, db: &DB, timeout: Duration) -> Result<Option<ScanReport>> {
+        let first = match self.events.recv_timeout(timeout) {
+            Ok(event) => event,
+            Err(RecvTimeoutError::Timeout) => return Ok(None),
+            Err(RecvTimeoutError::Disconnected) => bail!("The file watcher stopped"),
+        };
+        let started = Instant::now();
+        let mut changes = Changes::default();
+        changes.add(first);
+        while started.elapsed() < MAX_DELAY {
+            match self.events.recv_timeout(DEBOUNCE) {
+                Ok(event) => changes.add(event),
+                Err(_) => break,
+            }
+        }
+        if changes.is_empty() {
+            return Ok(None);
+        }
+        self.apply(db, changes).map(Some)
+    }
+
+    fn apply(&self, db: &DB, changes: Changes) -> Result<ScanReport> {
+        if changes.rescan {
+            return self.reconcile(db);
+        }
+        let mut report = ScanReport::default();
+        let mut touched = changes.touched;
+        for (from, to) in changes.moves {
+            let moved = db.move_tracks(&from.to_string_lossy(), &to.to_string_lossy())?;
+            report.moved += moved;
+            // Nothing known there: it may have been created and renamed in one burst
+            if moved == 0 {
+                touched.insert(to);
+            }
+        }
+        for path in &touched {
+            scanner::sync_path(db, path, &mut report)?;
+        }
+        db.prune_catalog()?;
+        Ok(report)
+    }
+
+    /// Keep the library in sync on a background thread with its own connection
+    /// to the database, starting with a full reconcile
+    pub fn spawn(self, db: DB) {
+        thread::spawn(move || {
+            match self.reconcile(&db) {
+                Ok(report) => log(&report),
+                Err(e) => eprintln!("Library sync failed: {:#}", e),
+            }
+            loop {
+                match self.poll(&db, Duration::from_secs(3600)) {
+                    Ok(Some(report)) => log(&report),
+                    Ok(None) => {}
+                    Err(e) => {
+                        eprintln!("Library sync stopped: {:#}", e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+/// Print a one-line summary of a sync, and any files that failed
+pub fn log(report: &ScanReport) {
+    for (file, error) in &report.failed {
+        eprintln!("Skipped {}: {}", file.display(), error);
+    }
+    if report.imported + report.moved + report.removed > 0 {
+        println!(
+            "Library sync: {} imported, {} moved, {} removed",
+            report.imported, report.moved, report.removed
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::tests::wav;
+    use notify::event::{CreateKind, RemoveKind};
+    use tempfile::{NamedTempFile, tempdir};
+
+    fn event(kind: EventKind, paths: &[&str]) -> notify::Result<Event> {
+        let mut event = Event::new(kind);
+        for path in paths {
+            event = event.add_path(PathBuf::from(path));
+        }
+        Ok(event)
+    }
+
+    #[test]
+    fn test_directory_rename_is_one_move() {
+        let rename = |mode| EventKind::Modify(ModifyKind::Name(mode));
+        let mut changes = Changes::default();
+        changes.add(event(
+            EventKind::Create(CreateKind::File),
+            &["/music/a/new.flac"],
+        ));
+        changes.add(event(rename(RenameMode::From), &["/music/a"]));
+        changes.add(event(rename(RenameMode::To), &["/music/b"]));
+        changes.add(event(rename(RenameMode::Both), &["/music/a", "/music/b"]));
+        changes.add(event(
+            EventKind::Remove(RemoveKind::File),
+            &["/music/c.flac"],
+        ));
+        changes.add(event(
+            EventKind::Remove(RemoveKind::File),
+            &["/music/a/old.flac"],
+        ));
+        assert_eq!(
+            changes,
+            Changes {
+                moves: vec![("/music/a".into(), "/music/b".into())],
+                touched: BTreeSet::from([
+                    "/music/a/old.flac".into(),
+                    "/music/b/new.flac".into(),
+                    "/music/b/old.flac".into(),
+                    "/music/c.flac".into(),
+                ]),
+                rescan: false,
+            }
+        );
+    }
+
+    #[test]
+    fn test_watch_follows_changes() {
+        let dir = tempdir().unwrap();
+        let album = dir.path().join("Album");
+        std::fs::create_dir(&album).unwrap();
+        std::fs::write(album.join("one.wav"), wav(&[(b"INAM", "One")])).unwrap();
+        std::fs::write(album.join("two.wav"), wav(&[(b"INAM", "Two")])).unwrap();
+
+        let file = NamedTempFile::new().unwrap();
+        let db = DB::open(file.path()).unwrap();
+        let watcher = LibraryWatcher::new(&[dir.path().to_owned()]).unwrap();
+        assert_eq!(watcher.reconcile(&db).unwrap().imported, 2);
+        let id = db
+            .track_by_path(&album.join("one.wav").to_string_lossy())
+            .unwrap()
+            .unwrap()
+            .id;
+
+        let renamed = dir.path().join("Renamed");
+        std::fs::rename(&album, &renamed).unwrap();
+        std::fs::remove_file(renamed.join("two.wav")).unwrap();
+        std::fs::write(renamed.join("three.wav"), wav(&[(b"INAM", "Three")])).unwrap();
+
+        let mut report = ScanReport::default();
+        let deadline = Instant::now() + Duration::from_secs(20);
+        while report.imported + report.moved + report.removed < 4 && Instant::now() < deadline {
+            if let Some(burst) = watcher.poll(&db, Duration::from_secs(5)).unwrap() {
+                report.imported += burst.imported;
+                report.moved += burst.moved;
+                report.removed += burst.removed;
+            }
+        }
+        assert_eq!((report.moved, report.removed, report.imported), (2, 1, 1));
+        let moved = db
+            .track_by_path(&renamed.join("one.wav").to_string_lossy())
+            .unwrap()
+            .unwrap();
+        assert_eq!(moved.id, id);
+        assert_eq!(db.track_count().unwrap(), 2);
+    }
+}